@@ -9,10 +9,23 @@ method=${1:-"fast"}
 case "$method" in
 	fast)
 		./download.sh 'libs/*'
+
+		# The release archive doesn't ship the archive libraries yet.
+		if ! [ -e libs/libarchive.so.13 ]; then
+			cd thirdparty
+			./download.sh zlib bzip2 xz libarchive
+			./build.sh zlib bzip2 xz libarchive
+			cd ..
+			cp thirdparty/xz/src/liblzma/.libs/liblzma.so libs/liblzma.so.5
+			cp thirdparty/libarchive/.libs/libarchive.so libs/libarchive.so.13
+		fi
+
 		cd libs
 		
 		ln -s libz.so.1 libz.so
 		ln -s libbz2.so.1.0 libbz2.so
+		ln -s liblzma.so.5 liblzma.so
+		ln -s libarchive.so.13 libarchive.so
 
 		ln -s libpng16.so.16 libpng16.so
 		ln -s libjpeg.so.9 libjpeg.so
@@ -38,6 +51,8 @@ case "$method" in
 
 		cp thirdparty/zlib/libz.so libs
 		cp thirdparty/bzip2/libbz2.so libs
+		cp thirdparty/xz/src/liblzma/.libs/liblzma.so libs
+		cp thirdparty/libarchive/.libs/libarchive.so libs
 
 		cp thirdparty/libpng/.libs/libpng16.so libs
 		cp thirdparty/libjpeg/.libs/libjpeg.so libs
@@ -60,7 +75,7 @@ case "$method" in
 		;;
 esac
 
-cargo build --release --target=arm-unknown-linux-gnueabihf
+cargo build --release --features opds,sqlite --target=arm-unknown-linux-gnueabihf
 RESULT=$?
 if [ $RESULT -eq 0 ]; then
 	cp target/arm-unknown-linux-gnueabihf/release/plato .
@@ -10,6 +10,8 @@ mkdir dist/dictionaries
 
 cp libs/libz.so dist/libs/libz.so.1
 cp libs/libbz2.so dist/libs/libbz2.so.1.0
+cp libs/liblzma.so dist/libs/liblzma.so.5
+cp libs/libarchive.so dist/libs/libarchive.so.13
 
 cp libs/libpng16.so dist/libs/libpng16.so.16
 cp libs/libjpeg.so dist/libs/libjpeg.so.9
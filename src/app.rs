@@ -21,11 +21,15 @@ use crate::view::keyboard::{Layout};
 use crate::view::dictionary::Dictionary as DictionaryApp;
 use crate::view::calculator::Calculator;
 use crate::view::sketch::Sketch;
+#[cfg(feature = "opds")]
+use crate::view::opds::Opds;
+use crate::view::statistics::Statistics;
 use crate::input::{DeviceEvent, PowerSource, ButtonCode, ButtonStatus, VAL_RELEASE, VAL_PRESS};
 use crate::input::{raw_events, device_events, usb_events, display_rotate_event, button_scheme_event};
 use crate::gesture::{GestureEvent, gesture_events};
-use crate::helpers::{load_json, save_json, load_toml, save_toml};
-use crate::metadata::{Metadata, METADATA_FILENAME, auto_import};
+use crate::helpers::{load_json, load_toml, save_toml};
+use crate::metadata::{Info, Metadata, auto_import};
+use crate::library::{Library, open_library};
 use crate::settings::{ButtonScheme, Settings, SETTINGS_PATH, RotationLock};
 use crate::frontlight::{Frontlight, StandardFrontlight, NaturalFrontlight, PremixedFrontlight};
 use crate::lightsensor::{LightSensor, KoboLightSensor};
@@ -39,6 +43,7 @@ use crate::view::notification::Notification;
 use crate::device::{CURRENT_DEVICE, Orientation, FrontlightKind, INTERNAL_CARD_ROOT};
 use crate::font::Fonts;
 use crate::rtc::Rtc;
+use crate::document::passwords::{PasswordStore, PASSWORDS_PATH};
 
 pub const APP_NAME: &str = "Plato";
 const INPUT_HISTORY_SIZE: usize = 32;
@@ -55,11 +60,13 @@ pub struct Context {
     pub display: Display,
     pub settings: Settings,
     pub metadata: Metadata,
+    pub library: Box<dyn Library>,
     pub filename: PathBuf,
     pub fonts: Fonts,
     pub dictionaries: BTreeMap<String, Dictionary>,
     pub keyboard_layouts: BTreeMap<String, Layout>,
     pub input_history: HashMap<ViewId, VecDeque<String>>,
+    pub passwords: PasswordStore,
     pub frontlight: Box<dyn Frontlight>,
     pub battery: Box<dyn Battery>,
     pub lightsensor: Box<dyn LightSensor>,
@@ -73,13 +80,13 @@ pub struct Context {
 
 impl Context {
     pub fn new(fb: Box<dyn Framebuffer>, rtc: Option<Rtc>, settings: Settings, metadata: Metadata,
-               filename: PathBuf, fonts: Fonts, battery: Box<dyn Battery>,
+               library: Box<dyn Library>, filename: PathBuf, fonts: Fonts, battery: Box<dyn Battery>,
                frontlight: Box<dyn Frontlight>, lightsensor: Box<dyn LightSensor>) -> Context {
         let dims = fb.dims();
         let rotation = CURRENT_DEVICE.transformed_rotation(fb.rotation());
         Context { fb, rtc, display: Display { dims, rotation },
-                  settings, metadata, filename, fonts, dictionaries: BTreeMap::new(), keyboard_layouts: BTreeMap::new(),
-                  input_history: HashMap::new(), battery, frontlight, lightsensor, notification_index: 0,
+                  settings, metadata, library, filename, fonts, dictionaries: BTreeMap::new(), keyboard_layouts: BTreeMap::new(),
+                  input_history: HashMap::new(), passwords: PasswordStore::default(), battery, frontlight, lightsensor, notification_index: 0,
                   kb_rect: Rectangle::default(), plugged: false, covered: false, shared: false, online: false }
     }
 
@@ -93,6 +100,10 @@ impl Context {
         }
     }
 
+    pub fn load_passwords(&mut self) {
+        self.passwords = PasswordStore::load(PASSWORDS_PATH);
+    }
+
     pub fn load_dictionaries(&mut self) {
         if let Ok(entries) = glob("dictionaries/**/*.index") {
             for entry in entries.into_iter().filter_map(|e| e.ok()) {
@@ -174,24 +185,39 @@ fn build_context(fb: Box<dyn Framebuffer>) -> Result<Context, Error> {
 
     let mut settings = settings.unwrap_or_default();
 
-    let path = settings.library_path.join(METADATA_FILENAME);
-    let mut metadata = load_json::<Metadata, _>(path)
+    let filename = PathBuf::from(settings.library_backend.filename());
+    let mut library = open_library(settings.library_path.join(&filename))
+                                  .context("Can't open library.")?;
+    let mut metadata = library.load()
                                  .map_err(|e| eprintln!("Can't load metadata: {}", e))
                                  .or_else(|_| auto_import(&settings.library_path,
                                                           &Vec::new(),
-                                                          &settings.import))
+                                                          &settings.import,
+                                                          &settings.reader.export_path)
+                                                 .map(|metadata| {
+                                                     library.save(&metadata)
+                                                            .map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
+                                                     metadata
+                                                 }))
                                  .unwrap_or_default();
 
     if initial_run && metadata.is_empty() && settings.library_path != PathBuf::from(INTERNAL_CARD_ROOT) {
         settings.library_path = PathBuf::from(INTERNAL_CARD_ROOT);
-        metadata = auto_import(&settings.library_path, &Vec::new(), &settings.import).unwrap_or_default();
+        library = open_library(settings.library_path.join(&filename))
+                              .context("Can't open library.")?;
+        metadata = auto_import(&settings.library_path, &Vec::new(),
+                               &settings.import, &settings.reader.export_path).unwrap_or_default();
+        library.save(&metadata).map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
     }
 
     if settings.import.startup_trigger {
-        let imported_metadata = auto_import(&settings.library_path,
-                                            &metadata,
-                                            &settings.import);
-        metadata.append(&mut imported_metadata.unwrap_or_default());
+        let mut imported_metadata = auto_import(&settings.library_path,
+                                                &metadata,
+                                                &settings.import,
+                                                &settings.reader.export_path).unwrap_or_default();
+        library.update(&imported_metadata.iter().collect::<Vec<&Info>>())
+               .map_err(|e| eprintln!("Can't add the imported metadata: {}", e)).ok();
+        metadata.append(&mut imported_metadata);
     }
 
     let fonts = Fonts::load().context("Can't load fonts.")?;
@@ -214,7 +240,7 @@ fn build_context(fb: Box<dyn Framebuffer>) -> Result<Context, Error> {
                                         .context("Can't create premixed frontlight.")?) as Box<dyn Frontlight>,
     };
 
-    Ok(Context::new(fb, rtc, settings, metadata, PathBuf::from(METADATA_FILENAME),
+    Ok(Context::new(fb, rtc, settings, metadata, library, filename,
                     fonts, battery, frontlight, lightsensor))
 }
 
@@ -305,6 +331,7 @@ pub fn run() -> Result<(), Error> {
 
     context.load_dictionaries();
     context.load_keyboard_layouts();
+    context.load_passwords();
 
     let paths = vec!["/dev/input/event0".to_string(),
                      "/dev/input/event1".to_string()];
@@ -546,18 +573,24 @@ pub fn run() -> Result<(), Error> {
                             if Path::new("/mnt/onboard/.kobo/KoboRoot.tgz").exists() {
                                 tx.send(Event::Select(EntryId::Reboot)).ok();
                             }
-                            let path = context.settings.library_path.join(&context.filename);
-                            let metadata = load_json::<Metadata, _>(path)
-                                                     .map_err(|e| eprintln!("Can't load metadata: {}", e))
-                                                     .unwrap_or_default();
+                            if let Ok(library) = open_library(context.settings.library_path.join(&context.filename))
+                                                            .map_err(|e| eprintln!("Can't open library: {}", e)) {
+                                context.library = library;
+                            }
+                            let metadata = context.library.load()
+                                                  .map_err(|e| eprintln!("Can't load metadata: {}", e))
+                                                  .unwrap_or_default();
                             if !metadata.is_empty() {
                                 context.metadata = metadata;
                             }
                             if context.settings.import.unshare_trigger {
-                                let metadata = auto_import(&context.settings.library_path,
-                                                           &context.metadata,
-                                                           &context.settings.import);
-                                context.metadata.append(&mut metadata.unwrap_or_default());
+                                let mut metadata = auto_import(&context.settings.library_path,
+                                                               &context.metadata,
+                                                               &context.settings.import,
+                                                               &context.settings.reader.export_path).unwrap_or_default();
+                                context.library.update(&metadata.iter().collect::<Vec<&Info>>())
+                                       .map_err(|e| eprintln!("Can't add the imported metadata: {}", e)).ok();
+                                context.metadata.append(&mut metadata);
                             }
                             view.handle_event(&Event::Reseed, &tx, &mut bus, &mut context);
                         } else {
@@ -627,8 +660,7 @@ pub fn run() -> Result<(), Error> {
                 updating.retain(|tok, _| context.fb.wait(*tok).is_err());
                 let path = Path::new(SETTINGS_PATH);
                 save_toml(&context.settings, path).map_err(|e| eprintln!("Can't save settings: {}", e)).ok();
-                let path = context.settings.library_path.join(&context.filename);
-                save_json(&context.metadata, path).map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
+                context.library.flush(&context.metadata).map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
                 if context.settings.frontlight {
                     context.settings.frontlight_levels = context.frontlight.levels();
                     context.frontlight.set_intensity(0.0);
@@ -701,8 +733,8 @@ pub fn run() -> Result<(), Error> {
                 }
                 let path = Path::new(SETTINGS_PATH);
                 save_toml(&context.settings, path).map_err(|e| eprintln!("Can't save settings: {}", e)).ok();
-                let path = context.settings.library_path.join(&context.filename);
-                save_json(&context.metadata, path).map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
+                context.library.flush(&context.metadata).map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
+                context.library.close();
                 if context.settings.frontlight {
                     context.settings.frontlight_levels = context.frontlight.levels();
                     context.frontlight.set_intensity(0.0);
@@ -853,6 +885,9 @@ pub fn run() -> Result<(), Error> {
                     },
                     AppCmd::Calculator => Box::new(Calculator::new(context.fb.rect(), &tx, &mut context)?),
                     AppCmd::Dictionary { ref query, ref language } => Box::new(DictionaryApp::new(context.fb.rect(), query, language, &tx, &mut context)),
+                    #[cfg(feature = "opds")]
+                    AppCmd::Opds => Box::new(Opds::new(context.fb.rect(), &tx, &mut context)),
+                    AppCmd::Statistics => Box::new(Statistics::new(context.fb.rect(), &tx, &mut context)),
                 };
                 transfer_notifications(view.as_mut(), next_view.as_mut(), &mut context);
                 history.push(HistoryItem {
@@ -986,6 +1021,30 @@ pub fn run() -> Result<(), Error> {
                                               msg, &tx, &mut context);
                 view.children_mut().push(Box::new(notif) as Box<dyn View>);
             },
+            // The password prompt belongs to the home view.
+            Event::AskPassword(..) if !view.is::<Home>() => {
+                view.handle_event(&Event::Back, &tx, &mut bus, &mut context);
+                while let Some(mut item) = history.pop() {
+                    if item.monochrome != context.fb.monochrome() {
+                        context.fb.set_monochrome(item.monochrome);
+                    }
+                    if item.rotation != context.display.rotation {
+                        updating.retain(|tok, _| context.fb.wait(*tok).is_err());
+                        if let Ok(dims) = context.fb.set_rotation(item.rotation) {
+                            raw_sender.send(display_rotate_event(item.rotation)).ok();
+                            context.display.rotation = item.rotation;
+                            context.display.dims = dims;
+                        }
+                    }
+                    if history.is_empty() {
+                        view = item.view;
+                        break;
+                    }
+                    item.view.handle_event(&Event::Back, &tx, &mut bus, &mut context);
+                }
+                view.handle_event(&Event::Reseed, &tx, &mut bus, &mut context);
+                tx.send(evt).ok();
+            },
             Event::AddDocument(..) | Event::RemoveDocument(..) => {
                 if view.is::<Home>() {
                     view.handle_event(&evt, &tx, &mut bus, &mut context);
@@ -1046,8 +1105,7 @@ pub fn run() -> Result<(), Error> {
         context.settings.frontlight_levels = context.frontlight.levels();
     }
 
-    let path = context.settings.library_path.join(&context.filename);
-    save_json(&context.metadata, path).context("Can't save metadata.")?;
+    context.library.flush(&context.metadata).context("Can't save metadata.")?;
 
     let path = Path::new(SETTINGS_PATH);
     save_toml(&context.settings, path).context("Can't save settings.")?;
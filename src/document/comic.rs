@@ -0,0 +1,415 @@
+use super::libarchive_sys::*;
+
+use std::ptr;
+use std::fs;
+use std::sync::Mutex;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::ffi::{CString, CStr};
+use std::os::unix::ffi::OsStrExt;
+use fnv::FnvHashMap;
+use failure::{Error, format_err};
+use super::{Document, Location, BoundedText, TocEntry, file_kind};
+use super::{chapter, chapter_relative};
+use super::pdf::{PdfOpener, PdfDocument};
+use super::html::dom::Node;
+use super::html::xml::XmlParser;
+use crate::helpers::decode_entities;
+use crate::metadata::TextAlign;
+use crate::framebuffer::Pixmap;
+use crate::geom::CycleDir;
+
+// The kind given to the directories of images.
+pub const IMAGES_DIRECTORY_KIND: &str = "dir";
+pub const COMIC_INFO_NAME: &str = "ComicInfo.xml";
+const IMAGE_KINDS: [&str; 9] = ["jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "jp2", "jpx"];
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+struct Archive(*mut LaArchive);
+
+impl Archive {
+    fn open(path: &Path) -> Result<Archive, Error> {
+        unsafe {
+            let a = archive_read_new();
+            if a.is_null() {
+                return Err(format_err!("Can't create archive reader."));
+            }
+            let archive = Archive(a);
+            archive_read_support_filter_all(a);
+            archive_read_support_format_all(a);
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            if archive_read_open_filename(a, c_path.as_ptr(), BLOCK_SIZE) != ARCHIVE_OK {
+                return Err(archive.error());
+            }
+            Ok(archive)
+        }
+    }
+
+    fn error(&self) -> Error {
+        unsafe {
+            let msg = archive_error_string(self.0);
+            if msg.is_null() {
+                format_err!("Unknown archive error.")
+            } else {
+                format_err!("{}.", CStr::from_ptr(msg).to_string_lossy())
+            }
+        }
+    }
+
+    // Advances to the next regular file and returns its name.
+    fn next_file(&mut self) -> Result<Option<String>, Error> {
+        loop {
+            unsafe {
+                let mut entry = ptr::null_mut();
+                match archive_read_next_header(self.0, &mut entry) {
+                    ARCHIVE_EOF => return Ok(None),
+                    ARCHIVE_OK | ARCHIVE_WARN => {
+                        if archive_entry_filetype(entry) & AE_IFMT != AE_IFREG {
+                            continue;
+                        }
+                        let mut name = archive_entry_pathname_utf8(entry);
+                        if name.is_null() {
+                            name = archive_entry_pathname(entry);
+                        }
+                        if name.is_null() {
+                            continue;
+                        }
+                        return Ok(Some(CStr::from_ptr(name).to_string_lossy().into_owned()));
+                    },
+                    _ => return Err(self.error()),
+                }
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let n = unsafe { archive_read_data(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                return Err(self.error());
+            }
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n as usize]);
+        }
+        Ok(data)
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        unsafe { archive_read_free(self.0); }
+    }
+}
+
+enum Source {
+    Archive(ArchiveSource),
+    Directory(PathBuf),
+}
+
+// The entries of an archive can only be read in order: the archive is kept
+// open between reads and only reopened when going backward.
+struct ArchiveSource {
+    path: PathBuf,
+    // The position of each file within the archive.
+    indices: FnvHashMap<String, usize>,
+    // The open archive and the position of its next file.
+    reader: Mutex<Option<(Archive, usize)>>,
+}
+
+impl ArchiveSource {
+    fn new(path: &Path) -> ArchiveSource {
+        ArchiveSource {
+            path: path.to_path_buf(),
+            indices: FnvHashMap::default(),
+            reader: Mutex::new(None),
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let index = *self.indices.get(name)
+                         .ok_or_else(|| format_err!("Can't find {}.", name))?;
+        let mut reader = self.reader.lock().unwrap();
+        if reader.as_ref().map_or(true, |(_, next)| *next > index) {
+            *reader = Some((Archive::open(&self.path)?, 0));
+        }
+        let (archive, next) = reader.as_mut().unwrap();
+        let data = read_entry(archive, next, index, name);
+        if data.is_err() {
+            *reader = None;
+        }
+        data
+    }
+}
+
+fn read_entry(archive: &mut Archive, next: &mut usize, index: usize, name: &str) -> Result<Vec<u8>, Error> {
+    while let Some(entry_name) = archive.next_file()? {
+        *next += 1;
+        if *next > index {
+            if entry_name == name {
+                return archive.read_data();
+            }
+            break;
+        }
+    }
+    Err(format_err!("Can't find {}.", name))
+}
+
+fn list_directory(root: &Path, dir: &Path, names: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_directory(root, &path, names)?;
+        } else if let Ok(relat) = path.strip_prefix(root) {
+            names.push(relat.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+impl Source {
+    fn names(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        match self {
+            Source::Archive(source) => {
+                let mut archive = Archive::open(&source.path)?;
+                while let Some(name) = archive.next_file()? {
+                    names.push(name);
+                }
+            },
+            Source::Directory(path) => list_directory(path, path, &mut names)?,
+        }
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Source::Archive(source) => source.read(name),
+            Source::Directory(path) => fs::read(path.join(name)).map_err(Into::into),
+        }
+    }
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.split('/').any(|c| c.starts_with('.') || c == "__MACOSX")
+}
+
+pub fn is_image(name: &str) -> bool {
+    !is_hidden(name) && file_kind(name).map_or(false, |k| IMAGE_KINDS.contains(&k.as_str()))
+}
+
+fn is_comic_info(name: &str) -> bool {
+    !is_hidden(name) && Path::new(name).file_name()
+                                       .map_or(false, |n| n.to_string_lossy().eq_ignore_ascii_case(COMIC_INFO_NAME))
+}
+
+// Compares the digit sequences by their numerical values, so that *2.jpg* comes before *10.jpg*.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut ca = a.chars().peekable();
+    let mut cb = b.chars().peekable();
+
+    loop {
+        match (ca.peek().cloned(), cb.peek().cloned()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut na = String::new();
+                let mut nb = String::new();
+                while let Some(d) = ca.peek().cloned().filter(char::is_ascii_digit) {
+                    na.push(d);
+                    ca.next();
+                }
+                while let Some(d) = cb.peek().cloned().filter(char::is_ascii_digit) {
+                    nb.push(d);
+                    cb.next();
+                }
+                let na = na.trim_start_matches('0');
+                let nb = nb.trim_start_matches('0');
+                let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            },
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                ca.next();
+                cb.next();
+            },
+        }
+    }
+}
+
+pub struct ComicDocument {
+    source: Source,
+    pages: Vec<String>,
+    comic_info: Option<Node>,
+    opener: PdfOpener,
+    dims: Mutex<FnvHashMap<usize, (f32, f32)>>,
+}
+
+unsafe impl Send for ComicDocument {}
+unsafe impl Sync for ComicDocument {}
+
+impl ComicDocument {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<ComicDocument, Error> {
+        let path = path.as_ref();
+        let mut source = if path.is_dir() {
+            Source::Directory(path.to_path_buf())
+        } else {
+            Source::Archive(ArchiveSource::new(path))
+        };
+        let names = source.names()?;
+        if let Source::Archive(ref mut source) = source {
+            source.indices = names.iter().enumerate()
+                                  .map(|(index, name)| (name.clone(), index))
+                                  .collect();
+        }
+        let mut pages = names.iter().filter(|name| is_image(name))
+                             .cloned().collect::<Vec<String>>();
+        if pages.is_empty() {
+            return Err(format_err!("No images found."));
+        }
+        pages.sort_by(|a, b| natural_cmp(a, b));
+        let comic_info = names.iter().find(|name| is_comic_info(name))
+                              .and_then(|name| source.read(name).ok())
+                              .map(|bytes| XmlParser::new(&String::from_utf8_lossy(&bytes)).parse());
+        let opener = PdfOpener::new().ok_or_else(|| format_err!("Can't create MuPDF context."))?;
+        Ok(ComicDocument {
+            source,
+            pages,
+            comic_info,
+            opener,
+            dims: Mutex::new(FnvHashMap::default()),
+        })
+    }
+
+    pub fn has_comic_info(&self) -> bool {
+        self.comic_info.is_some()
+    }
+
+    fn image(&self, index: usize) -> Option<PdfDocument> {
+        let name = self.pages.get(index)?;
+        let bytes = self.source.read(name)
+                        .map_err(|e| eprintln!("Can't read {}: {}", name, e)).ok()?;
+        let kind = file_kind(name)?;
+        let doc = self.opener.open_memory(&kind, &bytes)?;
+        if let Some(dims) = doc.dims(0) {
+            self.dims.lock().unwrap().insert(index, dims);
+        }
+        Some(doc)
+    }
+}
+
+impl Document for ComicDocument {
+    fn dims(&self, index: usize) -> Option<(f32, f32)> {
+        if let Some(dims) = self.dims.lock().unwrap().get(&index) {
+            return Some(*dims);
+        }
+        self.image(index).and_then(|doc| doc.dims(0))
+    }
+
+    fn pages_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn pixmap(&mut self, loc: Location, scale: f32) -> Option<(Pixmap, usize)> {
+        let index = self.resolve_location(loc)?;
+        let mut doc = self.image(index)?;
+        doc.pixmap(Location::Exact(0), scale).map(|(pixmap, _)| (pixmap, index))
+    }
+
+    fn toc(&mut self) -> Option<Vec<TocEntry>> {
+        None
+    }
+
+    fn chapter<'a>(&mut self, offset: usize, toc: &'a [TocEntry]) -> Option<&'a TocEntry> {
+        chapter(offset, toc)
+    }
+
+    fn chapter_relative<'a>(&mut self, offset: usize, dir: CycleDir, toc: &'a [TocEntry]) -> Option<&'a TocEntry> {
+        chapter_relative(offset, dir, toc)
+    }
+
+    fn words(&mut self, loc: Location) -> Option<(Vec<BoundedText>, usize)> {
+        let index = self.resolve_location(loc)?;
+        Some((Vec::new(), index))
+    }
+
+    fn lines(&mut self, loc: Location) -> Option<(Vec<BoundedText>, usize)> {
+        let index = self.resolve_location(loc)?;
+        Some((Vec::new(), index))
+    }
+
+    fn links(&mut self, loc: Location) -> Option<(Vec<BoundedText>, usize)> {
+        let index = self.resolve_location(loc)?;
+        Some((Vec::new(), index))
+    }
+
+    fn title(&self) -> Option<String> {
+        self.metadata("Title")
+    }
+
+    fn author(&self) -> Option<String> {
+        self.metadata("Writer")
+    }
+
+    fn metadata(&self, key: &str) -> Option<String> {
+        self.comic_info.as_ref()
+            .and_then(|root| root.find(key))
+            .map(|node| decode_entities(node.text_content().trim()).into_owned())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn is_reflowable(&self) -> bool {
+        false
+    }
+
+    fn is_right_to_left(&self) -> bool {
+        self.metadata("Manga").map_or(false, |value| value == "YesAndRightToLeft")
+    }
+
+    fn layout(&mut self, _width: u32, _height: u32, _font_size: f32, _dpi: u16) {
+    }
+
+    fn set_text_align(&mut self, _text_align: TextAlign) {
+    }
+
+    fn set_font_family(&mut self, _family_name: &str, _search_path: &str) {
+    }
+
+    fn set_margin_width(&mut self, _width: i32) {
+    }
+
+    fn set_line_height(&mut self, _line_height: f32) {
+    }
+
+    fn set_embedded_fonts(&mut self, _enable: bool) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_order() {
+        let mut names = vec!["p10.jpg", "p2.jpg", "P1.jpg", "p02b.jpg", "cover.png", "p010.jpg"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["cover.png", "P1.jpg", "p2.jpg", "p02b.jpg", "p10.jpg", "p010.jpg"]);
+    }
+
+    #[test]
+    fn test_image_names() {
+        assert!(is_image("Chapter 1/001.JPG"));
+        assert!(!is_image("__MACOSX/Chapter 1/._001.jpg"));
+        assert!(!is_image("ComicInfo.xml"));
+        assert!(is_comic_info("comicinfo.xml"));
+    }
+}
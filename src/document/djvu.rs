@@ -249,6 +249,9 @@ impl Document for DjvuDocument {
 
     fn set_line_height(&mut self, _line_height: f32) {
     }
+
+    fn set_embedded_fonts(&mut self, _enable: bool) {
+    }
 }
 
 impl DjvuDocument {
@@ -1,25 +1,33 @@
+mod pagination;
+
 use std::io::Read;
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::borrow::Cow;
-use std::collections::{HashMap, BTreeSet};
+use std::hash::Hasher;
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use fnv::FnvHasher;
 use zip::ZipArchive;
 use failure::{Error, format_err};
 use crate::framebuffer::Pixmap;
-use crate::helpers::{Normalize, decode_entities};
-use crate::document::{Document, Location, TextLocation, TocEntry, BoundedText, chapter_from_uri};
+use crate::helpers::{Normalize, decode_entities, percent_decode};
+use crate::document::{Document, Location, TextLocation, TocEntry, BoundedText, chapter_from_uri, fingerprint};
 use crate::unit::pt_to_px;
 use crate::geom::{Rectangle, Edge, CycleDir};
 use super::html::dom::Node;
 use super::html::engine::{Page, Engine, ResourceFetcher};
-use super::html::layout::{StyleData, LoopContext};
+use super::html::layout::{StyleData, LoopContext, Direction};
+use super::html::parse::parse_direction;
 use super::html::layout::{RootData, DrawState, DrawCommand, TextCommand, ImageCommand};
 use super::html::layout::TextAlign;
 use super::html::css::{CssParser, RuleKind};
 use super::html::xml::XmlParser;
+use super::html::html5::parse_markup;
+use self::pagination::{Pagination, PAGINATION_DIR, LAYOUT_VERSION};
 
 const VIEWER_STYLESHEET: &str = "css/epub.css";
 const USER_STYLESHEET: &str = "css/epub-user.css";
+const MAX_NOTE_SIZE: usize = 2048;
 
 type UriCache = HashMap<String, usize>;
 
@@ -39,6 +47,10 @@ pub struct EpubDocument {
     engine: Engine,
     spine: Vec<Chunk>,
     cache: HashMap<usize, Vec<Page>>,
+    // The page breaks for the current layout, loaded on demand.
+    pagination: Option<Pagination>,
+    pagination_dir: PathBuf,
+    path: PathBuf,
     ignore_document_css: bool,
 }
 
@@ -53,7 +65,7 @@ unsafe impl Sync for EpubDocument {}
 
 impl EpubDocument {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<EpubDocument, Error> {
-        let file = File::open(path)?;
+        let file = File::open(path.as_ref())?;
         let mut archive = ZipArchive::new(file)?;
 
         let opf_path = {
@@ -119,6 +131,9 @@ impl EpubDocument {
             engine: Engine::new(),
             spine,
             cache: HashMap::new(),
+            pagination: None,
+            pagination_dir: PathBuf::from(PAGINATION_DIR),
+            path: path.as_ref().to_path_buf(),
             ignore_document_css: false,
         })
     }
@@ -163,17 +178,82 @@ impl EpubDocument {
 
     fn set_margin(&mut self, margin: &Edge) {
         self.engine.set_margin(margin);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     fn set_font_size(&mut self, font_size: f32) {
         self.engine.set_font_size(font_size);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     pub fn set_ignore_document_css(&mut self, value: bool) {
         self.ignore_document_css = value;
+        self.clear_cache();
+    }
+
+    fn clear_cache(&mut self) {
         self.cache.clear();
+        self.save_pagination();
+        self.pagination = None;
+    }
+
+    fn save_pagination(&mut self) {
+        if let Some(pagination) = self.pagination.as_mut() {
+            pagination.save()
+                      .map_err(|e| eprintln!("Can't save the pagination: {}", e)).ok();
+        }
+    }
+
+    // The paginations are keyed by the fingerprint of the book and a hash of
+    // every parameter that can move the page breaks.
+    fn pagination_key(&self) -> Option<String> {
+        let fingerprint = fingerprint(&self.path).ok()?;
+        let engine = &self.engine;
+        let mut hasher = FnvHasher::default();
+        hasher.write(format!("{} {:?} {} {:?} {} {:?} {} {} {} {}",
+                             LAYOUT_VERSION, engine.dims, engine.dpi, engine.margin,
+                             engine.font_size, engine.text_align, engine.line_height,
+                             engine.font_family, engine.embedded_fonts,
+                             self.ignore_document_css).as_bytes());
+        for path in &[VIEWER_STYLESHEET, USER_STYLESHEET] {
+            if let Ok(text) = fs::read_to_string(path) {
+                hasher.write(text.as_bytes());
+            }
+        }
+        Some(format!("{}-{:016X}", fingerprint, hasher.finish()))
+    }
+
+    fn pagination_path(&self) -> Option<PathBuf> {
+        self.pagination_key()
+            .map(|key| self.pagination_dir.join(format!("{}.json", key)))
+    }
+
+    fn pagination_mut(&mut self) -> &mut Pagination {
+        if self.pagination.is_none() {
+            let pagination = self.pagination_path()
+                                 .map(Pagination::load)
+                                 .unwrap_or_default();
+            self.pagination = Some(pagination);
+        }
+        self.pagination.as_mut().unwrap()
+    }
+
+    fn display_list(&mut self, index: usize, start_offset: usize) -> Option<&Vec<Page>> {
+        if !self.cache.contains_key(&index) {
+            let display_list = self.build_display_list(index, start_offset);
+            self.pagination_mut().insert(index, page_breaks(&display_list));
+            self.cache.insert(index, display_list);
+        }
+        self.cache.get(&index)
+    }
+
+    // The offsets at which the pages of the given chunk start. The chunk is
+    // only laid out when its page breaks aren't known.
+    fn page_breaks(&mut self, index: usize, start_offset: usize) -> Option<&Vec<usize>> {
+        if self.pagination_mut().get(index).is_none() {
+            self.display_list(index, start_offset);
+        }
+        self.pagination.as_ref().and_then(|p| p.get(index))
     }
 
     #[inline]
@@ -227,26 +307,130 @@ impl EpubDocument {
         entries
     }
 
-    #[inline]
-    fn page_index(&mut self, offset: usize, index: usize, start_offset: usize) -> Option<usize> {
-        if !self.cache.contains_key(&index) {
-            let display_list = self.build_display_list(index, start_offset);
-            self.cache.insert(index, display_list);
-        }
-        self.cache.get(&index).map(|display_list| {
-            if display_list.len() < 2 || display_list[1].first().map(|dc| offset < dc.offset()) == Some(true) {
-                return 0;
-            } else if display_list[display_list.len() - 1].first().map(|dc| offset >= dc.offset()) == Some(true) {
-                return display_list.len() - 1;
-            } else {
-                for i in 1..display_list.len()-1 {
-                    if display_list[i].first().map(|dc| offset >= dc.offset()) == Some(true) &&
-                       display_list[i+1].first().map(|dc| offset < dc.offset()) == Some(true) {
-                        return i;
-                    }
+    fn walk_nav(&self, node: &Node, toc_dir: &Path, index: &mut usize) -> Vec<TocEntry> {
+        let mut entries = Vec::new();
+
+        if let Some(children) = node.children() {
+            for child in children {
+                if child.tag_name() != Some("li") {
+                    continue;
+                }
+
+                let label = child.children().and_then(|children| {
+                    children.iter().find(|c| c.tag_name() == Some("a") ||
+                                             c.tag_name() == Some("span"))
+                });
+
+                let title = label.map(|label| {
+                    let text = label.text_content();
+                    decode_entities(&text).split_whitespace()
+                                          .collect::<Vec<&str>>().join(" ")
+                }).unwrap_or_default();
+
+                // Example URI: chapter_001.xhtml#section_3
+                let loc = label.and_then(|label| label.attr("href")).and_then(|href| {
+                    toc_dir.join(String::from_utf8_lossy(&percent_decode(href)).as_ref()).normalize().to_str()
+                           .map(|uri| Location::Uri(uri.to_string()))
+                });
+
+                let current_index = *index;
+                *index += 1;
+
+                let sub_entries = child.children().and_then(|children| {
+                    children.iter().find(|c| c.tag_name() == Some("ol"))
+                }).map(|list| {
+                    self.walk_nav(list, toc_dir, index)
+                }).unwrap_or_default();
+
+                // Headings without a link point to their first child.
+                let location = loc.or_else(|| sub_entries.first().map(|e| e.location.clone()));
+
+                if let Some(location) = location {
+                    entries.push(TocEntry {
+                        title,
+                        location,
+                        index: current_index,
+                        children: sub_entries,
+                    });
                 }
             }
-            0
+        }
+
+        entries
+    }
+
+    fn nav_toc(&mut self, kind: &str) -> Option<Vec<TocEntry>> {
+        let name = self.info.find("manifest").and_then(Node::children).and_then(|children| {
+            children.iter().find(|child| {
+                child.attr("properties")
+                     .map_or(false, |props| props.split_whitespace().any(|p| p == "nav"))
+            })
+        }).and_then(|entry| {
+            entry.attr("href")
+        }).map(|href| {
+            self.parent.join(String::from_utf8_lossy(&percent_decode(href)).as_ref()).normalize()
+                .to_string_lossy().into_owned()
+        })?;
+
+        let toc_dir = Path::new(&name).parent()
+                           .unwrap_or_else(|| Path::new(""));
+
+        let mut text = String::new();
+        if let Ok(mut zf) = self.archive.by_name(&name) {
+            zf.read_to_string(&mut text).ok()?;
+        } else {
+            return None;
+        }
+
+        let root = parse_content(&name, &text);
+        find_nav(&root, kind).and_then(|nav| nav.find("ol")).map(|list| {
+            let mut index = 0;
+            self.walk_nav(list, &toc_dir, &mut index)
+        })
+    }
+
+    fn ncx_toc(&mut self) -> Option<Vec<TocEntry>> {
+        let name = self.info.find("spine").and_then(|spine| {
+            spine.attr("toc")
+        }).and_then(|toc_id| {
+            self.info.find("manifest")
+                .and_then(|manifest| manifest.find_by_id(toc_id))
+                .and_then(|entry| entry.attr("href"))
+        }).map(|href| {
+            self.parent.join(href).normalize()
+                .to_string_lossy().into_owned()
+        })?;
+
+        let toc_dir = Path::new(&name).parent()
+                           .unwrap_or_else(|| Path::new(""));
+
+        let mut text = String::new();
+        if let Ok(mut zf) = self.archive.by_name(&name) {
+            zf.read_to_string(&mut text).ok()?;
+        } else {
+            return None;
+        }
+
+        let root = XmlParser::new(&text).parse();
+        root.find("navMap").map(|map| {
+            let mut cache = HashMap::new();
+            let mut index = 0;
+            self.walk_toc(&map, &toc_dir, &mut index, &mut cache)
+        })
+    }
+
+    pub fn landmarks(&mut self) -> Option<Vec<TocEntry>> {
+        self.nav_toc("landmarks").filter(|entries| !entries.is_empty())
+    }
+
+    pub fn page_list(&mut self) -> Option<Vec<TocEntry>> {
+        self.nav_toc("page-list").filter(|entries| !entries.is_empty())
+    }
+
+    #[inline]
+    fn page_index(&mut self, offset: usize, index: usize, start_offset: usize) -> Option<usize> {
+        self.page_breaks(index, start_offset).map(|breaks| {
+            breaks.iter().skip(1).take_while(|&&b| offset >= b).count()
         })
     }
 
@@ -262,19 +446,23 @@ impl EpubDocument {
                 let mut zf = self.archive.by_name(name).ok()?;
                 zf.read_to_string(&mut text).ok()?;
             }
-            let root = XmlParser::new(&text).parse();
+            let root = parse_content(name, &text);
             self.cache_uris(&root, name, start_offset, cache);
             cache.get(uri).cloned()
         } else {
-            let page_index = self.page_index(start_offset, index, start_offset)?;
-            let offset = self.cache.get(&index)
-                             .and_then(|display_list| display_list[page_index].first())
-                             .map(DrawCommand::offset)?;
+            let offset = self.page_breaks(index, start_offset)?.first().cloned()?;
             cache.insert(uri.to_string(), offset);
             Some(offset)
         }
     }
 
+    fn parse_entry(&mut self, name: &str) -> Option<Node> {
+        let mut text = String::new();
+        let mut zf = self.archive.by_name(name).ok()?;
+        zf.read_to_string(&mut text).ok()?;
+        Some(parse_content(name, &text))
+    }
+
     fn cache_uris(&mut self, node: &Node, name: &str, start_offset: usize, cache: &mut UriCache) {
         if let Some(id) = node.attr("id") {
             let location = start_offset + node.offset();
@@ -296,8 +484,8 @@ impl EpubDocument {
         let (index, start_offset) = self.vertebra_coordinates(offset)?;
         let page_index = self.page_index(offset, index, start_offset)?;
 
-        self.cache.get(&index).map(|display_list| {
-            (display_list[page_index].iter().filter_map(|dc| {
+        self.display_list(index, start_offset)?.get(page_index).map(|page| {
+            (page.iter().filter_map(|dc| {
                 match dc {
                     DrawCommand::Image(ImageCommand { rect, .. }) => Some(*rect),
                     _ => None,
@@ -309,19 +497,17 @@ impl EpubDocument {
     fn build_display_list(&mut self, index: usize, start_offset: usize) -> Vec<Page> {
         let mut text = String::new();
         let mut spine_dir = PathBuf::from("");
+        let path = self.spine[index].path.clone();
 
-        {
-            let path = &self.spine[index].path;
-            if let Some(parent) = Path::new(path).parent() {
-                spine_dir = parent.to_path_buf();
-            }
+        if let Some(parent) = Path::new(&path).parent() {
+            spine_dir = parent.to_path_buf();
+        }
 
-            if let Ok(mut zf) = self.archive.by_name(path) {
-                zf.read_to_string(&mut text).ok();
-            }
+        if let Ok(mut zf) = self.archive.by_name(&path) {
+            zf.read_to_string(&mut text).ok();
         }
 
-        let mut root = XmlParser::new(&text).parse();
+        let mut root = parse_content(&path, &text);
         root.wrap_lost_inlines();
 
         let mut stylesheet = Vec::new();
@@ -346,15 +532,21 @@ impl EpubDocument {
                                     let mut text = String::new();
                                     if let Ok(mut zf) = self.archive.by_name(name) {
                                         zf.read_to_string(&mut text).ok();
-                                        let (mut css, _) = CssParser::new(&text).parse(RuleKind::Document);
-                                        stylesheet.append(&mut css);
+                                    } else {
+                                        continue;
                                     }
+                                    let (mut css, at_rules) = CssParser::new(&text).parse(RuleKind::Document);
+                                    stylesheet.append(&mut css);
+                                    let css_dir = Path::new(name).parent()
+                                                      .unwrap_or_else(|| Path::new(""));
+                                    self.engine.load_font_faces(&at_rules, css_dir, &mut self.archive);
                                 }
                             }
                         } else if child.tag_name() == Some("style") && child.attr("type") == Some("text/css") {
                             if let Some(text) = child.text() {
-                                let (mut css, _) = CssParser::new(text).parse(RuleKind::Document);
+                                let (mut css, at_rules) = CssParser::new(text).parse(RuleKind::Document);
                                 stylesheet.append(&mut css);
+                                self.engine.load_font_faces(&at_rules, &spine_dir, &mut self.archive);
                             }
                         }
                     }
@@ -362,6 +554,9 @@ impl EpubDocument {
             }
         }
 
+        let device = self.engine.device();
+        stylesheet.retain(|rule| rule.matches_media(&device));
+
         let mut display_list = Vec::new();
 
         if let Some(body) = root.find("body").as_mut() {
@@ -374,18 +569,37 @@ impl EpubDocument {
                     .map(String::from)
             });
 
+            let direction = root.find("html")
+                                .and_then(|html| html.attr("dir"))
+                                .and_then(parse_direction)
+                                .unwrap_or_else(|| {
+                                    if self.is_right_to_left() {
+                                        Direction::Rtl
+                                    } else {
+                                        Direction::Ltr
+                                    }
+                                });
+
+            let text_align = if direction == Direction::Rtl {
+                self.engine.text_align.mirror()
+            } else {
+                self.engine.text_align
+            };
+
             let style = StyleData {
                 language,
+                direction,
                 font_size: self.engine.font_size,
                 line_height: pt_to_px(self.engine.line_height * self.engine.font_size, self.engine.dpi).round() as i32,
-                text_align: self.engine.text_align,
+                text_align,
                 start_x: rect.min.x,
                 end_x: rect.max.x,
                 width: rect.max.x - rect.min.x,
                 .. Default::default()
             };
 
-            let loop_context = LoopContext::default();
+            let mut loop_context = LoopContext::default();
+            loop_context.ancestors = root.find("html").into_iter().collect();
             let mut draw_state = DrawState {
                 position: rect.min,
                 .. Default::default()
@@ -549,33 +763,9 @@ impl Document for EpubDocument {
     }
 
     fn toc(&mut self) -> Option<Vec<TocEntry>> {
-        let name = self.info.find("spine").and_then(|spine| {
-            spine.attr("toc")
-        }).and_then(|toc_id| {
-            self.info.find("manifest")
-                .and_then(|manifest| manifest.find_by_id(toc_id))
-                .and_then(|entry| entry.attr("href"))
-        }).map(|href| {
-            self.parent.join(href).normalize()
-                .to_string_lossy().into_owned()
-        })?;
-
-        let toc_dir = Path::new(&name).parent()
-                           .unwrap_or_else(|| Path::new(""));
-
-        let mut text = String::new();
-        if let Ok(mut zf) = self.archive.by_name(&name) {
-            zf.read_to_string(&mut text).ok()?;
-        } else {
-            return None;
-        }
-
-        let root = XmlParser::new(&text).parse();
-        root.find("navMap").map(|map| {
-            let mut cache = HashMap::new();
-            let mut index = 0;
-            self.walk_toc(&map, &toc_dir, &mut index, &mut cache)
-        })
+        self.nav_toc("toc").filter(|entries| !entries.is_empty())
+            .or_else(|| self.ncx_toc().filter(|entries| !entries.is_empty()))
+            .or_else(|| self.landmarks())
     }
 
     fn chapter<'a>(&mut self, offset: usize, toc: &'a [TocEntry]) -> Option<&'a TocEntry> {
@@ -621,45 +811,33 @@ impl Document for EpubDocument {
             Location::Exact(offset) => {
                 let (index, start_offset) = self.vertebra_coordinates(offset)?;
                 let page_index = self.page_index(offset, index, start_offset)?;
-                self.cache.get(&index)
-                    .and_then(|display_list| display_list[page_index].first())
-                    .map(DrawCommand::offset)
+                self.page_breaks(index, start_offset)?.get(page_index).cloned()
             },
             Location::Previous(offset) => {
                 let (index, start_offset) = self.vertebra_coordinates(offset)?;
                 let page_index = self.page_index(offset, index, start_offset)?;
                 if page_index > 0 {
-                    self.cache.get(&index)
-                        .and_then(|display_list| display_list[page_index-1].first().map(DrawCommand::offset))
+                    self.page_breaks(index, start_offset)?.get(page_index-1).cloned()
                 } else {
                     if index == 0 {
                         return None;
                     }
                     let (index, start_offset) = (index - 1, start_offset - self.spine[index-1].size);
-                    if !self.cache.contains_key(&index) {
-                        let display_list = self.build_display_list(index, start_offset);
-                        self.cache.insert(index, display_list);
-                    }
-                    self.cache.get(&index)
-                        .and_then(|display_list| display_list.last().and_then(|page| page.first()).map(DrawCommand::offset))
+                    self.page_breaks(index, start_offset)?.last().cloned()
                 }
             },
             Location::Next(offset) => {
                 let (index, start_offset) = self.vertebra_coordinates(offset)?;
                 let page_index = self.page_index(offset, index, start_offset)?;
-                if page_index < self.cache.get(&index).map(Vec::len)? - 1 {
-                    self.cache.get(&index).and_then(|display_list| display_list[page_index+1].first().map(DrawCommand::offset))
+                let breaks = self.page_breaks(index, start_offset)?;
+                if page_index + 1 < breaks.len() {
+                    breaks.get(page_index+1).cloned()
                 } else {
                     if index == self.spine.len() - 1 {
                         return None;
                     }
                     let (index, start_offset) = (index + 1, start_offset + self.spine[index].size);
-                    if !self.cache.contains_key(&index) {
-                        let display_list = self.build_display_list(index, start_offset);
-                        self.cache.insert(index, display_list);
-                    }
-                    self.cache.get(&index)
-                        .and_then(|display_list| display_list.first().and_then(|page| page.first()).map(|dc| dc.offset()))
+                    self.page_breaks(index, start_offset)?.first().cloned()
                 }
             },
             Location::LocalUri(offset, ref uri) => {
@@ -694,8 +872,8 @@ impl Document for EpubDocument {
         let (index, start_offset) = self.vertebra_coordinates(offset)?;
         let page_index = self.page_index(offset, index, start_offset)?;
 
-        self.cache.get(&index).map(|display_list| {
-            (display_list[page_index].iter().filter_map(|dc| {
+        self.display_list(index, start_offset)?.get(page_index).map(|page| {
+            (page.iter().filter_map(|dc| {
                 match dc {
                     DrawCommand::Text(TextCommand { text, rect, offset, .. }) => {
                         Some(BoundedText {
@@ -723,8 +901,8 @@ impl Document for EpubDocument {
         let (index, start_offset) = self.vertebra_coordinates(offset)?;
         let page_index = self.page_index(offset, index, start_offset)?;
 
-        self.cache.get(&index).map(|display_list| {
-            (display_list[page_index].iter().filter_map(|dc| {
+        self.display_list(index, start_offset)?.get(page_index).map(|page| {
+            (page.iter().filter_map(|dc| {
                 match dc {
                     DrawCommand::Text(TextCommand { uri, rect, offset, .. }) |
                     DrawCommand::Image(ImageCommand { uri, rect, offset, .. }) if uri.is_some() => {
@@ -749,7 +927,7 @@ impl Document for EpubDocument {
         let (index, start_offset) = self.vertebra_coordinates(offset)?;
 
         let page_index = self.page_index(offset, index, start_offset)?;
-        let page = self.cache.get(&index)?.get(page_index)?.clone();
+        let page = self.display_list(index, start_offset)?.get(page_index)?.clone();
 
         let pixmap = self.engine.render_page(&page, &mut self.archive);
 
@@ -758,27 +936,32 @@ impl Document for EpubDocument {
 
     fn layout(&mut self, width: u32, height: u32, font_size: f32, dpi: u16) {
         self.engine.layout(width, height, font_size, dpi);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     fn set_text_align(&mut self, text_align: TextAlign) {
         self.engine.set_text_align(text_align);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     fn set_font_family(&mut self, family_name: &str, search_path: &str) {
         self.engine.set_font_family(family_name, search_path);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     fn set_margin_width(&mut self, width: i32) {
         self.engine.set_margin_width(width);
-        self.cache.clear();
+        self.clear_cache();
     }
 
     fn set_line_height(&mut self, line_height: f32) {
         self.engine.set_line_height(line_height);
-        self.cache.clear();
+        self.clear_cache();
+    }
+
+    fn set_embedded_fonts(&mut self, enable: bool) {
+        self.engine.set_embedded_fonts(enable);
+        self.clear_cache();
     }
 
     fn title(&self) -> Option<String> {
@@ -802,7 +985,286 @@ impl Document for EpubDocument {
         true
     }
 
+    fn is_right_to_left(&self) -> bool {
+        self.info.find("spine")
+            .and_then(|spine| spine.attr("page-progression-direction"))
+            .map_or(false, |dir| dir == "rtl")
+    }
+
     fn has_synthetic_page_numbers(&self) -> bool {
         true
     }
+
+    fn page_numbers(&mut self, offset: usize) -> Option<(usize, usize)> {
+        let (index, _) = self.vertebra_coordinates(offset)?;
+        let chunks_count = self.spine.len();
+        let pagination = self.pagination_mut();
+        let pages_count = pagination.pages_count(chunks_count)?;
+        pagination.page_number(index, offset).map(|number| (number, pages_count))
+    }
+
+    // Lays out the first chunk whose page breaks aren't known, without
+    // keeping its display list. The pagination is saved once it's complete.
+    fn paginate(&mut self) -> bool {
+        self.engine.load_fonts();
+        let chunks_count = self.spine.len();
+        let index = {
+            let pagination = self.pagination_mut();
+            (0..chunks_count).find(|index| pagination.get(*index).is_none())
+        };
+        let index = match index {
+            Some(index) => index,
+            None => return false,
+        };
+        let start_offset = self.offset(index);
+        let display_list = self.build_display_list(index, start_offset);
+        let pagination = self.pagination_mut();
+        pagination.insert(index, page_breaks(&display_list));
+        if pagination.pages_count(chunks_count).is_some() {
+            self.save_pagination();
+            return false;
+        }
+        true
+    }
+
+    // The labels of the page list, at the locations of their targets.
+    fn page_names(&mut self) -> BTreeMap<usize, String> {
+        let mut names = BTreeMap::new();
+        let entries = self.page_list().unwrap_or_default();
+        let mut cache = HashMap::new();
+        for entry in entries {
+            if let Location::Uri(ref uri) = entry.location {
+                let offset = cache.get(uri).cloned()
+                                  .or_else(|| self.resolve_link(uri, &mut cache));
+                if let Some(offset) = offset {
+                    names.insert(offset, entry.title.clone());
+                }
+            }
+        }
+        names
+    }
+
+    fn footnote(&mut self, offset: usize, uri: &str) -> Option<String> {
+        let (index, start_offset) = self.vertebra_coordinates(offset)?;
+        let path = self.spine[index].path.clone();
+        let frag_index = uri.find('#')?;
+        let id = &uri[frag_index+1..];
+        let name = if frag_index == 0 {
+            path.clone()
+        } else {
+            let parent = Path::new(&path).parent()
+                              .unwrap_or_else(|| Path::new(""));
+            parent.join(&uri[..frag_index]).normalize()
+                  .to_string_lossy().into_owned()
+        };
+
+        let root = self.parse_entry(&path)?;
+        let mut link = None;
+        find_link(&root, uri, offset - start_offset, &mut link);
+        let link = link?;
+
+        let target = if name == path {
+            None
+        } else {
+            Some(self.parse_entry(&name)?)
+        };
+        let note = target.as_ref().unwrap_or(&root).find_by_id(id)?;
+
+        if is_noteref(link) || (note.tag_name() == Some("aside") &&
+                                note.text_content().len() <= MAX_NOTE_SIZE) {
+            Some(note.to_html())
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for EpubDocument {
+    fn drop(&mut self) {
+        self.save_pagination();
+    }
+}
+
+fn page_breaks(display_list: &[Page]) -> Vec<usize> {
+    display_list.iter()
+                .filter_map(|page| page.first().map(DrawCommand::offset))
+                .collect()
+}
+
+// The content documents with an *.html* extension aren't XHTML.
+fn parse_content(name: &str, text: &str) -> Node {
+    let name = name.to_lowercase();
+    parse_markup(text, name.ends_with(".html") || name.ends_with(".htm"))
+}
+
+fn find_nav<'a>(node: &'a Node, kind: &str) -> Option<&'a Node> {
+    if node.tag_name() == Some("nav") &&
+       node.attr("epub:type").map_or(false, |t| t.split_whitespace().any(|v| v == kind)) {
+        return Some(node);
+    }
+    node.children().and_then(|children| {
+        children.iter().filter_map(|child| find_nav(child, kind)).next()
+    })
+}
+
+// Finds the last link to *uri* that starts before *offset*.
+fn find_link<'a>(node: &'a Node, uri: &str, offset: usize, link: &mut Option<&'a Node>) {
+    if node.offset() > offset {
+        return;
+    }
+    if node.tag_name() == Some("a") && node.attr("href") == Some(uri) {
+        *link = Some(node);
+    }
+    if let Some(children) = node.children() {
+        for child in children {
+            find_link(child, uri, offset, link);
+        }
+    }
+}
+
+fn is_noteref(node: &Node) -> bool {
+    node.attr("epub:type").map_or(false, |t| t.split_whitespace().any(|v| v == "noteref")) ||
+    node.attr("role") == Some("doc-noteref")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+
+    const NCX: &str = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1"><navMap>
+<navPoint id="n1"><navLabel><text>NCX One</text></navLabel><content src="ch1.xhtml"/></navPoint>
+</navMap></ncx>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><body>
+<p id="p1">First page.</p><p id="p2">Second page.</p><p id="p3">Third page.</p>
+</body></html>"#;
+
+    fn nav(navs: &str) -> String {
+        format!(r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>{}</body></html>"#, navs)
+    }
+
+    const TOC_NAV: &str = r#"<nav epub:type="toc"><ol>
+<li><a href="ch1.xhtml">Chapter One</a><ol><li><a href="ch%31.xhtml#p2">Part Two</a></li></ol></li>
+</ol></nav>"#;
+
+    const PAGE_LIST_NAV: &str = r#"<nav epub:type="page-list" hidden=""><ol>
+<li><a href="ch1.xhtml#p1">i</a></li><li><a href="ch1.xhtml#p2">ii</a></li><li><a href="ch1.xhtml#p3">1</a></li>
+</ol></nav>"#;
+
+    const LANDMARKS_NAV: &str = r#"<nav epub:type="landmarks"><ol>
+<li><a epub:type="bodymatter" href="ch1.xhtml#p1">Start of Content</a></li>
+</ol></nav>"#;
+
+    fn build_epub(name: &str, nav: &str, ncx: bool) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let opf = format!(r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0"><manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+<item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/>
+</manifest><spine{}><itemref idref="ch1"/></spine></package>"#, if ncx { r#" toc="ncx""# } else { "" });
+        let mut entries = vec![("META-INF/container.xml", CONTAINER.to_string()),
+                               ("OEBPS/content.opf", opf),
+                               ("OEBPS/nav.xhtml", nav.to_string()),
+                               ("OEBPS/ch1.xhtml", CHAPTER.to_string())];
+        if ncx {
+            entries.push(("OEBPS/toc.ncx", NCX.to_string()));
+        }
+        for (name, text) in entries {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_navigation() {
+        let navs = format!("{}{}{}", TOC_NAV, PAGE_LIST_NAV, LANDMARKS_NAV);
+        let path = build_epub("plato-test-navigation.epub", &nav(&navs), true);
+        let mut doc = EpubDocument::new(&path).unwrap();
+
+        let toc = doc.toc().unwrap();
+        assert_eq!(toc.len(), 1);
+        assert_eq!(toc[0].title, "Chapter One");
+        assert_eq!(toc[0].children[0].title, "Part Two");
+        match toc[0].children[0].location {
+            Location::Uri(ref uri) => assert_eq!(uri, "OEBPS/ch1.xhtml#p2"),
+            _ => panic!("Unexpected location."),
+        }
+
+        let landmarks = doc.landmarks().unwrap();
+        assert_eq!(landmarks[0].title, "Start of Content");
+
+        let names = doc.page_names();
+        assert_eq!(names.values().map(String::as_str).collect::<Vec<&str>>(), vec!["i", "ii", "1"]);
+        let p2 = doc.resolve_location(Location::Uri("OEBPS/ch1.xhtml#p2".to_string()));
+        assert_eq!(names.iter().find(|(_, name)| *name == "ii").map(|(offset, _)| *offset), p2);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_pagination() {
+        let path = build_epub("plato-test-pagination.epub", &nav(TOC_NAV), false);
+        let dir = env::temp_dir().join("plato-test-pagination");
+        fs::remove_dir_all(&dir).ok();
+        let mut doc = EpubDocument::new(&path).unwrap();
+        doc.pagination_dir = dir.clone();
+        doc.layout(600, 800, 12.0, 300);
+
+        let key = doc.pagination_key().unwrap();
+        doc.layout(600, 800, 14.0, 300);
+        assert_ne!(doc.pagination_key().unwrap(), key);
+        doc.layout(600, 800, 12.0, 300);
+        assert_eq!(doc.pagination_key().unwrap(), key);
+
+        assert_eq!(doc.page_numbers(0), None);
+        while doc.paginate() {}
+        let (number, count) = doc.page_numbers(0).unwrap();
+        assert_eq!(number, 0);
+        assert!(count > 0);
+
+        let pagination_path = doc.pagination_path().unwrap();
+        assert_eq!(pagination_path, dir.join(format!("{}.json", key)));
+        let pagination = Pagination::load(&pagination_path);
+        assert_eq!(pagination.pages_count(1), Some(count));
+
+        let mut doc = EpubDocument::new(&path).unwrap();
+        doc.pagination_dir = dir.clone();
+        doc.layout(600, 800, 12.0, 300);
+        assert_eq!(doc.page_numbers(0), Some((0, count)));
+        assert!(!doc.paginate());
+
+        fs::remove_dir_all(&dir).ok();
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_navigation_fallbacks() {
+        let path = build_epub("plato-test-ncx.epub", &nav(PAGE_LIST_NAV), true);
+        let mut doc = EpubDocument::new(&path).unwrap();
+        assert_eq!(doc.toc().unwrap()[0].title, "NCX One");
+        fs::remove_file(path).ok();
+
+        let path = build_epub("plato-test-landmarks.epub", &nav(LANDMARKS_NAV), false);
+        let mut doc = EpubDocument::new(&path).unwrap();
+        assert_eq!(doc.toc().unwrap()[0].title, "Start of Content");
+        assert!(doc.page_names().is_empty());
+        fs::remove_file(path).ok();
+    }
 }
@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use failure::Error;
+use crate::helpers::{load_json, save_json};
+
+pub const PAGINATION_DIR: &str = ".pagination";
+// Bump this whenever a change in the layout engine moves the page breaks.
+pub const LAYOUT_VERSION: u32 = 11;
+// Maximum number of paginations kept on disk.
+const MAX_ENTRIES: usize = 128;
+
+// The page breaks of a book for a given set of layout parameters.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Pagination {
+    // The offsets at which the pages of each chunk of the spine start.
+    breaks: BTreeMap<usize, Vec<usize>>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    modified: bool,
+}
+
+impl Pagination {
+    pub fn load<P: AsRef<Path>>(path: P) -> Pagination {
+        let path = path.as_ref();
+        let mut pagination = if path.exists() {
+            load_json::<Pagination, _>(path)
+                .map_err(|e| eprintln!("Can't load {}: {}", path.display(), e))
+                .unwrap_or_default()
+        } else {
+            Pagination::default()
+        };
+        pagination.path = Some(path.to_path_buf());
+        pagination
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Vec<usize>> {
+        self.breaks.get(&index)
+    }
+
+    // The number of pages of the book, once every chunk has been paginated.
+    pub fn pages_count(&self, chunks_count: usize) -> Option<usize> {
+        if self.breaks.len() < chunks_count {
+            return None;
+        }
+        Some(self.breaks.values().map(Vec::len).sum())
+    }
+
+    // The index, within the book, of the page holding the given offset.
+    pub fn page_number(&self, index: usize, offset: usize) -> Option<usize> {
+        let breaks = self.breaks.get(&index)?;
+        let before = self.breaks.range(..index).map(|(_, b)| b.len()).sum::<usize>();
+        Some(before + breaks.iter().skip(1).take_while(|&&b| offset >= b).count())
+    }
+
+    pub fn insert(&mut self, index: usize, breaks: Vec<usize>) {
+        if self.breaks.get(&index) != Some(&breaks) {
+            self.breaks.insert(index, breaks);
+            self.modified = true;
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), Error> {
+        if !self.modified {
+            return Ok(());
+        }
+        if let Some(path) = self.path.as_ref() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+                prune(parent, path)?;
+            }
+            save_json(self, path)?;
+            self.modified = false;
+        }
+        Ok(())
+    }
+}
+
+// Removes the least recently written paginations, keeping room for *current*.
+fn prune(dir: &Path, current: &Path) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?.filter_map(|e| e.ok())
+                        .filter(|e| e.path() != current)
+                        .filter_map(|e| e.metadata().and_then(|m| m.modified())
+                                                    .ok().map(|t| (t, e.path())))
+                        .collect::<Vec<_>>();
+    if entries.len() < MAX_ENTRIES {
+        return Ok(());
+    }
+    entries.sort();
+    for (_, path) in entries.iter().take(entries.len() + 1 - MAX_ENTRIES) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_numbers() {
+        let mut pagination = Pagination::default();
+        pagination.insert(0, vec![0, 120, 260]);
+        assert_eq!(pagination.pages_count(2), None);
+        pagination.insert(1, vec![400, 530]);
+        assert_eq!(pagination.pages_count(2), Some(5));
+        assert_eq!(pagination.page_number(0, 130), Some(1));
+        assert_eq!(pagination.page_number(1, 400), Some(3));
+        assert_eq!(pagination.page_number(1, 600), Some(4));
+    }
+}
@@ -0,0 +1,603 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::collections::BTreeSet;
+use fnv::FnvHashMap;
+use zip::ZipArchive;
+use failure::{Error, format_err};
+use super::{Document, Location, TocEntry, file_kind};
+use super::html::HtmlDocument;
+use super::html::dom::Node;
+use super::html::xml::XmlParser;
+use super::html::engine::ResourceFetcher;
+use super::text::decode_text;
+use crate::helpers::decode_entities;
+
+pub const ZIPPED_FB2_KIND: &str = "fb2.zip";
+const VIEWER_STYLESHEET: &str = "css/epub.css";
+const USER_STYLESHEET: &str = "css/epub-user.css";
+const IMAGE_KINDS: [&str; 4] = ["jpg", "jpeg", "png", "gif"];
+
+const FB2_STYLE: &str = "
+.subtitle, .cover { text-align: center; }
+.subtitle { font-weight: bold; }
+.epigraph { margin-left: 30%; font-style: italic; }
+.text-author { text-align: right; font-style: italic; }
+.poem { margin: 1em 0 1em 2em; }
+.stanza { margin: 1em 0; }
+.verse, .text-author, .date { margin: 0; }
+.notes { font-size: 0.9em; }
+";
+
+// The characters of Windows-1251 above 0x7F, except the contiguous range
+// of the Russian alphabet.
+const WINDOWS_1251: [char; 64] = [
+    'Ђ', 'Ѓ', '‚', 'ѓ', '„', '…', '†', '‡', '€', '‰', 'Љ', '‹', 'Њ', 'Ќ', 'Ћ', 'Џ',
+    'ђ', '‘', '’', '“', '”', '•', '–', '—', '\u{98}', '™', 'љ', '›', 'њ', 'ќ', 'ћ', 'џ',
+    '\u{A0}', 'Ў', 'ў', 'Ј', '¤', 'Ґ', '¦', '§', 'Ё', '©', 'Є', '«', '¬', '\u{AD}', '®', 'Ї',
+    '°', '±', 'І', 'і', 'ґ', 'µ', '¶', '·', 'ё', '№', 'є', '»', 'ј', 'Ѕ', 'ѕ', 'ї',
+];
+
+// Many FictionBook files are encoded in Windows-1251, which is declared
+// in the XML prolog.
+fn decode(bytes: &[u8]) -> String {
+    let prolog = String::from_utf8_lossy(&bytes[..bytes.len().min(128)]).to_lowercase();
+    let is_cyrillic = prolog.starts_with("<?xml") &&
+                      (prolog.contains("windows-1251") || prolog.contains("cp1251"));
+    if !is_cyrillic {
+        return decode_text(bytes);
+    }
+    bytes.iter().map(|&b| {
+        match b {
+            0x00..=0x7F => b as char,
+            0x80..=0xBF => WINDOWS_1251[(b - 0x80) as usize],
+            _ => std::char::from_u32(0x0410 + (b - 0xC0) as u32).unwrap(),
+        }
+    }).collect()
+}
+
+fn read_zipped(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    for i in 0..archive.len() {
+        let mut zf = archive.by_index(i)?;
+        if zf.name().to_lowercase().ends_with(".fb2") {
+            let mut buf = Vec::new();
+            zf.read_to_end(&mut buf)?;
+            return Ok(buf);
+        }
+    }
+    Err(format_err!("Can't find a FictionBook file in the archive."))
+}
+
+fn read_book(path: &Path) -> Result<String, Error> {
+    let bytes = if path.to_string_lossy().to_lowercase().ends_with(ZIPPED_FB2_KIND) {
+        read_zipped(path)?
+    } else {
+        fs::read(path)?
+    };
+    Ok(decode(&bytes))
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn href(node: &Node) -> Option<&str> {
+    node.attributes().and_then(|attributes| {
+        attributes.iter().find(|(key, _)| local_name(key) == "href")
+                  .map(|(_, value)| value.as_str())
+    })
+}
+
+fn clean_text(node: &Node) -> String {
+    decode_entities(&node.text_content()).split_whitespace()
+                                         .collect::<Vec<&str>>().join(" ")
+}
+
+fn title_text(node: &Node) -> Option<String> {
+    let title = node.children()?.iter()
+                    .find(|child| child.tag_name().map(local_name) == Some("title"))?;
+    let text = title.children()?.iter()
+                    .filter(|child| child.tag_name().map(local_name) == Some("p"))
+                    .map(clean_text)
+                    .filter(|text| !text.is_empty())
+                    .collect::<Vec<String>>().join(" ");
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+// The binaries are given file names with the appropriate extensions so that MuPDF can open them.
+fn resource_name(id: &str, content_type: Option<&str>) -> String {
+    if file_kind(id).map_or(false, |k| IMAGE_KINDS.contains(&k.as_str())) {
+        return id.to_string();
+    }
+    let extension = match content_type {
+        Some("image/png") => "png",
+        Some("image/gif") => "gif",
+        _ => "jpg",
+    };
+    format!("{}.{}", id, extension)
+}
+
+struct Binaries(FnvHashMap<String, Vec<u8>>);
+
+impl ResourceFetcher for Binaries {
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        self.0.get(name).cloned()
+            .ok_or_else(|| format_err!("Can't find {}.", name))
+    }
+}
+
+fn load_binaries(root: &Node) -> (FnvHashMap<String, String>, Binaries) {
+    let mut names = FnvHashMap::default();
+    let mut data = FnvHashMap::default();
+    if let Some(children) = root.children() {
+        for child in children {
+            if child.tag_name().map(local_name) != Some("binary") {
+                continue;
+            }
+            if let Some(id) = child.id() {
+                let name = resource_name(id, child.attr("content-type"));
+                let encoded = child.text_content().split_whitespace().collect::<String>();
+                match base64::decode(&encoded) {
+                    Ok(bytes) => {
+                        names.insert(id.to_string(), name.clone());
+                        data.insert(name, bytes);
+                    },
+                    Err(e) => eprintln!("Can't decode binary {}: {}.", id, e),
+                }
+            }
+        }
+    }
+    (names, Binaries(data))
+}
+
+struct Conversion {
+    html: String,
+    toc: Vec<TocEntry>,
+    notes: FnvHashMap<String, String>,
+}
+
+struct Converter<'a> {
+    names: &'a FnvHashMap<String, String>,
+    html: String,
+    notes: FnvHashMap<String, String>,
+    index: usize,
+    // The images are inline within paragraphs and block elsewhere.
+    in_paragraph: bool,
+}
+
+impl<'a> Converter<'a> {
+    fn push_attributes(&mut self, node: &Node, class: Option<&str>) {
+        if let Some(class) = class {
+            self.html.push_str(&format!(" class=\"{}\"", class));
+        }
+        if let Some(id) = node.id() {
+            self.html.push_str(&format!(" id=\"{}\"", id.replace('"', "&quot;")));
+        }
+    }
+
+    fn image(&mut self, node: &Node, is_block: bool) {
+        let src = match href(node) {
+            Some(href) if href.starts_with('#') => {
+                self.names.get(&href[1..]).map(String::as_str).unwrap_or(&href[1..])
+            },
+            Some(href) => href,
+            None => return,
+        };
+        let src = src.replace('"', "&quot;");
+        if is_block {
+            self.html.push_str("<div class=\"image\"");
+            self.push_attributes(node, None);
+            self.html.push_str(&format!("><img src=\"{}\"/></div>", src));
+        } else {
+            self.html.push_str(&format!("<img src=\"{}\"/>", src));
+        }
+    }
+
+    fn title(&mut self, node: &Node, depth: usize) {
+        let tag = format!("h{}", depth.min(6));
+        self.html.push_str(&format!("<{}", tag));
+        self.push_attributes(node, Some("title"));
+        self.html.push('>');
+        let mut first = true;
+        for child in node.children().into_iter().flatten() {
+            if child.tag_name().map(local_name) == Some("p") {
+                if !first {
+                    self.html.push_str("<br/>");
+                }
+                self.children(child, depth, &mut Vec::new(), false);
+                first = false;
+            }
+        }
+        self.html.push_str(&format!("</{}>", tag));
+    }
+
+    fn section(&mut self, node: &Node, depth: usize, toc: &mut Vec<TocEntry>, is_note: bool) {
+        let offset = self.html.len();
+        self.html.push_str("<div");
+        self.push_attributes(node, Some("section"));
+        self.html.push('>');
+
+        let title = title_text(node);
+        let mut start = self.html.len();
+        if let (Some(title), false) = (title, is_note) {
+            let index = self.index;
+            self.index += 1;
+            let mut children = Vec::new();
+            self.children(node, depth, &mut children, is_note);
+            toc.push(TocEntry {
+                title,
+                location: Location::Exact(offset),
+                index,
+                children,
+            });
+        } else {
+            for child in node.children().into_iter().flatten() {
+                self.node(child, depth, toc, is_note);
+                if child.tag_name().map(local_name) == Some("title") {
+                    start = self.html.len();
+                }
+            }
+        }
+
+        if is_note {
+            if let Some(id) = node.id() {
+                self.notes.insert(id.to_string(), self.html[start..].to_string());
+            }
+        }
+
+        self.html.push_str("</div>");
+    }
+
+    fn children(&mut self, node: &Node, depth: usize, toc: &mut Vec<TocEntry>, is_note: bool) {
+        for child in node.children().into_iter().flatten() {
+            self.node(child, depth, toc, is_note);
+        }
+    }
+
+    fn wrap(&mut self, node: &Node, tag: &str, class: Option<&str>, depth: usize, toc: &mut Vec<TocEntry>, is_note: bool) {
+        let in_paragraph = self.in_paragraph;
+        self.in_paragraph = in_paragraph || tag == "p";
+        self.html.push_str(&format!("<{}", tag));
+        self.push_attributes(node, class);
+        self.html.push('>');
+        self.children(node, depth, toc, is_note);
+        self.html.push_str(&format!("</{}>", tag));
+        self.in_paragraph = in_paragraph;
+    }
+
+    fn node(&mut self, node: &Node, depth: usize, toc: &mut Vec<TocEntry>, is_note: bool) {
+        let name = match node.tag_name() {
+            Some(name) => local_name(name),
+            None => {
+                if let Some(text) = node.text() {
+                    self.html.push_str(text);
+                }
+                return;
+            },
+        };
+
+        match name {
+            "section" => self.section(node, depth + 1, toc, is_note),
+            "title" => self.title(node, depth),
+            "image" => {
+                let is_block = !self.in_paragraph;
+                self.image(node, is_block);
+            },
+            "p" => self.wrap(node, "p", None, depth, toc, is_note),
+            "subtitle" | "text-author" => self.wrap(node, "p", Some(name), depth, toc, is_note),
+            "v" => self.wrap(node, "p", Some("verse"), depth, toc, is_note),
+            "date" => self.wrap(node, "p", Some("date"), depth, toc, is_note),
+            "epigraph" | "annotation" | "poem" | "stanza" => self.wrap(node, "div", Some(name), depth, toc, is_note),
+            "cite" => self.wrap(node, "blockquote", Some("cite"), depth, toc, is_note),
+            "empty-line" => self.html.push_str("<p class=\"empty-line\">\u{A0}</p>"),
+            "emphasis" => self.wrap(node, "em", None, depth, toc, is_note),
+            "strong" | "sub" | "sup" | "code" => self.wrap(node, name, None, depth, toc, is_note),
+            "strikethrough" | "style" => self.wrap(node, "span", Some(name), depth, toc, is_note),
+            "table" | "tr" | "td" | "th" => {
+                self.html.push_str(&format!("<{}", name));
+                self.push_attributes(node, None);
+                for key in &["colspan", "rowspan", "align"] {
+                    if let Some(value) = node.attr(key) {
+                        self.html.push_str(&format!(" {}=\"{}\"", key, value.replace('"', "&quot;")));
+                    }
+                }
+                self.html.push('>');
+                self.children(node, depth, toc, is_note);
+                self.html.push_str(&format!("</{}>", name));
+            },
+            "a" => {
+                self.html.push_str("<a");
+                if let Some(href) = href(node) {
+                    self.html.push_str(&format!(" href=\"{}\"", href.replace('"', "&quot;")));
+                }
+                let class = if node.attr("type") == Some("note") { Some("noteref") } else { None };
+                self.push_attributes(node, class);
+                self.html.push('>');
+                self.children(node, depth, toc, is_note);
+                self.html.push_str("</a>");
+            },
+            _ => self.children(node, depth, toc, is_note),
+        }
+    }
+}
+
+fn convert(root: &Node, names: &FnvHashMap<String, String>) -> Conversion {
+    let mut converter = Converter {
+        names,
+        html: String::new(),
+        notes: FnvHashMap::default(),
+        index: 0,
+        in_paragraph: false,
+    };
+    let mut toc = Vec::new();
+
+    let title_info = root.find("title-info");
+    let title = title_info.and_then(|info| info.find("book-title"))
+                          .map(|node| node.text_content())
+                          .unwrap_or_default();
+    let lang = title_info.and_then(|info| info.find("lang"))
+                         .map(clean_text).unwrap_or_default();
+
+    converter.html.push_str(&format!("<html xml:lang=\"{}\"><head><title>{}</title>\
+                                      <style type=\"text/css\">{}</style></head><body>",
+                                     lang.replace('"', "&quot;"), title, FB2_STYLE));
+
+    if let Some(coverpage) = title_info.and_then(|info| info.find("coverpage")) {
+        converter.html.push_str("<div class=\"cover\">");
+        for child in coverpage.children().into_iter().flatten() {
+            if child.tag_name().map(local_name) == Some("image") {
+                converter.image(child, false);
+            }
+        }
+        converter.html.push_str("</div>");
+    }
+
+    for body in root.children().into_iter().flatten() {
+        if body.tag_name().map(local_name) != Some("body") {
+            continue;
+        }
+        let is_note = body.attr("name").map_or(false, |name| name == "notes" || name == "comments");
+        let class = if is_note { "notes" } else { "body" };
+        let offset = converter.html.len();
+        converter.html.push_str(&format!("<div class=\"{}\">", class));
+        if is_note {
+            // The notes are gathered under a single entry.
+            if let Some(title) = title_text(body) {
+                toc.push(TocEntry {
+                    title,
+                    location: Location::Exact(offset),
+                    index: converter.index,
+                    children: Vec::new(),
+                });
+                converter.index += 1;
+            }
+        }
+        converter.children(body, 1, &mut toc, is_note);
+        converter.html.push_str("</div>");
+    }
+
+    converter.html.push_str("</body></html>");
+
+    Conversion {
+        html: converter.html,
+        toc,
+        notes: converter.notes,
+    }
+}
+
+pub struct Fb2Document {
+    doc: HtmlDocument,
+    description: Fb2Description,
+    toc: Vec<TocEntry>,
+    notes: FnvHashMap<String, String>,
+}
+
+impl Fb2Document {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Fb2Document, Error> {
+        let root = XmlParser::new(&read_book(path.as_ref())?).parse();
+        let root = root.find("FictionBook")
+                       .ok_or_else(|| format_err!("Can't find the FictionBook element."))?;
+        let (names, binaries) = load_binaries(root);
+        let conversion = convert(root, &names);
+        let mut doc = HtmlDocument::new_from_memory(&conversion.html);
+        doc.set_fetcher(binaries);
+        doc.set_viewer_stylesheet(VIEWER_STYLESHEET);
+        doc.set_user_stylesheet(USER_STYLESHEET);
+        Ok(Fb2Document {
+            doc,
+            description: Fb2Description(root.find("description").cloned()),
+            toc: conversion.toc,
+            notes: conversion.notes,
+        })
+    }
+}
+
+// The description element of a book, which holds its metadata.
+pub struct Fb2Description(Option<Node>);
+
+impl Fb2Description {
+    // Only the description is parsed: the rest of the book, and its binaries
+    // in particular, aren't needed to import it.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Fb2Description, Error> {
+        Fb2Description::parse(&read_book(path.as_ref())?)
+    }
+
+    fn parse(text: &str) -> Result<Fb2Description, Error> {
+        let start = text.find("<description")
+                        .ok_or_else(|| format_err!("Can't find the description element."))?;
+        let end = text[start..].find("</description>")
+                               .map(|index| start + index + "</description>".len())
+                               .ok_or_else(|| format_err!("Unterminated description element."))?;
+        let root = XmlParser::new(&text[start..end]).parse();
+        Ok(Fb2Description(root.find("description").cloned()))
+    }
+
+    fn info(&self, name: &str) -> Option<&Node> {
+        self.0.as_ref().and_then(|description| description.find(name))
+    }
+
+    fn info_text(&self, info: &str, key: &str) -> Option<String> {
+        self.info(info).and_then(|node| node.find(key))
+            .map(clean_text)
+            .filter(|value| !value.is_empty())
+    }
+
+    pub fn categories(&self) -> BTreeSet<String> {
+        self.info("title-info").and_then(Node::children).map(|children| {
+            children.iter().filter(|child| child.tag_name().map(local_name) == Some("genre"))
+                    .map(clean_text)
+                    .filter(|genre| !genre.is_empty())
+                    .collect()
+        }).unwrap_or_default()
+    }
+
+    pub fn series(&self) -> Option<String> {
+        self.info("title-info").and_then(|info| info.find("sequence"))
+            .and_then(|sequence| sequence.attr("name"))
+            .map(|name| decode_entities(name).into_owned())
+    }
+
+    pub fn series_index(&self) -> Option<String> {
+        self.info("title-info").and_then(|info| info.find("sequence"))
+            .and_then(|sequence| sequence.attr("number"))
+            .map(String::from)
+    }
+
+    pub fn description(&self) -> Option<String> {
+        self.info_text("title-info", "annotation")
+    }
+
+    pub fn publisher(&self) -> Option<String> {
+        self.info_text("publish-info", "publisher")
+    }
+
+    pub fn isbn(&self) -> Option<String> {
+        self.info_text("publish-info", "isbn")
+    }
+
+    pub fn language(&self) -> Option<String> {
+        self.info_text("title-info", "lang")
+    }
+
+    pub fn year(&self) -> Option<String> {
+        self.info("title-info").and_then(|info| info.find("date"))
+            .and_then(|date| date.attr("value").map(String::from)
+                                 .or_else(|| Some(clean_text(date))))
+            .filter(|date| !date.is_empty())
+            .or_else(|| self.info_text("publish-info", "year"))
+            .map(|date| date.chars().take(4).collect())
+    }
+
+    pub fn title(&self) -> Option<String> {
+        self.info_text("title-info", "book-title")
+    }
+
+    pub fn author(&self) -> Option<String> {
+        let authors = self.info("title-info").and_then(Node::children).map(|children| {
+            children.iter().filter(|child| child.tag_name().map(local_name) == Some("author")).filter_map(|author| {
+                let name = ["first-name", "middle-name", "last-name"].iter()
+                                .filter_map(|key| author.find(key).map(clean_text))
+                                .filter(|part| !part.is_empty())
+                                .collect::<Vec<String>>().join(" ");
+                if name.is_empty() {
+                    author.find("nickname").map(clean_text)
+                } else {
+                    Some(name)
+                }
+            }).collect::<Vec<String>>()
+        }).unwrap_or_default();
+        if authors.is_empty() {
+            None
+        } else {
+            Some(authors.join(", "))
+        }
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<String> {
+        self.info_text("title-info", key)
+            .or_else(|| self.info_text("publish-info", key))
+    }
+}
+
+impl Document for Fb2Document {
+    delegate_to_html_document!();
+
+    fn footnote(&mut self, _offset: usize, uri: &str) -> Option<String> {
+        let id = uri.trim_start_matches('#');
+        self.notes.get(id).cloned()
+    }
+
+    fn title(&self) -> Option<String> {
+        self.description.title()
+    }
+
+    fn author(&self) -> Option<String> {
+        self.description.author()
+    }
+
+    fn metadata(&self, key: &str) -> Option<String> {
+        self.description.metadata(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOK: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<FictionBook xmlns:l="http://www.w3.org/1999/xlink">
+<description><title-info><book-title>Tales</book-title>
+<coverpage><image l:href="#cover"/></coverpage></title-info></description>
+<body><title><p>Tales</p></title>
+<section><title><p>Part One</p></title>
+<section id="c1"><title><p>Chapter</p><p>The Start</p></title><p>Text<a l:href="#n1" type="note">1</a>.</p></section>
+</section></body>
+<body name="notes"><title><p>Notes</p></title><section id="n1"><title><p>1</p></title><p>A note.</p></section></body>
+<binary id="cover" content-type="image/png">aGVs
+bG8=</binary>
+</FictionBook>"##;
+
+    #[test]
+    fn test_conversion() {
+        let root = XmlParser::new(BOOK).parse();
+        let root = root.find("FictionBook").unwrap();
+        let (names, binaries) = load_binaries(root);
+        assert_eq!(names.get("cover").map(String::as_str), Some("cover.png"));
+        assert_eq!(binaries.0.get("cover.png").map(Vec::as_slice), Some(&b"hello"[..]));
+
+        let conversion = convert(root, &names);
+        assert!(conversion.html.contains("<img src=\"cover.png\"/>"));
+        assert!(conversion.html.contains("<a href=\"#n1\" class=\"noteref\">1</a>"));
+        assert_eq!(conversion.notes.get("n1").map(String::as_str), Some("<p>A note.</p>"));
+
+        let titles = conversion.toc.iter().map(|e| e.title.as_str()).collect::<Vec<&str>>();
+        assert_eq!(titles, vec!["Part One", "Notes"]);
+        let chapter = &conversion.toc[0].children[0];
+        assert_eq!(chapter.title, "Chapter The Start");
+        assert_eq!(chapter.index, 1);
+        if let Location::Exact(offset) = chapter.location {
+            assert!(conversion.html[offset..].starts_with("<div class=\"section\" id=\"c1\">"));
+        } else {
+            panic!("Unexpected location.");
+        }
+    }
+
+    #[test]
+    fn test_description() {
+        let book = BOOK.replace("</book-title>", "</book-title><fb:genre>sf</fb:genre><genre>humor</genre>");
+        let description = Fb2Description::parse(&book).unwrap();
+        assert_eq!(description.title().as_ref().map(String::as_str), Some("Tales"));
+        assert_eq!(description.categories().into_iter().collect::<Vec<String>>(), vec!["humor", "sf"]);
+    }
+
+    #[test]
+    fn test_windows_1251() {
+        let bytes = b"<?xml version=\"1.0\" encoding=\"windows-1251\"?><p>\xcf\xf0\xe8\xe2\xe5\xf2 \xb9</p>";
+        assert!(decode(bytes).ends_with("<p>Привет №</p>"));
+    }
+}
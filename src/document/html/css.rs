@@ -1,10 +1,28 @@
 use fnv::FnvHashSet;
+use super::media::{MediaQueryList, Device};
 
+// A complex selector: a compound selector, the subject, preceded by other
+// compound selectors, each one linked to the next by a combinator.
 #[derive(Debug, Clone)]
-pub enum Selector {
-    Simple(SimpleSelector),
-    ParentChild(SimpleSelector, SimpleSelector),
-    Siblings(SimpleSelector, SimpleSelector),
+pub struct Selector {
+    pub subject: SimpleSelector,
+    // The preceding compound selectors, from right to left.
+    pub combinators: Vec<(Combinator, SimpleSelector)>,
+    pub pseudo_element: Option<PseudoElement>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Combinator {
+    Descendant,
+    Child,
+    NextSibling,
+    SubsequentSibling,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PseudoElement {
+    FirstLetter,
+    FirstLine,
 }
 
 #[derive(Debug, Clone)]
@@ -12,38 +30,91 @@ pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub classes: FnvHashSet<String>,
     pub id: Option<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
 }
 
+#[derive(Debug, Clone)]
+pub struct AttributeSelector {
+    pub name: String,
+    pub operator: AttributeOperator,
+    pub value: String,
+    pub case_insensitive: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AttributeOperator {
+    Exists,
+    Equals,
+    Includes,
+    DashMatch,
+    Prefix,
+    Suffix,
+    Substring,
+}
+
+// The *An+B* arguments are stored as *(A, B)*.
+#[derive(Debug, Clone)]
+pub enum PseudoClass {
+    Root,
+    Empty,
+    Link,
+    FirstChild,
+    LastChild,
+    OnlyChild,
+    FirstOfType,
+    LastOfType,
+    OnlyOfType,
+    NthChild(i32, i32),
+    NthLastChild(i32, i32),
+    NthOfType(i32, i32),
+    NthLastOfType(i32, i32),
+    Not(Vec<SimpleSelector>),
+}
 
 impl Default for SimpleSelector {
     fn default() -> SimpleSelector {
-        SimpleSelector { tag_name: None, id: None, classes: FnvHashSet::default() }
+        SimpleSelector {
+            tag_name: None,
+            id: None,
+            classes: FnvHashSet::default(),
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
+        }
     }
 }
 
 pub type Specificity = [usize; 3];
 
+fn add_specificities(a: Specificity, b: Specificity) -> Specificity {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
 impl SimpleSelector {
     // http://www.w3.org/TR/selectors/#specificity
     pub fn specificity(&self) -> Specificity {
         let a = self.id.iter().count();
-        let b = self.classes.len();
+        let b = self.classes.len() + self.attributes.len();
         let c = self.tag_name.iter().count();
-        [a, b, c]
+        self.pseudo_classes.iter().fold([a, b, c], |s, pc| {
+            match pc {
+                // The specificity of the most specific argument.
+                PseudoClass::Not(selectors) => {
+                    add_specificities(s, selectors.iter().map(SimpleSelector::specificity)
+                                                  .max().unwrap_or([0, 0, 0]))
+                },
+                _ => add_specificities(s, [0, 1, 0]),
+            }
+        })
     }
 }
 
 impl Selector {
     pub fn specificity(&self) -> Specificity {
-        match self {
-            Selector::Simple(sel) => sel.specificity(),
-            Selector::ParentChild(sel1, sel2) |
-            Selector::Siblings(sel1, sel2) => {
-                let s1 = sel1.specificity();
-                let s2 = sel2.specificity();
-                [s1[0] + s2[0], s1[1] + s2[1], s1[2] + s2[2]]
-            },
-        }
+        let s = self.combinators.iter().fold(self.subject.specificity(), |s, (_, sel)| {
+            add_specificities(s, sel.specificity())
+        });
+        add_specificities(s, [0, 0, self.pseudo_element.iter().count()])
     }
 }
 
@@ -67,6 +138,14 @@ pub struct Rule {
     pub kind: RuleKind,
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    // The queries of the enclosing media blocks.
+    pub media: Vec<MediaQueryList>,
+}
+
+impl Rule {
+    pub fn matches_media(&self, device: &Device) -> bool {
+        self.media.iter().all(|list| list.matches(device))
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -127,66 +206,246 @@ impl<'a> CssParser<'a> {
         }
     }
 
-    fn parse_selectors(&mut self) -> Vec<Selector> {
-        let mut selectors = Vec::new();
-        let mut simple_selectors = Vec::new();
-        let mut sel = SimpleSelector::default();
-        let mut combinator = None;
+    fn parse_identifier(&mut self) -> &'a str {
+        let offset = self.offset;
+        self.advance_while(|&c| c.is_alphanumeric() || c == '-' || c == '_' || c == '@');
+        &self.input[offset..self.offset]
+    }
+
+    fn parse_string(&mut self) -> &'a str {
+        match self.next() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                self.advance(1);
+                let offset = self.offset;
+                self.advance_while(|&c| c != quote);
+                let end = self.offset;
+                self.advance(1);
+                &self.input[offset..end]
+            },
+            _ => self.parse_identifier(),
+        }
+    }
+
+    // The text between the current opening parenthesis and the matching one.
+    fn parse_arguments(&mut self) -> &'a str {
+        self.advance(1);
+        let offset = self.offset;
+        let mut depth = 1;
+        while let Some(c) = self.next() {
+            if c == '(' {
+                depth += 1;
+            } else if c == ')' {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            self.advance(1);
+        }
+        let end = self.offset;
+        self.advance(1);
+        &self.input[offset..end]
+    }
 
+    fn parse_attribute_selector(&mut self) -> Option<AttributeSelector> {
+        self.advance(1);
+        self.advance_while(|&c| c.is_whitespace());
+        let mut name = self.parse_identifier().to_string();
+        // Namespace prefixes: *ns|name*.
+        if self.next() == Some('|') && !self.starts_with("|=") {
+            self.advance(1);
+            let local_name = self.parse_identifier();
+            name = if name.is_empty() { local_name.to_string() } else { format!("{}:{}", name, local_name) };
+        }
+        if name.is_empty() {
+            return None;
+        }
+        self.advance_while(|&c| c.is_whitespace());
+        let operator = match self.next()? {
+            ']' => {
+                self.advance(1);
+                return Some(AttributeSelector { name, operator: AttributeOperator::Exists,
+                                                value: String::new(), case_insensitive: false });
+            },
+            '=' => AttributeOperator::Equals,
+            '~' => AttributeOperator::Includes,
+            '|' => AttributeOperator::DashMatch,
+            '^' => AttributeOperator::Prefix,
+            '$' => AttributeOperator::Suffix,
+            '*' => AttributeOperator::Substring,
+            _ => return None,
+        };
+        self.advance(if operator == AttributeOperator::Equals { 1 } else { 2 });
+        self.advance_while(|&c| c.is_whitespace());
+        let value = self.parse_string().to_string();
         self.advance_while(|&c| c.is_whitespace());
+        let case_insensitive = self.next().map_or(false, |c| c == 'i' || c == 'I');
+        if case_insensitive || self.next().map_or(false, |c| c == 's' || c == 'S') {
+            self.advance(1);
+            self.advance_while(|&c| c.is_whitespace());
+        }
+        if self.next() != Some(']') {
+            return None;
+        }
+        self.advance(1);
+        Some(AttributeSelector { name, operator, value, case_insensitive })
+    }
 
-        while !self.eof() {
+    fn parse_simple_selector(&mut self, pseudo_element: &mut Option<PseudoElement>) -> Option<SimpleSelector> {
+        let mut sel = SimpleSelector::default();
+        let start = self.offset;
+
+        if self.next() == Some('*') {
+            self.advance(1);
+        } else {
+            let name = self.parse_identifier();
+            if !name.is_empty() {
+                sel.tag_name = Some(name.to_string());
+            }
+        }
+
+        loop {
             match self.next() {
                 Some('#') => {
                     self.advance(1);
-                    let offset = self.offset;
-                    self.advance_while(|&c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
-                    sel.id = Some(self.input[offset..self.offset].to_string());
-
+                    sel.id = Some(self.parse_identifier().to_string());
                 },
                 Some('.') => {
                     self.advance(1);
-                    let offset = self.offset;
-                    self.advance_while(|&c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
-                    sel.classes.insert(self.input[offset..self.offset].to_string());
+                    sel.classes.insert(self.parse_identifier().to_string());
                 },
-                _ => {
-                    let offset = self.offset;
-                    self.advance_while(|&c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '@');
-                    if self.offset > offset {
-                        sel.tag_name = Some(self.input[offset..self.offset].to_string());
-                    } else {
-                        self.advance_while(|&c| c.is_whitespace());
-                        simple_selectors.push(sel);
-                        sel = SimpleSelector::default();
-                        let next = self.next();
+                Some('[') => {
+                    sel.attributes.push(self.parse_attribute_selector()?);
+                },
+                Some(':') => {
+                    self.advance(1);
+                    let double_colon = self.next() == Some(':');
+                    if double_colon {
                         self.advance(1);
-                        if next == Some('>') || next == Some('+') {
-                            combinator = next;
-                        } else if next == Some('{') || next == Some(',') {
-                            match combinator {
-                                Some('>') if simple_selectors.len() == 2 => {
-                                    selectors.push(Selector::ParentChild(simple_selectors[0].clone(),
-                                                                         simple_selectors[1].clone()));
-                                },
-                                Some('+') if simple_selectors.len() == 2 => {
-                                    selectors.push(Selector::Siblings(simple_selectors[0].clone(),
-                                                                      simple_selectors[1].clone()));
-                                },
-                                None if simple_selectors.len() == 1 => {
-                                    selectors.push(Selector::Simple(simple_selectors[0].clone()));
-                                },
-                                _ => (),
-                            }
-                            simple_selectors.clear();
-                            if next == Some('{') {
-                                break;
+                    }
+                    let name = self.parse_identifier().to_ascii_lowercase();
+                    let arguments = if self.next() == Some('(') {
+                        Some(self.parse_arguments())
+                    } else {
+                        None
+                    };
+                    match (name.as_str(), arguments) {
+                        ("first-letter", None) => *pseudo_element = Some(PseudoElement::FirstLetter),
+                        ("first-line", None) => *pseudo_element = Some(PseudoElement::FirstLine),
+                        _ if double_colon || pseudo_element.is_some() => return None,
+                        ("root", None) => sel.pseudo_classes.push(PseudoClass::Root),
+                        ("empty", None) => sel.pseudo_classes.push(PseudoClass::Empty),
+                        ("link", None) | ("any-link", None) => sel.pseudo_classes.push(PseudoClass::Link),
+                        ("first-child", None) => sel.pseudo_classes.push(PseudoClass::FirstChild),
+                        ("last-child", None) => sel.pseudo_classes.push(PseudoClass::LastChild),
+                        ("only-child", None) => sel.pseudo_classes.push(PseudoClass::OnlyChild),
+                        ("first-of-type", None) => sel.pseudo_classes.push(PseudoClass::FirstOfType),
+                        ("last-of-type", None) => sel.pseudo_classes.push(PseudoClass::LastOfType),
+                        ("only-of-type", None) => sel.pseudo_classes.push(PseudoClass::OnlyOfType),
+                        ("nth-child", Some(args)) => {
+                            let (a, b) = parse_nth(args)?;
+                            sel.pseudo_classes.push(PseudoClass::NthChild(a, b));
+                        },
+                        ("nth-last-child", Some(args)) => {
+                            let (a, b) = parse_nth(args)?;
+                            sel.pseudo_classes.push(PseudoClass::NthLastChild(a, b));
+                        },
+                        ("nth-of-type", Some(args)) => {
+                            let (a, b) = parse_nth(args)?;
+                            sel.pseudo_classes.push(PseudoClass::NthOfType(a, b));
+                        },
+                        ("nth-last-of-type", Some(args)) => {
+                            let (a, b) = parse_nth(args)?;
+                            sel.pseudo_classes.push(PseudoClass::NthLastOfType(a, b));
+                        },
+                        ("not", Some(args)) => {
+                            let mut selectors = Vec::new();
+                            for arg in args.split(',') {
+                                let mut parser = CssParser::new(arg.trim());
+                                let mut pe = None;
+                                let simple_selector = parser.parse_simple_selector(&mut pe)?;
+                                if !parser.eof() || pe.is_some() {
+                                    return None;
+                                }
+                                selectors.push(simple_selector);
                             }
-                        }
+                            sel.pseudo_classes.push(PseudoClass::Not(selectors));
+                        },
+                        // The dynamic pseudo-classes never match.
+                        _ => return None,
                     }
-                    self.advance_while(|&c| c.is_whitespace());
+                },
+                _ => break,
+            }
+        }
+
+        if self.offset > start {
+            Some(sel)
+        } else {
+            None
+        }
+    }
+
+    fn parse_selector(&mut self) -> Option<Selector> {
+        let mut simple_selectors = Vec::new();
+        let mut combinators = Vec::new();
+        let mut pseudo_element = None;
+
+        loop {
+            if pseudo_element.is_some() {
+                return None;
+            }
+            simple_selectors.push(self.parse_simple_selector(&mut pseudo_element)?);
+            let offset = self.offset;
+            self.advance_while(|&c| c.is_whitespace());
+            let combinator = match self.next() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(',') | Some('{') | None => break,
+                _ if self.offset > offset => Combinator::Descendant,
+                _ => return None,
+            };
+            if combinator != Combinator::Descendant {
+                self.advance(1);
+                self.advance_while(|&c| c.is_whitespace());
+            }
+            combinators.push(combinator);
+        }
+
+        let subject = simple_selectors.pop()?;
+        let combinators = combinators.into_iter().rev()
+                                     .zip(simple_selectors.into_iter().rev())
+                                     .collect();
+
+        Some(Selector { subject, combinators, pseudo_element })
+    }
+
+    // The invalid selectors are skipped, instead of the whole rule.
+    fn parse_selectors(&mut self) -> Vec<Selector> {
+        let mut selectors = Vec::new();
+
+        loop {
+            self.advance_while(|&c| c.is_whitespace());
+            let selector = self.parse_selector();
+            if selector.is_none() {
+                let mut depth = 0;
+                while let Some(c) = self.next() {
+                    match c {
+                        '(' | '[' => depth += 1,
+                        ')' | ']' => depth -= 1,
+                        ',' | '{' if depth <= 0 => break,
+                        _ => (),
+                    }
+                    self.advance(1);
                 }
             }
+            let next = self.next();
+            self.advance(1);
+            selectors.extend(selector);
+            if next != Some(',') {
+                break;
+            }
         }
 
         selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
@@ -250,32 +509,24 @@ impl<'a> CssParser<'a> {
         declarations
     }
 
-    fn parse_rule(&mut self, kind: RuleKind) -> Rule {
+    fn parse_rule(&mut self, kind: RuleKind, media: &[MediaQueryList]) -> Rule {
         let selectors = self.parse_selectors();
         let declarations = self.parse_declarations();
-        Rule { kind, selectors, declarations }
+        Rule { kind, selectors, declarations, media: media.to_vec() }
     }
 
-    fn skip_nested_rules(&mut self) {
-        self.advance_while(|&c| c != '{');
-        self.advance(1);
-        while !self.eof() {
-            self.parse_rule(RuleKind::Viewer);
-            self.advance_while(|&c| c.is_whitespace());
-            if let Some('}') = self.next() {
-                self.advance(1);
-                break;
-            }
-        }
-    }
-
-    pub fn parse(&mut self, kind: RuleKind) -> (Vec<Rule>, Vec<Rule>) {
-        let mut rules = Vec::new();
-        let mut at_rules = Vec::new();
+    // Parses the rules until the end of the input, or of the current media block.
+    fn parse_rules(&mut self, kind: RuleKind, media: &[MediaQueryList], rules: &mut Vec<Rule>, at_rules: &mut Vec<Rule>) {
         while !self.eof() {
             self.advance_while(|&c| c.is_whitespace());
             match self.next() {
                 None => break,
+                Some('}') => {
+                    self.advance(1);
+                    if !media.is_empty() {
+                        break;
+                    }
+                },
                 Some('/') => {
                     if self.starts_with("/*") {
                         self.advance(2);
@@ -285,24 +536,67 @@ impl<'a> CssParser<'a> {
                     }
                 },
                 Some('@') => {
-                    if self.starts_with("@namespace") || self.starts_with("@charset") {
+                    if self.starts_with("@namespace") || self.starts_with("@charset") || self.starts_with("@import") {
                         self.advance_while(|&c| c != ';');
                         self.advance(1);
-                        continue;
                     } else if self.starts_with("@media") {
-                        self.skip_nested_rules();
-                        continue;
+                        self.advance(6);
+                        let offset = self.offset;
+                        self.advance_while(|&c| c != '{');
+                        let mut media = media.to_vec();
+                        media.push(MediaQueryList::parse(&self.input[offset..self.offset]));
+                        self.advance(1);
+                        self.parse_rules(kind, &media, rules, at_rules);
                     } else {
-                        at_rules.push(self.parse_rule(kind));
+                        at_rules.push(self.parse_rule(kind, media));
                     }
                 },
-                _ => rules.push(self.parse_rule(kind)),
+                _ => rules.push(self.parse_rule(kind, media)),
             }
         }
+    }
+
+    // The rules of the media blocks are returned with their queries: they're
+    // evaluated when the stylesheet is applied, since they depend on the layout.
+    pub fn parse(&mut self, kind: RuleKind) -> (Vec<Rule>, Vec<Rule>) {
+        let mut rules = Vec::new();
+        let mut at_rules = Vec::new();
+        self.parse_rules(kind, &[], &mut rules, &mut at_rules);
         (rules, at_rules)
     }
 }
 
+// Parses the argument of the *nth-* pseudo-classes.
+fn parse_nth(text: &str) -> Option<(i32, i32)> {
+    let text = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+    match text.as_str() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => (),
+    }
+    if let Some(index) = text.find('n') {
+        let a = match &text[..index] {
+            "" | "+" => 1,
+            "-" => -1,
+            v => v.parse().ok()?,
+        };
+        let rest = &text[index+1..];
+        let b = if rest.is_empty() {
+            0
+        } else if rest.starts_with('+') {
+            rest[1..].parse().ok()?
+        } else if rest.starts_with('-') {
+            rest.parse().ok()?
+        } else {
+            return None;
+        };
+        Some((a, b))
+    } else {
+        let b = if text.starts_with('+') { &text[1..] } else { &text[..] };
+        b.parse().ok().map(|b| (0, b))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -320,4 +614,30 @@ mod tests {
         let (css, _) = CssParser::new(text).parse(RuleKind::User);
         println!("{:?}", css);
     }
+
+    #[test]
+    fn media_css() {
+        let text = "a { x: y } @media amzn-kf8 { b { x: y } @media (orientation: landscape) { c { x: y } } } d { x: y }";
+        let (css, _) = CssParser::new(text).parse(RuleKind::Document);
+        let device = Device { width: 600, height: 800, dpi: 167, font_size: 11.0, color: 0, monochrome: 8 };
+        let names = css.iter().filter(|rule| rule.matches_media(&device))
+                       .filter_map(|rule| rule.selectors[0].subject.tag_name.clone())
+                       .collect::<Vec<String>>();
+        assert_eq!(names, vec!["a", "b", "d"]);
+    }
+
+    #[test]
+    fn selectors_css() {
+        let text = "ul li:nth-child(2n+1) > a[href^='#fn'], p::first-letter, a:hover, :not(.x, #y) ~ p { x: y }";
+        let (css, _) = CssParser::new(text).parse(RuleKind::User);
+        let selectors = &css[0].selectors;
+        assert_eq!(selectors.len(), 3);
+        assert_eq!(selectors[0].specificity(), [1, 0, 1]);
+        assert_eq!(selectors[1].specificity(), [0, 2, 3]);
+        assert_eq!(selectors[1].combinators.len(), 2);
+        assert_eq!(selectors[2].pseudo_element, Some(PseudoElement::FirstLetter));
+        assert_eq!(parse_nth("-n + 3"), Some((-1, 3)));
+        assert_eq!(parse_nth("odd"), Some((2, 1)));
+        assert_eq!(parse_nth("5"), Some((0, 5)));
+    }
 }
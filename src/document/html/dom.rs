@@ -83,6 +83,45 @@ impl Node {
         }
     }
 
+    pub fn text_content(&self) -> String {
+        match *self {
+            Node::Text(TextData { ref text, .. }) |
+            Node::Whitespace(TextData { ref text, .. }) => text.clone(),
+            Node::Element(ElementData { ref children, .. }) => {
+                children.iter().map(Self::text_content).collect()
+            },
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut buf = String::new();
+        self.write_html(&mut buf);
+        buf
+    }
+
+    fn write_html(&self, buf: &mut String) {
+        match *self {
+            Node::Text(TextData { ref text, .. }) |
+            Node::Whitespace(TextData { ref text, .. }) => buf.push_str(text),
+            Node::Element(ElementData { ref name, ref attributes, ref children, .. }) => {
+                buf.push('<');
+                buf.push_str(name);
+                for (key, value) in attributes {
+                    buf.push_str(&format!(" {}=\"{}\"", key, value.replace('"', "&quot;")));
+                }
+                if children.is_empty() {
+                    buf.push_str("/>");
+                } else {
+                    buf.push('>');
+                    for child in children {
+                        child.write_html(buf);
+                    }
+                    buf.push_str(&format!("</{}>", name));
+                }
+            },
+        }
+    }
+
     pub fn is_inline(&self) -> bool {
         match *self {
             Node::Text(..) => true,
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::mem;
+use std::ptr;
+use std::path::{Path, PathBuf};
 use std::convert::TryFrom;
 use failure::Error;
 use kl_hyphenate::{Standard, Hyphenator, Iter};
@@ -6,31 +8,43 @@ use paragraph_breaker::{Item as ParagraphItem, Breakpoint, INFINITE_PENALTY};
 use paragraph_breaker::{total_fit, standard_fit};
 use xi_unicode::LineBreakIterator;
 use septem::Roman;
-use crate::helpers::{Normalize, decode_entities};
+use crate::helpers::{Normalize, decode_reference, percent_decode};
 use crate::framebuffer::{Framebuffer, Pixmap};
-use crate::font::{FontOpener, FontFamily};
+use crate::font::{FontOpener, FontFamily, Font, RenderPlan};
 use crate::document::{Document, Location};
 use crate::document::pdf::PdfOpener;
 use crate::unit::{mm_to_px, pt_to_px};
 use crate::geom::{Rectangle, Edge};
-use crate::settings::{DEFAULT_FONT_SIZE, DEFAULT_MARGIN_WIDTH, DEFAULT_TEXT_ALIGN, DEFAULT_LINE_HEIGHT};
+use crate::settings::{DEFAULT_FONT_SIZE, DEFAULT_MARGIN_WIDTH, DEFAULT_TEXT_ALIGN, DEFAULT_LINE_HEIGHT, DEFAULT_FONT_FAMILY};
 use super::parse::{parse_display, parse_edge, parse_float, parse_text_align, parse_text_indent, parse_width, parse_height, parse_inline_material};
 use super::parse::{parse_font_kind, parse_font_style, parse_font_weight, parse_font_size, parse_font_features, parse_font_variant, parse_letter_spacing};
-use super::parse::{parse_line_height, parse_vertical_align, parse_color, parse_list_style_type};
+use super::parse::{parse_line_height, parse_vertical_align, parse_color, parse_list_style_type, parse_direction};
+use super::parse::parse_border_width;
 use super::dom::{Node, ElementData, TextData};
 use super::layout::{StyleData, InlineMaterial, TextMaterial, ImageMaterial};
-use super::layout::{GlueMaterial, PenaltyMaterial, ChildArtifact, SiblingStyle, LoopContext};
-use super::layout::{RootData, DrawState, DrawCommand, TextCommand, ImageCommand, FontKind, Fonts};
+use super::layout::{GlueMaterial, PenaltyMaterial, ChildArtifact, SiblingStyle, LoopContext, KeepState};
+use super::layout::{RootData, DrawState, DrawCommand, TextCommand, ImageCommand, RectangleCommand, FontKind, Fonts};
+use super::layout::{TableState, TableCell, RowSpan, Border};
+use super::layout::{EmbeddedFamily, EmbeddedFace, FontStyle, FontWeight, Direction};
 use super::layout::{TextAlign, ParagraphElement, TextElement, ImageElement, Display, Float, ListStyleType, LineStats};
-use super::layout::{hyph_lang, collapse_margins, DEFAULT_HYPH_LANG, HYPHENATION_PATTERNS};
+use super::layout::{hyph_lang, collapse_margins, text_direction, visual_order, DEFAULT_HYPH_LANG, HYPHENATION_PATTERNS};
 use super::layout::{EM_SPACE_RATIOS, WORD_SPACE_RATIOS, FONT_SPACES};
-use super::style::{Stylesheet, specified_values};
+use super::css::{Rule, PseudoElement};
+use super::media::Device;
+use super::svg::{is_svg, svg_size, svg_document};
+use super::xml::XmlParser;
+use super::style::{Stylesheet, PropertyMap, specified_values, pseudo_element_values, element_children};
 
 const DEFAULT_DPI: u16 = 300;
 const DEFAULT_WIDTH: u32 = 1404;
 const DEFAULT_HEIGHT: u32 = 1872;
 const HYPHEN_PENALTY: i32 = 50;
 const STRETCH_TOLERANCE: f32 = 1.26;
+// The smallest scale applied to the text of the tables that are too wide.
+const MIN_TABLE_SCALE: f32 = 0.6;
+// https://html.spec.whatwg.org/multipage/tables.html#attr-tdth-colspan
+const MAX_COLSPAN: usize = 1000;
+const MAX_ROWSPAN: usize = 65534;
 
 pub type Page = Vec<DrawCommand>;
 
@@ -53,6 +67,10 @@ pub struct Engine {
     pub dims: (u32, u32),
     // Device DPI.
     pub dpi: u16,
+    // Use the fonts declared by @font-face rules.
+    pub embedded_fonts: bool,
+    // Name of the serif font family.
+    pub font_family: String,
 }
 
 impl Engine {
@@ -68,6 +86,8 @@ impl Engine {
             line_height,
             dims: (DEFAULT_WIDTH, DEFAULT_HEIGHT),
             dpi: DEFAULT_DPI,
+            embedded_fonts: true,
+            font_family: DEFAULT_FONT_FAMILY.to_string(),
         }
     }
 
@@ -102,10 +122,114 @@ impl Engine {
             self.load_fonts();
             if let Some(fonts) = self.fonts.as_mut() {
                 fonts.serif = serif_family;
+                self.font_family = family_name.to_string();
             }
         }
     }
 
+    pub fn set_embedded_fonts(&mut self, enable: bool) {
+        self.embedded_fonts = enable;
+        if !enable {
+            if let Some(fonts) = self.fonts.as_mut() {
+                fonts.embedded.clear();
+            }
+        }
+    }
+
+    pub fn load_font_faces(&mut self, rules: &[Rule], parent: &Path, resource_fetcher: &mut dyn ResourceFetcher) {
+        if !self.embedded_fonts {
+            return;
+        }
+
+        self.load_fonts();
+
+        if self.fonts.is_none() {
+            return;
+        }
+
+        let opener = if let Ok(opener) = FontOpener::new() {
+            opener
+        } else {
+            return;
+        };
+
+        let device = self.device();
+
+        for rule in rules {
+            let is_font_face = rule.selectors.iter().any(|sel| {
+                sel.combinators.is_empty() &&
+                sel.subject.tag_name.as_ref().map(String::as_str) == Some("@font-face")
+            });
+
+            if !is_font_face || !rule.matches_media(&device) {
+                continue;
+            }
+
+            let mut name = None;
+            let mut path = None;
+            let mut font_style = FontStyle::Normal;
+            let mut font_weight = FontWeight::Normal;
+
+            for decl in &rule.declarations {
+                match decl.name.as_str() {
+                    "font-family" => name = Some(decl.value.trim_matches(|c| c == '"' || c == '\'').to_string()),
+                    "src" => path = font_face_url(&decl.value).and_then(|url| {
+                        parent.join(url).normalize().to_str().map(String::from)
+                    }),
+                    "font-style" => font_style = parse_font_style(&decl.value).unwrap_or(font_style),
+                    "font-weight" => font_weight = parse_font_weight(&decl.value).unwrap_or(font_weight),
+                    _ => (),
+                }
+            }
+
+            let (name, path) = match (name, path) {
+                (Some(name), Some(path)) => (name, path),
+                _ => continue,
+            };
+
+            let fonts = self.fonts.as_mut().unwrap();
+
+            if fonts.embedded.iter().any(|family| family.faces.iter().any(|face| face.path == path)) {
+                continue;
+            }
+
+            let data = match resource_fetcher.fetch(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Can't fetch font '{}': {}.", path, e);
+                    continue;
+                },
+            };
+
+            let font = match opener.open_memory(&data) {
+                Ok(font) => font,
+                Err(e) => {
+                    eprintln!("Can't open font '{}': {}.", path, e);
+                    continue;
+                },
+            };
+
+            let face = EmbeddedFace { path, font_style, font_weight, font, data };
+
+            if let Some(family) = fonts.embedded.iter_mut().find(|family| family.name.eq_ignore_ascii_case(&name)) {
+                family.faces.push(face);
+            } else {
+                fonts.embedded.push(EmbeddedFamily { name, faces: vec![face] });
+            }
+        }
+    }
+
+    fn font_kind(&self, value: &str) -> Option<FontKind> {
+        if let Some(fonts) = self.fonts.as_ref() {
+            for name in value.split(',').map(|v| v.trim().trim_matches(|c| c == '"' || c == '\'')) {
+                if let Some(index) = fonts.embedded.iter().position(|family| family.name.eq_ignore_ascii_case(name)) {
+                    return Some(FontKind::Embedded(index));
+                }
+            }
+        }
+        parse_font_kind(value)
+    }
+
     pub fn set_margin_width(&mut self, width: i32) {
         if width >= 0 && width <= 10 {
             self.margin = Edge::uniform(mm_to_px(width as f32, self.dpi).round() as i32);
@@ -116,6 +240,19 @@ impl Engine {
         self.line_height = line_height;
     }
 
+    // The features against which the media queries are evaluated.
+    pub fn device(&self) -> Device {
+        let (width, height) = self.dims;
+        Device {
+            width: width as i32,
+            height: height as i32,
+            dpi: self.dpi,
+            font_size: self.font_size,
+            color: 0,
+            monochrome: 8,
+        }
+    }
+
     #[inline]
     pub fn rect(&self) -> Rectangle {
         let (width, height) = self.dims;
@@ -128,7 +265,7 @@ impl Engine {
         let mut rects: Vec<Option<Rectangle>> = Vec::new();
         rects.push(None);
 
-        let props = specified_values(node, loop_context.parent, loop_context.sibling, stylesheet);
+        let props = specified_values(node, &loop_context.ancestors, loop_context.siblings(node), stylesheet);
 
         style.display = props.get("display").and_then(|value| parse_display(value))
                              .unwrap_or(Display::Block);
@@ -140,25 +277,38 @@ impl Engine {
                     margin: Edge::default(),
                 },
                 rects: Vec::new(),
+                keep_with_next: None,
             }
         }
 
+        // Save the state needed to move the block to the next page.
+        let saved_state = if (avoids_page_break(&props, "inside") || avoids_page_break(&props, "after")) &&
+                             loop_context.ancestors.iter().all(|a| a.tag_name() != Some("table")) &&
+                             props.get("page-break-before").map(String::as_str) != Some("always") {
+            Some(KeepState {
+                draw_state: draw_state.clone(),
+                pages_count: display_list.len(),
+                commands_count: display_list.last().map_or(0, Vec::len),
+            })
+        } else {
+            None
+        };
+
         style.font_style = parent_style.font_style;
+        style.font_scale = parent_style.font_scale;
         style.line_height = parent_style.line_height;
         style.retain_whitespace = parent_style.retain_whitespace;
 
+        let mut outer_table = None;
+
         match node.tag_name() {
             Some("pre") => style.retain_whitespace = true,
             Some("li") | Some("anonymous") => style.list_style_type = parent_style.list_style_type,
             Some("table") => {
-                let position = draw_state.position;
-                draw_state.column_widths.clear();
-                draw_state.min_column_widths.clear();
-                draw_state.max_column_widths.clear();
-                draw_state.center_table = style.display == Display::InlineTable &&
-                                          parent_style.text_align == TextAlign::Center;
-                self.compute_column_widths(node, parent_style, loop_context, stylesheet, root_data, resource_fetcher, draw_state);
-                draw_state.position = position;
+                outer_table = Some(mem::replace(&mut draw_state.table, TableState::default()));
+                draw_state.table.center_table = style.display == Display::InlineTable &&
+                                                parent_style.text_align == TextAlign::Center;
+                draw_state.table.border_collapse = props.get("border-collapse").map(String::as_str) == Some("collapse");
             },
             _ => (),
         }
@@ -167,11 +317,12 @@ impl Engine {
                               .or_else(|| parent_style.language.clone());
 
         style.font_size = props.get("font-size")
-                               .and_then(|value| parse_font_size(value, parent_style.font_size, self.font_size))
+                               .and_then(|value| self.scaled_font_size(value, parent_style))
                                .unwrap_or(parent_style.font_size);
 
         style.line_height = props.get("line-height")
-                                 .and_then(|value| parse_line_height(value, style.font_size, self.font_size, self.dpi))
+                                 .and_then(|value| parse_line_height(value, style.font_size / style.font_scale, self.font_size, self.dpi))
+                                 .map(|line_height| (line_height as f32 * style.font_scale).round() as i32)
                                  .unwrap_or_else(|| ((style.font_size / parent_style.font_size) * parent_style.line_height as f32).round() as i32);
 
         style.letter_spacing = props.get("letter-spacing")
@@ -183,7 +334,7 @@ impl Engine {
                                     .unwrap_or(parent_style.vertical_align);
 
         style.font_kind = props.get("font-family")
-                               .and_then(|value| parse_font_kind(value))
+                               .and_then(|value| self.font_kind(value))
                                .unwrap_or(parent_style.font_kind);
 
         style.font_style = props.get("font-style")
@@ -203,11 +354,21 @@ impl Engine {
                                                                  parent_style.width, self.dpi))
                                  .unwrap_or(parent_style.text_indent);
 
-        style.text_align = props.get("text-align")
-                                .map(String::as_str)
-                                .or_else(|| node.attr("align"))
-                                .and_then(|value| parse_text_align(value))
-                                .unwrap_or(parent_style.text_align);
+        style.direction = props.get("direction")
+                               .map(String::as_str)
+                               .or_else(|| node.attr("dir"))
+                               .and_then(|value| parse_direction(value))
+                               .unwrap_or(parent_style.direction);
+
+        let is_rtl = style.direction == Direction::Rtl;
+
+        style.text_align = match props.get("text-align").map(String::as_str).or_else(|| node.attr("align")) {
+            Some("start") => if is_rtl { TextAlign::Right } else { TextAlign::Left },
+            Some("end") => if is_rtl { TextAlign::Left } else { TextAlign::Right },
+            Some(value) => parse_text_align(value).unwrap_or(parent_style.text_align),
+            None if style.direction != parent_style.direction => parent_style.text_align.mirror(),
+            None => parent_style.text_align,
+        };
 
         style.font_features = props.get("font-feature-settings")
                                    .map(|value| parse_font_features(value))
@@ -218,6 +379,16 @@ impl Engine {
             style.list_style_type = value;
         }
 
+        style.widows = props.get("widows")
+                            .and_then(|value| value.parse::<usize>().ok())
+                            .filter(|value| *value > 0)
+                            .unwrap_or(parent_style.widows);
+
+        style.orphans = props.get("orphans")
+                             .and_then(|value| value.parse::<usize>().ok())
+                             .filter(|value| *value > 0)
+                             .unwrap_or(parent_style.orphans);
+
         if let Some(value) = props.get("font-variant") {
             let mut features = parse_font_variant(value);
             if let Some(v) = style.font_features.as_mut() {
@@ -287,6 +458,23 @@ impl Engine {
 
         style.width = width;
 
+        if outer_table.is_some() {
+            let position = draw_state.position;
+            self.compute_column_widths(node, &style, loop_context, stylesheet, root_data, resource_fetcher, draw_state);
+            let min_table_width: i32 = draw_state.table.min_column_widths.iter().sum();
+            // Shrink the text of the tables that don't fit.
+            if min_table_width > width {
+                let scale = (width as f32 / min_table_width as f32).max(MIN_TABLE_SCALE);
+                style.font_size *= scale;
+                style.font_scale *= scale;
+                style.line_height = (style.line_height as f32 * scale).round() as i32;
+                draw_state.table.min_column_widths.clear();
+                draw_state.table.max_column_widths.clear();
+                self.compute_column_widths(node, &style, loop_context, stylesheet, root_data, resource_fetcher, draw_state);
+            }
+            draw_state.position = position;
+        }
+
         if props.get("page-break-before").map(String::as_str) == Some("always") {
             display_list.push(Vec::new());
             draw_state.position.y = root_data.rect.min.y;
@@ -294,7 +482,8 @@ impl Engine {
 
         draw_state.position.y += style.padding.top;
 
-        let has_blocks = node.children().and_then(|children| {
+        // The SVG elements are laid out as images.
+        let has_blocks = node.children().filter(|_| !is_svg(node)).and_then(|children| {
             children.iter().skip_while(|child| child.is_whitespace())
                     .next().map(Node::is_block)
         });
@@ -307,37 +496,42 @@ impl Engine {
             if let Some(children) = node.children() {
                 let mut inner_loop_context = LoopContext::default();
                 inner_loop_context.parent = Some(node);
+                inner_loop_context.ancestors = loop_context.ancestors.clone();
+                inner_loop_context.ancestors.push(node);
 
                 if node.tag_name() == Some("tr") {
                     inner_loop_context.is_first = loop_context.is_first;
                     inner_loop_context.is_last = loop_context.is_last;
+                    inner_loop_context.siblings = element_children(node);
 
-                    if draw_state.column_widths.is_empty() {
-                        let min_row_width: i32 = draw_state.min_column_widths.iter().sum();
-                        let max_row_width: i32 = draw_state.max_column_widths.iter().sum();
+                    let table = &mut draw_state.table;
+
+                    if table.column_widths.is_empty() {
+                        let min_row_width: i32 = table.min_column_widths.iter().sum();
+                        let max_row_width: i32 = table.max_column_widths.iter().sum();
                         // https://www.w3.org/MarkUp/html3/tables.html
                         if min_row_width >= width {
-                            draw_state.column_widths =
-                                draw_state.min_column_widths.iter()
-                                          .map(|w| ((*w as f32 / min_row_width as f32) *
-                                                   width as f32).round() as i32)
-                                          .collect();
+                            table.column_widths =
+                                table.min_column_widths.iter()
+                                     .map(|w| ((*w as f32 / min_row_width as f32) *
+                                              width as f32).round() as i32)
+                                     .collect();
                         } else if max_row_width <= width {
-                            draw_state.column_widths = draw_state.max_column_widths.clone();
+                            table.column_widths = table.max_column_widths.clone();
                         } else {
                             let dw = (width - min_row_width) as f32;
                             let dr = (max_row_width - min_row_width) as f32;
                             let gf = dw / dr;
-                            draw_state.column_widths =
-                                draw_state.min_column_widths.iter()
-                                          .zip(draw_state.max_column_widths.iter())
-                                          .map(|(a, b)| a + ((b - a) as f32 * gf).round() as i32)
-                                          .collect();
+                            table.column_widths =
+                                table.min_column_widths.iter()
+                                     .zip(table.max_column_widths.iter())
+                                     .map(|(a, b)| a + ((b - a) as f32 * gf).round() as i32)
+                                     .collect();
                         }
                     }
 
-                    if draw_state.center_table {
-                        let actual_width = draw_state.column_widths.iter().sum();
+                    if table.center_table {
+                        let actual_width = table.column_widths.iter().sum();
                         let delta_width = width - actual_width;
                         let left_shift = delta_width / 2;
                         let right_shift = delta_width - left_shift;
@@ -348,83 +542,221 @@ impl Engine {
 
                     let start_x = style.start_x;
                     let end_x = style.end_x;
-                    let mut cur_x = start_x;
-                    let position = draw_state.position;
-                    let mut final_page = (0, position);
-                    let page_index = display_list.len() - 1;
-                    let mut index = 0;
+                    let collapse = table.border_collapse;
+                    let column_widths = table.column_widths.clone();
+                    let first_page = table.row_page.take().unwrap_or(display_list.len() - 1);
+                    let mut page_index = first_page;
+                    let mut position = draw_state.position;
+
+                    // The header of the table is repeated when a body row doesn't fit
+                    // at the bottom of a page.
+                    let header = loop_context.ancestors.iter()
+                                             .rposition(|a| a.tag_name() == Some("table"))
+                                             .filter(|_| loop_context.parent.and_then(Node::tag_name) != Some("thead"))
+                                             .and_then(|i| {
+                                                 loop_context.ancestors[i].children().and_then(|children| {
+                                                     children.iter().find(|child| child.tag_name() == Some("thead"))
+                                                 }).map(|thead| (i, thead))
+                                             });
+                    let mut saved_state = if header.is_some() && table.row_spans.is_empty() &&
+                                             position.y > root_data.rect.min.y {
+                        Some((draw_state.clone(), display_list.len(), display_list[page_index].len()))
+                    } else {
+                        None
+                    };
 
-                    // TODO: rowspan, vertical-align
-                    for child in children.iter().filter(|child| child.is_element()) {
-                        if index >= draw_state.column_widths.len() {
-                            break;
-                        }
+                    loop {
+                        let mut cur_x = start_x;
+                        let mut bottom = (page_index, position.y);
+                        let mut last_page = page_index;
+                        let mut cells = Vec::new();
+                        let previous_spans = draw_state.table.row_spans.len();
+                        let mut index = 0;
+
+                        // TODO: vertical-align
+                        for (sibling_index, child) in children.iter().filter(|child| child.is_element()).enumerate() {
+                            inner_loop_context.sibling_index = sibling_index;
+                            // Skip the columns covered by the cells of the previous rows.
+                            while index < column_widths.len() &&
+                                  draw_state.table.row_spans[..previous_spans].iter()
+                                            .any(|s| index >= s.column && index < s.column + s.colspan) {
+                                cur_x += column_widths[index];
+                                index += 1;
+                            }
 
-                        let colspan = child.attr("colspan")
-                                           .and_then(|v| v.parse().ok())
-                                           .unwrap_or(1);
-                        let column_width = draw_state.column_widths[index..index+colspan]
-                                                     .iter().sum::<i32>();
-                        let mut child_display_list = Vec::new();
-                        child_display_list.push(Vec::new());
-                        style.start_x = cur_x;
-                        style.end_x = cur_x + column_width;
-                        draw_state.position = position;
-                        let artifact = self.build_display_list(child, &style, &inner_loop_context, stylesheet, root_data, resource_fetcher, draw_state, &mut child_display_list);
-                        let pages_count = child_display_list.len();
-                        if pages_count > final_page.0 ||
-                           (pages_count == final_page.0 && draw_state.position.y > final_page.1.y) {
-                            final_page = (pages_count, draw_state.position);
-                        }
+                            if index >= column_widths.len() {
+                                break;
+                            }
 
-                        for (i, mut pg) in child_display_list.into_iter().enumerate() {
-                            if let Some(page) = display_list.get_mut(page_index+i) {
-                                page.append(&mut pg);
+                            let (colspan, rowspan) = cell_spans(child);
+                            let colspan = colspan.min(column_widths.len() - index);
+                            let column_width = column_widths[index..index+colspan]
+                                                            .iter().sum::<i32>();
+                            let border = self.cell_border(child, &inner_loop_context.ancestors, inner_loop_context.siblings(child), stylesheet, &style);
+                            let inset = border_inset(border.width, collapse);
+                            let mut child_display_list = Vec::new();
+                            child_display_list.push(Vec::new());
+                            style.start_x = cur_x + inset.left;
+                            style.end_x = cur_x + column_width - inset.right;
+                            draw_state.position = position;
+                            draw_state.position.y += inset.top;
+                            let artifact = self.build_display_list(child, &style, &inner_loop_context, stylesheet, root_data, resource_fetcher, draw_state, &mut child_display_list);
+                            draw_state.position.y += inset.bottom;
+                            let pages_count = child_display_list.len();
+                            let cell_bottom = (page_index + pages_count - 1, draw_state.position.y);
+                            let cell = TableCell {
+                                offset: root_data.start_offset + child.offset(),
+                                start_x: cur_x,
+                                end_x: cur_x + column_width,
+                                top: (page_index, position.y),
+                                border,
+                            };
+                            last_page = last_page.max(cell_bottom.0);
+
+                            if rowspan > 1 {
+                                draw_state.table.row_spans.push(RowSpan {
+                                    cell,
+                                    column: index,
+                                    colspan,
+                                    rows: rowspan - 1,
+                                    bottom: cell_bottom,
+                                });
                             } else {
-                                display_list.push(pg);
+                                bottom = bottom.max(cell_bottom);
+                                cells.push(cell);
                             }
-                        }
 
-                        for (i, rect) in artifact.rects.into_iter().enumerate() {
-                            if let Some(page_rect) = rects.get_mut(i) {
-                                if let Some(pr) = page_rect.as_mut() {
-                                    if let Some(r) = rect.as_ref() {
-                                        pr.absorb(r);
+                            for (i, mut pg) in child_display_list.into_iter().enumerate() {
+                                if let Some(page) = display_list.get_mut(page_index+i) {
+                                    page.append(&mut pg);
+                                } else {
+                                    display_list.push(pg);
+                                }
+                            }
+
+                            let rects_offset = page_index - first_page;
+                            if rects.len() < rects_offset {
+                                rects.resize(rects_offset, None);
+                            }
+
+                            for (i, rect) in artifact.rects.into_iter().enumerate() {
+                                if let Some(page_rect) = rects.get_mut(rects_offset + i) {
+                                    if let Some(pr) = page_rect.as_mut() {
+                                        if let Some(r) = rect.as_ref() {
+                                            pr.absorb(r);
+                                        }
+                                    } else {
+                                        *page_rect = rect;
                                     }
                                 } else {
-                                    *page_rect = rect;
+                                    rects.push(rect);
                                 }
-                            } else {
-                                rects.push(rect);
                             }
+
+                            inner_loop_context.sibling_style = artifact.sibling_style;
+
+                            if inner_loop_context.is_last {
+                                style.margin.bottom = collapse_margins(inner_loop_context.sibling_style.margin.bottom, style.margin.bottom);
+                            }
+
+                            index += colspan;
+                            cur_x += column_width;
                         }
 
-                        inner_loop_context.sibling = Some(&child);
-                        inner_loop_context.sibling_style = artifact.sibling_style;
+                        style.start_x = start_x;
+                        style.end_x = end_x;
+
+                        if last_page > page_index {
+                            if let Some((state, pages_count, commands_count)) = saved_state.take() {
+                                let (table_index, thead) = header.unwrap();
+                                *draw_state = state;
+                                display_list.truncate(pages_count);
+                                display_list[page_index].truncate(commands_count);
+                                rects.clear();
+                                rects.push(None);
+
+                                let mut header_loop_context = LoopContext::default();
+                                header_loop_context.parent = Some(loop_context.ancestors[table_index]);
+                                header_loop_context.ancestors = loop_context.ancestors[..=table_index].to_vec();
+                                let mut header_display_list = Vec::new();
+                                header_display_list.push(Vec::new());
+                                draw_state.position.y = root_data.rect.min.y;
+                                self.build_display_list(thead, parent_style, &header_loop_context, stylesheet, root_data, resource_fetcher, draw_state, &mut header_display_list);
+                                draw_state.table.row_page = None;
+
+                                let offset = root_data.start_offset + node.offset();
+                                for page in header_display_list {
+                                    display_list.push(page.into_iter()
+                                                          .filter_map(|dc| repeated_command(dc, offset))
+                                                          .collect());
+                                }
 
-                        if inner_loop_context.is_last {
-                            style.margin.bottom = collapse_margins(inner_loop_context.sibling_style.margin.bottom, style.margin.bottom);
+                                page_index = display_list.len() - 1;
+                                position = draw_state.position;
+                                continue;
+                            }
                         }
 
-                        index += colspan;
-                        cur_x += column_width;
-                    }
+                        // Close the cells of the previous rows that end on this row.
+                        for span in &mut draw_state.table.row_spans[..previous_spans] {
+                            span.rows -= 1;
+                            if span.rows == 0 {
+                                bottom = bottom.max(span.bottom);
+                            }
+                        }
+
+                        let (ended_spans, row_spans): (Vec<RowSpan>, Vec<RowSpan>) =
+                            mem::replace(&mut draw_state.table.row_spans, Vec::new())
+                               .into_iter().partition(|s| s.rows == 0);
+                        draw_state.table.row_spans = row_spans;
+
+                        for cell in cells.iter().chain(ended_spans.iter().map(|s| &s.cell)) {
+                            self.draw_cell_border(cell, bottom, collapse, root_data, display_list);
+                        }
 
-                    style.start_x = start_x;
-                    style.end_x = end_x;
-                    draw_state.position = final_page.1;
+                        draw_state.position = pt!(position.x, bottom.1);
+                        draw_state.table.row_page = Some(bottom.0);
+                        break;
+                    }
                 } else {
-                    let mut iter = children.iter().filter(|child| child.is_element()).peekable();
+                    // The elements and their positions among their element siblings.
+                    let (siblings, mut position) = children_siblings(node, loop_context);
+                    inner_loop_context.siblings = siblings;
+                    let mut elements: Vec<(&Node, usize)> = children.iter().filter(|child| child.is_element()).map(|child| {
+                        let element = (child, position);
+                        position += if child.tag_name() == Some("anonymous") {
+                            element_children(child).len()
+                        } else {
+                            1
+                        };
+                        element
+                    }).collect();
+
+                    // The captions placed below the table are laid out after its rows.
+                    if outer_table.is_some() {
+                        let siblings = &inner_loop_context.siblings;
+                        let (mut captions, mut others): (Vec<(&Node, usize)>, Vec<(&Node, usize)>) = elements.into_iter().partition(|&(child, position)| {
+                            child.tag_name() == Some("caption") &&
+                            specified_values(child, &inner_loop_context.ancestors, Some((siblings, position)), stylesheet)
+                                .get("caption-side").map(String::as_str) == Some("bottom")
+                        });
+                        others.append(&mut captions);
+                        elements = others;
+                    }
+
                     inner_loop_context.is_first = true;
                     let mut index = 0;
+                    // The state before the first of the previous blocks that must
+                    // stay on the same page as the current one.
+                    let mut kept: Option<(KeepState, usize, SiblingStyle, Vec<Option<Rectangle>>)> = None;
+                    let mut kept_end = (0, 0);
 
-                    while let Some(child) = iter.next() {
-                        if iter.peek().is_none() {
-                            inner_loop_context.is_last = true;
-                        }
+                    while let Some((child, position)) = elements.get(index).cloned() {
+                        inner_loop_context.is_last = index == elements.len() - 1;
 
                         inner_loop_context.parent = Some(node);
                         inner_loop_context.index = index;
+                        inner_loop_context.sibling_index = position;
 
                         if child.tag_name() == Some("anonymous") {
                             inner_loop_context.parent = loop_context.parent.clone();
@@ -432,7 +764,39 @@ impl Engine {
                         }
 
                         let artifact = self.build_display_list(child, &style, &inner_loop_context, stylesheet, root_data, resource_fetcher, draw_state, display_list);
-                        inner_loop_context.sibling = Some(&child);
+
+                        // Nothing from the current block was placed on the page of the previous ones.
+                        let moved = kept.is_some() && display_list.len() > kept_end.0 &&
+                                    display_list[kept_end.0 - 1][kept_end.1..].iter().all(|dc| {
+                                        if let DrawCommand::Marker(..) = dc { true } else { false }
+                                    });
+
+                        if moved {
+                            let (state, start_index, sibling_style, saved_rects) = kept.take().unwrap();
+                            if state.draw_state.position.y > root_data.rect.min.y {
+                                *draw_state = state.draw_state;
+                                display_list.truncate(state.pages_count);
+                                display_list.last_mut().unwrap().truncate(state.commands_count);
+                                display_list.push(Vec::new());
+                                draw_state.position.y = root_data.rect.min.y;
+                                rects = saved_rects;
+                                rects.push(None);
+                                inner_loop_context.sibling_style = sibling_style;
+                                inner_loop_context.is_first = start_index == 0;
+                                index = start_index;
+                                continue;
+                            }
+                        }
+
+                        if let Some(state) = artifact.keep_with_next {
+                            if kept.is_none() {
+                                kept = Some((state, index, inner_loop_context.sibling_style.clone(), rects.clone()));
+                            }
+                            kept_end = (display_list.len(), display_list.last().map_or(0, Vec::len));
+                        } else {
+                            kept = None;
+                        }
+
                         inner_loop_context.sibling_style = artifact.sibling_style;
                         inner_loop_context.is_first = false;
 
@@ -473,10 +837,26 @@ impl Engine {
                     if node.id().is_some() {
                         markers.push(node.offset());
                     }
-                    let mut sibling = None;
-                    for child in children {
-                        self.gather_inline_material(child, Some(node), sibling, stylesheet, &style, &root_data.spine_dir, &mut markers, &mut inlines);
-                        sibling = Some(child);
+                    let mut ancestors = loop_context.ancestors.clone();
+                    if is_svg(node) {
+                        self.gather_inline_material(node, &mut ancestors, loop_context.siblings(node), stylesheet, &style, &root_data.spine_dir, resource_fetcher, &mut markers, &mut inlines);
+                    } else {
+                        let (siblings, mut position) = children_siblings(node, loop_context);
+                        ancestors.push(node);
+                        for child in children {
+                            let child_siblings = next_sibling(child, &siblings, &mut position);
+                            self.gather_inline_material(child, &mut ancestors, child_siblings, stylesheet, &style, &root_data.spine_dir, resource_fetcher, &mut markers, &mut inlines);
+                        }
+                    }
+                    if !inlines.is_empty() && node.tag_name() != Some("anonymous") {
+                        let props = pseudo_element_values(node, &loop_context.ancestors, loop_context.siblings(node), stylesheet, PseudoElement::FirstLine);
+                        if !props.is_empty() {
+                            self.style_first_line(&mut inlines, &props, &style, resource_fetcher);
+                        }
+                        let props = pseudo_element_values(node, &loop_context.ancestors, loop_context.siblings(node), stylesheet, PseudoElement::FirstLetter);
+                        if !props.is_empty() {
+                            self.style_first_letter(&mut inlines, &props);
+                        }
                     }
                     if !inlines.is_empty() {
                         draw_state.prefix = match style.list_style_type {
@@ -495,6 +875,11 @@ impl Engine {
             }
         }
 
+        if let Some(table) = outer_table {
+            self.close_row_spans(root_data, draw_state, display_list);
+            draw_state.table = table;
+        }
+
         if style.height > 0 {
             let height = rects.iter()
                               .filter_map(|v| v.map(|r| r.height() as i32))
@@ -510,6 +895,27 @@ impl Engine {
 
         draw_state.position.y += style.padding.bottom;
 
+        // Move the block to the next page if it crosses a single page boundary,
+        // and is shorter than a page.
+        let move_block = saved_state.as_ref().map_or(false, |state| {
+            avoids_page_break(&props, "inside") &&
+            state.draw_state.position.y > root_data.rect.min.y &&
+            display_list.len() == state.pages_count + 1 &&
+            draw_state.position.y <= state.draw_state.position.y
+        });
+
+        if move_block {
+            let state = saved_state.unwrap();
+            *draw_state = state.draw_state;
+            display_list.truncate(state.pages_count);
+            display_list.last_mut().unwrap().truncate(state.commands_count);
+            display_list.push(Vec::new());
+            draw_state.position.y = root_data.rect.min.y;
+            let mut artifact = self.build_display_list(node, parent_style, loop_context, stylesheet, root_data, resource_fetcher, draw_state, display_list);
+            artifact.rects.insert(0, None);
+            return artifact;
+        }
+
         if props.get("page-break-after").map(String::as_str) == Some("always") {
             display_list.push(Vec::new());
             draw_state.position.y = root_data.rect.min.y;
@@ -521,65 +927,225 @@ impl Engine {
                 margin: style.margin,
             },
             rects,
+            keep_with_next: saved_state.filter(|_| avoids_page_break(&props, "after")),
         }
     }
 
     fn compute_column_widths(&mut self, node: &Node, parent_style: &StyleData, loop_context: &LoopContext, stylesheet: &Stylesheet, root_data: &RootData, resource_fetcher: &mut dyn ResourceFetcher, draw_state: &mut DrawState) {
+        let mut row_spans = Vec::new();
+        let mut spanning_cells = Vec::new();
+
+        self.gather_column_widths(node, parent_style, loop_context, stylesheet, root_data, resource_fetcher, &mut row_spans, &mut spanning_cells, draw_state);
+
+        let table = &mut draw_state.table;
+
+        // Widen the columns covered by the cells that span several columns.
+        spanning_cells.sort_by_key(|&(_, colspan, _, _)| colspan);
+        for (index, colspan, min_width, max_width) in spanning_cells {
+            let len = table.min_column_widths.len().max(index + colspan);
+            table.min_column_widths.resize(len, 0);
+            table.max_column_widths.resize(len, 0);
+            distribute_width(&mut table.min_column_widths[index..index+colspan], min_width);
+            distribute_width(&mut table.max_column_widths[index..index+colspan], max_width);
+        }
+
+        for (max_width, min_width) in table.max_column_widths.iter_mut().zip(table.min_column_widths.iter()) {
+            *max_width = (*max_width).max(*min_width);
+        }
+    }
+
+    // The *row spans* are the numbers of rows still covered by the cells of each column.
+    fn gather_column_widths(&mut self, node: &Node, parent_style: &StyleData, loop_context: &LoopContext, stylesheet: &Stylesheet, root_data: &RootData, resource_fetcher: &mut dyn ResourceFetcher, row_spans: &mut Vec<usize>, spanning_cells: &mut Vec<(usize, usize, i32, i32)>, draw_state: &mut DrawState) {
+        let mut inner_loop_context = loop_context.clone();
+        inner_loop_context.ancestors.push(node);
+
         if node.tag_name() == Some("tr") {
             if let Some(children) = node.children() {
+                let mut next_row_spans: Vec<usize> = row_spans.iter().map(|n| n.saturating_sub(1)).collect();
                 let mut index = 0;
-                for child in children.iter().filter(|c| c.is_element()) {
-                    let colspan = child.attr("colspan")
-                                       .and_then(|v| v.parse().ok())
-                                       .unwrap_or(1);
-                    let mut display_list = Vec::new();
-                    display_list.push(Vec::new());
-                    let artifact = self.build_display_list(child, parent_style, loop_context, stylesheet, root_data, resource_fetcher, draw_state, &mut display_list);
-                    let horiz_padding = artifact.sibling_style.padding.left +
-                                        artifact.sibling_style.padding.right;
-                    let min_width = display_list.into_iter()
-                                                .flatten()
-                                                .filter_map(|dc| {
-                                                    match dc {
-                                                        DrawCommand::Text(TextCommand { rect, .. }) => Some(rect.width() as i32 + horiz_padding),
-                                                        DrawCommand::Image(ImageCommand { rect, .. }) => Some((rect.width() as i32).min(pt_to_px(parent_style.font_size, self.dpi).round().max(1.0) as i32) + horiz_padding),
-                                                        _ => None,
-                                                    }
-                                                })
+                inner_loop_context.siblings = element_children(node);
+                for (position, child) in children.iter().filter(|c| c.is_element()).enumerate() {
+                    inner_loop_context.sibling_index = position;
+                    while row_spans.get(index).map_or(false, |n| *n > 0) {
+                        index += 1;
+                    }
+
+                    let (colspan, rowspan) = cell_spans(child);
+                    // The cells, and the tables nested inside them, are only measured once.
+                    let key = (child.offset(), parent_style.font_size.to_bits());
+                    let (min_width, max_width) = if let Some(&widths) = draw_state.cell_widths.get(&key) {
+                        widths
+                    } else {
+                        let border = self.cell_border(child, &inner_loop_context.ancestors, inner_loop_context.siblings(child), stylesheet, parent_style);
+                        let inset = border_inset(border.width, draw_state.table.border_collapse);
+                        let mut display_list = Vec::new();
+                        display_list.push(Vec::new());
+                        let artifact = self.build_display_list(child, parent_style, &inner_loop_context, stylesheet, root_data, resource_fetcher, draw_state, &mut display_list);
+                        let horiz_padding = artifact.sibling_style.padding.left +
+                                            artifact.sibling_style.padding.right +
+                                            inset.left + inset.right;
+                        let min_width = display_list.into_iter()
+                                                    .flatten()
+                                                    .filter_map(|dc| {
+                                                        match dc {
+                                                            DrawCommand::Text(TextCommand { rect, .. }) => Some(rect.width() as i32 + horiz_padding),
+                                                            DrawCommand::Image(ImageCommand { rect, .. }) => Some((rect.width() as i32).min(pt_to_px(parent_style.font_size, self.dpi).round().max(1.0) as i32) + horiz_padding),
+                                                            _ => None,
+                                                        }
+                                                    })
+                                                    .max().unwrap_or(0);
+                        let max_width = artifact.rects.into_iter()
+                                                .filter_map(|v| v.map(|r| r.width() as i32 + horiz_padding))
                                                 .max().unwrap_or(0);
-                    let max_width = artifact.rects.into_iter()
-                                            .filter_map(|v| v.map(|r| r.width() as i32 + horiz_padding))
-                                            .max().unwrap_or(0);
+                        draw_state.cell_widths.insert(key, (min_width, max_width));
+                        (min_width, max_width)
+                    };
+
                     if colspan == 1 {
-                        if let Some(cw) = draw_state.min_column_widths.get_mut(index) {
-                            *cw = (*cw).max(min_width);
-                        } else {
-                            draw_state.min_column_widths.push(min_width);
-                        }
-                        if let Some(cw) = draw_state.max_column_widths.get_mut(index) {
-                            *cw = (*cw).max(max_width);
-                        } else {
-                            draw_state.max_column_widths.push(max_width);
+                        let table = &mut draw_state.table;
+                        if table.min_column_widths.len() <= index {
+                            table.min_column_widths.resize(index + 1, 0);
+                            table.max_column_widths.resize(index + 1, 0);
                         }
+                        table.min_column_widths[index] = table.min_column_widths[index].max(min_width);
+                        table.max_column_widths[index] = table.max_column_widths[index].max(max_width);
+                    } else {
+                        spanning_cells.push((index, colspan, min_width, max_width));
+                    }
+
+                    if next_row_spans.len() < index + colspan {
+                        next_row_spans.resize(index + colspan, 0);
+                    }
+
+                    for n in &mut next_row_spans[index..index+colspan] {
+                        *n = rowspan - 1;
                     }
 
                     index += colspan;
                 }
+                *row_spans = next_row_spans;
             }
         } else if let Some(children) = node.children() {
-            for child in children.iter().filter(|c| c.is_element()) {
-                self.compute_column_widths(child, parent_style, loop_context, stylesheet, root_data, resource_fetcher, draw_state);
+            for child in children.iter().filter(|c| c.is_element() && c.tag_name() != Some("caption")) {
+                self.gather_column_widths(child, parent_style, &inner_loop_context, stylesheet, root_data, resource_fetcher, row_spans, spanning_cells, draw_state);
             }
         }
     }
 
-    fn gather_inline_material(&self, node: &Node, parent: Option<&Node>, sibling: Option<&Node>, stylesheet: &Stylesheet, parent_style: &StyleData, spine_dir: &PathBuf, markers: &mut Vec<usize>, inlines: &mut Vec<InlineMaterial>) {
+    // The absolute font sizes are scaled with the text of the enclosing table.
+    fn scaled_font_size(&self, value: &str, parent_style: &StyleData) -> Option<f32> {
+        parse_font_size(value, parent_style.font_size / parent_style.font_scale, self.font_size)
+            .map(|font_size| font_size * parent_style.font_scale)
+    }
+
+    fn cell_border(&self, node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, stylesheet: &Stylesheet, parent_style: &StyleData) -> Border {
+        let props = specified_values(node, ancestors, siblings, stylesheet);
+        let font_size = props.get("font-size")
+                             .and_then(|value| self.scaled_font_size(value, parent_style))
+                             .unwrap_or(parent_style.font_size);
+        let color = props.get("color")
+                         .and_then(|value| parse_color(value))
+                         .unwrap_or(parent_style.color);
+        let mut border = Border::default();
+        let mut widths = [0; 4];
+
+        for (i, side) in ["top", "right", "bottom", "left"].iter().enumerate() {
+            let style = props.get(&format!("border-{}-style", side)).map(String::as_str);
+            if style.map_or(true, |s| s == "none" || s == "hidden") {
+                continue;
+            }
+            widths[i] = parse_border_width(props.get(&format!("border-{}-width", side)).map_or("medium", String::as_str),
+                                           font_size, self.font_size, self.dpi).unwrap_or(0);
+            border.color[i] = props.get(&format!("border-{}-color", side))
+                                   .and_then(|value| parse_color(value))
+                                   .unwrap_or(color);
+        }
+
+        border.width = Edge { top: widths[0], right: widths[1], bottom: widths[2], left: widths[3] };
+        border
+    }
+
+    fn draw_cell_border(&self, cell: &TableCell, bottom: (usize, i32), collapse: bool, root_data: &RootData, display_list: &mut Vec<Page>) {
+        let Border { width, color } = cell.border;
+        let inset = border_inset(width, collapse);
+        let outset = Edge {
+            top: width.top - inset.top,
+            right: width.right - inset.right,
+            bottom: width.bottom - inset.bottom,
+            left: width.left - inset.left,
+        };
+
+        for page_index in cell.top.0..=bottom.0 {
+            let min_y = if page_index == cell.top.0 { cell.top.1 } else { root_data.rect.min.y };
+            let max_y = if page_index == bottom.0 { bottom.1 } else { root_data.rect.max.y };
+            let min_x = cell.start_x - outset.left;
+            let max_x = cell.end_x + outset.right;
+            let mut edges = Vec::new();
+
+            if page_index == cell.top.0 && width.top > 0 {
+                edges.push((rect![min_x, min_y - outset.top, max_x, min_y + inset.top], color[0]));
+            }
+            if width.right > 0 {
+                edges.push((rect![cell.end_x - inset.right, min_y, max_x, max_y], color[1]));
+            }
+            if page_index == bottom.0 && width.bottom > 0 {
+                edges.push((rect![min_x, max_y - inset.bottom, max_x, max_y + outset.bottom], color[2]));
+            }
+            if width.left > 0 {
+                edges.push((rect![min_x, min_y, cell.start_x + inset.left, max_y], color[3]));
+            }
+
+            if let Some(page) = display_list.get_mut(page_index) {
+                page.extend(edges.into_iter().map(|(rect, color)| {
+                    DrawCommand::Rectangle(RectangleCommand { offset: cell.offset, rect, color })
+                }));
+            }
+        }
+    }
+
+    // Closes the cells that span beyond the last row of the table.
+    fn close_row_spans(&self, root_data: &RootData, draw_state: &mut DrawState, display_list: &mut Vec<Page>) {
+        let row_spans = mem::replace(&mut draw_state.table.row_spans, Vec::new());
+        let current = (draw_state.table.row_page.take().unwrap_or(display_list.len() - 1), draw_state.position.y);
+        let bottom = row_spans.iter().map(|s| s.bottom).fold(current, |a, b| a.max(b));
+
+        for span in &row_spans {
+            self.draw_cell_border(&span.cell, bottom, draw_state.table.border_collapse, root_data, display_list);
+        }
+
+        draw_state.position.y = bottom.1;
+    }
+
+    // Converts an SVG element into a data URI. The size of the image is
+    // given by the element when the style doesn't specify it.
+    fn svg_image(&self, node: &Node, dir: &Path, max_width: i32, style: &mut StyleData, resource_fetcher: &mut dyn ResourceFetcher) -> String {
+        let max_height = self.rect().height() as i32 - self.margin.top - self.margin.bottom;
+        let (width, height) = svg_size(node, max_width, max_height, style.font_size, self.dpi);
+        if style.width == 0 && style.height == 0 {
+            style.width = width;
+            style.height = height;
+        }
+        let document = svg_document(node, (width, height), style.font_size, self.dpi, dir, resource_fetcher);
+        format!("data:image/svg+xml;base64,{}", base64::encode(&document))
+    }
+
+    fn load_svg_image(&self, path: &str, max_width: i32, style: &mut StyleData, resource_fetcher: &mut dyn ResourceFetcher) -> Option<String> {
+        let data = resource_fetcher.fetch(path).ok()?;
+        let text = String::from_utf8_lossy(&data);
+        let root = XmlParser::new(&text).parse();
+        let node = root.find("svg").or_else(|| root.find("svg:svg"))?;
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        Some(self.svg_image(node, dir, max_width, style, resource_fetcher))
+    }
+
+    fn gather_inline_material<'a>(&self, node: &'a Node, ancestors: &mut Vec<&'a Node>, siblings: Option<(&[&Node], usize)>, stylesheet: &Stylesheet, parent_style: &StyleData, spine_dir: &PathBuf, resource_fetcher: &mut dyn ResourceFetcher, markers: &mut Vec<usize>, inlines: &mut Vec<InlineMaterial>) {
         match node {
             Node::Element(ElementData { offset, name, attributes, children }) => {
                 let mut style = StyleData::default();
-                let props = specified_values(node, parent, sibling, stylesheet);
+                let props = specified_values(node, ancestors, siblings, stylesheet);
 
                 style.font_style = parent_style.font_style;
+                style.font_scale = parent_style.font_scale;
                 style.line_height = parent_style.line_height;
                 style.text_indent = parent_style.text_indent;
                 style.retain_whitespace = parent_style.retain_whitespace;
@@ -594,7 +1160,7 @@ impl Engine {
                 }
 
                 style.font_size = props.get("font-size")
-                                       .and_then(|value| parse_font_size(value, parent_style.font_size, self.font_size))
+                                       .and_then(|value| self.scaled_font_size(value, parent_style))
                                        .unwrap_or(parent_style.font_size);
 
                 style.width = props.get("width")
@@ -606,7 +1172,7 @@ impl Engine {
                                     .unwrap_or(0);
 
                 style.font_kind = props.get("font-family")
-                                       .and_then(|value| parse_font_kind(value))
+                                       .and_then(|value| self.font_kind(value))
                                        .unwrap_or(parent_style.font_kind);
 
                 style.color = props.get("color")
@@ -646,12 +1212,32 @@ impl Engine {
                 }
 
                 match name.as_ref() {
-                    "img" | "image" | "svg:image" => {
-                        let attr = if name == "img" { "src" } else { "xlink:href" };
+                    "img" | "image" | "svg:image" | "svg" | "svg:svg" => {
+                        let max_width = if parent_style.width > 0 {
+                            parent_style.width
+                        } else {
+                            self.rect().width() as i32 - self.margin.left - self.margin.right
+                        };
 
-                        let path = attributes.get(attr).and_then(|src| {
-                            spine_dir.join(src).normalize().to_str().map(String::from)
-                        }).unwrap_or_default();
+                        let path = if is_svg(node) {
+                            self.svg_image(node, spine_dir, max_width, &mut style, resource_fetcher)
+                        } else {
+                            let attr = if name == "img" { "src" } else { "xlink:href" };
+                            let path = attributes.get(attr).and_then(|src| {
+                                if src.starts_with("data:") {
+                                    Some(src.clone())
+                                } else {
+                                    spine_dir.join(src).normalize().to_str().map(String::from)
+                                }
+                            }).unwrap_or_default();
+
+                            if path.to_lowercase().ends_with(".svg") {
+                                self.load_svg_image(&path, max_width, &mut style, resource_fetcher)
+                                    .unwrap_or(path)
+                            } else {
+                                path
+                            }
+                        };
 
                         style.float = props.get("float").and_then(|value| parse_float(value));
 
@@ -691,11 +1277,14 @@ impl Engine {
                     inlines.append(&mut v);
                 }
 
-                let mut sibling = None;
+                let siblings = element_children(node);
+                let mut position = 0;
+                ancestors.push(node);
                 for child in children {
-                    self.gather_inline_material(child, Some(node), sibling, stylesheet, &style, spine_dir, markers, inlines);
-                    sibling = Some(child);
+                    let child_siblings = next_sibling(child, &siblings, &mut position);
+                    self.gather_inline_material(child, ancestors, child_siblings, stylesheet, &style, spine_dir, resource_fetcher, markers, inlines);
                 }
+                ancestors.pop();
 
                 if let Some(mut v) = props.get("-plato-insert-after")
                                           .map(|value| parse_inline_material(value, style.font_size, self.font_size, self.dpi)) {
@@ -703,31 +1292,34 @@ impl Engine {
                 }
             },
             Node::Text(TextData { offset, text }) => {
+                let mut start = 0;
                 let mut index = 0;
-                while let Some(start_delta) = text[index..].find('&') {
-                    if start_delta > 0 {
-                        inlines.push(InlineMaterial::Text(TextMaterial {
-                            offset: *offset + index,
-                            text: text[index..index+start_delta].to_string(),
-                            style: parent_style.clone(),
-                        }));
-                    }
-                    index += start_delta;
-                    if let Some(end_delta) = text[index..].find(';') {
+                // The ampersands that don't start a reference are kept as is.
+                while let Some(delta) = text[index..].find('&') {
+                    index += delta;
+                    if let Some((decoded, len)) = decode_reference(&text[index..]) {
+                        if index > start {
+                            inlines.push(InlineMaterial::Text(TextMaterial {
+                                offset: *offset + start,
+                                text: text[start..index].to_string(),
+                                style: parent_style.clone(),
+                            }));
+                        }
                         inlines.push(InlineMaterial::Text(TextMaterial {
                             offset: *offset + index,
-                            text: decode_entities(&text[index..=index+end_delta]).into_owned(),
+                            text: decoded,
                             style: parent_style.clone(),
                         }));
-                        index += end_delta + 1;
+                        index += len;
+                        start = index;
                     } else {
-                        break;
+                        index += 1;
                     }
                 }
-                if index < text.len() {
+                if start < text.len() {
                     inlines.push(InlineMaterial::Text(TextMaterial {
-                        offset: *offset + index,
-                        text: text[index..].to_string(),
+                        offset: *offset + start,
+                        text: text[start..].to_string(),
                         style: parent_style.clone(),
                     }));
                 }
@@ -743,6 +1335,177 @@ impl Engine {
         }
     }
 
+    fn apply_pseudo_element_style(&self, props: &PropertyMap, style: &mut StyleData) {
+        if let Some(font_size) = props.get("font-size")
+                                      .and_then(|value| self.scaled_font_size(value, style)) {
+            style.font_size = font_size;
+        }
+
+        if let Some(font_kind) = props.get("font-family").and_then(|value| self.font_kind(value)) {
+            style.font_kind = font_kind;
+        }
+
+        if let Some(font_style) = props.get("font-style").and_then(|value| parse_font_style(value)) {
+            style.font_style = font_style;
+        }
+
+        if let Some(font_weight) = props.get("font-weight").and_then(|value| parse_font_weight(value)) {
+            style.font_weight = font_weight;
+        }
+
+        if let Some(color) = props.get("color").and_then(|value| parse_color(value)) {
+            style.color = color;
+        }
+
+        if let Some(letter_spacing) = props.get("letter-spacing")
+                                           .and_then(|value| parse_letter_spacing(value, style.font_size, self.font_size, self.dpi)) {
+            style.letter_spacing = letter_spacing;
+        }
+
+        if let Some(vertical_align) = props.get("vertical-align")
+                                           .and_then(|value| parse_vertical_align(value, style.font_size, self.font_size, style.line_height, self.dpi)) {
+            style.vertical_align = vertical_align;
+        }
+
+        if let Some(value) = props.get("font-variant") {
+            let mut features = parse_font_variant(value);
+            if !features.is_empty() {
+                style.font_features.get_or_insert_with(Vec::new)
+                     .append(&mut features);
+            }
+        }
+    }
+
+    // The first line is found by a greedy fit of the styled beginning of the paragraph.
+    fn style_first_line(&mut self, inlines: &mut Vec<InlineMaterial>, props: &PropertyMap, style: &StyleData, resource_fetcher: &mut dyn ResourceFetcher) {
+        let line_width = style.end_x - style.start_x;
+        let text_indent = if style.text_align == TextAlign::Center { 0 } else { style.text_indent };
+        let stretch_tolerance = if style.text_align == TextAlign::Justify { STRETCH_TOLERANCE } else { 10.0 };
+
+        // Only keep the materials that might fit on the first line: a glyph is
+        // assumed to be at least a fifth of an em wide.
+        let mut budget = line_width.max(line_width - text_indent) as f32;
+        let mut prefix = Vec::new();
+
+        for inline in inlines.iter() {
+            let mut inline = inline.clone();
+            let mut done = false;
+            if let InlineMaterial::Text(TextMaterial { ref mut text, ref mut style, .. }) = inline {
+                self.apply_pseudo_element_style(props, style);
+                let advance = (pt_to_px(style.font_size, self.dpi) / 5.0).max(1.0);
+                let mut end = None;
+                for (i, _) in text.char_indices().filter(|(_, c)| !c.is_whitespace()) {
+                    if budget < 0.0 {
+                        end = Some(i);
+                        break;
+                    }
+                    budget -= advance;
+                }
+                if let Some(end) = end {
+                    text.truncate(end);
+                    done = true;
+                }
+            }
+            prefix.push(inline);
+            if done {
+                break;
+            }
+        }
+
+        let (items, _) = self.make_paragraph_items(&prefix, style, line_width, resource_fetcher);
+        let line_lengths = vec![line_width - text_indent, line_width];
+        let bps = standard_fit(&items, &line_lengths, stretch_tolerance);
+
+        // The number of words on the first line.
+        let mut count = match bps.first() {
+            Some(bp) => items[..bp.index].iter().filter(|itm| {
+                match itm {
+                    ParagraphItem::Box { data: ParagraphElement::Text(..), .. } => true,
+                    _ => false,
+                }
+            }).count(),
+            None => return,
+        };
+
+        // Each word of a text material yields one box.
+        let mut index = 0;
+
+        while count > 0 && index < inlines.len() {
+            if let InlineMaterial::Text(TextMaterial { ref text, .. }) = inlines[index] {
+                let mut end = None;
+                let mut in_word = false;
+                for (i, c) in text.char_indices() {
+                    if !c.is_whitespace() {
+                        in_word = true;
+                    } else if in_word {
+                        in_word = false;
+                        count -= 1;
+                        if count == 0 {
+                            end = Some(i);
+                            break;
+                        }
+                    }
+                }
+                if in_word {
+                    count -= 1;
+                }
+                if let Some(end) = end {
+                    split_text_material(inlines, index, end);
+                }
+            }
+            if let InlineMaterial::Text(TextMaterial { ref mut style, .. }) = inlines[index] {
+                self.apply_pseudo_element_style(props, style);
+            }
+            index += 1;
+        }
+    }
+
+    // The first letter includes the punctuation that surrounds it.
+    fn style_first_letter(&self, inlines: &mut Vec<InlineMaterial>, props: &PropertyMap) {
+        let mut found_letter = false;
+        let mut found_anything = false;
+        let mut index = 0;
+
+        while index < inlines.len() {
+            let (start, end, done) = match inlines[index] {
+                InlineMaterial::Text(TextMaterial { ref text, .. }) => {
+                    let mut start = 0;
+                    let mut end = None;
+                    for (i, c) in text.char_indices() {
+                        if c.is_whitespace() && !found_anything {
+                            start = i + c.len_utf8();
+                        } else if is_punctuation(c) || (!found_letter && c.is_alphanumeric()) {
+                            found_letter = found_letter || !is_punctuation(c);
+                            found_anything = true;
+                        } else {
+                            end = Some(i);
+                            break;
+                        }
+                    }
+                    (start, end.unwrap_or_else(|| text.len()), end.is_some())
+                },
+                _ => break,
+            };
+
+            if end > start {
+                split_text_material(inlines, index, end);
+                if start > 0 {
+                    split_text_material(inlines, index, start);
+                    index += 1;
+                }
+                if let InlineMaterial::Text(TextMaterial { ref mut style, .. }) = inlines[index] {
+                    self.apply_pseudo_element_style(props, style);
+                }
+            }
+
+            if done {
+                break;
+            }
+
+            index += 1;
+        }
+    }
+
     fn make_paragraph_items(&mut self, inlines: &[InlineMaterial], parent_style: &StyleData, line_width: i32, resource_fetcher: &mut dyn ResourceFetcher) -> (Vec<ParagraphItem<ParagraphElement>>, Vec<ImageElement>) {
         let mut items = Vec::new();
         let mut floats = Vec::new();
@@ -773,8 +1536,8 @@ impl Engine {
                     let mut scale = 1.0;
                     let dpi = self.dpi;
 
-                    if let Ok(buf) = resource_fetcher.fetch(path) {
-                        if let Some(doc) = PdfOpener::new().and_then(|opener| opener.open_memory(path, &buf)) {
+                    if let Some((magic, buf)) = fetch_image(path, resource_fetcher) {
+                        if let Some(doc) = PdfOpener::new().and_then(|opener| opener.open_memory(&magic, &buf)) {
                             if let Some((w, h)) = doc.dims(0) {
                                 if width == 0 && height == 0 {
                                     width = pt_to_px(w, dpi).round() as i32;
@@ -826,7 +1589,7 @@ impl Engine {
                                                             style.font_style,
                                                             style.font_weight);
                                     font.set_size(font_size, self.dpi);
-                                    font.plan(&buf, None, style.font_features.as_ref().map(Vec::as_slice))
+                                    plan_text(font, &buf, style.font_features.as_ref().map(Vec::as_slice))
                                 };
                                 plan.space_out(style.letter_spacing.max(0) as u32);
 
@@ -949,7 +1712,7 @@ impl Engine {
                                                     style.font_style,
                                                     style.font_weight);
                             font.set_size(font_size, self.dpi);
-                            font.plan(&buf, None, style.font_features.as_ref().map(Vec::as_slice))
+                            plan_text(font, &buf, style.font_features.as_ref().map(Vec::as_slice))
                         };
                         plan.space_out(style.letter_spacing.max(0) as u32);
                         items.push(ParagraphItem::Box {
@@ -1014,6 +1777,7 @@ impl Engine {
 
     fn place_paragraphs(&mut self, inlines: &[InlineMaterial], style: &StyleData, root_data: &RootData, markers: &[usize], resource_fetcher: &mut dyn ResourceFetcher, draw_state: &mut DrawState, rects: &mut Vec<Option<Rectangle>>, display_list: &mut Vec<Page>) {
         let position = &mut draw_state.position;
+        let is_rtl = style.direction == Direction::Rtl;
 
         let text_indent = if style.text_align == TextAlign::Center {
             0
@@ -1195,6 +1959,62 @@ impl Engine {
             items = self.cleanup_paragraph(items, &hyph_indices, &mut glue_drifts, &mut bps);
         }
 
+        let lines_count = bps.len();
+        // The index of the first line of the paragraph on the current page.
+        let mut page_start = 0;
+
+        // The vertical space taken by each line: the lines made of a single image also take its height.
+        let mut line_start = 0;
+        let line_heights = bps.iter().map(|bp| {
+            let mut boxes = items[line_start..bp.index].iter().filter_map(|itm| {
+                match itm {
+                    ParagraphItem::Box { width, data } if *width > 0 => Some(data),
+                    _ => None,
+                }
+            });
+            line_start = bp.index;
+            match (boxes.next(), boxes.next()) {
+                (Some(ParagraphElement::Image(element)), None) => {
+                    let mut height = style.line_height + element.margin.top + element.height + element.margin.bottom;
+                    if element.display == Display::Block {
+                        height -= space_top + space_bottom;
+                    }
+                    height.max(style.line_height)
+                },
+                _ => style.line_height,
+            }
+        }).collect::<Vec<i32>>();
+
+        // The number of lines, starting from the given one, that fit on the current page, below the given position.
+        let lines_room = |mut y: i32, start: usize| {
+            let mut count = 0;
+            for height in &line_heights[start..] {
+                if y > root_data.rect.max.y - space_bottom {
+                    break;
+                }
+                count += 1;
+                y += height;
+            }
+            count
+        };
+
+        // Move the paragraph to the next page if too few lines would be left at the bottom of the current page.
+        if lines_count > 1 && position.y > root_data.rect.min.y + space_top &&
+           !draw_state.floats.contains_key(&page_index) {
+            let mut first_lines = lines_room(position.y, 0);
+            if first_lines < lines_count {
+                if lines_count - first_lines < style.widows {
+                    first_lines = lines_count.saturating_sub(style.widows);
+                }
+                if first_lines < style.orphans {
+                    rects.push(page_rect.take());
+                    display_list.push(page);
+                    position.y = root_data.rect.min.y + space_top;
+                    page = Vec::new();
+                }
+            }
+        }
+
         let mut last_index = 0;
         let mut markers_index = 0;
         let mut last_x_position = 0;
@@ -1209,8 +2029,12 @@ impl Engine {
                 font.set_size(font_size, self.dpi);
                 font.plan(prefix, None, style.font_features.as_ref().map(Vec::as_slice))
             };
-            let (start_x, _) = para_shape[0];
-            let pt = pt!(start_x - prefix_plan.width as i32, position.y);
+            let (start_x, end_x) = para_shape[0];
+            let pt = if is_rtl {
+                pt!(end_x, position.y)
+            } else {
+                pt!(start_x - prefix_plan.width as i32, position.y)
+            };
             let rect = rect![pt + pt!(0, -ascender), pt + pt!(prefix_plan.width as i32, -descender)];
             if let Some(first_offset) = inlines.iter().filter_map(|elt| elt.offset()).next() {
                 page.push(DrawCommand::ExtraText(TextCommand {
@@ -1230,6 +2054,15 @@ impl Engine {
         }
 
         for bp in bps {
+            // Move the last lines of the paragraph to the next page together.
+            if j > page_start && lines_count - j <= style.widows && lines_room(position.y, j) < lines_count - j {
+                rects.push(page_rect.take());
+                display_list.push(page);
+                position.y = root_data.rect.min.y + space_top;
+                page = Vec::new();
+                page_start = j;
+            }
+
             let drift = if glue_drifts.is_empty() {
                 0.0
             } else {
@@ -1244,6 +2077,7 @@ impl Engine {
 
             match style.text_align {
                 TextAlign::Right => position.x = end_x - width - current_text_indent,
+                _ if is_rtl => position.x = start_x,
                 _ => position.x = start_x + current_text_indent,
             }
 
@@ -1255,7 +2089,9 @@ impl Engine {
                 last_index += 1;
             }
 
-            for i in last_index..index {
+            let mut last_text: Option<(usize, usize)> = None;
+
+            for i in bidi_order(&items[last_index..index], is_rtl).into_iter().map(|k| last_index + k) {
                 match items[i] {
                     ParagraphItem::Box { ref data, width } => {
                         match data {
@@ -1288,6 +2124,9 @@ impl Engine {
                                     font_size: element.font_size,
                                     color: element.color,
                                 }));
+                                if last_text.map_or(true, |(k, _)| i > k) {
+                                    last_text = Some((i, page.len() - 1));
+                                }
                             },
                             ParagraphElement::Image(element) => {
                                 while let Some(offset) = markers.get(markers_index) {
@@ -1319,6 +2158,8 @@ impl Engine {
                                             display_list.push(page);
                                             position.y = root_data.rect.min.y;
                                             page = Vec::new();
+                                            page_start = j;
+                                            last_text = None;
                                             ratio = ((root_data.rect.max.y - position.y - space_bottom) as f32 / height as f32).min(1.0);
                                         }
                                         height = (height as f32 * ratio).round() as i32;
@@ -1417,7 +2258,7 @@ impl Engine {
                         font.set_size(font_size, self.dpi);
                         font.plan("-", None, style.font_features.as_ref().map(Vec::as_slice))
                     };
-                    if let Some(DrawCommand::Text(TextCommand { ref mut rect, ref mut plan, ref mut text, .. })) = page.last_mut() {
+                    if let Some(DrawCommand::Text(TextCommand { ref mut rect, ref mut plan, ref mut text, .. })) = last_text.and_then(|(_, k)| page.get_mut(k)) {
                         rect.max.x += hyphen_plan.width as i32;
                         plan.append(&mut hyphen_plan);
                         text.push('\u{00AD}');
@@ -1437,6 +2278,7 @@ impl Engine {
                 display_list.push(page);
                 position.y = root_data.rect.min.y + space_top;
                 page = Vec::new();
+                page_start = j + 1;
             }
 
             j += 1;
@@ -1463,7 +2305,7 @@ impl Engine {
                                     element.font_style,
                                     element.font_weight);
             font.set_size(element.font_size, self.dpi);
-            font.plan(chunk, None, element.font_features.as_ref().map(Vec::as_slice))
+            plan_text(font, chunk, element.font_features.as_ref().map(Vec::as_slice))
         };
         plan.space_out(element.letter_spacing.max(0) as u32);
         ParagraphItem::Box {
@@ -1591,7 +2433,7 @@ impl Engine {
                         let font = self.fonts.as_mut().unwrap()
                                        .get_mut(font_kind, font_style, font_weight);
                         font.set_size(font_size, self.dpi);
-                        font.plan(text, None, font_features.as_ref().map(Vec::as_slice))
+                        plan_text(font, text, font_features.as_ref().map(Vec::as_slice))
                     };
                     plan.space_out(letter_spacing.max(0) as u32);
                     merged_width = plan.width as i32;
@@ -1650,7 +2492,7 @@ impl Engine {
                             let font = self.fonts.as_mut().unwrap()
                                            .get_mut(font_kind, font_style, font_weight);
                             font.set_size(font_size, self.dpi);
-                            font.plan(text, None, font_features.as_ref().map(Vec::as_slice))
+                            plan_text(font, text, font_features.as_ref().map(Vec::as_slice))
                         };
                         plan.space_out(letter_spacing.max(0) as u32);
                         merged_width = plan.width as i32;
@@ -1675,6 +2517,7 @@ impl Engine {
     pub fn render_page(&mut self, page: &[DrawCommand], resource_fetcher: &mut dyn ResourceFetcher) -> Pixmap {
         let (width, height) = self.dims;
         let mut fb = Pixmap::new(width, height);
+        let fb_rect = self.rect();
 
         for dc in page {
             match dc {
@@ -1686,9 +2529,9 @@ impl Engine {
                     font.render(&mut fb, *color, plan, *position);
                 },
                 DrawCommand::Image(ImageCommand { position, path, scale, .. }) => {
-                    if let Ok(buf) = resource_fetcher.fetch(path) {
+                    if let Some((magic, buf)) = fetch_image(path, resource_fetcher) {
                         PdfOpener::new().and_then(|opener| {
-                            opener.open_memory(path, &buf)
+                            opener.open_memory(&magic, &buf)
                         }).and_then(|mut doc| {
                             doc.pixmap(Location::Exact(0), *scale)
                         }).map(|(pixmap, _)| {
@@ -1696,6 +2539,11 @@ impl Engine {
                         });
                     }
                 },
+                DrawCommand::Rectangle(RectangleCommand { rect, color, .. }) => {
+                    if let Some(rect) = rect.intersection(&fb_rect) {
+                        fb.draw_rectangle(&rect, *color);
+                    }
+                },
                 _ => (),
             }
         }
@@ -1704,6 +2552,141 @@ impl Engine {
     }
 }
 
+// Returns the data of an image, given by a resource path or a data URI,
+// and the file name or MIME type used to open it.
+fn fetch_image(path: &str, resource_fetcher: &mut dyn ResourceFetcher) -> Option<(String, Vec<u8>)> {
+    if path.starts_with("data:") {
+        decode_data_uri(path)
+    } else {
+        resource_fetcher.fetch(path).ok().map(|buf| (path.to_string(), buf))
+    }
+}
+
+// https://tools.ietf.org/html/rfc2397
+fn decode_data_uri(uri: &str) -> Option<(String, Vec<u8>)> {
+    let index = uri.find(',')?;
+    let header = &uri[5..index];
+    let payload = &uri[index+1..];
+    let mime_type = header.split(';').next().filter(|m| !m.is_empty())
+                          .unwrap_or("text/plain");
+    let data = if header.split(';').any(|p| p.eq_ignore_ascii_case("base64")) {
+        let payload: String = payload.chars().filter(|c| !c.is_whitespace()).collect();
+        base64::decode(&payload).ok()?
+    } else {
+        percent_decode(payload)
+    };
+    Some((mime_type.to_string(), data))
+}
+
+// The numbers of columns and rows covered by a table cell.
+fn cell_spans(node: &Node) -> (usize, usize) {
+    let colspan = node.attr("colspan")
+                      .and_then(|v| v.trim().parse::<usize>().ok())
+                      .filter(|n| *n > 0)
+                      .map_or(1, |n| n.min(MAX_COLSPAN));
+    // A row span of zero extends the cell to the end of the table.
+    let rowspan = node.attr("rowspan")
+                      .and_then(|v| v.trim().parse::<usize>().ok())
+                      .map_or(1, |n| if n == 0 { MAX_ROWSPAN } else { n.min(MAX_ROWSPAN) });
+    (colspan, rowspan)
+}
+
+// The part of the borders that lies within a table cell. Collapsed borders
+// are centered on the grid lines, and shared by the adjacent cells.
+fn border_inset(width: Edge, collapse: bool) -> Edge {
+    if collapse {
+        Edge {
+            top: width.top - width.top / 2,
+            right: width.right / 2,
+            bottom: width.bottom / 2,
+            left: width.left - width.left / 2,
+        }
+    } else {
+        width
+    }
+}
+
+// Widens the given columns, proportionally to their widths, until their sum reaches the given width.
+fn distribute_width(widths: &mut [i32], width: i32) {
+    let total: i32 = widths.iter().sum();
+    if widths.is_empty() || total >= width {
+        return;
+    }
+
+    let excess = width - total;
+    let count = widths.len();
+    let mut remaining = excess;
+
+    for (i, w) in widths.iter_mut().enumerate() {
+        let share = if i == count - 1 {
+            remaining
+        } else if total > 0 {
+            excess * *w / total
+        } else {
+            excess / count as i32
+        };
+        *w += share;
+        remaining -= share;
+    }
+}
+
+// Whether the *break-{name}* or *page-break-{name}* property avoids page breaks.
+fn avoids_page_break(props: &PropertyMap, name: &str) -> bool {
+    props.get(&format!("break-{}", name))
+         .or_else(|| props.get(&format!("page-break-{}", name)))
+         .map_or(false, |value| value == "avoid" || value == "avoid-page")
+}
+
+// The repeated header rows are attached to the row that follows them, and aren't part of the text.
+fn repeated_command(dc: DrawCommand, offset: usize) -> Option<DrawCommand> {
+    match dc {
+        DrawCommand::Text(tc) | DrawCommand::ExtraText(tc) => Some(DrawCommand::ExtraText(TextCommand { offset, .. tc })),
+        DrawCommand::Image(ic) => Some(DrawCommand::Image(ImageCommand { offset, .. ic })),
+        DrawCommand::Rectangle(rc) => Some(DrawCommand::Rectangle(RectangleCommand { offset, .. rc })),
+        DrawCommand::Marker(..) => None,
+    }
+}
+
+// The element siblings of the children of a node, and the position of the first one.
+// The children of an anonymous box are siblings of the box's siblings.
+fn children_siblings<'a>(node: &'a Node, loop_context: &LoopContext<'a>) -> (Vec<&'a Node>, usize) {
+    if node.tag_name() == Some("anonymous") {
+        (loop_context.siblings.clone(), loop_context.sibling_index)
+    } else {
+        (element_children(node), 0)
+    }
+}
+
+// The element siblings of *child* if it's the one found at *position* in *siblings*.
+fn next_sibling<'b, 'c>(child: &Node, siblings: &'b [&'c Node], position: &mut usize) -> Option<(&'b [&'c Node], usize)> {
+    if siblings.get(*position).map_or(false, |s| ptr::eq(*s, child)) {
+        *position += 1;
+        Some((siblings, *position - 1))
+    } else {
+        None
+    }
+}
+
+// Splits the text material at the given index, at the given byte position.
+fn split_text_material(inlines: &mut Vec<InlineMaterial>, index: usize, at: usize) {
+    let tail = match inlines[index] {
+        InlineMaterial::Text(TextMaterial { offset, ref mut text, ref style }) => {
+            if at == 0 || at >= text.len() {
+                return;
+            }
+            let tail = text[at..].to_string();
+            text.truncate(at);
+            TextMaterial { offset: offset + at, text: tail, style: style.clone() }
+        },
+        _ => return,
+    };
+    inlines.insert(index + 1, InlineMaterial::Text(tail));
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || "«»‹›“”‘’„‚¡¿—–…".contains(c)
+}
+
 fn format_list_prefix(kind: ListStyleType, index: usize) -> Option<String> {
     match kind {
         ListStyleType::None => None,
@@ -1753,8 +2736,63 @@ fn default_fonts() -> Result<Fonts, Error> {
         },
         cursive: opener.open("fonts/Parisienne-Regular.ttf")?,
         fantasy: opener.open("fonts/Delius-Regular.ttf")?,
+        embedded: Vec::new(),
     };
     fonts.monospace.bold.set_variations(&["wght=600"]);
     fonts.monospace.bold_italic.set_variations(&["wght=600"]);
     Ok(fonts)
 }
+
+fn font_face_url(value: &str) -> Option<&str> {
+    let start = value.find("url(")? + 4;
+    let end = start + value[start..].find(')')?;
+    let url = value[start..end].trim().trim_matches(|c| c == '"' || c == '\'');
+    if url.starts_with("data:") {
+        None
+    } else {
+        Some(url)
+    }
+}
+
+fn bidi_order(items: &[ParagraphItem<ParagraphElement>], is_rtl: bool) -> Vec<usize> {
+    let base_level = if is_rtl { 1 } else { 0 };
+    let levels: Vec<Option<u8>> = items.iter().map(|itm| {
+        match itm {
+            ParagraphItem::Box { data: ParagraphElement::Text(element), .. } => {
+                text_direction(&element.text).map(|dir| {
+                    match dir {
+                        Direction::Rtl => 1,
+                        Direction::Ltr => if is_rtl { 2 } else { 0 },
+                    }
+                })
+            },
+            _ => None,
+        }
+    }).collect();
+
+    if !is_rtl && levels.iter().all(|l| l.map_or(true, |l| l == 0)) {
+        return (0..items.len()).collect();
+    }
+
+    // Neutral items take the level of their neighbors when both sides agree.
+    let resolved: Vec<u8> = (0..levels.len()).map(|i| {
+        levels[i].unwrap_or_else(|| {
+            let before = levels[..i].iter().rev().filter_map(|l| *l).next();
+            let after = levels[i+1..].iter().filter_map(|l| *l).next();
+            match (before, after) {
+                (Some(a), Some(b)) if a == b => a,
+                _ => base_level,
+            }
+        })
+    }).collect();
+
+    visual_order(&resolved)
+}
+
+fn plan_text(font: &mut Font, text: &str, features: Option<&[String]>) -> RenderPlan {
+    if text_direction(text) == Some(Direction::Rtl) {
+        font.plan_rtl(text, features)
+    } else {
+        font.plan(text, None, features)
+    }
+}
@@ -0,0 +1,531 @@
+use fnv::FnvHashMap;
+use super::dom::{Node, ElementData, Attributes, text, element, whitespace};
+use super::xml::XmlParser;
+
+const VOID_ELEMENTS: [&str; 18] = ["area", "base", "basefont", "bgsound", "br", "col", "embed",
+                                   "frame", "hr", "img", "input", "keygen", "link", "meta",
+                                   "param", "source", "track", "wbr"];
+// Elements whose content is text up to the matching end tag.
+const RAW_TEXT_ELEMENTS: [&str; 5] = ["script", "style", "title", "textarea", "xmp"];
+const HEAD_ELEMENTS: [&str; 10] = ["base", "basefont", "bgsound", "link", "meta", "noscript",
+                                   "script", "style", "template", "title"];
+const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+// Start tags that close an open paragraph.
+const PARAGRAPH_CLOSERS: [&str; 41] = ["address", "article", "aside", "blockquote", "center",
+                                       "details", "dialog", "dir", "div", "dl", "dd", "dt",
+                                       "fieldset", "figcaption", "figure", "footer", "form",
+                                       "h1", "h2", "h3", "h4", "h5", "h6", "header", "hgroup",
+                                       "hr", "li", "listing", "main", "menu", "nav", "ol", "p",
+                                       "plaintext", "pre", "section", "summary", "table", "ul",
+                                       "xmp", "search"];
+const TABLE_ELEMENTS: [&str; 9] = ["table", "caption", "colgroup", "tbody", "thead", "tfoot",
+                                   "tr", "td", "th"];
+
+// The elements beyond which the open elements aren't searched when matching tags.
+const DEFAULT_SCOPE: &[&str] = &["html", "table", "td", "th", "caption", "template",
+                                 "object", "applet", "marquee", "svg", "math"];
+const BUTTON_SCOPE: &[&str] = &["html", "table", "td", "th", "caption", "template",
+                                "object", "applet", "marquee", "svg", "math", "button"];
+const LIST_SCOPE: &[&str] = &["html", "table", "td", "th", "caption", "template",
+                              "object", "applet", "marquee", "svg", "math", "ul", "ol"];
+const DEFINITION_SCOPE: &[&str] = &["html", "table", "td", "th", "caption", "template",
+                                    "object", "applet", "marquee", "svg", "math", "dl"];
+const TABLE_SCOPE: &[&str] = &["html", "table", "template"];
+const ROW_SCOPE: &[&str] = &["html", "table", "template", "tbody", "thead", "tfoot", "tr"];
+
+const COMMON_ELEMENTS: [&str; 10] = ["html", "head", "body", "span", "a", "i", "b", "em",
+                                    "strong", "table"];
+
+// The open elements implicitly closed by the start tag of an element.
+fn implied_end_tags(name: &str) -> Option<(&'static [&'static str], &'static [&'static str])> {
+    match name {
+        "li" => Some((&["li", "p"], LIST_SCOPE)),
+        "dt" | "dd" => Some((&["dt", "dd", "p"], DEFINITION_SCOPE)),
+        "tr" => Some((&["tr", "td", "th"], TABLE_SCOPE)),
+        "td" | "th" => Some((&["td", "th"], ROW_SCOPE)),
+        "tbody" | "thead" | "tfoot" => Some((&["tbody", "thead", "tfoot", "tr", "td", "th", "caption", "colgroup"], TABLE_SCOPE)),
+        "option" => Some((&["option"], &["html", "select", "datalist", "optgroup"])),
+        "optgroup" => Some((&["option", "optgroup"], &["html", "select"])),
+        "rb" | "rt" | "rtc" | "rp" => Some((&["rb", "rt", "rtc", "rp"], &["html", "ruby"])),
+        "a" => Some((&["a"], DEFAULT_SCOPE)),
+        _ if PARAGRAPH_CLOSERS.contains(&name) => Some((&["p"], BUTTON_SCOPE)),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct OpenElement {
+    name: String,
+    offset: usize,
+    attributes: Attributes,
+    children: Vec<Node>,
+    // Inside SVG or MathML: the names keep their case and the
+    // self-closing tags are honored.
+    foreign: bool,
+}
+
+// A forgiving parser, following the broad lines of the HTML5 tree
+// construction: the end tags can be implied, the void elements have no
+// end tag, the attribute values don't need quotes, and the resulting tree
+// always has *html*, *head* and *body* elements.
+#[derive(Debug)]
+pub struct HtmlParser<'a> {
+    input: &'a str,
+    offset: usize,
+    stack: Vec<OpenElement>,
+}
+
+impl<'a> HtmlParser<'a> {
+    pub fn new(input: &str) -> HtmlParser {
+        HtmlParser {
+            input,
+            offset: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        self.offset >= self.input.len()
+    }
+
+    fn next(&self) -> Option<char> {
+        self.input[self.offset..].chars().next()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.input[self.offset..].starts_with(s)
+    }
+
+    fn advance(&mut self, n: usize) {
+        for c in self.input[self.offset..].chars().take(n) {
+            self.offset += c.len_utf8();
+        }
+    }
+
+    fn advance_while<F>(&mut self, test: F) where F: FnMut(&char) -> bool {
+        for c in self.input[self.offset..].chars().take_while(test) {
+            self.offset += c.len_utf8();
+        }
+    }
+
+    fn advance_past(&mut self, target: &str) {
+        if let Some(index) = self.input[self.offset..].find(target) {
+            self.offset += index + target.len();
+        } else {
+            self.offset = self.input.len();
+        }
+    }
+
+    // Whether the input is at the start of a tag, a comment or a declaration.
+    fn at_markup(&self) -> bool {
+        let mut chars = self.input[self.offset..].chars();
+        if chars.next() != Some('<') {
+            return false;
+        }
+        match chars.next() {
+            Some('!') | Some('?') => true,
+            Some('/') => chars.next().map_or(false, |c| c.is_ascii_alphabetic()),
+            Some(c) => c.is_ascii_alphabetic(),
+            None => false,
+        }
+    }
+
+    fn in_foreign(&self) -> bool {
+        self.stack.last().map_or(false, |e| e.foreign)
+    }
+
+    fn is_open(&self, name: &str) -> bool {
+        self.stack.iter().skip(1).any(|e| e.name == name)
+    }
+
+    fn append(&mut self, node: Node) {
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push(node);
+        }
+    }
+
+    fn pop(&mut self) {
+        if self.stack.len() > 1 {
+            if let Some(OpenElement { name, offset, attributes, children, .. }) = self.stack.pop() {
+                self.append(element(&name, offset, attributes, children));
+            }
+        }
+    }
+
+    // Closes the innermost open element in *names*, and the ones it contains,
+    // unless one of the *boundaries* is found first.
+    fn close_any(&mut self, names: &[&str], boundaries: &[&str]) -> bool {
+        for index in (1..self.stack.len()).rev() {
+            let name = self.stack[index].name.as_str();
+            if names.contains(&name) {
+                while self.stack.len() > index {
+                    self.pop();
+                }
+                return true;
+            }
+            if boundaries.contains(&name) {
+                break;
+            }
+        }
+        false
+    }
+
+    fn parse_attributes(&mut self, foreign: bool) -> (Attributes, bool) {
+        let mut attrs = FnvHashMap::default();
+        let mut self_closing = false;
+
+        loop {
+            self.advance_while(|&c| c.is_whitespace());
+            match self.next() {
+                None => break,
+                Some('>') => {
+                    self.advance(1);
+                    break;
+                },
+                Some('/') => {
+                    self.advance(1);
+                    if self.next() == Some('>') {
+                        self.advance(1);
+                        self_closing = true;
+                        break;
+                    }
+                },
+                _ => {
+                    let offset = self.offset;
+                    self.advance(1);
+                    self.advance_while(|&c| !c.is_whitespace() && c != '/' && c != '>' && c != '=');
+                    let key = &self.input[offset..self.offset];
+                    let key = if foreign { key.to_string() } else { key.to_ascii_lowercase() };
+                    self.advance_while(|&c| c.is_whitespace());
+                    let mut value = String::new();
+                    if self.next() == Some('=') {
+                        self.advance(1);
+                        self.advance_while(|&c| c.is_whitespace());
+                        match self.next() {
+                            Some(quote @ '"') | Some(quote @ '\'') => {
+                                self.advance(1);
+                                let offset = self.offset;
+                                self.advance_while(|&c| c != quote);
+                                value = self.input[offset..self.offset].to_string();
+                                self.advance(1);
+                            },
+                            _ => {
+                                let offset = self.offset;
+                                self.advance_while(|&c| !c.is_whitespace() && c != '>');
+                                value = self.input[offset..self.offset].to_string();
+                            },
+                        }
+                    }
+                    // The first occurrence of an attribute wins.
+                    attrs.entry(key).or_insert(value);
+                },
+            }
+        }
+
+        (attrs, self_closing)
+    }
+
+    fn parse_start_tag(&mut self) {
+        let offset = self.offset;
+        self.advance(1);
+        let start = self.offset;
+        self.advance_while(|&c| !c.is_whitespace() && c != '/' && c != '>');
+        let raw_name = &self.input[start..self.offset];
+        let foreign = self.in_foreign() || raw_name.eq_ignore_ascii_case("svg") ||
+                      raw_name.eq_ignore_ascii_case("math");
+        let name = if self.in_foreign() {
+            raw_name.to_string()
+        } else {
+            raw_name.to_ascii_lowercase()
+        };
+        let (attributes, self_closing) = self.parse_attributes(foreign);
+
+        if !foreign {
+            match name.as_str() {
+                "html" => return,
+                "head" if self.is_open("body") || self.is_open("head") => return,
+                "body" if self.is_open("body") => return,
+                _ => (),
+            }
+
+            if !HEAD_ELEMENTS.contains(&name.as_str()) && name != "head" {
+                self.close_any(&["head"], &["html"]);
+            }
+
+            if HEADINGS.contains(&name.as_str()) &&
+               self.stack.last().map_or(false, |e| HEADINGS.contains(&e.name.as_str())) {
+                self.pop();
+            }
+
+            while let Some((names, boundaries)) = implied_end_tags(&name) {
+                if !self.close_any(names, boundaries) {
+                    break;
+                }
+            }
+
+            if VOID_ELEMENTS.contains(&name.as_str()) {
+                self.append(element(&name, offset, attributes, Vec::new()));
+                return;
+            }
+
+            if RAW_TEXT_ELEMENTS.contains(&name.as_str()) && !self_closing {
+                let start = self.offset;
+                let end = self.input[start..].to_ascii_lowercase()
+                              .find(&format!("</{}", name))
+                              .map_or(self.input.len(), |index| start + index);
+                let content = &self.input[start..end];
+                let children = if content.is_empty() {
+                    Vec::new()
+                } else if content.trim().is_empty() {
+                    vec![whitespace(content, start)]
+                } else {
+                    vec![text(content, start)]
+                };
+                self.append(element(&name, offset, attributes, children));
+                self.offset = end;
+                self.advance_past(">");
+                return;
+            }
+        } else if self_closing {
+            self.append(element(&name, offset, attributes, Vec::new()));
+            return;
+        }
+
+        self.stack.push(OpenElement { name, offset, attributes, children: Vec::new(), foreign });
+    }
+
+    fn parse_end_tag(&mut self) {
+        let offset = self.offset;
+        self.advance(2);
+        let start = self.offset;
+        self.advance_while(|&c| !c.is_whitespace() && c != '/' && c != '>');
+        let raw_name = &self.input[start..self.offset];
+        self.advance_past(">");
+
+        if self.in_foreign() {
+            for index in (1..self.stack.len()).rev() {
+                if self.stack[index].name.eq_ignore_ascii_case(raw_name) {
+                    while self.stack.len() > index {
+                        self.pop();
+                    }
+                    return;
+                }
+                if !self.stack[index].foreign {
+                    break;
+                }
+            }
+            return;
+        }
+
+        let name = raw_name.to_ascii_lowercase();
+        match name.as_str() {
+            // The content that follows these is still part of the body.
+            "html" | "body" => (),
+            "br" => self.append(element("br", offset, FnvHashMap::default(), Vec::new())),
+            _ => {
+                let boundaries = if TABLE_ELEMENTS.contains(&name.as_str()) {
+                    TABLE_SCOPE
+                } else if name == "li" {
+                    LIST_SCOPE
+                } else {
+                    DEFAULT_SCOPE
+                };
+                self.close_any(&[name.as_str()], boundaries);
+            },
+        }
+    }
+
+    fn parse_text(&mut self) {
+        let offset = self.offset;
+        loop {
+            self.advance(1);
+            self.advance_while(|&c| c != '<');
+            if self.eof() || self.at_markup() {
+                break;
+            }
+        }
+        let content = &self.input[offset..self.offset];
+        if content.trim().is_empty() {
+            self.append(whitespace(content, offset));
+        } else {
+            self.append(text(content, offset));
+        }
+    }
+
+    pub fn parse(&mut self) -> Node {
+        self.stack.clear();
+        self.stack.push(OpenElement { name: String::new(), offset: 0,
+                                      attributes: FnvHashMap::default(),
+                                      children: Vec::new(), foreign: false });
+
+        while !self.eof() {
+            if self.starts_with("<!--") {
+                self.advance_past("-->");
+            } else if self.starts_with("<![CDATA[") {
+                self.advance_past("]]>");
+            } else if self.starts_with("<!") || self.starts_with("<?") {
+                self.advance_past(">");
+            } else if self.at_markup() {
+                if self.starts_with("</") {
+                    self.parse_end_tag();
+                } else {
+                    self.parse_start_tag();
+                }
+            } else {
+                self.parse_text();
+            }
+        }
+
+        while self.stack.len() > 1 {
+            self.pop();
+        }
+
+        let nodes = self.stack.pop().map(|e| e.children).unwrap_or_default();
+        document(nodes, self.html_attributes())
+    }
+
+    // The attributes of the html element, which is never opened by the parser.
+    fn html_attributes(&self) -> (usize, Attributes) {
+        let lower = self.input.to_ascii_lowercase();
+        let mut index = 0;
+        while let Some(delta) = lower[index..].find("<html") {
+            index += delta;
+            let rest = &self.input[index + 5..];
+            if rest.starts_with(|c: char| c.is_whitespace() || c == '>') {
+                let mut parser = HtmlParser::new(rest);
+                let (attributes, _) = parser.parse_attributes(false);
+                return (index, attributes);
+            }
+            index += 5;
+        }
+        (0, FnvHashMap::default())
+    }
+}
+
+// Wraps the parsed nodes into html, head and body elements.
+fn document(nodes: Vec<Node>, (html_offset, html_attributes): (usize, Attributes)) -> Node {
+    let mut head = None;
+    let mut body = None;
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+
+    for node in nodes {
+        match node.tag_name() {
+            Some("head") if head.is_none() => head = Some(node),
+            Some("body") if body.is_none() => body = Some(node),
+            _ => {
+                let is_head_material = node.tag_name().map_or(false, |n| HEAD_ELEMENTS.contains(&n));
+                if is_head_material && body.is_none() && before.iter().all(is_blank) {
+                    match head {
+                        Some(Node::Element(ElementData { ref mut children, .. })) => children.push(node),
+                        _ => head = Some(element("head", node.offset(), FnvHashMap::default(), vec![node])),
+                    }
+                } else if body.is_none() {
+                    before.push(node);
+                } else {
+                    after.push(node);
+                }
+            },
+        }
+    }
+
+    let body = match body {
+        Some(Node::Element(mut data)) => {
+            if before.iter().any(|n| !is_blank(n)) {
+                before.append(&mut data.children);
+                data.children = before;
+            }
+            data.children.extend(after.into_iter().filter(|n| !is_blank(n)));
+            Node::Element(data)
+        },
+        _ => {
+            let offset = before.iter().find(|n| !is_blank(n))
+                               .map_or(html_offset, Node::offset);
+            let children = before.into_iter().skip_while(is_blank).collect();
+            element("body", offset, FnvHashMap::default(), children)
+        },
+    };
+
+    let head = head.unwrap_or_else(|| element("head", html_offset, FnvHashMap::default(), Vec::new()));
+
+    element("html", html_offset, html_attributes, vec![head, body])
+}
+
+fn is_blank(node: &Node) -> bool {
+    match *node {
+        Node::Whitespace(..) => true,
+        _ => false,
+    }
+}
+
+// Parses the documents with an *.html* extension, and the others
+// that aren't well-formed, with the HTML parser.
+pub fn parse_markup(text: &str, html: bool) -> Node {
+    if !html {
+        let mut parser = XmlParser::new(text);
+        let root = parser.parse();
+        if parser.errors == 0 && !has_uppercase_tags(&root) {
+            return root;
+        }
+    }
+    HtmlParser::new(text).parse()
+}
+
+// Whether some HTML tags are written in uppercase.
+fn has_uppercase_tags(node: &Node) -> bool {
+    match *node {
+        Node::Element(ElementData { ref name, ref children, .. }) => {
+            if name == "svg" || name == "math" {
+                return false;
+            }
+            let lower = name.to_ascii_lowercase();
+            (lower != *name && (VOID_ELEMENTS.contains(&lower.as_str()) ||
+                                PARAGRAPH_CLOSERS.contains(&lower.as_str()) ||
+                                COMMON_ELEMENTS.contains(&lower.as_str()))) ||
+            children.iter().any(has_uppercase_tags)
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_implied_end_tags() {
+        let html = HtmlParser::new("<p>one<p>two<ul><li>a<li>b</ul>").parse();
+        let body = html.find("body").unwrap();
+        let tags = body.children().unwrap().iter()
+                       .map(|n| n.tag_name().unwrap())
+                       .collect::<Vec<&str>>();
+        assert_eq!(tags, vec!["p", "p", "ul"]);
+        assert_eq!(body.child(2).and_then(|ul| ul.children()).map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn test_void_elements_and_attributes() {
+        let html = HtmlParser::new("<BODY><P CLASS=intro hidden>a<BR>b<IMG SRC='x.png'></P>").parse();
+        let p = html.find("p").unwrap();
+        assert_eq!(p.attr("class"), Some("intro"));
+        assert_eq!(p.attr("hidden"), Some(""));
+        assert_eq!(p.children().map(Vec::len), Some(4));
+        assert_eq!(p.child(1).and_then(Node::tag_name), Some("br"));
+    }
+
+    #[test]
+    fn test_offsets() {
+        let text = "<html><body><p>a<b>c</b></body></html>";
+        let html = HtmlParser::new(text).parse();
+        assert_eq!(html.find("p").map(Node::offset), Some(12));
+        assert_eq!(html.find("b").map(Node::offset), Some(16));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let xhtml = parse_markup("<html><body><p>a</p></body></html>", false);
+        assert_eq!(xhtml.find("p").and_then(Node::text), Some("a"));
+        let html = parse_markup("<html><body><p>a<p>b</body></html>", false);
+        assert_eq!(html.find("body").and_then(Node::children).map(Vec::len), Some(2));
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs;
+use std::ptr;
 use std::path::{Path, PathBuf};
 use std::fmt::Debug;
 use fnv::FnvHashMap;
@@ -24,10 +25,9 @@ pub struct DrawState {
     pub position: Point,
     pub floats: FnvHashMap<usize, Vec<Rectangle>>,
     pub prefix: Option<String>,
-    pub min_column_widths: Vec<i32>,
-    pub max_column_widths: Vec<i32>,
-    pub column_widths: Vec<i32>,
-    pub center_table: bool,
+    pub table: TableState,
+    // The minimum and maximum widths of the table cells, by offset and font size.
+    pub cell_widths: FnvHashMap<(usize, u32), (i32, i32)>,
 }
 
 impl Default for DrawState {
@@ -36,10 +36,72 @@ impl Default for DrawState {
             position: Point::default(),
             floats: FnvHashMap::default(),
             prefix: None,
+            table: TableState::default(),
+            cell_widths: FnvHashMap::default(),
+        }
+    }
+}
+
+// The state of the innermost table being laid out.
+#[derive(Debug, Clone)]
+pub struct TableState {
+    pub min_column_widths: Vec<i32>,
+    pub max_column_widths: Vec<i32>,
+    pub column_widths: Vec<i32>,
+    pub center_table: bool,
+    pub border_collapse: bool,
+    // The cells of the previous rows that span the next rows.
+    pub row_spans: Vec<RowSpan>,
+    // The page on which the next row starts.
+    pub row_page: Option<usize>,
+}
+
+impl Default for TableState {
+    fn default() -> Self {
+        TableState {
             min_column_widths: Vec::new(),
             max_column_widths: Vec::new(),
             column_widths: Vec::new(),
             center_table: false,
+            border_collapse: false,
+            row_spans: Vec::new(),
+            row_page: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TableCell {
+    pub offset: usize,
+    pub start_x: i32,
+    pub end_x: i32,
+    // The page index and the vertical position of the top of the cell.
+    pub top: (usize, i32),
+    pub border: Border,
+}
+
+#[derive(Debug, Clone)]
+pub struct RowSpan {
+    pub cell: TableCell,
+    pub column: usize,
+    pub colspan: usize,
+    // The number of rows, after the current one, covered by the cell.
+    pub rows: usize,
+    pub bottom: (usize, i32),
+}
+
+// The colors are given in the same order as the edges.
+#[derive(Debug, Copy, Clone)]
+pub struct Border {
+    pub width: Edge,
+    pub color: [u8; 4],
+}
+
+impl Default for Border {
+    fn default() -> Self {
+        Border {
+            width: Edge::default(),
+            color: [BLACK; 4],
         }
     }
 }
@@ -58,17 +120,28 @@ pub struct StyleData {
     pub text_align: TextAlign,
     pub text_indent: i32,
     pub line_height: i32,
+    pub direction: Direction,
     pub language: Option<String>,
     pub font_kind: FontKind,
     pub font_style: FontStyle,
     pub font_weight: FontWeight,
     pub font_size: f32,
+    // The scale applied to the text of the tables that are too wide.
+    pub font_scale: f32,
     pub font_features: Option<Vec<String>>,
     pub color: u8,
     pub letter_spacing: i32,
     pub vertical_align: i32,
     pub list_style_type: Option<ListStyleType>,
     pub uri: Option<String>,
+    pub widows: usize,
+    pub orphans: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Ltr,
+    Rtl,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -104,6 +177,15 @@ pub enum ListStyleType {
 pub struct ChildArtifact {
     pub sibling_style: SiblingStyle,
     pub rects: Vec<Option<Rectangle>>,
+    pub keep_with_next: Option<KeepState>,
+}
+
+// The state before a block that might have to be moved to the next page.
+#[derive(Debug, Clone)]
+pub struct KeepState {
+    pub draw_state: DrawState,
+    pub pages_count: usize,
+    pub commands_count: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -144,18 +226,33 @@ impl Default for SiblingStyle {
 pub struct LoopContext<'a> {
     pub index: usize,
     pub parent: Option<&'a Node>,
-    pub sibling: Option<&'a Node>,
+    // From the root to the parent.
+    pub ancestors: Vec<&'a Node>,
+    // The element children of the parent.
+    pub siblings: Vec<&'a Node>,
+    pub sibling_index: usize,
     pub sibling_style: SiblingStyle,
     pub is_first: bool,
     pub is_last: bool,
 }
 
+impl<'a> LoopContext<'a> {
+    // The element siblings of the node and its position among them, when it's the current one.
+    pub fn siblings(&self, node: &Node) -> Option<(&[&'a Node], usize)> {
+        self.siblings.get(self.sibling_index)
+            .filter(|s| ptr::eq(**s, node))
+            .map(|_| (self.siblings.as_slice(), self.sibling_index))
+    }
+}
+
 impl<'a> Default for LoopContext<'a> {
     fn default() -> Self {
         LoopContext {
             index: 0,
             parent: None,
-            sibling: None,
+            ancestors: Vec::new(),
+            siblings: Vec::new(),
+            sibling_index: 0,
             sibling_style: SiblingStyle::default(),
             is_first: false,
             is_last: false,
@@ -178,17 +275,21 @@ impl Default for StyleData {
             text_align: TextAlign::Left,
             text_indent: 0,
             line_height: 0,
+            direction: Direction::Ltr,
             language: None,
             font_kind: FontKind::Serif,
             font_style: FontStyle::Normal,
             font_weight: FontWeight::Normal,
             font_size: 0.0,
+            font_scale: 1.0,
             font_features: None,
             color: BLACK,
             letter_spacing: 0,
             vertical_align: 0,
             list_style_type: None,
             uri: None,
+            widows: 1,
+            orphans: 1,
         }
     }
 }
@@ -248,15 +349,16 @@ pub enum FontKind {
     Monospace,
     Cursive,
     Fantasy,
+    Embedded(usize),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FontStyle {
     Normal,
     Italic,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FontWeight {
     Normal,
     Bold,
@@ -268,6 +370,21 @@ pub struct Fonts {
     pub monospace: FontFamily,
     pub cursive: Font,
     pub fantasy: Font,
+    pub embedded: Vec<EmbeddedFamily>,
+}
+
+pub struct EmbeddedFamily {
+    pub name: String,
+    pub faces: Vec<EmbeddedFace>,
+}
+
+pub struct EmbeddedFace {
+    pub path: String,
+    pub font_style: FontStyle,
+    pub font_weight: FontWeight,
+    pub font: Font,
+    // The face reads its data from this buffer: it must be dropped after the font.
+    pub data: Vec<u8>,
 }
 
 impl Fonts {
@@ -299,6 +416,14 @@ impl Fonts {
             },
             FontKind::Cursive => &mut self.cursive,
             FontKind::Fantasy => &mut self.fantasy,
+            FontKind::Embedded(index) => {
+                let faces = &mut self.embedded[index].faces;
+                let face_index = faces.iter().position(|f| f.font_style == font_style && f.font_weight == font_weight)
+                                      .or_else(|| faces.iter().position(|f| f.font_style == font_style))
+                                      .or_else(|| faces.iter().position(|f| f.font_weight == font_weight))
+                                      .unwrap_or(0);
+                &mut faces[face_index].font
+            },
         }
     }
 }
@@ -346,6 +471,7 @@ pub enum DrawCommand {
     Text(TextCommand),
     ExtraText(TextCommand),
     Image(ImageCommand),
+    Rectangle(RectangleCommand),
     Marker(usize),
 }
 
@@ -374,12 +500,20 @@ pub struct ImageCommand {
     pub rect: Rectangle,
 }
 
+#[derive(Debug, Clone)]
+pub struct RectangleCommand {
+    pub offset: usize,
+    pub rect: Rectangle,
+    pub color: u8,
+}
+
 impl DrawCommand {
     pub fn offset(&self) -> usize {
         match *self {
             DrawCommand::Text(TextCommand { offset, .. }) => offset,
             DrawCommand::ExtraText(TextCommand { offset, .. }) => offset,
             DrawCommand::Image(ImageCommand { offset, .. }) => offset,
+            DrawCommand::Rectangle(RectangleCommand { offset, .. }) => offset,
             DrawCommand::Marker(offset) => offset,
         }
     }
@@ -404,10 +538,67 @@ pub fn collapse_margins(a: i32, b: i32) -> i32 {
     }
 }
 
+// Returns the direction of the first strong character.
+pub fn text_direction(text: &str) -> Option<Direction> {
+    text.chars().filter_map(char_direction).next()
+}
+
+pub fn char_direction(c: char) -> Option<Direction> {
+    match u32::from(c) {
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF |
+        0x10800..=0x10FFF | 0x1E800..=0x1EFFF => Some(Direction::Rtl),
+        _ if c.is_alphanumeric() => Some(Direction::Ltr),
+        _ => None,
+    }
+}
+
+// Reverses the runs of items whose embedding level is at least k,
+// for k going from the highest level down to the lowest odd level.
+pub fn visual_order(levels: &[u8]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..levels.len()).collect();
+    let max_level = levels.iter().cloned().max().unwrap_or(0);
+    let min_odd_level = match levels.iter().cloned().filter(|l| l % 2 == 1).min() {
+        Some(level) => level,
+        None => return order,
+    };
+
+    for level in (min_odd_level..=max_level).rev() {
+        let mut i = 0;
+        while i < order.len() {
+            if levels[order[i]] >= level {
+                let start = i;
+                while i < order.len() && levels[order[i]] >= level {
+                    i += 1;
+                }
+                order[start..i].reverse();
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    order
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_visual_order() {
+        assert_eq!(visual_order(&[0, 0, 0]), vec![0, 1, 2]);
+        assert_eq!(visual_order(&[1, 1, 1]), vec![2, 1, 0]);
+        assert_eq!(visual_order(&[0, 1, 1, 0]), vec![0, 2, 1, 3]);
+        assert_eq!(visual_order(&[1, 2, 2, 1, 1]), vec![4, 3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_text_direction() {
+        assert_eq!(text_direction("«שלום»"), Some(Direction::Rtl));
+        assert_eq!(text_direction("(hello)"), Some(Direction::Ltr));
+        assert_eq!(text_direction("..."), None);
+    }
+
     #[test]
     fn test_hyph_lang() {
         assert_eq!(hyph_lang("zh-latn-pinyin"), Some(Language::Chinese));
@@ -0,0 +1,298 @@
+use super::parse::parse_length;
+
+// The media types whose rules apply to the current layout.
+const MEDIA_TYPES: [&str; 3] = ["all", "screen", "amzn-kf8"];
+const OPERATORS: [&str; 5] = ["<=", ">=", "<", ">", "="];
+
+// The features of the output device. The sizes are in pixels, and the font size in points.
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub width: i32,
+    pub height: i32,
+    pub dpi: u16,
+    pub font_size: f32,
+    pub color: u8,
+    pub monochrome: u8,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn from_operator(op: &str) -> Option<Comparison> {
+        match op {
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            "=" => Some(Comparison::Equal),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            ">" => Some(Comparison::Greater),
+            _ => None,
+        }
+    }
+
+    // The comparison obtained by swapping the operands.
+    fn flip(self) -> Comparison {
+        match self {
+            Comparison::Less => Comparison::Greater,
+            Comparison::LessOrEqual => Comparison::GreaterOrEqual,
+            Comparison::Equal => Comparison::Equal,
+            Comparison::GreaterOrEqual => Comparison::LessOrEqual,
+            Comparison::Greater => Comparison::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f32),
+    Ident(String),
+}
+
+// A feature, compared to the given values. When there are no comparisons,
+// the feature is evaluated in a boolean context.
+#[derive(Debug, Clone)]
+struct MediaFeature {
+    name: String,
+    comparisons: Vec<(Comparison, String)>,
+}
+
+#[derive(Debug, Clone)]
+struct MediaQuery {
+    negated: bool,
+    media_type: Option<String>,
+    features: Vec<MediaFeature>,
+}
+
+// The queries are `None` when they can't be parsed, and never match.
+#[derive(Debug, Clone)]
+pub struct MediaQueryList(Vec<Option<MediaQuery>>);
+
+impl MediaQueryList {
+    pub fn parse(text: &str) -> MediaQueryList {
+        let text = text.trim().to_ascii_lowercase();
+        if text.is_empty() {
+            return MediaQueryList(Vec::new());
+        }
+        MediaQueryList(split_top_level(&text, ',').into_iter()
+                                                  .map(parse_media_query)
+                                                  .collect())
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        self.0.is_empty() || self.0.iter().any(|query| {
+            query.as_ref().map_or(false, |query| query.matches(device))
+        })
+    }
+}
+
+impl MediaQuery {
+    fn matches(&self, device: &Device) -> bool {
+        let result = self.media_type.as_ref().map_or(true, |t| MEDIA_TYPES.contains(&t.as_str())) &&
+                     self.features.iter().all(|feature| feature.matches(device));
+        result != self.negated
+    }
+}
+
+impl MediaFeature {
+    fn matches(&self, device: &Device) -> bool {
+        let actual = match device_value(&self.name, device) {
+            Some(value) => value,
+            None => return false,
+        };
+
+        if self.comparisons.is_empty() {
+            return match actual {
+                Value::Number(n) => n != 0.0,
+                Value::Ident(ref s) => s != "none",
+            };
+        }
+
+        self.comparisons.iter().all(|(comparison, text)| {
+            match (&actual, parse_value(text, device)) {
+                (Value::Number(a), Some(Value::Number(b))) => {
+                    match comparison {
+                        Comparison::Less => *a < b,
+                        Comparison::LessOrEqual => *a <= b,
+                        Comparison::Equal => (*a - b).abs() < 1e-3,
+                        Comparison::GreaterOrEqual => *a >= b,
+                        Comparison::Greater => *a > b,
+                    }
+                },
+                (Value::Ident(a), Some(Value::Ident(ref b))) => *comparison == Comparison::Equal && a == b,
+                _ => false,
+            }
+        })
+    }
+}
+
+fn device_value(name: &str, device: &Device) -> Option<Value> {
+    let (width, height) = (device.width as f32, device.height as f32);
+    match name {
+        "width" | "device-width" => Some(Value::Number(width)),
+        "height" | "device-height" => Some(Value::Number(height)),
+        "aspect-ratio" | "device-aspect-ratio" => Some(Value::Number(width / height.max(1.0))),
+        "orientation" => Some(Value::Ident(if height >= width { "portrait" } else { "landscape" }.to_string())),
+        "resolution" => Some(Value::Number(device.dpi as f32)),
+        "color" => Some(Value::Number(device.color as f32)),
+        "monochrome" => Some(Value::Number(device.monochrome as f32)),
+        "color-index" | "grid" => Some(Value::Number(0.0)),
+        "hover" | "any-hover" => Some(Value::Ident("none".to_string())),
+        "pointer" | "any-pointer" => Some(Value::Ident("coarse".to_string())),
+        "update" => Some(Value::Ident("slow".to_string())),
+        "scan" => Some(Value::Ident("progressive".to_string())),
+        "prefers-color-scheme" => Some(Value::Ident("light".to_string())),
+        _ => None,
+    }
+}
+
+// Lengths are converted to pixels, resolutions to dots per inch, and ratios to numbers.
+fn parse_value(text: &str, device: &Device) -> Option<Value> {
+    let text = text.trim();
+    if let Some(index) = text.find('/') {
+        let a: f32 = text[..index].trim().parse().ok()?;
+        let b: f32 = text[index+1..].trim().parse().ok()?;
+        return if b != 0.0 { Some(Value::Number(a / b)) } else { None };
+    }
+    if !text.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '-' || c == '+') {
+        return Some(Value::Ident(text.to_string()));
+    }
+    if let Ok(n) = text.parse::<f32>() {
+        return Some(Value::Number(n));
+    }
+    let index = text.find(|c: char| c.is_ascii_alphabetic())?;
+    let number: f32 = text[..index].parse().ok()?;
+    match &text[index..] {
+        "dpi" => Some(Value::Number(number)),
+        "dpcm" => Some(Value::Number(number * 2.54)),
+        "dppx" | "x" => Some(Value::Number(number * 96.0)),
+        _ => parse_length(text, device.font_size, device.font_size, device.dpi)
+                 .map(|v| Value::Number(v as f32)),
+    }
+}
+
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ if c == separator && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            },
+            _ => (),
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn parse_media_query(text: &str) -> Option<MediaQuery> {
+    let mut query = MediaQuery { negated: false, media_type: None, features: Vec::new() };
+    let mut rest = text.trim();
+    let mut first = true;
+
+    while !rest.is_empty() {
+        if rest.starts_with('(') {
+            let end = rest.find(')')?;
+            query.features.push(parse_media_feature(&rest[1..end])?);
+            rest = rest[end+1..].trim_start();
+        } else {
+            let end = rest.find(|c: char| c.is_whitespace() || c == '(').unwrap_or_else(|| rest.len());
+            let word = &rest[..end];
+            match word {
+                "only" | "not" if first => query.negated = word == "not",
+                "and" if !first => (),
+                _ if query.media_type.is_none() && query.features.is_empty() => {
+                    query.media_type = Some(word.to_string());
+                },
+                // The *or* keyword and the nested conditions aren't supported.
+                _ => return None,
+            }
+            rest = rest[end..].trim_start();
+        }
+        first = false;
+    }
+
+    if query.media_type.is_none() && query.features.is_empty() {
+        return None;
+    }
+
+    Some(query)
+}
+
+fn parse_media_feature(text: &str) -> Option<MediaFeature> {
+    if text.contains('(') {
+        return None;
+    }
+
+    if let Some(index) = text.find(':') {
+        let name = text[..index].trim();
+        let value = text[index+1..].trim().to_string();
+        let (comparison, name) = if name.starts_with("min-") {
+            (Comparison::GreaterOrEqual, &name[4..])
+        } else if name.starts_with("max-") {
+            (Comparison::LessOrEqual, &name[4..])
+        } else {
+            (Comparison::Equal, name)
+        };
+        return Some(MediaFeature { name: name.to_string(), comparisons: vec![(comparison, value)] });
+    }
+
+    // Range syntax: *value op name*, *name op value* or *value op name op value*.
+    let mut parts = Vec::new();
+    let mut operators = Vec::new();
+    let mut rest = text;
+    while let Some((index, op)) = OPERATORS.iter().filter_map(|op| rest.find(op).map(|i| (i, *op)))
+                                                  .min_by_key(|&(i, op)| (i, -(op.len() as i32))) {
+        parts.push(rest[..index].trim());
+        operators.push(Comparison::from_operator(op)?);
+        rest = &rest[index+op.len()..];
+    }
+    parts.push(rest.trim());
+
+    let is_name = |s: &str| s.starts_with(|c: char| c.is_ascii_alphabetic());
+
+    match (parts.len(), operators.len()) {
+        (1, 0) => Some(MediaFeature { name: parts[0].to_string(), comparisons: Vec::new() }),
+        (2, 1) if is_name(parts[0]) => {
+            Some(MediaFeature { name: parts[0].to_string(), comparisons: vec![(operators[0], parts[1].to_string())] })
+        },
+        (2, 1) if is_name(parts[1]) => {
+            Some(MediaFeature { name: parts[1].to_string(), comparisons: vec![(operators[0].flip(), parts[0].to_string())] })
+        },
+        (3, 2) if is_name(parts[1]) => {
+            Some(MediaFeature { name: parts[1].to_string(),
+                                comparisons: vec![(operators[0].flip(), parts[0].to_string()),
+                                                  (operators[1], parts[2].to_string())] })
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_queries() {
+        let device = Device { width: 1072, height: 1448, dpi: 300, font_size: 11.0, color: 0, monochrome: 8 };
+        let queries = [("amzn-kf8", true), ("amzn-mobi", false), ("screen and (orientation: portrait)", true),
+                       ("print, (monochrome)", true), ("not screen", false), ("(color)", false),
+                       ("only screen and (min-width: 300px) and (max-width: 400px)", true),
+                       ("(min-resolution: 2dppx)", true), ("(width >= 400px)", false),
+                       ("(300px < width < 400px)", true), ("(min-aspect-ratio: 16/9)", false),
+                       ("screen and (hover: hover)", false), ("(unknown-feature)", false)];
+        for &(text, result) in &queries {
+            assert_eq!(MediaQueryList::parse(text).matches(&device), result, "{}", text);
+        }
+    }
+}
@@ -1,6 +1,93 @@
+// Implements the methods of `Document` shared by the documents converted to
+// HTML: they're delegated to the `HtmlDocument` held in the `doc` field, and
+// the table of contents is read from the `toc` field.
+macro_rules! delegate_to_html_document {
+    () => {
+        #[inline]
+        fn dims(&self, index: usize) -> Option<(f32, f32)> {
+            self.doc.dims(index)
+        }
+
+        fn pages_count(&self) -> usize {
+            self.doc.pages_count()
+        }
+
+        fn toc(&mut self) -> Option<Vec<$crate::document::TocEntry>> {
+            if self.toc.is_empty() {
+                None
+            } else {
+                Some(self.toc.clone())
+            }
+        }
+
+        fn chapter<'a>(&mut self, offset: usize, toc: &'a [$crate::document::TocEntry]) -> Option<&'a $crate::document::TocEntry> {
+            $crate::document::chapter(offset, toc)
+        }
+
+        fn chapter_relative<'a>(&mut self, offset: usize, dir: $crate::geom::CycleDir, toc: &'a [$crate::document::TocEntry]) -> Option<&'a $crate::document::TocEntry> {
+            $crate::document::chapter_relative(offset, dir, toc)
+        }
+
+        fn resolve_location(&mut self, loc: $crate::document::Location) -> Option<usize> {
+            self.doc.resolve_location(loc)
+        }
+
+        fn words(&mut self, loc: $crate::document::Location) -> Option<(Vec<$crate::document::BoundedText>, usize)> {
+            self.doc.words(loc)
+        }
+
+        fn lines(&mut self, loc: $crate::document::Location) -> Option<(Vec<$crate::document::BoundedText>, usize)> {
+            self.doc.lines(loc)
+        }
+
+        fn links(&mut self, loc: $crate::document::Location) -> Option<(Vec<$crate::document::BoundedText>, usize)> {
+            self.doc.links(loc)
+        }
+
+        fn pixmap(&mut self, loc: $crate::document::Location, scale: f32) -> Option<($crate::framebuffer::Pixmap, usize)> {
+            self.doc.pixmap(loc, scale)
+        }
+
+        fn layout(&mut self, width: u32, height: u32, font_size: f32, dpi: u16) {
+            self.doc.layout(width, height, font_size, dpi);
+        }
+
+        fn set_text_align(&mut self, text_align: $crate::metadata::TextAlign) {
+            self.doc.set_text_align(text_align);
+        }
+
+        fn set_font_family(&mut self, family_name: &str, search_path: &str) {
+            self.doc.set_font_family(family_name, search_path);
+        }
+
+        fn set_margin_width(&mut self, width: i32) {
+            self.doc.set_margin_width(width);
+        }
+
+        fn set_line_height(&mut self, line_height: f32) {
+            self.doc.set_line_height(line_height);
+        }
+
+        fn set_embedded_fonts(&mut self, enable: bool) {
+            self.doc.set_embedded_fonts(enable);
+        }
+
+        fn is_reflowable(&self) -> bool {
+            true
+        }
+
+        fn has_synthetic_page_numbers(&self) -> bool {
+            true
+        }
+    };
+}
+
 pub mod dom;
 pub mod xml;
+pub mod html5;
 pub mod css;
+pub mod media;
+pub mod svg;
 pub mod parse;
 pub mod style;
 pub mod layout;
@@ -18,10 +105,11 @@ use crate::unit::pt_to_px;
 use crate::geom::{Rectangle, Edge, CycleDir};
 use self::dom::Node;
 use self::layout::{RootData, StyleData, DrawState, LoopContext};
-use self::layout::{DrawCommand, TextCommand, ImageCommand, TextAlign};
+use self::layout::{DrawCommand, TextCommand, ImageCommand, TextAlign, Direction};
+use self::parse::parse_direction;
 use self::engine::{Page, Engine, ResourceFetcher};
 use self::css::{CssParser, RuleKind};
-use self::xml::XmlParser;
+use self::html5::parse_markup;
 
 const VIEWER_STYLESHEET: &str = "css/html.css";
 const USER_STYLESHEET: &str = "css/html-user.css";
@@ -32,7 +120,7 @@ pub struct HtmlDocument {
     content: Node,
     engine: Engine,
     pages: Vec<Page>,
-    parent: PathBuf,
+    fetcher: Box<dyn ResourceFetcher>,
     size: usize,
     viewer_stylesheet: PathBuf,
     user_stylesheet: PathBuf,
@@ -57,7 +145,10 @@ impl HtmlDocument {
         let size = file.metadata()?.len() as usize;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
-        let mut content = XmlParser::new(&content).parse();
+        let html = path.as_ref().extension()
+                       .and_then(|e| e.to_str())
+                       .map_or(false, |e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+        let mut content = parse_markup(&content, html);
         content.wrap_lost_inlines();
         let parent = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
 
@@ -65,7 +156,7 @@ impl HtmlDocument {
             content,
             engine: Engine::new(),
             pages: Vec::new(),
-            parent: parent.to_path_buf(),
+            fetcher: Box::new(parent.to_path_buf()),
             size,
             viewer_stylesheet: PathBuf::from(VIEWER_STYLESHEET),
             user_stylesheet: PathBuf::from(USER_STYLESHEET),
@@ -75,14 +166,14 @@ impl HtmlDocument {
 
     pub fn new_from_memory(content: &str) -> HtmlDocument {
         let size = content.len();
-        let mut content = XmlParser::new(content).parse();
+        let mut content = parse_markup(content, false);
         content.wrap_lost_inlines();
 
         HtmlDocument {
             content,
             engine: Engine::new(),
             pages: Vec::new(),
-            parent: PathBuf::from(""),
+            fetcher: Box::new(PathBuf::from("")),
             size,
             viewer_stylesheet: PathBuf::from(VIEWER_STYLESHEET),
             user_stylesheet: PathBuf::from(USER_STYLESHEET),
@@ -92,7 +183,7 @@ impl HtmlDocument {
 
     pub fn update(&mut self, content: &str) {
         self.size = content.len();
-        self.content = XmlParser::new(content).parse();
+        self.content = parse_markup(content, false);
         self.content.wrap_lost_inlines();
         self.pages.clear();
     }
@@ -107,6 +198,16 @@ impl HtmlDocument {
         self.pages.clear();
     }
 
+    pub fn set_parent<P: AsRef<Path>>(&mut self, path: P) {
+        self.fetcher = Box::new(path.as_ref().to_path_buf());
+        self.pages.clear();
+    }
+
+    pub fn set_fetcher<F: ResourceFetcher + 'static>(&mut self, fetcher: F) {
+        self.fetcher = Box::new(fetcher);
+        self.pages.clear();
+    }
+
     pub fn set_viewer_stylesheet<P: AsRef<Path>>(&mut self, path: P) {
         self.viewer_stylesheet = path.as_ref().to_path_buf();
         self.pages.clear();
@@ -189,18 +290,22 @@ impl HtmlDocument {
                         if child.tag_name() == Some("link") && child.attr("rel") == Some("stylesheet") {
                             if let Some(href) = child.attr("href") {
                                 if let Some(name) = spine_dir.join(href).normalize().to_str() {
-                                    if let Ok(buf) = self.parent.fetch(name) {
+                                    if let Ok(buf) = self.fetcher.fetch(name) {
                                         if let Ok(text) = String::from_utf8(buf) {
-                                            let (mut css, _) = CssParser::new(&text).parse(RuleKind::Document);
+                                            let (mut css, at_rules) = CssParser::new(&text).parse(RuleKind::Document);
                                             stylesheet.append(&mut css);
+                                            let css_dir = Path::new(name).parent()
+                                                              .unwrap_or_else(|| Path::new(""));
+                                            self.engine.load_font_faces(&at_rules, css_dir, self.fetcher.as_mut());
                                         }
                                     }
                                 }
                             }
                         } else if child.tag_name() == Some("style") && child.attr("type") == Some("text/css") {
                             if let Some(text) = child.text() {
-                                let (mut css, _) = CssParser::new(text).parse(RuleKind::Document);
+                                let (mut css, at_rules) = CssParser::new(text).parse(RuleKind::Document);
                                 stylesheet.append(&mut css);
+                                self.engine.load_font_faces(&at_rules, &spine_dir, self.fetcher.as_mut());
                             }
                         }
                     }
@@ -208,6 +313,9 @@ impl HtmlDocument {
             }
         }
 
+        let device = self.engine.device();
+        stylesheet.retain(|rule| rule.matches_media(&device));
+
         let mut pages = Vec::new();
 
         let mut rect = self.engine.rect();
@@ -217,11 +325,23 @@ impl HtmlDocument {
                            .and_then(|html| html.attr("xml:lang"))
                            .map(String::from);
 
+        let direction = self.content.find("html")
+                            .and_then(|html| html.attr("dir"))
+                            .and_then(parse_direction)
+                            .unwrap_or(Direction::Ltr);
+
+        let text_align = if direction == Direction::Rtl {
+            self.engine.text_align.mirror()
+        } else {
+            self.engine.text_align
+        };
+
         let style = StyleData {
             language,
+            direction,
             font_size: self.engine.font_size,
             line_height: pt_to_px(self.engine.line_height * self.engine.font_size, self.engine.dpi).round() as i32,
-            text_align: self.engine.text_align,
+            text_align,
             start_x: rect.min.x,
             end_x: rect.max.x,
             width: rect.max.x - rect.min.x,
@@ -242,7 +362,7 @@ impl HtmlDocument {
 
         pages.push(Vec::new());
 
-        self.engine.build_display_list(&self.content, &style, &loop_context, &stylesheet, &root_data, &mut self.parent, &mut draw_state, &mut pages);
+        self.engine.build_display_list(&self.content, &style, &loop_context, &stylesheet, &root_data, self.fetcher.as_mut(), &mut draw_state, &mut pages);
 
         pages.retain(|page| !page.is_empty());
 
@@ -371,7 +491,7 @@ impl Document for HtmlDocument {
         let offset = self.resolve_location(loc)?;
         let page_index = self.page_index(offset)?;
         let page = self.pages[page_index].clone();
-        let pixmap = self.engine.render_page(&page, &mut self.parent);
+        let pixmap = self.engine.render_page(&page, self.fetcher.as_mut());
 
         Some((pixmap, offset))
     }
@@ -401,6 +521,11 @@ impl Document for HtmlDocument {
         self.pages.clear();
     }
 
+    fn set_embedded_fonts(&mut self, enable: bool) {
+        self.engine.set_embedded_fonts(enable);
+        self.pages.clear();
+    }
+
     fn title(&self) -> Option<String> {
         self.content.find("head")
             .and_then(Node::children)
@@ -428,3 +553,76 @@ impl Document for HtmlDocument {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::HtmlDocument;
+    use super::layout::DrawCommand;
+    use crate::document::Document;
+    use crate::geom::{Edge, Rectangle};
+
+    // Lays out the given content on pages of the given height, with lines
+    // that are twelve pixels high, and returns the words of each page.
+    fn page_words(content: &str, height: u32) -> Vec<Vec<String>> {
+        page_texts(content, height).into_iter().map(|page| {
+            page.into_iter().map(|(text, _)| text).collect()
+        }).collect()
+    }
+
+    fn page_texts(content: &str, height: u32) -> Vec<Vec<(String, Rectangle)>> {
+        let mut doc = HtmlDocument::new_from_memory(content);
+        doc.set_viewer_stylesheet("");
+        doc.set_user_stylesheet("");
+        doc.set_margin(&Edge::uniform(0));
+        doc.layout(300, height, 10.0, 72);
+        doc.engine.load_fonts();
+        doc.build_pages().iter().map(|page| {
+            page.iter().filter_map(|dc| {
+                match dc {
+                    DrawCommand::Text(tc) | DrawCommand::ExtraText(tc) => Some((tc.text.clone(), tc.rect)),
+                    _ => None,
+                }
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_page_breaks() {
+        // Five lines fit on a page.
+        let height = 60;
+        let words = page_words("<p>1<br/>2<br/>3<br/>4</p><p>5<br/>6<br/>7</p>", height);
+        assert_eq!(words, vec![vec!["1", "2", "3", "4", "5"], vec!["6", "7"]]);
+        let words = page_words("<p>1<br/>2<br/>3<br/>4</p><p style='orphans: 2'>5<br/>6<br/>7</p>", height);
+        assert_eq!(words, vec![vec!["1", "2", "3", "4"], vec!["5", "6", "7"]]);
+        let words = page_words("<p style='widows: 2'>1<br/>2<br/>3<br/>4<br/>5<br/>6</p>", height);
+        assert_eq!(words, vec![vec!["1", "2", "3", "4"], vec!["5", "6"]]);
+        let words = page_words("<p>1<br/>2<br/>3<br/>4</p><h1 style='break-after: avoid'>T</h1><p>5<br/>6</p>", height);
+        assert_eq!(words, vec![vec!["1", "2", "3", "4"], vec!["T", "5", "6"]]);
+    }
+
+    #[test]
+    fn test_table_grid() {
+        let table = "<table><tr><td rowspan='2'>A</td><td colspan='2'>B</td></tr>\
+                     <tr><td>C</td><td>D</td></tr>\
+                     <tr><td colspan='2'>E</td><td>F</td></tr></table>";
+        let texts = page_texts(table, 600);
+        let rect = |text: &str| texts[0].iter().find(|(t, _)| t == text).unwrap().1;
+        let (a, b, c, d, e, f) = (rect("A"), rect("B"), rect("C"), rect("D"), rect("E"), rect("F"));
+        assert_eq!(a.min.y, b.min.y);
+        assert_eq!(c.min.y, d.min.y);
+        assert_eq!(e.min.y, f.min.y);
+        assert!(c.min.y > a.min.y && e.min.y > c.min.y);
+        assert_eq!(a.min.x, e.min.x);
+        assert_eq!(b.min.x, c.min.x);
+        assert_eq!(d.min.x, f.min.x);
+        assert!(c.min.x > a.min.x && d.min.x > c.min.x);
+    }
+
+    #[test]
+    fn test_table_header() {
+        let rows: String = (1..=6).map(|i| format!("<tr><td>{}</td></tr>", i)).collect();
+        let table = format!("<table><thead><tr><th>H</th></tr></thead><tbody>{}</tbody></table>", rows);
+        let words = page_words(&table, 60);
+        assert_eq!(words, vec![vec!["H", "1", "2", "3", "4"], vec!["H", "5", "6"]]);
+    }
+}
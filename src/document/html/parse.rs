@@ -1,6 +1,6 @@
 use fnv::FnvHashSet;
 use regex::Regex;
-use super::layout::{FontKind, FontStyle, FontWeight, TextAlign, Display, Float, ListStyleType};
+use super::layout::{FontKind, FontStyle, FontWeight, TextAlign, Display, Float, ListStyleType, Direction};
 use super::layout::{InlineMaterial, GlueMaterial, PenaltyMaterial};
 use crate::geom::Edge;
 use crate::unit::{pt_to_px, pc_to_px, mm_to_px, in_to_px};
@@ -10,8 +10,8 @@ const SIZE_FACTOR: f32 = 1.26;
 const ABSOLUTE_SIZE_KEYWORDS: [&str; 7] = ["xx-small", "x-small", "small", "medium", "large", "x-large", "xx-large"];
 const RELATIVE_SIZE_KEYWORDS: [&str; 2] = ["smaller", "larger"];
 // Map to: 0.25, 0.5 and 1.0 pt.
-const BORDER_WIDTH_KEYWORDS: [&str; 3] = ["thin", "medium", "thick"];
-const BORDER_STYLE_KEYWORDS: [&str; 10] = ["none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset"];
+pub const BORDER_WIDTH_KEYWORDS: [&str; 3] = ["thin", "medium", "thick"];
+pub const BORDER_STYLE_KEYWORDS: [&str; 10] = ["none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset"];
 
 // TODO: vh, vw, vmin, vmax?
 pub fn parse_length(value: &str, em: f32, rem: f32, dpi: u16) -> Option<i32> {
@@ -141,8 +141,14 @@ pub fn parse_vertical_align(value: &str, em: f32, rem: f32, line_height: i32, dp
 pub fn parse_font_weight(value: &str) -> Option<FontWeight> {
     if value == "normal" {
         Some(FontWeight::Normal)
-    } else if value == "bold" {
+    } else if value == "bold" || value == "bolder" {
         Some(FontWeight::Bold)
+    } else if let Ok(weight) = value.parse::<u16>() {
+        if weight >= 600 {
+            Some(FontWeight::Bold)
+        } else {
+            Some(FontWeight::Normal)
+        }
     } else {
         None
     }
@@ -151,7 +157,7 @@ pub fn parse_font_weight(value: &str) -> Option<FontWeight> {
 pub fn parse_font_style(value: &str) -> Option<FontStyle> {
     if value == "normal" {
         Some(FontStyle::Normal)
-    } else if value == "italic" {
+    } else if value == "italic" || value == "oblique" {
         Some(FontStyle::Italic)
     } else {
         None
@@ -253,6 +259,15 @@ pub fn parse_edge(top_edge: Option<&str>, right_edge: Option<&str>, bottom_edge:
     e
 }
 
+pub fn parse_border_width(value: &str, em: f32, rem: f32, dpi: u16) -> Option<i32> {
+    if let Some(index) = BORDER_WIDTH_KEYWORDS.iter().position(|&k| k == value) {
+        let size = 0.25 * 2.0f32.powi(index as i32);
+        Some(pt_to_px(size, dpi).round().max(1.0) as i32)
+    } else {
+        parse_length(value, em, rem, dpi).map(|w| w.max(0))
+    }
+}
+
 pub fn parse_text_align(value: &str) -> Option<TextAlign> {
     match value {
         "justify" => Some(TextAlign::Justify),
@@ -263,6 +278,14 @@ pub fn parse_text_align(value: &str) -> Option<TextAlign> {
     }
 }
 
+pub fn parse_direction(value: &str) -> Option<Direction> {
+    match value {
+        "ltr" => Some(Direction::Ltr),
+        "rtl" => Some(Direction::Rtl),
+        _ => None,
+    }
+}
+
 pub fn parse_line_height(value: &str, em: f32, rem: f32, dpi: u16) -> Option<i32> {
     if value == "normal" {
         Some(pt_to_px(1.2 * em, dpi).round() as i32)
@@ -374,4 +397,12 @@ mod tests {
         assert_eq!(d, Some(18));
         assert_eq!(e, Some(255));
     }
+
+    #[test]
+    fn test_parse_font_weight() {
+        assert_eq!(parse_font_weight("bold"), Some(FontWeight::Bold));
+        assert_eq!(parse_font_weight("700"), Some(FontWeight::Bold));
+        assert_eq!(parse_font_weight("400"), Some(FontWeight::Normal));
+        assert_eq!(parse_font_weight("lighter"), None);
+    }
 }
@@ -1,6 +1,11 @@
+use std::ptr;
 use fnv::FnvHashMap;
 use super::dom::Node;
 use super::css::{CssParser, Rule, Selector, SimpleSelector, Specificity};
+use super::css::{Combinator, PseudoClass, PseudoElement, AttributeSelector, AttributeOperator};
+use super::parse::{BORDER_WIDTH_KEYWORDS, BORDER_STYLE_KEYWORDS};
+
+const SIDES: [&str; 4] = ["top", "right", "bottom", "left"];
 
 pub type PropertyMap = FnvHashMap<String, String>;
 pub type Stylesheet = [Rule];
@@ -8,8 +13,8 @@ type MatchedRule<'a> = (Specificity, &'a Rule);
 
 #[cfg(test)]
 mod tests {
-    use super::specified_values;
-    use super::super::css::{CssParser, RuleKind};
+    use super::{specified_values, pseudo_element_values, element_children};
+    use super::super::css::{CssParser, RuleKind, PseudoElement};
     use super::super::xml::XmlParser;
 
     #[test]
@@ -21,15 +26,76 @@ mod tests {
         let (mut css3, _) = CssParser::new(".y { b: 2 }").parse(RuleKind::Document);
         css1.append(&mut css3);
         css1.append(&mut css2);
-        assert_eq!(specified_values(&xml1, None, None, &css1), [("b".to_string(), "6".to_string()),
+        assert_eq!(specified_values(&xml1, &[], None, &css1), [("b".to_string(), "6".to_string()),
                                                     ("c".to_string(), "7".to_string())].iter().cloned().collect());
-        assert_eq!(specified_values(&xml2, None, None, &css1), [("b".to_string(), "2".to_string())].iter().cloned().collect());
+        assert_eq!(specified_values(&xml2, &[], None, &css1), [("b".to_string(), "2".to_string())].iter().cloned().collect());
+    }
+
+    #[test]
+    fn structural_style() {
+        let root = XmlParser::new("<div><h1/><p class='x'/><p/><ul><li/><li><a href='#fn1'/></li></ul></div>").parse();
+        let children = root.children().unwrap();
+        let (css, _) = CssParser::new("p:first-of-type { a: 1 } h1 ~ p:not(.x) { b: 2 } \
+                                       li:nth-child(even) a[href^='#fn'] { c: 3 } div > :last-child { d: 4 } \
+                                       p::first-letter { e: 5 }").parse(RuleKind::Document);
+        let ancestors = [&root];
+        let props = specified_values(&children[1], &ancestors, None, &css);
+        assert_eq!(props.get("a").map(String::as_str), Some("1"));
+        assert!(props.get("b").is_none());
+        assert!(props.get("e").is_none());
+        let props = specified_values(&children[2], &ancestors, None, &css);
+        assert_eq!(props.get("b").map(String::as_str), Some("2"));
+        assert!(props.get("a").is_none());
+        let props = specified_values(&children[3], &ancestors, None, &css);
+        assert_eq!(props.get("d").map(String::as_str), Some("4"));
+        let siblings = element_children(&root);
+        let props = specified_values(&children[2], &ancestors, Some((&siblings, 2)), &css);
+        assert_eq!(props.get("b").map(String::as_str), Some("2"));
+        let li = children[3].child(1).unwrap();
+        let props = specified_values(li.child(0).unwrap(), &[&root, &children[3], li], None, &css);
+        assert_eq!(props.get("c").map(String::as_str), Some("3"));
+        let props = pseudo_element_values(&children[2], &ancestors, None, &css, PseudoElement::FirstLetter);
+        assert_eq!(props.get("e").map(String::as_str), Some("5"));
+    }
+
+    #[test]
+    fn border_style() {
+        let xml = XmlParser::new("<td style='border-left: thick rgb(0, 0, 0)'/>").parse();
+        let (css, _) = CssParser::new("td { border: 1px solid; border-color: red blue }").parse(RuleKind::Document);
+        let props = specified_values(&xml, &[], None, &css);
+        assert_eq!(props.get("border-top-width").map(String::as_str), Some("1px"));
+        assert_eq!(props.get("border-bottom-style").map(String::as_str), Some("solid"));
+        assert_eq!(props.get("border-right-color").map(String::as_str), Some("blue"));
+        assert_eq!(props.get("border-bottom-color").map(String::as_str), Some("red"));
+        assert_eq!(props.get("border-left-width").map(String::as_str), Some("thick"));
+        assert_eq!(props.get("border-left-style").map(String::as_str), Some("none"));
+        assert_eq!(props.get("border-left-color").map(String::as_str), Some("rgb(0, 0, 0)"));
+    }
+}
+
+// The *ancestors* of a node are listed from the root to its parent.
+// The *siblings* of a node, if known, are its element siblings and its position among them.
+pub fn specified_values(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, stylesheet: &Stylesheet) -> PropertyMap {
+    let mut props = cascade(node, ancestors, siblings, stylesheet, None);
+
+    let local_declarations = node.attr("style").map(|text| {
+        CssParser::new(text).parse_declarations()
+    }).unwrap_or_default();
+
+    for declaration in &local_declarations {
+        expand_and_insert(&declaration.name, &declaration.value, &mut props);
     }
+
+    props
 }
 
-pub fn specified_values(node: &Node, parent: Option<&Node>, sibling: Option<&Node>, stylesheet: &Stylesheet) -> PropertyMap {
+pub fn pseudo_element_values(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, stylesheet: &Stylesheet, pseudo_element: PseudoElement) -> PropertyMap {
+    cascade(node, ancestors, siblings, stylesheet, Some(pseudo_element))
+}
+
+fn cascade(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, stylesheet: &Stylesheet, pseudo_element: Option<PseudoElement>) -> PropertyMap {
     let mut props = FnvHashMap::default();
-    let mut rules = matching_rules(node, parent, sibling, stylesheet);
+    let mut rules = matching_rules(node, ancestors, siblings, stylesheet, pseudo_element);
 
     rules.sort_by(|&(sa, ra), &(sb, rb)| {
         if ra.kind == rb.kind {
@@ -45,48 +111,71 @@ pub fn specified_values(node: &Node, parent: Option<&Node>, sibling: Option<&Nod
         }
     }
 
-    let local_declarations = node.attr("style").map(|text| {
-        CssParser::new(text).parse_declarations()
-    }).unwrap_or_default();
-
-    for declaration in &local_declarations {
-        expand_and_insert(&declaration.name, &declaration.value, &mut props);
-    }
-
     props
 }
 
-fn matching_rules<'a>(node: &Node, parent: Option<&Node>, sibling: Option<&Node>, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-    stylesheet.iter().filter_map(|rule| match_rule(node, parent, sibling, rule)).collect()
+fn matching_rules<'a>(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, stylesheet: &'a Stylesheet, pseudo_element: Option<PseudoElement>) -> Vec<MatchedRule<'a>> {
+    stylesheet.iter().filter_map(|rule| match_rule(node, ancestors, siblings, rule, pseudo_element)).collect()
 }
 
-fn match_rule<'a>(node: &Node, parent: Option<&Node>, sibling: Option<&Node>, rule: &'a Rule) -> Option<MatchedRule<'a>> {
-    rule.selectors.iter().find(|selector| matches(node, parent, sibling, *selector))
+fn match_rule<'a>(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, rule: &'a Rule, pseudo_element: Option<PseudoElement>) -> Option<MatchedRule<'a>> {
+    rule.selectors.iter().find(|selector| selector.pseudo_element == pseudo_element &&
+                                          matches(node, ancestors, siblings, *selector))
         .map(|selector| (selector.specificity(), rule))
 }
 
-fn matches(node: &Node, parent: Option<&Node>, sibling: Option<&Node>, selector: &Selector) -> bool {
-    match selector {
-        Selector::Simple(sel) => matches_simple_selector(node, sel),
-        Selector::ParentChild(sel1, sel2) => {
-            if let Some(p) = parent {
-                matches_simple_selector(p, sel1) && matches_simple_selector(node, sel2)
-            } else {
-                false
-            }
+fn matches(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, selector: &Selector) -> bool {
+    matches_simple_selector(node, ancestors, siblings, &selector.subject) &&
+    matches_combinators(node, ancestors, siblings, &selector.combinators)
+}
+
+fn matches_combinators(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, combinators: &[(Combinator, SimpleSelector)]) -> bool {
+    let (combinator, sel) = match combinators.first() {
+        Some(&(combinator, ref sel)) => (combinator, sel),
+        None => return true,
+    };
+    let rest = &combinators[1..];
+
+    match combinator {
+        Combinator::Child => {
+            parent(ancestors).map_or(false, |(p, ancestors)| {
+                matches_simple_selector(p, ancestors, None, sel) &&
+                matches_combinators(p, ancestors, None, rest)
+            })
         },
-        Selector::Siblings(sel1, sel2) => {
-            if let Some(s) = sibling {
-                matches_simple_selector(s, sel1) && matches_simple_selector(node, sel2)
-            } else {
-                false
+        Combinator::Descendant => {
+            let mut ancestors = ancestors;
+            while let Some((p, upper_ancestors)) = parent(ancestors) {
+                if matches_simple_selector(p, upper_ancestors, None, sel) &&
+                   matches_combinators(p, upper_ancestors, None, rest) {
+                    return true;
+                }
+                ancestors = upper_ancestors;
             }
+            false
+        },
+        Combinator::NextSibling => {
+            with_siblings(node, ancestors, siblings, |siblings, index| {
+                index.checked_sub(1).map_or(false, |index| {
+                    let sibling = siblings[index];
+                    matches_simple_selector(sibling, ancestors, Some((siblings, index)), sel) &&
+                    matches_combinators(sibling, ancestors, Some((siblings, index)), rest)
+                })
+            })
+        },
+        Combinator::SubsequentSibling => {
+            with_siblings(node, ancestors, siblings, |siblings, index| {
+                siblings[..index].iter().enumerate().any(|(i, s)| {
+                    matches_simple_selector(s, ancestors, Some((siblings, i)), sel) &&
+                    matches_combinators(s, ancestors, Some((siblings, i)), rest)
+                })
+            })
         },
     }
 }
 
-fn matches_simple_selector(node: &Node, selector: &SimpleSelector) -> bool {
-    if selector.tag_name.iter().any(|name| node.tag_name() != Some(name)) {
+fn matches_simple_selector(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, selector: &SimpleSelector) -> bool {
+    if selector.tag_name.iter().any(|name| node.tag_name().map_or(true, |tag_name| !tag_name.eq_ignore_ascii_case(name))) {
         return false;
     }
 
@@ -99,7 +188,146 @@ fn matches_simple_selector(node: &Node, selector: &SimpleSelector) -> bool {
         return false;
     }
 
-    true
+    if !selector.attributes.iter().all(|attr| matches_attribute(node, attr)) {
+        return false;
+    }
+
+    selector.pseudo_classes.iter().all(|pc| matches_pseudo_class(node, ancestors, siblings, pc))
+}
+
+fn matches_attribute(node: &Node, selector: &AttributeSelector) -> bool {
+    let value = match node.attr(&selector.name) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    let (value, expected) = if selector.case_insensitive {
+        (value.to_lowercase(), selector.value.to_lowercase())
+    } else {
+        (value.to_string(), selector.value.clone())
+    };
+
+    match selector.operator {
+        AttributeOperator::Exists => true,
+        AttributeOperator::Equals => value == expected,
+        AttributeOperator::Includes => value.split_whitespace().any(|v| v == expected),
+        AttributeOperator::DashMatch => value == expected || value.starts_with(&format!("{}-", expected)),
+        AttributeOperator::Prefix => !expected.is_empty() && value.starts_with(&expected),
+        AttributeOperator::Suffix => !expected.is_empty() && value.ends_with(&expected),
+        AttributeOperator::Substring => !expected.is_empty() && value.contains(&expected),
+    }
+}
+
+fn matches_pseudo_class(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, pseudo_class: &PseudoClass) -> bool {
+    match *pseudo_class {
+        PseudoClass::Root => parent(ancestors).is_none(),
+        PseudoClass::Empty => node.children().map_or(true, |children| children.iter().all(Node::is_whitespace)),
+        PseudoClass::Link => node.tag_name() == Some("a") && node.attr("href").is_some(),
+        PseudoClass::Not(ref selectors) => !selectors.iter().any(|sel| matches_simple_selector(node, ancestors, siblings, sel)),
+        _ => with_siblings(node, ancestors, siblings, |siblings, index| {
+            let same_type = |s: &&Node| s.tag_name() == node.tag_name();
+            let (before, after) = (&siblings[..index], &siblings[index+1..]);
+            match *pseudo_class {
+                PseudoClass::FirstChild => before.is_empty(),
+                PseudoClass::LastChild => after.is_empty(),
+                PseudoClass::OnlyChild => before.is_empty() && after.is_empty(),
+                PseudoClass::FirstOfType => !before.iter().any(same_type),
+                PseudoClass::LastOfType => !after.iter().any(same_type),
+                PseudoClass::OnlyOfType => !before.iter().chain(after.iter()).any(same_type),
+                PseudoClass::NthChild(a, b) => matches_nth(a, b, before.len() + 1),
+                PseudoClass::NthLastChild(a, b) => matches_nth(a, b, after.len() + 1),
+                PseudoClass::NthOfType(a, b) => matches_nth(a, b, before.iter().filter(|s| same_type(*s)).count() + 1),
+                PseudoClass::NthLastOfType(a, b) => matches_nth(a, b, after.iter().filter(|s| same_type(*s)).count() + 1),
+                _ => false,
+            }
+        }),
+    }
+}
+
+// Whether *index* is of the form *an+b*, for some non-negative integer *n*.
+fn matches_nth(a: i32, b: i32, index: usize) -> bool {
+    let delta = index as i32 - b;
+    if a == 0 {
+        delta == 0
+    } else {
+        delta % a == 0 && delta / a >= 0
+    }
+}
+
+// The parent of a node, and the ancestors of its parent.
+// The anonymous boxes, added around the lost inlines, aren't part of the document tree.
+fn parent<'a, 'b>(ancestors: &'b [&'a Node]) -> Option<(&'a Node, &'b [&'a Node])> {
+    ancestors.iter().rposition(|a| a.tag_name() != Some("anonymous"))
+             .map(|index| (ancestors[index], &ancestors[..index]))
+}
+
+// The element children of a node, the ones of its anonymous children included.
+pub fn element_children(node: &Node) -> Vec<&Node> {
+    fn push_elements<'a>(node: &'a Node, children: &mut Vec<&'a Node>) {
+        if let Some(nodes) = node.children() {
+            for child in nodes.iter().filter(|c| c.is_element()) {
+                if child.tag_name() == Some("anonymous") {
+                    push_elements(child, children);
+                } else {
+                    children.push(child);
+                }
+            }
+        }
+    }
+
+    let mut children = Vec::new();
+    push_elements(node, &mut children);
+    children
+}
+
+// Calls *f* with the element siblings of a node and its position among them.
+// They're only gathered when they weren't given.
+fn with_siblings<F>(node: &Node, ancestors: &[&Node], siblings: Option<(&[&Node], usize)>, f: F) -> bool
+                   where F: FnOnce(&[&Node], usize) -> bool {
+    if let Some((siblings, index)) = siblings {
+        return f(siblings, index);
+    }
+
+    if node.tag_name() == Some("anonymous") {
+        return false;
+    }
+
+    let siblings = match parent(ancestors) {
+        Some((p, _)) => element_children(p),
+        None => vec![node],
+    };
+
+    match siblings.iter().position(|s| ptr::eq(*s, node)) {
+        Some(index) => f(&siblings, index),
+        None => false,
+    }
+}
+
+// Splits the value on whitespace, except within parentheses.
+fn split_components(value: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    let mut depth = 0;
+    let mut start = None;
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ if c.is_whitespace() && depth == 0 => {
+                if let Some(s) = start.take() {
+                    components.push(&value[s..i]);
+                }
+                continue;
+            },
+            _ => (),
+        }
+        if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        components.push(&value[s..]);
+    }
+    components
 }
 
 fn expand_and_insert(name: &str, value: &str, props: &mut PropertyMap) {
@@ -143,9 +371,40 @@ fn expand_and_insert(name: &str, value: &str, props: &mut PropertyMap) {
                 _ => (),
             }
         },
-        // TODO: border -> border-{top,right,bottom,left}-{width,style,color}
-        // border-left -> border-left-{width,style,color}
-        // border-style -> border-{top,right,bottom,left}-style
+        "border" | "border-top" | "border-right" | "border-bottom" | "border-left" => {
+            let mut width = "medium";
+            let mut style = "none";
+            let mut color = "currentcolor";
+            for component in split_components(value) {
+                if BORDER_STYLE_KEYWORDS.contains(&component) {
+                    style = component;
+                } else if BORDER_WIDTH_KEYWORDS.contains(&component) ||
+                          component.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+                    width = component;
+                } else {
+                    color = component;
+                }
+            }
+            let side = if name == "border" { None } else { Some(&name[7..]) };
+            for side in SIDES.iter().filter(|s| side.map_or(true, |n| n == **s)) {
+                props.insert(format!("border-{}-width", side), width.to_string());
+                props.insert(format!("border-{}-style", side), style.to_string());
+                props.insert(format!("border-{}-color", side), color.to_string());
+            }
+        },
+        "border-width" | "border-style" | "border-color" => {
+            let values = split_components(value);
+            let indices = match values.len() {
+                1 => [0, 0, 0, 0],
+                2 => [0, 1, 0, 1],
+                3 => [0, 1, 2, 1],
+                4 => [0, 1, 2, 3],
+                _ => return,
+            };
+            for (side, index) in SIDES.iter().zip(indices.iter()) {
+                props.insert(format!("border-{}-{}", side, &name[7..]), values[*index].to_string());
+            }
+        },
         _ => {
             props.insert(name.to_string(), value.to_string());
         }
@@ -0,0 +1,188 @@
+use std::path::Path;
+use super::dom::{Node, ElementData, TextData};
+use super::engine::ResourceFetcher;
+use super::parse::parse_length;
+use crate::helpers::Normalize;
+use crate::unit::pt_to_px;
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+// The default size of the replaced elements, in CSS pixels.
+const DEFAULT_WIDTH: f32 = 300.0;
+const DEFAULT_HEIGHT: f32 = 150.0;
+// The user units are CSS pixels: 96 of them per inch.
+const USER_UNIT_DPI: u16 = 96;
+
+pub fn is_svg(node: &Node) -> bool {
+    match node.tag_name() {
+        Some("svg") | Some("svg:svg") => true,
+        _ => false,
+    }
+}
+
+// The size of an SVG element, in pixels, given by its width, height and viewBox
+// attributes. The element is scaled down to fit within the given maximum size.
+pub fn svg_size(node: &Node, max_width: i32, max_height: i32, font_size: f32, dpi: u16) -> (i32, i32) {
+    let ratio = node.attr("viewBox").and_then(parse_view_box).map(|(_, _, w, h)| w / h);
+    let width = node.attr("width").and_then(|v| parse_svg_length(v, max_width, font_size, dpi));
+    // A percentage height is ignored when the ratio is known, since the
+    // height of the containing block is unknown.
+    let height = node.attr("height").filter(|v| ratio.is_none() || !v.trim().ends_with('%'))
+                     .and_then(|v| parse_svg_length(v, max_height, font_size, dpi));
+    let default_width = pt_to_px(0.75 * DEFAULT_WIDTH, dpi);
+    let default_height = pt_to_px(0.75 * DEFAULT_HEIGHT, dpi);
+
+    let (width, height) = match (width, height, ratio) {
+        (Some(w), Some(h), _) => (w, h),
+        (Some(w), None, Some(r)) => (w, w / r),
+        (Some(w), None, None) => (w, default_height),
+        (None, Some(h), Some(r)) => (h * r, h),
+        (None, Some(h), None) => (default_width, h),
+        (None, None, Some(r)) => (max_width as f32, max_width as f32 / r),
+        (None, None, None) => (default_width, default_height),
+    };
+
+    let scale = (max_width as f32 / width).min(max_height as f32 / height).min(1.0);
+    ((scale * width).round().max(1.0) as i32, (scale * height).round().max(1.0) as i32)
+}
+
+// Serializes an SVG element into a standalone document of the given size, in pixels.
+// The images it refers to are embedded, since they can't be resolved once the
+// document is loaded from memory. Their paths are relative to *dir*.
+pub fn svg_document(node: &Node, size: (i32, i32), font_size: f32, dpi: u16, dir: &Path, resource_fetcher: &mut dyn ResourceFetcher) -> String {
+    let (width, height) = size;
+    let to_user_units = |v: i32| v as f32 * USER_UNIT_DPI as f32 / dpi as f32;
+    let mut attributes = vec![("width", to_user_units(width).to_string()),
+                              ("height", to_user_units(height).to_string())];
+
+    // Preserve the initial coordinate system when the size changes.
+    if node.attr("viewBox").is_none() {
+        let user_length = |name| node.attr(name).and_then(|v| parse_user_length(v, font_size));
+        if let (Some(w), Some(h)) = (user_length("width"), user_length("height")) {
+            attributes.push(("viewBox", format!("0 0 {} {}", w, h)));
+        }
+    }
+
+    if node.attr("xmlns").is_none() {
+        attributes.push(("xmlns", SVG_NAMESPACE.to_string()));
+    }
+
+    if node.attr("xmlns:xlink").is_none() {
+        attributes.push(("xmlns:xlink", XLINK_NAMESPACE.to_string()));
+    }
+
+    let mut buf = String::new();
+    write_svg(node, &attributes, dir, resource_fetcher, &mut buf);
+    buf
+}
+
+fn write_svg(node: &Node, root_attributes: &[(&str, String)], dir: &Path, resource_fetcher: &mut dyn ResourceFetcher, buf: &mut String) {
+    match node {
+        Node::Text(TextData { text, .. }) |
+        Node::Whitespace(TextData { text, .. }) => buf.push_str(text),
+        Node::Element(ElementData { name, attributes, children, .. }) => {
+            let name = name.trim_start_matches("svg:");
+            buf.push('<');
+            buf.push_str(name);
+            for (key, value) in attributes {
+                if root_attributes.iter().any(|(k, _)| k == key) {
+                    continue;
+                }
+                let value = if name == "image" && (key == "href" || key == "xlink:href") {
+                    embed_resource(value, dir, resource_fetcher).unwrap_or_else(|| value.clone())
+                } else {
+                    value.clone()
+                };
+                buf.push_str(&format!(" {}=\"{}\"", key, value.replace('"', "&quot;")));
+            }
+            for (key, value) in root_attributes {
+                buf.push_str(&format!(" {}=\"{}\"", key, value));
+            }
+            if children.is_empty() {
+                buf.push_str("/>");
+            } else {
+                buf.push('>');
+                for child in children {
+                    write_svg(child, &[], dir, resource_fetcher, buf);
+                }
+                buf.push_str(&format!("</{}>", name));
+            }
+        },
+    }
+}
+
+fn embed_resource(value: &str, dir: &Path, resource_fetcher: &mut dyn ResourceFetcher) -> Option<String> {
+    if value.starts_with("data:") || value.starts_with('#') {
+        return None;
+    }
+    let path = dir.join(value).normalize();
+    let path = path.to_str()?;
+    let data = resource_fetcher.fetch(path).ok()?;
+    Some(format!("data:{};base64,{}", mime_type(path), base64::encode(&data)))
+}
+
+fn mime_type(path: &str) -> &'static str {
+    let extension = Path::new(path).extension()
+                                   .and_then(|e| e.to_str())
+                                   .map(|e| e.to_ascii_lowercase());
+    match extension.as_ref().map(String::as_str) {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+// The numbers without units are given in user units.
+fn parse_svg_length(value: &str, reference: i32, font_size: f32, dpi: u16) -> Option<f32> {
+    let value = value.trim();
+    let length = if value.ends_with('%') {
+        value[..value.len()-1].trim().parse::<f32>().ok()
+             .map(|v| v / 100.0 * reference as f32)
+    } else if let Ok(v) = value.parse::<f32>() {
+        Some(pt_to_px(0.75 * v, dpi))
+    } else {
+        parse_length(value, font_size, font_size, dpi).map(|v| v as f32)
+    };
+    length.filter(|v| *v > 0.0)
+}
+
+fn parse_user_length(value: &str, font_size: f32) -> Option<f32> {
+    if value.trim().ends_with('%') {
+        None
+    } else {
+        parse_svg_length(value, 0, font_size, USER_UNIT_DPI)
+    }
+}
+
+fn parse_view_box(value: &str) -> Option<(f32, f32, f32, f32)> {
+    let numbers = value.split(|c: char| c.is_whitespace() || c == ',')
+                       .filter(|s| !s.is_empty())
+                       .map(|s| s.parse::<f32>().ok())
+                       .collect::<Option<Vec<f32>>>()?;
+    if numbers.len() == 4 && numbers[2] > 0.0 && numbers[3] > 0.0 {
+        Some((numbers[0], numbers[1], numbers[2], numbers[3]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::xml::XmlParser;
+
+    #[test]
+    fn test_svg_size() {
+        let sizes = [("<svg width='600' height='800'/>", (1000, 1333), (900, 1200)),
+                     ("<svg viewBox='0 0 600 800' width='100%' height='100%'/>", (1000, 1333), (900, 1200)),
+                     ("<svg width='1in' viewBox='0,0,2,1'/>", (300, 150), (300, 150)),
+                     ("<svg><circle r='5'/></svg>", (937, 469), (937, 469))];
+        for &(text, large, small) in &sizes {
+            let svg = XmlParser::new(text).parse();
+            assert_eq!(svg_size(&svg, 1000, 3000, 11.0, 300), large, "{}", text);
+            assert_eq!(svg_size(&svg, 1000, 1200, 11.0, 300), small, "{}", text);
+        }
+    }
+}
@@ -5,6 +5,8 @@ use super::dom::{Node, Attributes, text, element, whitespace};
 pub struct XmlParser<'a> {
     pub input: &'a str,
     pub offset: usize,
+    // Number of well-formedness errors encountered so far.
+    pub errors: usize,
 }
 
 impl<'a> XmlParser<'a> {
@@ -12,6 +14,7 @@ impl<'a> XmlParser<'a> {
         XmlParser {
             input,
             offset: 0,
+            errors: 0,
         }
     }
 
@@ -62,7 +65,14 @@ impl<'a> XmlParser<'a> {
                     let offset = self.offset;
                     self.advance_while(|&c| c != '=');
                     let key = self.input[offset..self.offset].to_string();
+                    if key.contains(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+                        self.errors += 1;
+                    }
+                    let offset = self.offset;
                     self.advance_while(|&c| c != '"' && c != '\'');
+                    if self.input[offset..self.offset].contains(|c: char| c != '=' && !c.is_whitespace()) {
+                        self.errors += 1;
+                    }
                     let quote = self.next().unwrap_or('"');
                     self.advance(1);
                     let offset = self.offset;
@@ -89,15 +99,16 @@ impl<'a> XmlParser<'a> {
             },
             Some('>') => {
                 self.advance(1);
-                let children = self.parse_nodes();
+                let children = self.parse_nodes(Some(name));
                 nodes.push(element(name, offset - 1, attributes, children));
             }
-            _ => (),
+            _ => self.errors += 1,
         }
     }
 
-    fn parse_nodes(&mut self) -> Vec<Node> {
+    fn parse_nodes(&mut self, name: Option<&str>) -> Vec<Node> {
         let mut nodes = Vec::new();
+        let mut closed = false;
 
         while !self.eof() {
             let offset = self.offset;
@@ -110,8 +121,13 @@ impl<'a> XmlParser<'a> {
                     }
                     if self.starts_with("</") {
                         self.advance(2);
+                        let offset = self.offset;
                         self.advance_while(|&c| c != '>');
+                        if name != Some(self.input[offset..self.offset].trim_end()) {
+                            self.errors += 1;
+                        }
                         self.advance(1);
+                        closed = true;
                         break;
                     }
                     self.advance(1);
@@ -142,16 +158,25 @@ impl<'a> XmlParser<'a> {
                 },
                 Some(..) => {
                     self.advance_while(|&c| c != '<');
-                    nodes.push(text(&self.input[offset..self.offset], offset));
+                    let content = &self.input[offset..self.offset];
+                    if has_bare_ampersand(content) {
+                        self.errors += 1;
+                    }
+                    nodes.push(text(content, offset));
                 },
                 None => break,
             }
         }
+
+        if name.is_some() && !closed {
+            self.errors += 1;
+        }
+
         nodes
     }
 
     pub fn parse(&mut self) -> Node {
-        let mut nodes = self.parse_nodes();
+        let mut nodes = self.parse_nodes(None);
         if nodes.len() == 1 {
             nodes.remove(0)
         } else {
@@ -160,6 +185,16 @@ impl<'a> XmlParser<'a> {
     }
 }
 
+// Whether an ampersand isn't the start of an entity or character reference.
+fn has_bare_ampersand(text: &str) -> bool {
+    text.match_indices('&').any(|(index, _)| {
+        let rest = &text[index+1..];
+        let rest = if rest.starts_with('#') { &rest[1..] } else { rest };
+        let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or_else(|| rest.len());
+        len == 0 || !rest[len..].starts_with(';')
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -203,4 +238,24 @@ mod tests {
         let xml = XmlParser::new(text).parse();
         assert_eq!(xml.text(), Some(" "));
     }
+
+    #[test]
+    fn test_errors() {
+        let mut parser = XmlParser::new("<a><b>x</b></a>");
+        parser.parse();
+        assert_eq!(parser.errors, 0);
+        let mut parser = XmlParser::new("<a><p>x &amp; y<br></p></a>");
+        parser.parse();
+        assert!(parser.errors > 0);
+        let mut parser = XmlParser::new("<a>Q&A</a>");
+        parser.parse();
+        assert!(parser.errors > 0);
+    }
+
+    #[test]
+    fn test_to_html() {
+        let text = r#"<aside id="n1"><p>See <a href="a.html">this</a>.<br/></p></aside>"#;
+        let xml = XmlParser::new(text).parse();
+        assert_eq!(xml.to_html(), text);
+    }
 }
@@ -0,0 +1,34 @@
+#![allow(unused)]
+
+pub const ARCHIVE_EOF: libc::c_int = 1;
+pub const ARCHIVE_OK: libc::c_int = 0;
+pub const ARCHIVE_RETRY: libc::c_int = -10;
+pub const ARCHIVE_WARN: libc::c_int = -20;
+pub const ARCHIVE_FAILED: libc::c_int = -25;
+pub const ARCHIVE_FATAL: libc::c_int = -30;
+
+pub const AE_IFMT: libc::mode_t = 0o170000;
+pub const AE_IFREG: libc::mode_t = 0o100000;
+pub const AE_IFDIR: libc::mode_t = 0o040000;
+
+pub const BLOCK_SIZE: libc::size_t = 10240;
+
+pub enum LaArchive {}
+pub enum LaArchiveEntry {}
+
+#[link(name="archive")]
+extern {
+    pub fn archive_read_new() -> *mut LaArchive;
+    pub fn archive_read_support_filter_all(a: *mut LaArchive) -> libc::c_int;
+    pub fn archive_read_support_format_all(a: *mut LaArchive) -> libc::c_int;
+    pub fn archive_read_open_filename(a: *mut LaArchive, filename: *const libc::c_char, block_size: libc::size_t) -> libc::c_int;
+    pub fn archive_read_next_header(a: *mut LaArchive, entry: *mut *mut LaArchiveEntry) -> libc::c_int;
+    pub fn archive_read_data(a: *mut LaArchive, buf: *mut libc::c_void, size: libc::size_t) -> libc::ssize_t;
+    pub fn archive_read_data_skip(a: *mut LaArchive) -> libc::c_int;
+    pub fn archive_read_free(a: *mut LaArchive) -> libc::c_int;
+    pub fn archive_error_string(a: *mut LaArchive) -> *const libc::c_char;
+    pub fn archive_entry_pathname_utf8(entry: *mut LaArchiveEntry) -> *const libc::c_char;
+    pub fn archive_entry_pathname(entry: *mut LaArchiveEntry) -> *const libc::c_char;
+    pub fn archive_entry_filetype(entry: *mut LaArchiveEntry) -> libc::mode_t;
+    pub fn archive_entry_size(entry: *mut LaArchiveEntry) -> i64;
+}
@@ -0,0 +1,217 @@
+use byteorder::{BigEndian, ByteOrder};
+use failure::{Error, format_err};
+
+pub fn palmdoc_decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 * data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        let c = data[i];
+        i += 1;
+        match c {
+            0x01..=0x08 => {
+                let end = (i + c as usize).min(data.len());
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            },
+            0x80..=0xBF => {
+                if i >= data.len() {
+                    break;
+                }
+                let pair = ((c as usize) << 8) | data[i] as usize;
+                i += 1;
+                let distance = (pair >> 3) & 0x07FF;
+                let length = (pair & 0x07) + 3;
+                if distance == 0 || distance > out.len() {
+                    continue;
+                }
+                // The source and the destination can overlap.
+                let start = out.len() - distance;
+                for j in 0..length {
+                    let b = out[start + j];
+                    out.push(b);
+                }
+            },
+            0xC0..=0xFF => {
+                out.push(b' ');
+                out.push(c ^ 0x80);
+            },
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+// Each phrase is either already decompressed or needs to be unpacked before use.
+struct Phrase {
+    data: Vec<u8>,
+    is_final: bool,
+}
+
+pub struct HuffCdic {
+    dict1: Vec<(u32, bool, u64)>,
+    min_codes: Vec<u64>,
+    max_codes: Vec<u64>,
+    phrases: Vec<Phrase>,
+}
+
+const MAX_DEPTH: usize = 32;
+
+impl HuffCdic {
+    pub fn new(huff: &[u8], cdics: &[&[u8]]) -> Result<HuffCdic, Error> {
+        if huff.len() < 16 || &huff[0..8] != b"HUFF\x00\x00\x00\x18" {
+            return Err(format_err!("Invalid HUFF record."));
+        }
+
+        let offset1 = BigEndian::read_u32(&huff[8..12]) as usize;
+        let offset2 = BigEndian::read_u32(&huff[12..16]) as usize;
+
+        if huff.len() < offset1 + 256 * 4 || huff.len() < offset2 + 64 * 4 {
+            return Err(format_err!("Truncated HUFF record."));
+        }
+
+        let mut dict1 = Vec::with_capacity(256);
+        for i in 0..256 {
+            let v = BigEndian::read_u32(&huff[offset1+4*i..offset1+4*i+4]);
+            let code_len = v & 0x1F;
+            let term = v & 0x80 != 0;
+            let max_code = v as u64 >> 8;
+            if code_len == 0 {
+                return Err(format_err!("Invalid HUFF code length."));
+            }
+            dict1.push((code_len, term, ((max_code + 1) << (32 - code_len)) - 1));
+        }
+
+        let mut min_codes = vec![0];
+        let mut max_codes = vec![0];
+        for code_len in 1..=32 {
+            let i = offset2 + 8 * (code_len - 1);
+            let min_code = BigEndian::read_u32(&huff[i..i+4]) as u64;
+            let max_code = BigEndian::read_u32(&huff[i+4..i+8]) as u64;
+            min_codes.push(min_code << (32 - code_len));
+            max_codes.push(((max_code + 1) << (32 - code_len)) - 1);
+        }
+
+        let mut phrases = Vec::new();
+        for cdic in cdics {
+            if cdic.len() < 16 || &cdic[0..8] != b"CDIC\x00\x00\x00\x10" {
+                return Err(format_err!("Invalid CDIC record."));
+            }
+            let count = BigEndian::read_u32(&cdic[8..12]) as usize;
+            let bits = BigEndian::read_u32(&cdic[12..16]);
+            let n = (1usize << bits.min(16)).min(count.saturating_sub(phrases.len()));
+            for i in 0..n {
+                if cdic.len() < 18 + 2 * i {
+                    return Err(format_err!("Truncated CDIC record."));
+                }
+                let offset = BigEndian::read_u16(&cdic[16+2*i..18+2*i]) as usize;
+                if cdic.len() < 18 + offset {
+                    return Err(format_err!("Truncated CDIC record."));
+                }
+                let length = BigEndian::read_u16(&cdic[16+offset..18+offset]);
+                let start = 18 + offset;
+                let end = (start + (length & 0x7FFF) as usize).min(cdic.len());
+                phrases.push(Phrase {
+                    data: cdic[start..end].to_vec(),
+                    is_final: length & 0x8000 != 0,
+                });
+            }
+        }
+
+        Ok(HuffCdic { dict1, min_codes, max_codes, phrases })
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> Vec<u8> {
+        self.unpack(data, 0)
+    }
+
+    fn unpack(&mut self, data: &[u8], depth: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut bits_left = 8 * data.len() as i64;
+        let mut buf = data.to_vec();
+        buf.extend_from_slice(&[0; 8]);
+        let mut pos = 0;
+        let mut x = BigEndian::read_u64(&buf[pos..pos+8]);
+        let mut n: i32 = 32;
+
+        loop {
+            if n <= 0 {
+                pos += 4;
+                if pos + 8 > buf.len() {
+                    break;
+                }
+                x = BigEndian::read_u64(&buf[pos..pos+8]);
+                n += 32;
+            }
+            let code = (x >> n) & 0xFFFF_FFFF;
+            let (mut code_len, term, mut max_code) = self.dict1[(code >> 24) as usize];
+            if !term {
+                while (code_len as usize) < 32 && code < self.min_codes[code_len as usize] {
+                    code_len += 1;
+                }
+                max_code = self.max_codes[code_len as usize];
+            }
+            n -= code_len as i32;
+            bits_left -= code_len as i64;
+            if bits_left < 0 {
+                break;
+            }
+            let index = (max_code.saturating_sub(code) >> (32 - code_len)) as usize;
+            if index >= self.phrases.len() {
+                break;
+            }
+            if !self.phrases[index].is_final && depth < MAX_DEPTH {
+                let phrase = self.phrases[index].data.clone();
+                let data = self.unpack(&phrase, depth + 1);
+                self.phrases[index] = Phrase { data, is_final: true };
+            }
+            out.extend_from_slice(&self.phrases[index].data);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palmdoc() {
+        assert_eq!(palmdoc_decompress(b"ab\x03\xff\x00c"), b"ab\xff\x00c");
+        assert_eq!(palmdoc_decompress(b"a\xe2c"), b"a bc");
+        // Copies 4 bytes from 3 bytes back.
+        assert_eq!(palmdoc_decompress(b"abc\x80\x19"), b"abcabca");
+    }
+
+    #[test]
+    fn test_huff_cdic() {
+        // Every code is two bits long: 11, 10, 01 and 00 map to the phrases 0 to 3.
+        let mut huff = b"HUFF\x00\x00\x00\x18\x00\x00\x00\x18\x00\x00\x04\x18\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        for _ in 0..256 {
+            huff.extend_from_slice(&[0x00, 0x00, 0x03, 0x82]);
+        }
+        huff.extend_from_slice(&[0; 64 * 4]);
+
+        // The last phrase isn't final: it's made of the codes of the phrases 1, 2, 1 and 2.
+        let phrases: [(&[u8], bool); 4] = [(b"The", true), (b" cat", true), (b" sat", true), (&[0b1001_1001], false)];
+        let mut cdic = b"CDIC\x00\x00\x00\x10\x00\x00\x00\x04\x00\x00\x00\x02".to_vec();
+        let mut offset = 2 * phrases.len();
+        for (data, _) in &phrases {
+            cdic.extend_from_slice(&(offset as u16).to_be_bytes());
+            offset += 2 + data.len();
+        }
+        for (data, is_final) in &phrases {
+            let length = data.len() as u16 | if *is_final { 0x8000 } else { 0 };
+            cdic.extend_from_slice(&length.to_be_bytes());
+            cdic.extend_from_slice(data);
+        }
+
+        let mut huff_cdic = HuffCdic::new(&huff, &[&cdic]).unwrap();
+        assert_eq!(huff_cdic.decompress(&[0b1110_0100]), b"The cat sat cat sat cat sat".to_vec());
+        assert_eq!(huff_cdic.decompress(&[0b0000_1111]), b" cat sat cat sat cat sat cat satTheThe".to_vec());
+        assert!(HuffCdic::new(&huff[..512], &[&cdic]).is_err());
+        assert!(HuffCdic::new(&huff, &[&cdic[..12]]).is_err());
+    }
+}
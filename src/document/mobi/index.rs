@@ -0,0 +1,226 @@
+use byteorder::{BigEndian, ByteOrder};
+use fnv::FnvHashMap;
+use failure::{Error, format_err};
+use super::PalmDb;
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub name: String,
+    pub tags: FnvHashMap<u8, Vec<u32>>,
+}
+
+impl IndexEntry {
+    pub fn tag(&self, tag: u8, index: usize) -> Option<u32> {
+        self.tags.get(&tag).and_then(|values| values.get(index)).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+    // The strings of the CNCX records, by record and offset.
+    pub cncx: FnvHashMap<u32, String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TagX {
+    tag: u8,
+    values_count: u8,
+    mask: u8,
+    eof: u8,
+}
+
+struct IndxHeader {
+    length: usize,
+    start: usize,
+    count: usize,
+    cncx_count: usize,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    offset.checked_add(4).and_then(|end| data.get(offset..end))
+          .map(BigEndian::read_u32)
+          .ok_or_else(|| format_err!("Unexpected end of index record."))
+}
+
+fn parse_indx_header(data: &[u8]) -> Result<IndxHeader, Error> {
+    if !data.starts_with(b"INDX") {
+        return Err(format_err!("Invalid index record."));
+    }
+    Ok(IndxHeader {
+        length: read_u32(data, 4)? as usize,
+        start: read_u32(data, 20)? as usize,
+        count: read_u32(data, 24)? as usize,
+        cncx_count: read_u32(data, 52)? as usize,
+    })
+}
+
+fn parse_tagx(data: &[u8]) -> Result<(usize, Vec<TagX>), Error> {
+    if !data.starts_with(b"TAGX") {
+        return Err(format_err!("Invalid tags section."));
+    }
+    let first_entry_offset = read_u32(data, 4)? as usize;
+    let control_bytes_count = read_u32(data, 8)? as usize;
+    let tags = data.get(12..first_entry_offset.max(12)).unwrap_or_default()
+                   .chunks_exact(4)
+                   .map(|c| TagX { tag: c[0], values_count: c[1], mask: c[2], eof: c[3] })
+                   .collect();
+    Ok((control_bytes_count, tags))
+}
+
+// Forward variable width integer: the last byte has its high bit set.
+pub fn decint(data: &[u8]) -> (u32, usize) {
+    let mut value = 0u32;
+    let mut consumed = 0;
+    for &b in data {
+        consumed += 1;
+        value = (value << 7) | (b & 0x7F) as u32;
+        if b & 0x80 != 0 {
+            break;
+        }
+    }
+    (value, consumed)
+}
+
+fn tag_map(control_bytes_count: usize, tagx: &[TagX], data: &[u8]) -> FnvHashMap<u8, Vec<u32>> {
+    let mut result = FnvHashMap::default();
+    if data.len() < control_bytes_count {
+        return result;
+    }
+    let mut control_bytes = &data[..control_bytes_count];
+    let mut data = &data[control_bytes_count..];
+    let mut pending = Vec::new();
+
+    for x in tagx {
+        if x.eof == 1 {
+            if !control_bytes.is_empty() {
+                control_bytes = &control_bytes[1..];
+            }
+            continue;
+        }
+        let mut value = match control_bytes.first() {
+            Some(b) => b & x.mask,
+            None => break,
+        };
+        if value == 0 {
+            continue;
+        }
+        if value == x.mask {
+            if x.mask.count_ones() > 1 {
+                let (bytes_count, consumed) = decint(data);
+                data = &data[consumed..];
+                pending.push((x.tag, None, Some(bytes_count as usize), x.values_count));
+            } else {
+                pending.push((x.tag, Some(1), None, x.values_count));
+            }
+        } else {
+            let mut mask = x.mask;
+            while mask & 1 == 0 {
+                mask >>= 1;
+                value >>= 1;
+            }
+            pending.push((x.tag, Some(value as usize), None, x.values_count));
+        }
+    }
+
+    for (tag, count, bytes_count, values_count) in pending {
+        let mut values = Vec::new();
+        if let Some(count) = count {
+            for _ in 0..count * values_count as usize {
+                if data.is_empty() {
+                    break;
+                }
+                let (value, consumed) = decint(data);
+                data = &data[consumed..];
+                values.push(value);
+            }
+        } else if let Some(bytes_count) = bytes_count {
+            let mut total = 0;
+            while total < bytes_count && !data.is_empty() {
+                let (value, consumed) = decint(data);
+                data = &data[consumed..];
+                total += consumed;
+                values.push(value);
+            }
+        }
+        result.insert(tag, values);
+    }
+
+    result
+}
+
+fn parse_cncx(records: &[&[u8]]) -> FnvHashMap<u32, String> {
+    let mut cncx = FnvHashMap::default();
+    for (i, record) in records.iter().enumerate() {
+        let mut pos = 0;
+        while pos < record.len() {
+            let (length, consumed) = decint(&record[pos..]);
+            let start = pos + consumed;
+            let end = (start + length as usize).min(record.len());
+            if length > 0 {
+                cncx.insert((pos + i * 0x10000) as u32,
+                            String::from_utf8_lossy(&record[start..end]).into_owned());
+            }
+            pos = end;
+        }
+    }
+    cncx
+}
+
+pub fn read_index(db: &PalmDb, index: usize) -> Result<Index, Error> {
+    let data = db.record(index).ok_or_else(|| format_err!("Missing index record."))?;
+    let header = parse_indx_header(data)?;
+    let (control_bytes_count, tagx) = parse_tagx(data.get(header.length..).unwrap_or_default())?;
+
+    let last = index.checked_add(header.count)
+                    .ok_or_else(|| format_err!("Invalid index count."))?;
+    let cncx_records = (1..=header.cncx_count).filter_map(|i| last.checked_add(i).and_then(|i| db.record(i)))
+                                             .collect::<Vec<&[u8]>>();
+    let mut result = Index {
+        entries: Vec::new(),
+        cncx: parse_cncx(&cncx_records),
+    };
+
+    for i in index+1..=last {
+        let data = db.record(i).ok_or_else(|| format_err!("Missing index record."))?;
+        let header = parse_indx_header(data)?;
+        let mut positions = Vec::with_capacity(header.count.min(data.len() / 2) + 1);
+        for j in 0..header.count {
+            let offset = header.start.checked_add(4 + 2 * j);
+            let position = offset.and_then(|offset| data.get(offset..offset.checked_add(2)?))
+                                 .map(BigEndian::read_u16)
+                                 .ok_or_else(|| format_err!("Unexpected end of index record."))?;
+            positions.push(position as usize);
+        }
+        positions.push(header.start);
+
+        for w in positions.windows(2) {
+            let entry = match data.get(w[0]..w[1]) {
+                Some(entry) if !entry.is_empty() => entry,
+                _ => continue,
+            };
+            let length = entry[0] as usize;
+            let name_end = (1 + length).min(entry.len());
+            let name = String::from_utf8_lossy(&entry[1..name_end]).into_owned();
+            let tags = tag_map(control_bytes_count, &tagx, &entry[name_end..]);
+            result.entries.push(IndexEntry { name, tags });
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_map() {
+        let tagx = [TagX { tag: 1, values_count: 1, mask: 0x01, eof: 0 },
+                    TagX { tag: 6, values_count: 2, mask: 0x02, eof: 0 },
+                    TagX { tag: 0, values_count: 0, mask: 0, eof: 1 }];
+        let tags = tag_map(1, &tagx, &[0x03, 0x83, 0x01, 0x80, 0x85]);
+        assert_eq!(tags.get(&1), Some(&vec![3]));
+        assert_eq!(tags.get(&6), Some(&vec![128, 5]));
+    }
+}
@@ -0,0 +1,862 @@
+mod compression;
+mod index;
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::iter::Peekable;
+use std::collections::BTreeSet;
+use byteorder::{BigEndian, ByteOrder};
+use fnv::{FnvHashMap, FnvHashSet};
+use regex::bytes::{Regex, Captures};
+use lazy_static::lazy_static;
+use failure::{Error, format_err};
+use super::{Document, Location, TocEntry};
+use super::html::HtmlDocument;
+use super::html::engine::ResourceFetcher;
+use super::text::decode_windows_1252;
+use self::compression::{palmdoc_decompress, HuffCdic};
+use self::index::{Index, read_index};
+
+const VIEWER_STYLESHEET: &str = "css/epub.css";
+const USER_STYLESHEET: &str = "css/epub-user.css";
+const NULL_INDEX: u32 = 0xFFFF_FFFF;
+const PALMDB_HEADER_SIZE: usize = 78;
+const MAX_TEXT_RECORD_SIZE: usize = 4096;
+
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_ISBN: u32 = 104;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_DATE: u32 = 106;
+const EXTH_KF8_BOUNDARY: u32 = 121;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_TITLE: u32 = 503;
+const EXTH_LANGUAGE: u32 = 524;
+
+lazy_static! {
+    static ref FILEPOS: Regex = Regex::new(r#"(?i)filepos=["']?0*(\d+)["']?"#).unwrap();
+    static ref RECINDEX: Regex = Regex::new(r#"(?i)recindex=["']?0*(\d+)["']?"#).unwrap();
+    static ref KINDLE_POS: Regex = Regex::new(r"kindle:pos:fid:([0-9A-Va-v]{4}):off:([0-9A-Va-v]{10})").unwrap();
+    static ref KINDLE_EMBED: Regex = Regex::new(r#"kindle:embed:([0-9A-Va-v]{4})(\?mime=[^"')]*)?"#).unwrap();
+    static ref KINDLE_FLOW: Regex = Regex::new(r"kindle:flow:([0-9A-Va-v]{4})\?mime=text/css").unwrap();
+    static ref VOID_TAG: Regex = Regex::new(r"(?i)<(br|hr|img)\b([^>]*?)\s*/?>").unwrap();
+    static ref PAGE_BREAK: Regex = Regex::new(r"(?i)<mbp:pagebreak\s*/?>").unwrap();
+}
+
+pub struct PalmDb {
+    data: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl PalmDb {
+    fn new(data: Vec<u8>) -> Result<PalmDb, Error> {
+        if data.len() < PALMDB_HEADER_SIZE {
+            return Err(format_err!("Invalid Palm database."));
+        }
+        let count = BigEndian::read_u16(&data[76..78]) as usize;
+        let mut offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = PALMDB_HEADER_SIZE + 8 * i;
+            let offset = data.get(start..start+4).map(BigEndian::read_u32)
+                             .ok_or_else(|| format_err!("Truncated record list."))?;
+            offsets.push(offset as usize);
+        }
+        Ok(PalmDb { data, offsets })
+    }
+
+    fn kind(&self) -> &[u8] {
+        &self.data[60..68]
+    }
+
+    pub fn record(&self, index: usize) -> Option<&[u8]> {
+        let start = *self.offsets.get(index)?;
+        let end = self.offsets.get(index + 1).cloned()
+                      .unwrap_or_else(|| self.data.len());
+        self.data.get(start..end)
+    }
+}
+
+// Reads the first record of a Palm database: it holds the MOBI header and
+// the EXTH records.
+fn read_header_record<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut header = [0; PALMDB_HEADER_SIZE + 16];
+    reader.read_exact(&mut header)?;
+    check_kind(&header[60..68])?;
+
+    let count = BigEndian::read_u16(&header[76..78]);
+    if count == 0 {
+        return Err(format_err!("Missing header record."));
+    }
+
+    let start = read_u32(&header, PALMDB_HEADER_SIZE) as u64;
+    let end = if count > 1 {
+        read_u32(&header, PALMDB_HEADER_SIZE + 8) as u64
+    } else {
+        reader.seek(SeekFrom::End(0))?
+    };
+    if end < start {
+        return Err(format_err!("Invalid record list."));
+    }
+
+    let mut record = Vec::new();
+    reader.seek(SeekFrom::Start(start))?;
+    reader.take(end - start).read_to_end(&mut record)?;
+    Ok(record)
+}
+
+fn check_kind(kind: &[u8]) -> Result<(), Error> {
+    if kind != b"BOOKMOBI" {
+        return Err(format_err!("Unsupported database kind: {}.", String::from_utf8_lossy(kind)));
+    }
+    Ok(())
+}
+
+struct MobiHeader {
+    compression: u16,
+    text_length: usize,
+    text_records_count: usize,
+    encryption: u16,
+    encoding: u32,
+    version: u32,
+    full_name: String,
+    first_image: Option<usize>,
+    huff_record: usize,
+    huff_records_count: usize,
+    extra_flags: u16,
+    fdst: Option<usize>,
+    ncx: Option<usize>,
+    fragment: Option<usize>,
+    skeleton: Option<usize>,
+    exth: FnvHashMap<u32, Vec<Vec<u8>>>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    offset.checked_add(4).and_then(|end| data.get(offset..end))
+          .map(BigEndian::read_u32)
+          .unwrap_or(NULL_INDEX)
+}
+
+fn read_index_field(data: &[u8], offset: usize) -> Option<usize> {
+    Some(read_u32(data, offset)).filter(|&v| v != NULL_INDEX)
+                                .map(|v| v as usize)
+}
+
+impl MobiHeader {
+    fn new(record: &[u8]) -> Result<MobiHeader, Error> {
+        if record.len() < 24 || &record[16..20] != b"MOBI" {
+            return Err(format_err!("Missing MOBI header."));
+        }
+
+        let header_length = read_u32(record, 20) as usize;
+        let version = read_u32(record, 36);
+        let full_name_offset = read_u32(record, 84) as usize;
+        let full_name_length = read_u32(record, 88) as usize;
+        let encoding = read_u32(record, 28);
+        let full_name = full_name_offset.checked_add(full_name_length)
+                                        .and_then(|end| record.get(full_name_offset..end))
+                                        .map(|bytes| decode(bytes, encoding))
+                              .unwrap_or_default();
+
+        let extra_flags = if header_length >= 0xE4 {
+            record.get(0xF2..0xF4).map(BigEndian::read_u16).unwrap_or(0)
+        } else {
+            0
+        };
+
+        let mut exth = FnvHashMap::default();
+        if read_u32(record, 0x80) & 0x40 != 0 {
+            let start = header_length.saturating_add(16);
+            if record.get(start..).map_or(false, |data| data.starts_with(b"EXTH")) {
+                let count = read_u32(record, start + 8) as usize;
+                let mut offset = start + 12;
+                for _ in 0..count {
+                    let kind = read_u32(record, offset);
+                    let length = read_u32(record, offset + 4) as usize;
+                    let end = match offset.checked_add(length) {
+                        Some(end) if length >= 8 && end <= record.len() => end,
+                        _ => break,
+                    };
+                    exth.entry(kind).or_insert_with(Vec::new).push(record[offset+8..end].to_vec());
+                    offset = end;
+                }
+            }
+        }
+
+        let is_kf8 = version >= 8;
+
+        Ok(MobiHeader {
+            compression: BigEndian::read_u16(&record[0..2]),
+            text_length: read_u32(record, 4) as usize,
+            text_records_count: BigEndian::read_u16(&record[8..10]) as usize,
+            encryption: BigEndian::read_u16(&record[12..14]),
+            encoding,
+            version,
+            full_name,
+            first_image: read_index_field(record, 0x6C),
+            huff_record: read_u32(record, 0x70) as usize,
+            huff_records_count: read_u32(record, 0x74) as usize,
+            extra_flags,
+            fdst: if is_kf8 { read_index_field(record, 0xC0) } else { None },
+            ncx: read_index_field(record, 0xF4),
+            fragment: if is_kf8 { read_index_field(record, 0xF8) } else { None },
+            skeleton: if is_kf8 { read_index_field(record, 0xFC) } else { None },
+            exth,
+        })
+    }
+
+    fn exth_strings(&self, kind: u32) -> Vec<String> {
+        self.exth.get(&kind).map(|values| {
+            values.iter().map(|v| decode(v, self.encoding).trim().to_string())
+                  .filter(|v| !v.is_empty())
+                  .collect()
+        }).unwrap_or_default()
+    }
+
+    fn exth_string(&self, kind: u32) -> Option<String> {
+        self.exth_strings(kind).into_iter().next()
+    }
+
+    fn exth_u32(&self, kind: u32) -> Option<u32> {
+        self.exth.get(&kind).and_then(|values| values.first())
+            .filter(|v| v.len() == 4)
+            .map(|v| BigEndian::read_u32(v))
+            .filter(|&v| v != NULL_INDEX)
+    }
+}
+
+fn decode(bytes: &[u8], encoding: u32) -> String {
+    if encoding == 65001 {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        decode_windows_1252(bytes)
+    }
+}
+
+// Backward variable width integer: the first byte has its high bit set.
+fn backward_decint(data: &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    for &b in data.iter().rev().take(4) {
+        value |= ((b & 0x7F) as usize) << shift;
+        shift += 7;
+        if b & 0x80 != 0 {
+            break;
+        }
+    }
+    value
+}
+
+fn trailing_entries_size(data: &[u8], flags: u16) -> usize {
+    let mut size = 0;
+    let mut bits = flags >> 1;
+    while bits != 0 {
+        if bits & 1 == 1 {
+            size += backward_decint(&data[..data.len().saturating_sub(size)]);
+        }
+        bits >>= 1;
+    }
+    if flags & 1 == 1 && data.len() > size {
+        size += (data[data.len() - size - 1] & 0x03) as usize + 1;
+    }
+    size
+}
+
+fn read_text(db: &PalmDb, base: usize, header: &MobiHeader) -> Result<Vec<u8>, Error> {
+    let mut huff_cdic = if header.compression == 17480 {
+        let huff_record = base.saturating_add(header.huff_record);
+        let huff = db.record(huff_record)
+                     .ok_or_else(|| format_err!("Missing HUFF record."))?;
+        let cdics = (1..header.huff_records_count).filter_map(|i| db.record(huff_record.saturating_add(i)))
+                                                  .collect::<Vec<&[u8]>>();
+        Some(HuffCdic::new(huff, &cdics)?)
+    } else {
+        None
+    };
+
+    // The text length comes from the header and is only trusted up to the
+    // maximum size of the text records.
+    let mut text = Vec::with_capacity(header.text_length.min(header.text_records_count.saturating_mul(MAX_TEXT_RECORD_SIZE)));
+
+    for i in 1..=header.text_records_count {
+        let record = db.record(base.saturating_add(i)).ok_or_else(|| format_err!("Missing text record."))?;
+        let size = trailing_entries_size(record, header.extra_flags);
+        let record = &record[..record.len().saturating_sub(size)];
+        match header.compression {
+            1 => text.extend_from_slice(record),
+            2 => text.extend_from_slice(&palmdoc_decompress(record)),
+            17480 => text.extend_from_slice(&huff_cdic.as_mut().unwrap().decompress(record)),
+            n => return Err(format_err!("Unknown compression: {}.", n)),
+        }
+    }
+
+    text.truncate(header.text_length);
+    Ok(text)
+}
+
+fn image_extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\xFF\xD8\xFF") {
+        Some("jpg")
+    } else if data.starts_with(b"\x89PNG") {
+        Some("png")
+    } else if data.starts_with(b"GIF8") {
+        Some("gif")
+    } else if data.starts_with(b"BM") {
+        Some("bmp")
+    } else {
+        None
+    }
+}
+
+struct Resources(FnvHashMap<String, Vec<u8>>);
+
+impl ResourceFetcher for Resources {
+    fn fetch(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        self.0.get(name).cloned()
+            .ok_or_else(|| format_err!("Can't find {}.", name))
+    }
+}
+
+// The images are referenced by their one-based indices, relatively to the first image record.
+fn load_images(db: &PalmDb, first_image: Option<usize>) -> (FnvHashMap<usize, String>, Resources) {
+    let mut names = FnvHashMap::default();
+    let mut data = FnvHashMap::default();
+    if let Some(first_image) = first_image {
+        for i in first_image..db.offsets.len() {
+            if let Some(record) = db.record(i) {
+                if let Some(extension) = image_extension(record) {
+                    let n = i - first_image + 1;
+                    let name = format!("image{}.{}", n, extension);
+                    names.insert(n, name.clone());
+                    data.insert(name, record.to_vec());
+                }
+            }
+        }
+    }
+    (names, Resources(data))
+}
+
+// A chunk of markup and its position in the text.
+struct Part {
+    start: usize,
+    content: Vec<u8>,
+}
+
+struct Fragment {
+    insert_position: usize,
+}
+
+fn read_fdst(record: &[u8]) -> Vec<(usize, usize)> {
+    if !record.starts_with(b"FDST") {
+        return Vec::new();
+    }
+    let offset = read_u32(record, 4) as usize;
+    let count = (read_u32(record, 8) as usize).min(record.len() / 8);
+    (0..count).map(|i| (read_u32(record, offset.saturating_add(8 * i)) as usize,
+                        read_u32(record, offset.saturating_add(8 * i + 4)) as usize))
+              .collect()
+}
+
+// KF8 splits each file into a skeleton and fragments that are inserted into it.
+fn assemble_parts(text: &[u8], skeletons: &Index, fragments: &[Fragment], lengths: &[usize]) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut fragment_index = 0;
+
+    for skeleton in &skeletons.entries {
+        let count = skeleton.tag(1, 0).unwrap_or(0) as usize;
+        let start = skeleton.tag(6, 0).unwrap_or(0) as usize;
+        let length = skeleton.tag(6, 1).unwrap_or(0) as usize;
+        let mut base = match start.checked_add(length) {
+            Some(base) if base <= text.len() => base,
+            _ => break,
+        };
+        let mut content = text[start..base].to_vec();
+
+        for _ in 0..count {
+            let (fragment, &length) = match (fragments.get(fragment_index), lengths.get(fragment_index)) {
+                (Some(fragment), Some(length)) => (fragment, length),
+                _ => break,
+            };
+            let position = fragment.insert_position.saturating_sub(start).min(content.len());
+            let end = base.saturating_add(length);
+            let chunk = text.get(base..end).unwrap_or_default();
+            let tail = content.split_off(position);
+            content.extend_from_slice(chunk);
+            content.extend_from_slice(&tail);
+            base = end;
+            fragment_index += 1;
+        }
+
+        parts.push(Part { start, content });
+    }
+
+    parts
+}
+
+fn base32(bytes: &[u8]) -> Option<usize> {
+    std::str::from_utf8(bytes).ok()
+        .and_then(|s| usize::from_str_radix(s, 32).ok())
+}
+
+fn decimal(bytes: &[u8]) -> Option<usize> {
+    std::str::from_utf8(bytes).ok()
+        .and_then(|s| s.parse().ok())
+}
+
+fn anchor_id(position: usize) -> String {
+    format!("pos{}", position)
+}
+
+fn body_start(content: &[u8]) -> usize {
+    let lower = content.iter().map(u8::to_ascii_lowercase).collect::<Vec<u8>>();
+    lower.windows(5).position(|w| w == b"<body")
+         .and_then(|i| lower[i..].iter().position(|&b| b == b'>').map(|j| i + j + 1))
+         .unwrap_or(0)
+}
+
+// Inserts anchors at the given positions, moving them out of the tags they might fall into.
+fn insert_anchors(part: &mut Part, positions: &[usize]) {
+    let end = part.start + part.content.len();
+    let mut offsets = positions.iter()
+                               .filter(|&&p| p >= part.start && p < end)
+                               .cloned().collect::<Vec<usize>>();
+    if offsets.is_empty() {
+        return;
+    }
+    offsets.sort();
+    offsets.dedup();
+
+    let min_offset = body_start(&part.content);
+    let content = &part.content;
+    let mut result = Vec::with_capacity(content.len() + 24 * offsets.len());
+    let mut last = 0;
+
+    for position in offsets {
+        let mut offset = position - part.start;
+        if let Some(i) = content[..offset].iter().rposition(|&b| b == b'<') {
+            if !content[i..offset].contains(&b'>') {
+                offset = i;
+            }
+        }
+        let offset = offset.max(min_offset).max(last);
+        result.extend_from_slice(&content[last..offset]);
+        result.extend_from_slice(format!("<a id=\"{}\"></a>", anchor_id(position)).as_bytes());
+        last = offset;
+    }
+
+    result.extend_from_slice(&content[last..]);
+    part.content = result;
+}
+
+fn body_content(text: &str) -> &str {
+    let start = body_start(text.as_bytes());
+    let end = text.to_ascii_lowercase().rfind("</body>")
+                  .filter(|&end| end >= start)
+                  .unwrap_or_else(|| text.len());
+    &text[start..end]
+}
+
+fn nest_entries<I: Iterator<Item=(usize, TocEntry)>>(entries: &mut Peekable<I>, depth: usize) -> Vec<TocEntry> {
+    let mut result: Vec<TocEntry> = Vec::new();
+    while let Some(d) = entries.peek().map(|e| e.0) {
+        if d < depth {
+            break;
+        }
+        if d > depth {
+            if let Some(last) = result.last_mut() {
+                last.children = nest_entries(entries, d);
+                continue;
+            }
+        }
+        if let Some((_, entry)) = entries.next() {
+            result.push(entry);
+        }
+    }
+    result
+}
+
+fn toc_entries(index: &Index, positions: &[usize], html: &str) -> Vec<TocEntry> {
+    let mut entries: Vec<(usize, TocEntry)> = Vec::new();
+
+    for (i, (entry, &position)) in index.entries.iter().zip(positions).enumerate() {
+        let title = entry.tag(3, 0).and_then(|offset| index.cncx.get(&offset))
+                         .cloned().unwrap_or_default();
+        let depth = entry.tag(4, 0).unwrap_or(0) as usize;
+        let location = html.find(&format!("<a id=\"{}\">", anchor_id(position)))
+                           .map(Location::Exact)
+                           .unwrap_or_else(|| Location::Uri(format!("#{}", anchor_id(position))));
+        entries.push((depth, TocEntry { title, location, index: i, children: Vec::new() }));
+    }
+
+    nest_entries(&mut entries.into_iter().peekable(), 0)
+}
+
+pub struct MobiMetadata(MobiHeader);
+
+impl MobiMetadata {
+    // Only the header record is read: the text and the images of the book
+    // aren't needed to import it.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<MobiMetadata, Error> {
+        let mut file = File::open(path)?;
+        let record = read_header_record(&mut file)?;
+        MobiHeader::new(&record).map(MobiMetadata)
+    }
+
+    pub fn title(&self) -> Option<String> {
+        self.0.exth_string(EXTH_TITLE)
+            .or_else(|| Some(self.0.full_name.clone()))
+            .filter(|title| !title.is_empty())
+    }
+
+    pub fn author(&self) -> Option<String> {
+        let authors = self.0.exth_strings(EXTH_AUTHOR);
+        if authors.is_empty() {
+            None
+        } else {
+            Some(authors.join(", "))
+        }
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<String> {
+        key.parse().ok().and_then(|kind| self.0.exth_string(kind))
+    }
+
+    pub fn categories(&self) -> BTreeSet<String> {
+        self.0.exth_strings(EXTH_SUBJECT).into_iter().collect()
+    }
+
+    pub fn description(&self) -> Option<String> {
+        self.0.exth_string(EXTH_DESCRIPTION)
+    }
+
+    pub fn publisher(&self) -> Option<String> {
+        self.0.exth_string(EXTH_PUBLISHER)
+    }
+
+    pub fn isbn(&self) -> Option<String> {
+        self.0.exth_string(EXTH_ISBN)
+    }
+
+    pub fn language(&self) -> Option<String> {
+        self.0.exth_string(EXTH_LANGUAGE)
+    }
+
+    pub fn year(&self) -> Option<String> {
+        self.0.exth_string(EXTH_DATE).map(|s| s.chars().take(4).collect())
+    }
+}
+
+pub struct MobiDocument {
+    doc: HtmlDocument,
+    metadata: MobiMetadata,
+    toc: Vec<TocEntry>,
+}
+
+impl MobiDocument {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<MobiDocument, Error> {
+        let db = PalmDb::new(fs::read(path)?)?;
+
+        check_kind(db.kind())?;
+
+        let header = MobiHeader::new(db.record(0).ok_or_else(|| format_err!("Missing header record."))?)?;
+        let first_image = header.first_image;
+
+        // Joint files contain both a MOBI 7 and a KF8 version of the book.
+        let (base, header) = match header.exth_u32(EXTH_KF8_BOUNDARY) {
+            Some(boundary) if header.version < 8 => {
+                let kf8_header = db.record(boundary as usize).and_then(|record| MobiHeader::new(record).ok());
+                match kf8_header {
+                    Some(kf8_header) => (boundary as usize, kf8_header),
+                    None => (0, header),
+                }
+            },
+            _ => (0, header),
+        };
+
+        if header.encryption != 0 {
+            return Err(format_err!("Encrypted books aren't supported."));
+        }
+
+        let text = read_text(&db, base, &header)?;
+        let (image_names, resources) = load_images(&db, first_image);
+        let ncx = header.ncx.and_then(|i| read_index(&db, base.saturating_add(i)).ok()).unwrap_or_default();
+        let is_kf8 = header.skeleton.is_some() && header.fragment.is_some();
+
+        let mut stylesheet = String::new();
+        let mut parts;
+        let toc_positions;
+
+        if is_kf8 {
+            let flows = header.fdst.and_then(|i| db.record(base.saturating_add(i))).map(read_fdst)
+                              .filter(|flows| !flows.is_empty())
+                              .unwrap_or_else(|| vec![(0, text.len())]);
+            let (start, end) = flows[0];
+            let markup = text.get(start..end.min(text.len())).unwrap_or_default();
+            let skeletons = read_index(&db, base.saturating_add(header.skeleton.unwrap()))?;
+            let fragments_index = read_index(&db, base.saturating_add(header.fragment.unwrap()))?;
+            let fragments = fragments_index.entries.iter().map(|entry| {
+                Fragment { insert_position: decimal(entry.name.as_bytes()).unwrap_or(0) }
+            }).collect::<Vec<Fragment>>();
+            let lengths = fragments_index.entries.iter()
+                                         .map(|entry| entry.tag(6, 1).unwrap_or(0) as usize)
+                                         .collect::<Vec<usize>>();
+            parts = assemble_parts(markup, &skeletons, &fragments, &lengths);
+
+            let position = |fid: usize, offset: usize| {
+                fragments.get(fid).map(|f| f.insert_position + offset)
+            };
+
+            toc_positions = ncx.entries.iter().map(|entry| {
+                position(entry.tag(6, 0).unwrap_or(0) as usize, entry.tag(6, 1).unwrap_or(0) as usize)
+                        .unwrap_or(0)
+            }).collect::<Vec<usize>>();
+
+            let mut targets = toc_positions.clone();
+            let mut css_flows = FnvHashSet::default();
+            for part in &parts {
+                for caps in KINDLE_POS.captures_iter(&part.content) {
+                    if let (Some(fid), Some(offset)) = (base32(&caps[1]), base32(&caps[2])) {
+                        targets.extend(position(fid, offset));
+                    }
+                }
+                for caps in KINDLE_FLOW.captures_iter(&part.content) {
+                    css_flows.extend(base32(&caps[1]));
+                }
+            }
+
+            for part in &mut parts {
+                insert_anchors(part, &targets);
+                part.content = KINDLE_POS.replace_all(&part.content, |caps: &Captures| {
+                    let target = base32(&caps[1]).and_then(|fid| {
+                        base32(&caps[2]).and_then(|offset| position(fid, offset))
+                    }).unwrap_or(0);
+                    format!("#{}", anchor_id(target)).into_bytes()
+                }).into_owned();
+            }
+
+            let mut css_flows = css_flows.into_iter().collect::<Vec<usize>>();
+            css_flows.sort();
+            for i in css_flows {
+                if let Some(&(start, end)) = flows.get(i) {
+                    if let Some(css) = text.get(start..end.min(text.len())) {
+                        stylesheet.push_str(&decode(css, header.encoding));
+                        stylesheet.push('\n');
+                    }
+                }
+            }
+        } else {
+            let mut part = Part { start: 0, content: text };
+            toc_positions = ncx.entries.iter()
+                               .map(|entry| entry.tag(1, 0).unwrap_or(0) as usize)
+                               .collect::<Vec<usize>>();
+            let mut targets = toc_positions.clone();
+            targets.extend(FILEPOS.captures_iter(&part.content).filter_map(|caps| decimal(&caps[1])));
+            insert_anchors(&mut part, &targets);
+            part.content = FILEPOS.replace_all(&part.content, |caps: &Captures| {
+                format!("href=\"#{}\"", anchor_id(decimal(&caps[1]).unwrap_or(0))).into_bytes()
+            }).into_owned();
+            part.content = RECINDEX.replace_all(&part.content, |caps: &Captures| {
+                let name = decimal(&caps[1]).and_then(|n| image_names.get(&n))
+                                            .map(String::as_str).unwrap_or_default();
+                format!("src=\"{}\"", name).into_bytes()
+            }).into_owned();
+            parts = vec![part];
+        }
+
+        let mut body = String::new();
+
+        if !is_kf8 {
+            let cover = header.exth_u32(EXTH_COVER_OFFSET)
+                              .and_then(|offset| image_names.get(&(offset as usize + 1)));
+            if let Some(name) = cover {
+                body.push_str(&format!("<div class=\"cover\"><img src=\"{}\"/></div>", name));
+            }
+        }
+
+        for part in &parts {
+            let content = KINDLE_EMBED.replace_all(&part.content, |caps: &Captures| {
+                base32(&caps[1]).and_then(|n| image_names.get(&n))
+                                .cloned().unwrap_or_default().into_bytes()
+            });
+            let content = VOID_TAG.replace_all(&content, &b"<$1$2/>"[..]);
+            let content = PAGE_BREAK.replace_all(&content, &b"<div class=\"page-break\"></div>"[..]);
+            let text = decode(&content, header.encoding);
+            let class = if is_kf8 { "part" } else { "book" };
+            body.push_str(&format!("<div class=\"{}\">{}</div>", class, body_content(&text)));
+        }
+
+        let stylesheet = KINDLE_EMBED.replace_all(stylesheet.as_bytes(), |caps: &Captures| {
+            base32(&caps[1]).and_then(|n| image_names.get(&n))
+                            .cloned().unwrap_or_default().into_bytes()
+        });
+        let title = header.exth_string(EXTH_TITLE).unwrap_or_else(|| header.full_name.clone());
+        let language = header.exth_string(EXTH_LANGUAGE).unwrap_or_default();
+        let html = format!("<html xml:lang=\"{}\"><head><title>{}</title>\
+                            <style type=\"text/css\">\n\
+                            .part, .page-break {{ page-break-before: always; }}\n\
+                            .cover {{ text-align: center; }}\n\
+                            {}</style></head><body>{}</body></html>",
+                           language.replace('"', "&quot;"),
+                           title.replace('&', "&amp;").replace('<', "&lt;"),
+                           String::from_utf8_lossy(&stylesheet), body);
+
+        let toc = toc_entries(&ncx, &toc_positions, &html);
+
+        let mut doc = HtmlDocument::new_from_memory(&html);
+        doc.set_fetcher(resources);
+        doc.set_viewer_stylesheet(VIEWER_STYLESHEET);
+        doc.set_user_stylesheet(USER_STYLESHEET);
+
+        Ok(MobiDocument { doc, metadata: MobiMetadata(header), toc })
+    }
+}
+
+impl Document for MobiDocument {
+    delegate_to_html_document!();
+
+    fn title(&self) -> Option<String> {
+        self.metadata.title()
+    }
+
+    fn author(&self) -> Option<String> {
+        self.metadata.author()
+    }
+
+    fn metadata(&self, key: &str) -> Option<String> {
+        self.metadata.metadata(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use super::index::IndexEntry;
+
+    fn header_record(full_name: &str, exth: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut record = vec![0; 16 + 0xE8];
+        record[16..20].copy_from_slice(b"MOBI");
+        BigEndian::write_u32(&mut record[20..24], 0xE8);
+        BigEndian::write_u32(&mut record[28..32], 65001);
+        BigEndian::write_u32(&mut record[36..40], 6);
+        BigEndian::write_u32(&mut record[0x80..0x84], 0x40);
+
+        let mut entries = Vec::new();
+        for &(kind, value) in exth {
+            entries.extend_from_slice(&kind.to_be_bytes());
+            entries.extend_from_slice(&(8 + value.len() as u32).to_be_bytes());
+            entries.extend_from_slice(value);
+        }
+        record.extend_from_slice(b"EXTH");
+        record.extend_from_slice(&(12 + entries.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(exth.len() as u32).to_be_bytes());
+        record.extend_from_slice(&entries);
+
+        let full_name_offset = record.len() as u32;
+        BigEndian::write_u32(&mut record[84..88], full_name_offset);
+        BigEndian::write_u32(&mut record[88..92], full_name.len() as u32);
+        record.extend_from_slice(full_name.as_bytes());
+        record
+    }
+
+    fn palm_db(kind: &[u8], records: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0; PALMDB_HEADER_SIZE];
+        data[60..68].copy_from_slice(kind);
+        BigEndian::write_u16(&mut data[76..78], records.len() as u16);
+        let mut offset = PALMDB_HEADER_SIZE + 8 * records.len() + 2;
+        for record in records {
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+            offset += record.len();
+        }
+        data.extend_from_slice(&[0; 2]);
+        for record in records {
+            data.extend_from_slice(record);
+        }
+        data
+    }
+
+    fn index_entry(name: &str, tags: &[(u8, &[u32])]) -> IndexEntry {
+        IndexEntry {
+            name: name.to_string(),
+            tags: tags.iter().map(|&(tag, values)| (tag, values.to_vec())).collect(),
+        }
+    }
+
+    #[test]
+    fn test_exth() {
+        let record = header_record("Full Name", &[(EXTH_AUTHOR, b"Jane Doe"), (EXTH_TITLE, b"Title"),
+                                                  (EXTH_AUTHOR, b" John Roe "), (EXTH_SUBJECT, b" "),
+                                                  (EXTH_DATE, b"2019-05-01"), (EXTH_KF8_BOUNDARY, &[0, 0, 0, 5]),
+                                                  (EXTH_COVER_OFFSET, &[0xFF, 0xFF, 0xFF, 0xFF])]);
+        let header = MobiHeader::new(&record).unwrap();
+        assert_eq!(header.full_name, "Full Name");
+        assert_eq!(header.exth_strings(EXTH_AUTHOR), vec!["Jane Doe".to_string(), "John Roe".to_string()]);
+        assert!(header.exth_strings(EXTH_SUBJECT).is_empty());
+        assert_eq!(header.exth_u32(EXTH_KF8_BOUNDARY), Some(5));
+        assert_eq!(header.exth_u32(EXTH_COVER_OFFSET), None);
+
+        // The entries that overflow the record are ignored.
+        let mut record = header_record("", &[(EXTH_TITLE, b"Title"), (EXTH_PUBLISHER, b"Publisher")]);
+        let length = record.len();
+        BigEndian::write_u32(&mut record[length-13..length-9], 100);
+        let header = MobiHeader::new(&record).unwrap();
+        assert_eq!(header.exth_string(EXTH_TITLE), Some("Title".to_string()));
+        assert_eq!(header.exth_string(EXTH_PUBLISHER), None);
+    }
+
+    #[test]
+    fn test_header_record() {
+        let record = header_record("Full Name", &[(EXTH_AUTHOR, b"Jane Doe"), (EXTH_AUTHOR, b"John Roe"),
+                                                  (EXTH_DATE, b"2019-05-01"), (EXTH_SUBJECT, b"Fiction")]);
+        let data = palm_db(b"BOOKMOBI", &[&record, b"text"]);
+        let metadata = read_header_record(&mut Cursor::new(data)).and_then(|r| MobiHeader::new(&r))
+                                                                 .map(MobiMetadata).unwrap();
+        assert_eq!(metadata.title(), Some("Full Name".to_string()));
+        assert_eq!(metadata.author(), Some("Jane Doe, John Roe".to_string()));
+        assert_eq!(metadata.year(), Some("2019".to_string()));
+        assert_eq!(metadata.metadata("105"), Some("Fiction".to_string()));
+        assert_eq!(metadata.categories().into_iter().collect::<Vec<String>>(), vec!["Fiction".to_string()]);
+
+        let data = palm_db(b"BOOKMOBI", &[&record]);
+        assert_eq!(read_header_record(&mut Cursor::new(data)).ok(), Some(record.clone()));
+        let data = palm_db(b"TEXtREAd", &[&record]);
+        assert!(read_header_record(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn test_assemble_parts() {
+        let text = b"<html><body></body></html><p>A</p><p>B</p><html><body></body></html><p>C</p>";
+        let skeletons = Index {
+            entries: vec![index_entry("SKEL0000000", &[(1, &[2]), (6, &[0, 26])]),
+                          index_entry("SKEL0000001", &[(1, &[1]), (6, &[42, 26])])],
+            cncx: FnvHashMap::default(),
+        };
+        let fragments = [Fragment { insert_position: 12 },
+                         Fragment { insert_position: 20 },
+                         Fragment { insert_position: 54 }];
+        let parts = assemble_parts(text, &skeletons, &fragments, &[8, 8, 8]);
+        assert_eq!(parts.iter().map(|p| p.start).collect::<Vec<usize>>(), vec![0, 42]);
+        assert_eq!(String::from_utf8_lossy(&parts[0].content),
+                   "<html><body><p>A</p><p>B</p></body></html>");
+        assert_eq!(String::from_utf8_lossy(&parts[1].content),
+                   "<html><body><p>C</p></body></html>");
+    }
+
+    #[test]
+    fn test_trailing_entries() {
+        // A multibyte entry of two bytes, followed by an entry of three bytes.
+        assert_eq!(trailing_entries_size(b"text\xAA\x01\x00\x00\x83", 0b11), 5);
+        assert_eq!(trailing_entries_size(b"text\x00\x82", 0b10), 2);
+    }
+
+    #[test]
+    fn test_anchors() {
+        let mut part = Part { start: 10, content: b"<html><body><p class=\"a\">Text</p></body></html>".to_vec() };
+        insert_anchors(&mut part, &[12, 30, 35, 100]);
+        assert_eq!(String::from_utf8_lossy(&part.content),
+                   "<html><body><a id=\"pos12\"></a><a id=\"pos30\"></a><p class=\"a\">\
+                    <a id=\"pos35\"></a>Text</p></body></html>");
+    }
+}
@@ -1,26 +1,44 @@
 pub mod djvu;
 pub mod pdf;
 pub mod epub;
+#[macro_use]
 pub mod html;
+pub mod comic;
+pub mod text;
+pub mod fb2;
+pub mod mobi;
+pub mod passwords;
 
 mod djvulibre_sys;
 mod mupdf_sys;
+mod libarchive_sys;
 
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::ffi::OsStr;
-use fnv::FnvHashSet;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use fnv::{FnvHashSet, FnvHasher};
 use lazy_static::lazy_static;
 use unicode_normalization::UnicodeNormalization;
 use unicode_normalization::char::{is_combining_mark};
 use serde::{Serialize, Deserialize};
+use failure::{Error, format_err};
 use self::djvu::DjvuOpener;
 use self::pdf::PdfOpener;
 use self::epub::EpubDocument;
+use self::comic::ComicDocument;
+use self::text::open_text;
+use self::fb2::{Fb2Document, ZIPPED_FB2_KIND};
+use self::mobi::MobiDocument;
 use crate::geom::{Boundary, CycleDir};
-use crate::metadata::{TextAlign};
+use crate::metadata::{TextAlign, Annotation};
 use crate::framebuffer::Pixmap;
 
 pub const BYTES_PER_PAGE: f64 = 2048.0;
+// Number of bytes read at both ends of a file to compute its fingerprint.
+const FINGERPRINT_SAMPLE_SIZE: u64 = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub enum Location {
@@ -94,6 +112,7 @@ pub trait Document: Send+Sync {
     fn set_margin_width(&mut self, width: i32);
     fn set_text_align(&mut self, text_align: TextAlign);
     fn set_line_height(&mut self, line_height: f32);
+    fn set_embedded_fonts(&mut self, enable: bool);
 
     fn title(&self) -> Option<String>;
     fn author(&self) -> Option<String>;
@@ -101,10 +120,56 @@ pub trait Document: Send+Sync {
 
     fn is_reflowable(&self) -> bool;
 
+    fn is_protected(&self) -> bool {
+        false
+    }
+
+    // Returns whether the password unlocks the document.
+    fn authenticate(&mut self, _password: &str) -> bool {
+        true
+    }
+
     fn has_synthetic_page_numbers(&self) -> bool {
         false
     }
 
+    // The page number of the given location and the number of pages, when
+    // they're known for the current layout.
+    fn page_numbers(&mut self, _offset: usize) -> Option<(usize, usize)> {
+        None
+    }
+
+    // Computes the page numbers one step at a time, in the background.
+    // Returns whether there's more to compute.
+    fn paginate(&mut self) -> bool {
+        false
+    }
+
+    // The names of the pages of the print edition, by location.
+    fn page_names(&mut self) -> BTreeMap<usize, String> {
+        BTreeMap::new()
+    }
+
+    fn is_right_to_left(&self) -> bool {
+        false
+    }
+
+    fn footnote(&mut self, _offset: usize, _uri: &str) -> Option<String> {
+        None
+    }
+
+    // The annotations stored in the document file, on the given page. Returns
+    // `None` if the format doesn't support them.
+    fn annotations(&mut self, _index: usize) -> Option<Vec<Annotation>> {
+        None
+    }
+
+    // Writes the given annotations into the document file at *path*, and
+    // returns the number of annotations written.
+    fn save_annotations(&mut self, _annotations: &[Annotation], _path: &Path) -> Result<usize, Error> {
+        Err(format_err!("Unsupported document format."))
+    }
+
     fn resolve_location(&mut self, loc: Location) -> Option<usize> {
         if self.pages_count() == 0 {
             return None;
@@ -138,9 +203,19 @@ pub trait Document: Send+Sync {
 }
 
 pub fn file_kind<P: AsRef<Path>>(path: P) -> Option<String> {
-    path.as_ref().extension()
+    let path = path.as_ref();
+    path.extension()
         .and_then(OsStr::to_str)
         .map(str::to_lowercase)
+        .map(|kind| {
+            let is_zipped_fb2 = kind == "zip" && path.file_stem().and_then(OsStr::to_str)
+                                                     .map_or(false, |stem| stem.to_lowercase().ends_with(".fb2"));
+            if is_zipped_fb2 {
+                ZIPPED_FB2_KIND.to_string()
+            } else {
+                kind
+            }
+        })
 }
 
 pub trait HumanSize {
@@ -159,6 +234,24 @@ impl HumanSize for u64 {
     }
 }
 
+// Hashes the size and both ends of the file.
+pub fn fingerprint<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(size);
+    let mut buf = Vec::new();
+    file.by_ref().take(FINGERPRINT_SAMPLE_SIZE).read_to_end(&mut buf)?;
+    hasher.write(&buf);
+    if size > FINGERPRINT_SAMPLE_SIZE {
+        buf.clear();
+        file.seek(SeekFrom::Start(size.saturating_sub(FINGERPRINT_SAMPLE_SIZE).max(FINGERPRINT_SAMPLE_SIZE)))?;
+        file.read_to_end(&mut buf)?;
+        hasher.write(&buf);
+    }
+    Ok(format!("{:016X}", hasher.finish()))
+}
+
 pub fn asciify(name: &str) -> String {
     name.nfkd().filter(|&c| !is_combining_mark(c)).collect::<String>()
         .replace('œ', "oe")
@@ -172,8 +265,33 @@ pub fn asciify(name: &str) -> String {
 
 
 pub fn open<P: AsRef<Path>>(path: P) -> Option<Box<dyn Document>> {
+    if path.as_ref().is_dir() {
+        return ComicDocument::new(&path)
+                             .map_err(|e| eprintln!("{}: {}", path.as_ref().display(), e))
+                             .map(|d| Box::new(d) as Box<dyn Document>).ok();
+    }
     file_kind(path.as_ref()).and_then(|k| {
         match k.as_ref() {
+            "txt" | "md" | "markdown" => {
+                open_text(&path)
+                         .map_err(|e| eprintln!("{}: {}", path.as_ref().display(), e))
+                         .map(|d| Box::new(d) as Box<dyn Document>).ok()
+            },
+            "fb2" | ZIPPED_FB2_KIND => {
+                Fb2Document::new(&path)
+                            .map_err(|e| eprintln!("{}: {}", path.as_ref().display(), e))
+                            .map(|d| Box::new(d) as Box<dyn Document>).ok()
+            },
+            "mobi" | "azw" | "azw3" => {
+                MobiDocument::new(&path)
+                             .map_err(|e| eprintln!("{}: {}", path.as_ref().display(), e))
+                             .map(|d| Box::new(d) as Box<dyn Document>).ok()
+            },
+            "cbr" | "cb7" => {
+                ComicDocument::new(&path)
+                              .map_err(|e| eprintln!("{}: {}", path.as_ref().display(), e))
+                              .map(|d| Box::new(d) as Box<dyn Document>).ok()
+            },
             "epub" => {
                 EpubDocument::new(&path)
                              .map_err(|e| eprintln!("{}: {}.", path.as_ref().display(), e))
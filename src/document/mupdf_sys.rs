@@ -15,6 +15,12 @@ pub const FZ_TEXT_PRESERVE_IMAGES: libc::c_int = 4;
 pub const FZ_PAGE_BLOCK_TEXT: libc::c_int = 0;
 pub const FZ_PAGE_BLOCK_IMAGE: libc::c_int = 1;
 
+pub const PDF_ANNOT_TEXT: libc::c_int = 0;
+pub const PDF_ANNOT_HIGHLIGHT: libc::c_int = 8;
+pub const PDF_ANNOT_UNDERLINE: libc::c_int = 9;
+pub const PDF_ANNOT_SQUIGGLY: libc::c_int = 10;
+pub const PDF_ANNOT_STRIKE_OUT: libc::c_int = 11;
+
 pub const CACHE_SIZE: libc::size_t = 32 * 1024 * 1024;
 
 pub enum FzContext {}
@@ -31,6 +37,9 @@ pub enum FzCookie {}
 pub enum FzStoreDropFn {}
 pub enum FzSeparations {}
 pub enum FzImage {}
+pub enum FzPdfDocument {}
+pub enum FzPdfPage {}
+pub enum FzPdfAnnot {}
 
 #[link(name="mupdf")]
 #[link(name="mupdfwrapper")]
@@ -48,6 +57,7 @@ extern {
     pub fn mp_count_pages(ctx: *mut FzContext, doc: *mut FzDocument) -> libc::c_int;
     pub fn fz_lookup_metadata(ctx: *mut FzContext, doc: *mut FzDocument, key: *const libc::c_char, buf: *mut libc::c_char, size: libc::c_int) -> libc::c_int;
     pub fn fz_needs_password(ctx: *mut FzContext, doc: *mut FzDocument) -> libc::c_int;
+    pub fn mp_authenticate_password(ctx: *mut FzContext, doc: *mut FzDocument, password: *const libc::c_char) -> libc::c_int;
     pub fn fz_is_document_reflowable(ctx: *mut FzContext, doc: *mut FzDocument) -> libc::c_int;
     pub fn fz_layout_document(ctx: *mut FzContext, doc: *mut FzDocument, w: libc::c_float, h: libc::c_float, em: libc::c_float);
     pub fn mp_load_outline(ctx: *mut FzContext, doc: *mut FzDocument) -> *mut FzOutline;
@@ -72,6 +82,18 @@ extern {
     pub fn fz_union_rect(a: FzRect, b: FzRect) -> FzRect;
     pub fn fz_rect_from_quad(q: FzQuad) -> FzRect;
     pub fn fz_runetochar(buf: *mut u8, rune: libc::c_int) -> libc::c_int;
+    pub fn pdf_specifics(ctx: *mut FzContext, doc: *mut FzDocument) -> *mut FzPdfDocument;
+    pub fn pdf_page_from_fz_page(ctx: *mut FzContext, page: *mut FzPage) -> *mut FzPdfPage;
+    pub fn pdf_first_annot(ctx: *mut FzContext, page: *mut FzPdfPage) -> *mut FzPdfAnnot;
+    pub fn pdf_next_annot(ctx: *mut FzContext, annot: *mut FzPdfAnnot) -> *mut FzPdfAnnot;
+    pub fn mp_annot_type(ctx: *mut FzContext, annot: *mut FzPdfAnnot) -> libc::c_int;
+    pub fn mp_annot_contents(ctx: *mut FzContext, annot: *mut FzPdfAnnot) -> *const libc::c_char;
+    pub fn mp_annot_quad_point_count(ctx: *mut FzContext, annot: *mut FzPdfAnnot) -> libc::c_int;
+    pub fn mp_annot_quad_point(ctx: *mut FzContext, annot: *mut FzPdfAnnot, i: libc::c_int, quad: *mut FzQuad) -> libc::c_int;
+    pub fn mp_bound_annot(ctx: *mut FzContext, annot: *mut FzPdfAnnot) -> FzRect;
+    pub fn mp_create_highlight(ctx: *mut FzContext, page: *mut FzPdfPage, quads: *const FzQuad, count: libc::c_int, contents: *const libc::c_char) -> libc::c_int;
+    pub fn mp_save_document(ctx: *mut FzContext, doc: *mut FzPdfDocument, path: *const libc::c_char) -> libc::c_int;
+    pub fn mp_aes_crypt_cbc(key: *const libc::c_uchar, encrypt: libc::c_int, iv: *mut libc::c_uchar, input: *const libc::c_uchar, output: *mut libc::c_uchar, len: libc::size_t) -> libc::c_int;
     pub static fz_identity: FzMatrix;
 }
 
@@ -93,17 +115,23 @@ impl Default for FzRect {
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct FzPoint {
-    x: libc::c_float,
-    y: libc::c_float,
+    pub x: libc::c_float,
+    pub y: libc::c_float,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct FzQuad {
-    ul: FzPoint,
-    ur: FzPoint,
-    ll: FzPoint,
-    lr: FzPoint,
+    pub ul: FzPoint,
+    pub ur: FzPoint,
+    pub ll: FzPoint,
+    pub lr: FzPoint,
+}
+
+impl Default for FzQuad {
+    fn default() -> FzQuad {
+        unsafe { mem::zeroed() }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use rand::Rng;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use serde::{Serialize, Deserialize};
+use failure::Error;
+use crate::helpers::{load_json, save_json};
+use super::mupdf_sys::mp_aes_crypt_cbc;
+
+pub const PASSWORDS_PATH: &str = ".passwords.json";
+
+// This is obfuscation rather than protection: the key is derived from the
+// serial number of the device, which anyone holding the device can read.
+const DEVICE_INFO_PATH: &str = "/mnt/onboard/.kobo/version";
+const BLOCK_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct StoreData {
+    salt: String,
+    // The encrypted passwords, keyed by fingerprint.
+    passwords: BTreeMap<String, String>,
+}
+
+// The passwords of the protected documents. Those entered during the
+// current session are kept in memory, and the remembered ones are saved,
+// obfuscated, to disk.
+#[derive(Default)]
+pub struct PasswordStore {
+    path: PathBuf,
+    key: [u8; 32],
+    data: StoreData,
+    passwords: HashMap<String, String>,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&Sha256::digest(data));
+    digest
+}
+
+fn hmac_sha256(key: &[u8; 32], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.input(data);
+    mac
+}
+
+// The ciphertexts are authenticated with a distinct key.
+fn mac_key(key: &[u8; 32]) -> [u8; 32] {
+    let mut data = key.to_vec();
+    data.extend_from_slice(b"mac");
+    sha256(&data)
+}
+
+fn aes_cbc(key: &[u8; 32], encrypt: bool, iv: &[u8; BLOCK_SIZE], input: &[u8]) -> Option<Vec<u8>> {
+    let mut iv = *iv;
+    let mut output = vec![0; input.len()];
+    let ret = unsafe {
+        mp_aes_crypt_cbc(key.as_ptr(), encrypt as libc::c_int, iv.as_mut_ptr(),
+                         input.as_ptr(), output.as_mut_ptr(), input.len() as libc::size_t)
+    };
+    if ret == 0 {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+fn encrypt(key: &[u8; 32], text: &str) -> Option<String> {
+    let mut data = text.as_bytes().to_vec();
+    let padding = BLOCK_SIZE - data.len() % BLOCK_SIZE;
+    data.resize(data.len() + padding, padding as u8);
+    let iv = rand::thread_rng().gen::<[u8; BLOCK_SIZE]>();
+    let mut result = iv.to_vec();
+    result.extend(aes_cbc(key, true, &iv, &data)?);
+    let tag = hmac_sha256(&mac_key(key), &result).result().code();
+    result.extend_from_slice(&tag);
+    Some(base64::encode(&result))
+}
+
+fn decrypt(key: &[u8; 32], text: &str) -> Option<String> {
+    let data = base64::decode(text).ok()?;
+    if data.len() < 2 * BLOCK_SIZE + MAC_SIZE || (data.len() - MAC_SIZE) % BLOCK_SIZE != 0 {
+        return None;
+    }
+    let (data, tag) = data.split_at(data.len() - MAC_SIZE);
+    hmac_sha256(&mac_key(key), data).verify(tag).ok()?;
+    let mut iv = [0; BLOCK_SIZE];
+    iv.copy_from_slice(&data[..BLOCK_SIZE]);
+    let mut result = aes_cbc(key, false, &iv, &data[BLOCK_SIZE..])?;
+    let padding = *result.last()? as usize;
+    if padding == 0 || padding > BLOCK_SIZE ||
+       result[result.len() - padding..].iter().any(|&b| b as usize != padding) {
+        return None;
+    }
+    result.truncate(result.len() - padding);
+    String::from_utf8(result).ok()
+}
+
+fn device_secret() -> String {
+    fs::read_to_string(DEVICE_INFO_PATH).ok()
+       .and_then(|s| s.split(',').next().map(|s| s.trim().to_string()))
+       .unwrap_or_default()
+}
+
+impl PasswordStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> PasswordStore {
+        let path = path.as_ref();
+        let mut data = if path.exists() {
+            load_json::<StoreData, _>(path)
+                .map_err(|e| eprintln!("Can't load passwords: {}", e))
+                .unwrap_or_default()
+        } else {
+            StoreData::default()
+        };
+
+        if data.salt.is_empty() {
+            data.salt = base64::encode(&rand::thread_rng().gen::<[u8; BLOCK_SIZE]>());
+            data.passwords.clear();
+        }
+
+        let key = sha256(format!("{}{}", data.salt, device_secret()).as_bytes());
+        let passwords: HashMap<String, String> = data.passwords.iter()
+                                                     .filter_map(|(k, v)| decrypt(&key, v).map(|p| (k.clone(), p)))
+                                                     .collect();
+        // Forget the entries that can't be decrypted, so that they can be remembered anew.
+        data.passwords = data.passwords.into_iter()
+                             .filter(|(k, _)| passwords.contains_key(k))
+                             .collect();
+
+        PasswordStore {
+            path: path.to_path_buf(),
+            key,
+            data,
+            passwords,
+        }
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&str> {
+        self.passwords.get(fingerprint).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, fingerprint: String, password: String) {
+        self.passwords.insert(fingerprint, password);
+    }
+
+    // Saves the password of the given document to disk.
+    pub fn remember(&mut self, fingerprint: &str) -> Result<(), Error> {
+        if self.data.passwords.contains_key(fingerprint) {
+            return Ok(());
+        }
+        if let Some(text) = self.passwords.get(fingerprint).and_then(|p| encrypt(&self.key, p)) {
+            self.data.passwords.insert(fingerprint.to_string(), text);
+            save_json(&self.data, &self.path)?;
+        }
+        Ok(())
+    }
+
+    // Moves the password of a document whose file, and hence fingerprint, changed.
+    pub fn rename(&mut self, fingerprint: &str, new_fingerprint: &str) -> Result<(), Error> {
+        if let Some(password) = self.passwords.remove(fingerprint) {
+            self.passwords.insert(new_fingerprint.to_string(), password);
+        }
+        if let Some(text) = self.data.passwords.remove(fingerprint) {
+            self.data.passwords.insert(new_fingerprint.to_string(), text);
+            save_json(&self.data, &self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, fingerprint: &str) -> Result<(), Error> {
+        self.passwords.remove(fingerprint);
+        if self.data.passwords.remove(fingerprint).is_some() {
+            save_json(&self.data, &self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption() {
+        let key = sha256(b"salt");
+        let text = encrypt(&key, "correct horse").unwrap();
+        assert_eq!(decrypt(&key, &text).as_ref().map(String::as_str), Some("correct horse"));
+        assert_ne!(decrypt(&sha256(b"pepper"), &text).as_ref().map(String::as_str), Some("correct horse"));
+        let mut data = base64::decode(&text).unwrap();
+        data[BLOCK_SIZE] ^= 1;
+        assert_eq!(decrypt(&key, &base64::encode(&data)), None);
+    }
+}
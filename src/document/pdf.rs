@@ -9,10 +9,12 @@ use std::io::Read;
 use std::fs::File;
 use std::ffi::{CString, CStr};
 use std::os::unix::ffi::OsStrExt;
-use failure::Error;
+use fnv::FnvHashMap;
+use chrono::Local;
+use failure::{Error, format_err};
 use super::{Document, Location, TextLocation, BoundedText, TocEntry};
 use super::{chapter, chapter_relative};
-use crate::metadata::TextAlign;
+use crate::metadata::{TextAlign, Annotation};
 use crate::geom::{Boundary, CycleDir};
 use crate::unit::pt_to_px;
 use crate::framebuffer::Pixmap;
@@ -26,6 +28,17 @@ impl Into<Boundary> for FzRect {
     }
 }
 
+impl From<Boundary> for FzQuad {
+    fn from(rect: Boundary) -> FzQuad {
+        FzQuad {
+            ul: FzPoint { x: rect.min.x, y: rect.min.y },
+            ur: FzPoint { x: rect.max.x, y: rect.min.y },
+            ll: FzPoint { x: rect.min.x, y: rect.max.y },
+            lr: FzPoint { x: rect.max.x, y: rect.max.y },
+        }
+    }
+}
+
 struct PdfContext(*mut FzContext);
 
 pub struct PdfOpener(Rc<PdfContext>);
@@ -42,6 +55,56 @@ pub struct PdfPage<'a> {
     _doc: &'a PdfDocument,
 }
 
+struct PageAnnotation {
+    kind: libc::c_int,
+    rects: Vec<Boundary>,
+    contents: String,
+}
+
+fn center(rect: &Boundary) -> (f32, f32) {
+    ((rect.min.x + rect.max.x) / 2.0, (rect.min.y + rect.max.y) / 2.0)
+}
+
+// Merges the rectangles of the consecutive words that sit on the same line.
+fn line_rects<I>(rects: I) -> Vec<Boundary> where I: Iterator<Item=Boundary> {
+    let mut result: Vec<Boundary> = Vec::new();
+    for rect in rects {
+        if let Some(last) = result.last_mut() {
+            let overlap = last.max.y.min(rect.max.y) - last.min.y.max(rect.min.y);
+            if rect.min.x >= last.min.x && overlap > 0.5 * last.height().min(rect.height()) {
+                last.min.y = last.min.y.min(rect.min.y);
+                last.max.x = last.max.x.max(rect.max.x);
+                last.max.y = last.max.y.max(rect.max.y);
+                continue;
+            }
+        }
+        result.push(rect);
+    }
+    result
+}
+
+// The words covered by a markup annotation, or the word closest to a note.
+fn annotated_words<'a>(annot: &PageAnnotation, words: &'a [BoundedText]) -> Vec<&'a BoundedText> {
+    match annot.kind {
+        PDF_ANNOT_HIGHLIGHT | PDF_ANNOT_UNDERLINE | PDF_ANNOT_SQUIGGLY | PDF_ANNOT_STRIKE_OUT => {
+            words.iter().filter(|w| {
+                let (x, y) = center(&w.rect);
+                annot.rects.iter().any(|r| x >= r.min.x && x <= r.max.x && y >= r.min.y && y <= r.max.y)
+            }).collect()
+        },
+        PDF_ANNOT_TEXT => {
+            let (x0, y0) = annot.rects.first().map(center).unwrap_or_default();
+            let dist = |w: &BoundedText| {
+                let (x, y) = center(&w.rect);
+                (x - x0).powi(2) + (y - y0).powi(2)
+            };
+            words.iter().min_by(|a, b| dist(*a).partial_cmp(&dist(*b)).unwrap())
+                 .into_iter().collect()
+        },
+        _ => Vec::new(),
+    }
+}
+
 impl PdfOpener {
     pub fn new() -> Option<PdfOpener> {
         unsafe {
@@ -153,8 +216,25 @@ impl PdfDocument {
         }
     }
 
-    pub fn is_protected(&self) -> bool {
-        unsafe { fz_needs_password(self.ctx.0, self.doc) == 1 }
+    // The document is still read from the file it was opened from, hence a
+    // full copy is always written: an incremental update would be computed
+    // against the offsets of that file, which a previous save might have replaced.
+    // The copy is written to a temporary file next to the original one, and
+    // then renamed over it.
+    fn save(&mut self, path: &Path) -> Result<(), Error> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty())
+                      .unwrap_or_else(|| Path::new("."));
+        let tmp_path = tempfile::Builder::new().prefix(".").suffix(".plato-tmp")
+                                               .tempfile_in(dir)?.into_temp_path();
+        let c_path = CString::new(tmp_path.as_os_str().as_bytes())?;
+        unsafe {
+            let pdf = pdf_specifics(self.ctx.0, self.doc);
+            if mp_save_document(self.ctx.0, pdf, c_path.as_ptr()) != 0 {
+                return Err(format_err!("Can't write {}.", tmp_path.display()));
+            }
+        }
+        tmp_path.persist(path)?;
+        Ok(())
     }
 }
 
@@ -234,6 +314,116 @@ impl Document for PdfDocument {
         unsafe { fz_is_document_reflowable(self.ctx.0, self.doc) == 1 }
     }
 
+    fn is_protected(&self) -> bool {
+        unsafe { fz_needs_password(self.ctx.0, self.doc) == 1 }
+    }
+
+    fn authenticate(&mut self, password: &str) -> bool {
+        CString::new(password).map(|password| unsafe {
+            mp_authenticate_password(self.ctx.0, self.doc, password.as_ptr()) != 0
+        }).unwrap_or(false)
+    }
+
+    fn annotations(&mut self, index: usize) -> Option<Vec<Annotation>> {
+        if unsafe { pdf_specifics(self.ctx.0, self.doc).is_null() } {
+            return None;
+        }
+
+        let mut result = Vec::new();
+        let page = match self.page(index) {
+            Some(page) => page,
+            None => return Some(result),
+        };
+        let annots = page.annotations();
+        if annots.is_empty() {
+            return Some(result);
+        }
+
+        let words = page.words().unwrap_or_default();
+        for annot in &annots {
+            let selected = annotated_words(annot, &words);
+            if let (Some(first), Some(last)) = (selected.first(), selected.last()) {
+                let text = if annot.kind == PDF_ANNOT_TEXT {
+                    String::new()
+                } else {
+                    selected.iter().map(|w| w.text.as_str()).collect::<Vec<&str>>().join(" ")
+                };
+                result.push(Annotation {
+                    note: annot.contents.clone(),
+                    text,
+                    selection: [first.location, last.location],
+                    modified: Local::now(),
+                });
+            }
+        }
+
+        Some(result)
+    }
+
+    // The annotations already present in the file are skipped. The note of an
+    // annotation that spans several pages is attached to its first page.
+    fn save_annotations(&mut self, annotations: &[Annotation], path: &Path) -> Result<usize, Error> {
+        if unsafe { pdf_specifics(self.ctx.0, self.doc).is_null() } {
+            return Err(format_err!("Not a PDF document."));
+        }
+
+        // The annotations stored in the file, by page.
+        let mut existing: FnvHashMap<usize, Vec<Annotation>> = FnvHashMap::default();
+        let mut count = 0;
+
+        for annot in annotations {
+            let (start, end) = annot.selection[0].min_max(annot.selection[1]);
+            let (first_page, last_page) = match (start, end) {
+                (TextLocation::Static(first_page, _), TextLocation::Static(last_page, _)) => (first_page, last_page),
+                _ => continue,
+            };
+            if !existing.contains_key(&first_page) {
+                let annotations = self.annotations(first_page).unwrap_or_default();
+                existing.insert(first_page, annotations);
+            }
+            if existing[&first_page].iter().any(|a| a.selection == annot.selection) {
+                continue;
+            }
+            let note = CString::new(annot.note.replace('\0', ""))?;
+            let mut written = false;
+
+            for index in first_page..=last_page {
+                let page = match self.page(index) {
+                    Some(page) => page,
+                    None => continue,
+                };
+                let words = page.words().unwrap_or_default();
+                let rects = line_rects(words.iter()
+                                            .filter(|w| w.location >= start && w.location <= end)
+                                            .map(|w| w.rect));
+                if rects.is_empty() {
+                    continue;
+                }
+                let quads = rects.into_iter().map(FzQuad::from).collect::<Vec<FzQuad>>();
+                let contents = if written { ptr::null() } else { note.as_ptr() };
+                unsafe {
+                    let pdf_page = pdf_page_from_fz_page(self.ctx.0, page.page);
+                    if pdf_page.is_null() ||
+                       mp_create_highlight(self.ctx.0, pdf_page, quads.as_ptr(),
+                                           quads.len() as libc::c_int, contents) != 0 {
+                        return Err(format_err!("Can't create an annotation on page {}.", index + 1));
+                    }
+                }
+                written = true;
+            }
+
+            if written {
+                count += 1;
+            }
+        }
+
+        if count > 0 {
+            self.save(path)?;
+        }
+
+        Ok(count)
+    }
+
     fn layout(&mut self, width: u32, height: u32, font_size: f32, dpi: u16) {
         let em = pt_to_px(font_size, dpi);
         unsafe {
@@ -255,6 +445,9 @@ impl Document for PdfDocument {
 
     fn set_line_height(&mut self, _line_height: f32) {
     }
+
+    fn set_embedded_fonts(&mut self, _enable: bool) {
+    }
 }
 
 impl<'a> PdfPage<'a> {
@@ -382,6 +575,40 @@ impl<'a> PdfPage<'a> {
         }
     }
 
+    fn annotations(&self) -> Vec<PageAnnotation> {
+        let mut result = Vec::new();
+        unsafe {
+            let page = pdf_page_from_fz_page(self.ctx.0, self.page);
+            if page.is_null() {
+                return result;
+            }
+            let mut annot = pdf_first_annot(self.ctx.0, page);
+
+            while !annot.is_null() {
+                let kind = mp_annot_type(self.ctx.0, annot);
+                let contents = mp_annot_contents(self.ctx.0, annot);
+                let contents = if contents.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(contents).to_string_lossy().into_owned()
+                };
+                let mut rects = Vec::new();
+                for i in 0..mp_annot_quad_point_count(self.ctx.0, annot) {
+                    let mut quad = FzQuad::default();
+                    if mp_annot_quad_point(self.ctx.0, annot, i, &mut quad) == 0 {
+                        rects.push(fz_rect_from_quad(quad).into());
+                    }
+                }
+                if rects.is_empty() {
+                    rects.push(mp_bound_annot(self.ctx.0, annot).into());
+                }
+                result.push(PageAnnotation { kind, rects, contents });
+                annot = pdf_next_annot(self.ctx.0, annot);
+            }
+        }
+        result
+    }
+
     pub fn pixmap(&self, scale: f32) -> Option<Pixmap> {
         unsafe {
             let mat = fz_scale(scale as libc::c_float, scale as libc::c_float);
@@ -455,3 +682,18 @@ impl<'a> Drop for PdfPage<'a> {
         unsafe { fz_drop_page(self.ctx.0, self.page); }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_rects() {
+        let words = vec![bndr!(10.0, 10.0, 30.0, 20.0), bndr!(35.0, 11.0, 50.0, 21.0),
+                         bndr!(10.0, 30.0, 40.0, 40.0)];
+        let rects = line_rects(words.into_iter());
+        assert_eq!(rects.len(), 2);
+        assert_eq!((rects[0].min.y, rects[0].max.x, rects[0].max.y), (10.0, 50.0, 21.0));
+        assert_eq!(rects[1].min.x, 10.0);
+    }
+}
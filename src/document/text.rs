@@ -0,0 +1,182 @@
+use std::fs;
+use std::str;
+use std::path::Path;
+use failure::Error;
+use pulldown_cmark::{Parser, Options, Event, Tag, html};
+use super::file_kind;
+use super::html::HtmlDocument;
+
+const VIEWER_STYLESHEET: &str = "css/epub.css";
+const USER_STYLESHEET: &str = "css/epub-user.css";
+
+// The characters of Windows-1252 that differ from Latin-1.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn decode_utf16(bytes: &[u8], little_endian: bool) -> String {
+    let units = bytes.chunks_exact(2).map(|c| {
+        if little_endian {
+            u16::from_le_bytes([c[0], c[1]])
+        } else {
+            u16::from_be_bytes([c[0], c[1]])
+        }
+    }).collect::<Vec<u16>>();
+    String::from_utf16_lossy(&units)
+}
+
+// Text without a byte order mark is assumed to be UTF-16 if most of the
+// bytes at even or odd positions are null. Otherwise, if it isn't valid
+// UTF-8, it's decoded as Windows-1252.
+pub fn decode_text(bytes: &[u8]) -> String {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return String::from_utf8_lossy(&bytes[3..]).into_owned();
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        return decode_utf16(&bytes[2..], true);
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        return decode_utf16(&bytes[2..], false);
+    }
+
+    let half = bytes.len() / 2;
+    if half > 0 {
+        let odd_nulls = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+        let even_nulls = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+        if 2 * odd_nulls > half && even_nulls == 0 {
+            return decode_utf16(bytes, true);
+        } else if 2 * even_nulls > half && odd_nulls == 0 {
+            return decode_utf16(bytes, false);
+        }
+    }
+
+    match str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(..) => decode_windows_1252(bytes),
+    }
+}
+
+pub fn decode_windows_1252(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| {
+        if b >= 0x80 && b < 0xA0 {
+            WINDOWS_1252[(b - 0x80) as usize]
+        } else {
+            b as char
+        }
+    }).collect()
+}
+
+fn wrap_html(title: &str, body: &str) -> String {
+    format!("<html><head><title>{}</title></head><body>\n{}</body></html>",
+            escape(title), body)
+}
+
+// When the text has blank lines, they separate the paragraphs and the
+// other line breaks are considered to be hard wraps. Otherwise, each
+// line is a paragraph.
+pub fn text_to_html(text: &str, title: &str) -> String {
+    let has_blank_lines = text.lines().any(|line| line.trim().is_empty());
+    let mut body = String::new();
+    let mut paragraph = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || !has_blank_lines {
+            if !paragraph.is_empty() {
+                body.push_str(&format!("<p>{}</p>\n", escape(&paragraph.join(" "))));
+                paragraph.clear();
+            }
+            if line.is_empty() {
+                continue;
+            }
+        }
+        paragraph.push(line);
+    }
+
+    if !paragraph.is_empty() {
+        body.push_str(&format!("<p>{}</p>\n", escape(&paragraph.join(" "))));
+    }
+
+    wrap_html(title, &body)
+}
+
+// The title is given by the first level one heading, if any.
+pub fn markdown_to_html(text: &str, title: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    let events = Parser::new_ext(text, options).collect::<Vec<Event>>();
+
+    let mut heading = None;
+    let mut in_heading = false;
+    for event in &events {
+        match event {
+            Event::Start(Tag::Heading(1)) => {
+                in_heading = true;
+                heading = Some(String::new());
+            },
+            Event::End(Tag::Heading(1)) => break,
+            Event::Text(text) | Event::Code(text) if in_heading => {
+                if let Some(heading) = heading.as_mut() {
+                    heading.push_str(text);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    let mut body = String::new();
+    html::push_html(&mut body, events.into_iter());
+
+    let title = heading.filter(|h| !h.is_empty()).unwrap_or_else(|| title.to_string());
+    wrap_html(&title, &body)
+}
+
+pub fn open_text<P: AsRef<Path>>(path: P) -> Result<HtmlDocument, Error> {
+    let path = path.as_ref();
+    let text = decode_text(&fs::read(path)?);
+    let title = path.file_stem().map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+    let content = match file_kind(path).as_ref().map(String::as_str) {
+        Some("md") | Some("markdown") => markdown_to_html(&text, &title),
+        _ => text_to_html(&text, &title),
+    };
+    let mut doc = HtmlDocument::new_from_memory(&content);
+    doc.set_parent(path.parent().unwrap_or_else(|| Path::new("")));
+    doc.set_viewer_stylesheet(VIEWER_STYLESHEET);
+    doc.set_user_stylesheet(USER_STYLESHEET);
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paragraphs() {
+        let text = "First line\nwrapped.\n\nSecond & last.\n";
+        assert!(text_to_html(text, "T").contains("<p>First line wrapped.</p>\n<p>Second &amp; last.</p>"));
+        let text = "One.\r\nTwo.\r\n";
+        assert!(text_to_html(text, "T").contains("<p>One.</p>\n<p>Two.</p>"));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode_text(b"caf\xc3\xa9"), "café");
+        assert_eq!(decode_text(b"caf\xe9 \x93ok\x94"), "café “ok”");
+        assert_eq!(decode_text(b"\xff\xfea\x00b\x00"), "ab");
+        assert_eq!(decode_text(b"a\x00b\x00c\x00"), "abc");
+    }
+
+    #[test]
+    fn test_markdown_title() {
+        let html = markdown_to_html("Intro\n\n# The *Title*\n\nText.", "file");
+        assert!(html.contains("<title>The Title</title>"));
+        assert!(html.contains("<em>Title</em>"));
+    }
+}
@@ -12,6 +12,10 @@ mod helpers;
 mod dictionary;
 mod document;
 mod metadata;
+mod library;
+mod export;
+#[cfg(feature = "opds")]
+mod opds;
 mod settings;
 mod frontlight;
 mod lightsensor;
@@ -49,10 +53,14 @@ use crate::view::menu::{Menu, MenuKind};
 use crate::view::dictionary::Dictionary;
 use crate::view::calculator::Calculator;
 use crate::view::sketch::Sketch;
+#[cfg(feature = "opds")]
+use crate::view::opds::Opds;
+use crate::view::statistics::Statistics;
 use crate::view::common::{locate, locate_by_id, transfer_notifications, overlapping_rectangle};
 use crate::view::common::{toggle_input_history_menu, toggle_keyboard_layout_menu};
-use crate::helpers::{load_json, save_json, load_toml, save_toml};
-use crate::metadata::{Metadata, METADATA_FILENAME, auto_import};
+use crate::helpers::{load_toml, save_toml};
+use crate::metadata::{Info, auto_import};
+use crate::library::open_library;
 use crate::settings::{Settings, SETTINGS_PATH};
 use crate::geom::Rectangle;
 use crate::gesture::gesture_events;
@@ -70,19 +78,22 @@ const CLOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
 pub fn build_context(fb: Box<dyn Framebuffer>) -> Result<Context, Error> {
     let settings = load_toml::<Settings, _>(SETTINGS_PATH)?;
-    let path = settings.library_path.join(METADATA_FILENAME);
-    let mut metadata = load_json::<Metadata, _>(path)?;
+    let filename = PathBuf::from(settings.library_backend.filename());
+    let mut library = open_library(settings.library_path.join(&filename))?;
+    let mut metadata = library.load()?;
     if settings.import.startup_trigger {
-        let imported_metadata = auto_import(&settings.library_path,
-                                            &metadata,
-                                            &settings.import);
-        metadata.append(&mut imported_metadata.unwrap_or_default());
+        let mut imported_metadata = auto_import(&settings.library_path,
+                                                &metadata,
+                                                &settings.import,
+                                                &settings.reader.export_path).unwrap_or_default();
+        library.update(&imported_metadata.iter().collect::<Vec<&Info>>())?;
+        metadata.append(&mut imported_metadata);
     }
     let battery = Box::new(FakeBattery::new()) as Box<dyn Battery>;
     let frontlight = Box::new(LightLevels::default()) as Box<dyn Frontlight>;
     let lightsensor = Box::new(0u16) as Box<dyn LightSensor>;
     let fonts = Fonts::load()?;
-    Ok(Context::new(fb, None, settings, metadata, PathBuf::from(METADATA_FILENAME),
+    Ok(Context::new(fb, None, settings, metadata, library, filename,
                     fonts, battery, frontlight, lightsensor))
 }
 
@@ -228,6 +239,7 @@ pub fn run() -> Result<(), Error> {
 
     context.load_dictionaries();
     context.load_keyboard_layouts();
+    context.load_passwords();
 
     let (tx, rx) = mpsc::channel();
     let (ty, ry) = mpsc::channel();
@@ -378,6 +390,13 @@ pub fn run() -> Result<(), Error> {
                         AppCmd::Dictionary { ref query, ref language } => {
                             Box::new(Dictionary::new(context.fb.rect(), query, language, &tx, &mut context))
                         },
+                        #[cfg(feature = "opds")]
+                        AppCmd::Opds => {
+                            Box::new(Opds::new(context.fb.rect(), &tx, &mut context))
+                        },
+                        AppCmd::Statistics => {
+                            Box::new(Statistics::new(context.fb.rect(), &tx, &mut context))
+                        },
                     };
                     transfer_notifications(view.as_mut(), next_view.as_mut(), &mut context);
                     history.push(view as Box<dyn View>);
@@ -476,6 +495,25 @@ pub fn run() -> Result<(), Error> {
                                                   msg, &tx, &mut context);
                     view.children_mut().push(Box::new(notif) as Box<dyn View>);
                 },
+                // The password prompt belongs to the home view.
+                Event::AskPassword(..) if !view.is::<Home>() => {
+                    view.handle_event(&Event::Back, &tx, &mut bus, &mut context);
+                    while let Some(mut v) = history.pop() {
+                        if history.is_empty() {
+                            view = v;
+                            break;
+                        }
+                        v.handle_event(&Event::Back, &tx, &mut bus, &mut context);
+                    }
+                    if context.display.rotation % 2 != 1 {
+                        if let Ok(dims) = context.fb.set_rotation(DEFAULT_ROTATION) {
+                            context.display.rotation = DEFAULT_ROTATION;
+                            context.display.dims = dims;
+                        }
+                    }
+                    view.handle_event(&Event::Reseed, &tx, &mut bus, &mut context);
+                    tx.send(evt).ok();
+                },
                 Event::AddDocument(..) | Event::RemoveDocument(..) => {
                     if view.is::<Home>() {
                         view.handle_event(&evt, &tx, &mut bus, &mut context);
@@ -535,8 +573,7 @@ pub fn run() -> Result<(), Error> {
         context.settings.frontlight_levels = context.frontlight.levels();
     }
 
-    let path = context.settings.library_path.join(&context.filename);
-    save_json(&context.metadata, path).context("Can't save metadata.")?;
+    context.library.flush(&context.metadata).context("Can't save metadata.")?;
 
     let path = Path::new(SETTINGS_PATH);
     save_toml(&context.settings, path).context("Can't save settings.")?;
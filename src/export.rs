@@ -0,0 +1,285 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use fnv::FnvHasher;
+use chrono::{DateTime, Local};
+use failure::{Error, ResultExt};
+use serde::Serialize;
+use crate::document::{Document, asciify};
+use crate::metadata::Info;
+use crate::helpers::simple_date_format;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookExport {
+    pub title: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<AnnotationExport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bookmarks: Vec<BookmarkExport>,
+    #[serde(skip)]
+    pub reflowable: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnotationExport {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub text: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub note: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chapter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_name: Option<String>,
+    pub location: usize,
+    #[serde(with = "simple_date_format")]
+    pub modified: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkExport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chapter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_name: Option<String>,
+    pub location: usize,
+}
+
+impl BookExport {
+    pub fn new(info: &Info, doc: &mut dyn Document) -> BookExport {
+        let toc = doc.toc().unwrap_or_default();
+        let reflowable = doc.is_reflowable();
+        let mut annotations = Vec::new();
+        let mut bookmarks = Vec::new();
+
+        if let Some(ref r) = info.reader {
+            for annot in &r.annotations {
+                let location = annot.selection[0].location();
+                annotations.push(AnnotationExport {
+                    text: annot.text.clone(),
+                    note: annot.note.clone(),
+                    chapter: doc.chapter(location, &toc).map(|chap| chap.title.clone()),
+                    page_name: page_name(&r.page_names, location, reflowable),
+                    location,
+                    modified: annot.modified,
+                });
+            }
+
+            for &location in &r.bookmarks {
+                bookmarks.push(BookmarkExport {
+                    chapter: doc.chapter(location, &toc).map(|chap| chap.title.clone()),
+                    page_name: page_name(&r.page_names, location, reflowable),
+                    location,
+                });
+            }
+        }
+
+        annotations.sort_by_key(|annot| annot.location);
+
+        BookExport {
+            title: info.title.clone(),
+            author: info.author.clone(),
+            path: info.file.path.clone(),
+            annotations,
+            bookmarks,
+            reflowable,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.annotations.is_empty() && self.bookmarks.is_empty()
+    }
+
+    fn heading(&self) -> String {
+        if self.title.is_empty() {
+            self.path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            self.title.clone()
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut buf = format!("# {}\n\n", escape_markdown(&self.heading()));
+
+        if !self.author.is_empty() {
+            buf.push_str(&format!("*{}*\n\n", escape_markdown(&self.author)));
+        }
+
+        if !self.annotations.is_empty() {
+            buf.push_str("## Annotations\n\n");
+            let mut chapter = None;
+            for annot in &self.annotations {
+                if annot.chapter.is_some() && annot.chapter != chapter {
+                    chapter = annot.chapter.clone();
+                    buf.push_str(&format!("### {}\n\n", escape_markdown(chapter.as_ref().unwrap())));
+                }
+                for line in annot.text.lines() {
+                    buf.push_str(&format!("> {}\n", escape_markdown(line)));
+                }
+                if !annot.text.is_empty() {
+                    buf.push('\n');
+                }
+                for line in annot.note.lines() {
+                    buf.push_str(&format!("{}\n", escape_markdown(line)));
+                }
+                if !annot.note.is_empty() {
+                    buf.push('\n');
+                }
+                buf.push_str(&format!("— {}\n\n", escape_markdown(&self.position(annot.page_name.as_ref(), annot.location))));
+            }
+        }
+
+        if !self.bookmarks.is_empty() {
+            buf.push_str("## Bookmarks\n\n");
+            for bkm in &self.bookmarks {
+                let position = escape_markdown(&self.position(bkm.page_name.as_ref(), bkm.location));
+                match bkm.chapter {
+                    Some(ref chapter) => buf.push_str(&format!("- {}, {}\n", escape_markdown(chapter), position)),
+                    None => buf.push_str(&format!("- {}\n", position)),
+                }
+            }
+        }
+
+        buf
+    }
+
+    pub fn to_html(&self) -> String {
+        let heading = escape_html(&self.heading());
+        let mut buf = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", heading, heading);
+
+        if !self.author.is_empty() {
+            buf.push_str(&format!("<p class=\"author\">{}</p>\n", escape_html(&self.author)));
+        }
+
+        if !self.annotations.is_empty() {
+            buf.push_str("<h2>Annotations</h2>\n");
+            let mut chapter = None;
+            for annot in &self.annotations {
+                if annot.chapter.is_some() && annot.chapter != chapter {
+                    chapter = annot.chapter.clone();
+                    buf.push_str(&format!("<h3>{}</h3>\n", escape_html(chapter.as_ref().unwrap())));
+                }
+                if !annot.text.is_empty() {
+                    buf.push_str(&format!("<blockquote>{}</blockquote>\n", escape_html(&annot.text)));
+                }
+                if !annot.note.is_empty() {
+                    buf.push_str(&format!("<p class=\"note\">{}</p>\n", escape_html(&annot.note)));
+                }
+                buf.push_str(&format!("<p class=\"position\">{}</p>\n",
+                                      escape_html(&self.position(annot.page_name.as_ref(), annot.location))));
+            }
+        }
+
+        if !self.bookmarks.is_empty() {
+            buf.push_str("<h2>Bookmarks</h2>\n<ul>\n");
+            for bkm in &self.bookmarks {
+                let position = self.position(bkm.page_name.as_ref(), bkm.location);
+                match bkm.chapter {
+                    Some(ref chapter) => buf.push_str(&format!("<li>{}, {}</li>\n", escape_html(chapter), escape_html(&position))),
+                    None => buf.push_str(&format!("<li>{}</li>\n", escape_html(&position))),
+                }
+            }
+            buf.push_str("</ul>\n");
+        }
+
+        buf.push_str("</body>\n</html>\n");
+        buf
+    }
+
+    // The locations of fixed-layout documents are page indices.
+    fn position(&self, page_name: Option<&String>, location: usize) -> String {
+        match page_name {
+            Some(name) => format!("page {}", name),
+            None if self.reflowable => format!("location {}", location),
+            None => format!("page {}", location + 1),
+        }
+    }
+
+    // The name of the exported files. Books with the same title are told
+    // apart by a hash of their path.
+    fn file_name(&self) -> String {
+        let mut hasher = FnvHasher::default();
+        self.path.hash(&mut hasher);
+        format!("{} {:08X}", asciify(&self.heading()).replace('/', " "), hasher.finish() as u32)
+    }
+
+    // Writes the Markdown, HTML and JSON versions of the export in *dir*.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+           .with_context(|_| format!("Can't create directory {}.", dir.display()))?;
+        let name = self.file_name();
+        fs::write(dir.join(format!("{}.md", name)), self.to_markdown())?;
+        fs::write(dir.join(format!("{}.html", name)), self.to_html())?;
+        fs::write(dir.join(format!("{}.json", name)), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+fn page_name(page_names: &BTreeMap<usize, String>, location: usize, reflowable: bool) -> Option<String> {
+    let (index, name) = page_names.range(..=location).next_back()?;
+    if *index == location || reflowable {
+        Some(name.clone())
+    } else {
+        name.parse::<usize>().ok()
+            .map(|n| (n + location - index).to_string())
+    }
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len());
+    for c in text.chars() {
+        if ['\\', '*', '_', '#', '`'].contains(&c) {
+            buf.push('\\');
+        }
+        buf.push(c);
+    }
+    buf
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(title: &str, path: &str, reflowable: bool) -> BookExport {
+        BookExport {
+            title: title.to_string(),
+            author: "A_B".to_string(),
+            path: PathBuf::from(path),
+            annotations: Vec::new(),
+            bookmarks: vec![BookmarkExport { chapter: Some("#1 *Start*".to_string()), page_name: None, location: 0 }],
+            reflowable,
+        }
+    }
+
+    #[test]
+    fn test_markdown() {
+        let markdown = export("C# `code`", "a.pdf", false).to_markdown();
+        assert_eq!(markdown, "# C\\# \\`code\\`\n\n*A\\_B*\n\n## Bookmarks\n\n- \\#1 \\*Start\\*, page 1\n");
+        let markdown = export("Title", "a.epub", true).to_markdown();
+        assert!(markdown.ends_with("- \\#1 \\*Start\\*, location 0\n"));
+    }
+
+    #[test]
+    fn test_file_name() {
+        let a = export("Title", "a/book.epub", true).file_name();
+        let b = export("Title", "b/book.epub", true).file_name();
+        assert!(a.starts_with("Title "));
+        assert_ne!(a, b);
+        assert_eq!(a, export("Title", "a/book.epub", true).file_name());
+    }
+}
@@ -879,18 +879,27 @@ impl Font {
     }
 
     #[inline]
-    unsafe fn patch(&mut self, txt: &str, features: &[HbFeature], render_plan: &mut RenderPlan, missing_glyphs: Vec<(usize, usize)>, buf: *mut HbBuffer) {
+    unsafe fn patch(&mut self, txt: &str, features: &[HbFeature], render_plan: &mut RenderPlan, missing_glyphs: Vec<(usize, usize)>, buf: *mut HbBuffer, rtl: bool) {
         let mut drift = 0;
         for (mut start, mut end) in missing_glyphs.into_iter() {
             start = (start as i32 + drift).max(0) as usize;
             end = (end as i32 + drift).max(0) as usize;
             hb_buffer_clear_contents(buf);
-            let start_index = render_plan.glyphs[start].cluster;
-            let end_index = render_plan.glyphs.get(end).map(|g| g.cluster)
-                                       .unwrap_or_else(|| txt.len());
+            let (start_index, end_index) = if rtl {
+                (render_plan.glyphs[end-1].cluster,
+                 start.checked_sub(1).map(|i| render_plan.glyphs[i].cluster)
+                      .unwrap_or_else(|| txt.len()))
+            } else {
+                (render_plan.glyphs[start].cluster,
+                 render_plan.glyphs.get(end).map(|g| g.cluster)
+                            .unwrap_or_else(|| txt.len()))
+            };
             let chunk = &txt[start_index..end_index];
             hb_buffer_add_utf8(buf, chunk.as_ptr() as *const libc::c_char,
                                chunk.len() as libc::c_int, 0, -1);
+            if rtl {
+                hb_buffer_set_direction(buf, HB_DIRECTION_RTL);
+            }
             hb_buffer_guess_segment_properties(buf);
             let mut script = hb_buffer_get_script(buf);
             if script == HB_SCRIPT_INVALID || script == HB_SCRIPT_UNKNOWN {
@@ -932,13 +941,22 @@ impl Font {
     }
 
     pub fn plan(&mut self, txt: &str, max_width: Option<u32>, features: Option<&[String]>) -> RenderPlan {
+        self.shape(txt, max_width, features, HB_DIRECTION_LTR)
+    }
+
+    // The glyphs are given in visual order and their clusters decrease.
+    pub fn plan_rtl(&mut self, txt: &str, features: Option<&[String]>) -> RenderPlan {
+        self.shape(txt, None, features, HB_DIRECTION_RTL)
+    }
+
+    fn shape(&mut self, txt: &str, max_width: Option<u32>, features: Option<&[String]>, direction: HbDirection) -> RenderPlan {
         unsafe {
             let buf = hb_buffer_create();
             hb_buffer_add_utf8(buf, txt.as_ptr() as *const libc::c_char,
                                txt.len() as libc::c_int, 0, -1);
 
             // If the direction is RTL, the clusters are given in reverse order.
-            hb_buffer_set_direction(buf, HB_DIRECTION_LTR);
+            hb_buffer_set_direction(buf, direction);
             hb_buffer_guess_segment_properties(buf);
 
             let features_vec: Vec<HbFeature> = features.map(|ftr|
@@ -989,7 +1007,7 @@ impl Font {
                 render_plan.glyphs.push(glyph);
             }
 
-            self.patch(txt, &features_vec, &mut render_plan, missing_glyphs, buf);
+            self.patch(txt, &features_vec, &mut render_plan, missing_glyphs, buf, direction == HB_DIRECTION_RTL);
 
             hb_buffer_destroy(buf);
 
@@ -23,6 +23,7 @@ pub enum DiagDir {
 pub enum Axis {
     Horizontal,
     Vertical,
+    Diagonal,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
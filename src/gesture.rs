@@ -169,19 +169,21 @@ pub fn parse_gesture_events(rx: &Receiver<DeviceEvent>, ty: &Sender<Event>) {
                                 })).unwrap();
                             },
                             (GestureEvent::Swipe { dir: d1, start: s1, end: e1, .. },
-                             GestureEvent::Swipe { dir: d2, start: s2, end: e2, .. }) if d1 == d2.opposite() => {
+                             GestureEvent::Swipe { dir: d2, start: s2, end: e2, .. }) if d1 == d2.opposite() ||
+                                                                                         is_diagonal_pinch(s1, e1, s2, e2) => {
                                 let ds = (s2 - s1).length();
                                 let de = (e2 - e1).length();
+                                let axis = if d1 == d2.opposite() { d1.axis() } else { Axis::Diagonal };
                                 if ds > de {
                                     ty.send(Event::Gesture(GestureEvent::Pinch {
-                                        axis: d1.axis(),
+                                        axis,
                                         starts: [s1, s2],
                                         ends: [e1, e2],
                                         strength: (ds - de) as u32,
                                     })).unwrap();
                                 } else {
                                     ty.send(Event::Gesture(GestureEvent::Spread {
-                                        axis: d1.axis(),
+                                        axis,
                                         starts: [s1, s2],
                                         ends: [e1, e2],
                                         strength: (de - ds) as u32,
@@ -249,6 +251,14 @@ pub fn parse_gesture_events(rx: &Receiver<DeviceEvent>, ty: &Sender<Event>) {
     }
 }
 
+// Two fingers moving in roughly opposite directions along a diagonal are
+// classified as perpendicular swipes.
+fn is_diagonal_pinch(s1: Point, e1: Point, s2: Point, e2: Point) -> bool {
+    let v1: Vec2 = (e1 - s1).into();
+    let v2: Vec2 = (e2 - s2).into();
+    v1.dot(v2) < 0.0 && v1.dot(v2).abs() > 0.5 * v1.length() * v2.length()
+}
+
 fn interpret_segment(sp: &[Point], tap_jitter: f32) -> GestureEvent {
     let a = sp[0];
     let b = sp[sp.len()-1];
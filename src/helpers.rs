@@ -19,6 +19,32 @@ lazy_static! {
     };
 }
 
+// The references that HTML documents may use without their semicolon.
+const LEGACY_ENTITIES: [&str; 14] = ["amp", "lt", "gt", "quot", "nbsp", "copy", "reg", "shy",
+                                     "deg", "middot", "laquo", "raquo", "para", "sect"];
+
+// Decodes the character reference at the start of the given text.
+// Returns the decoded text and the length of the reference.
+pub fn decode_reference(text: &str) -> Option<(String, usize)> {
+    if !text.starts_with('&') {
+        return None;
+    }
+    let prefix_len = if text[1..].starts_with('#') { 1 } else { 0 };
+    let name = &text[1+prefix_len..];
+    let name_len = name.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or_else(|| name.len());
+    if name_len == 0 {
+        return None;
+    }
+    let end = 1 + prefix_len + name_len;
+    if text[end..].starts_with(';') {
+        Some((decode_entities(&text[..=end]).into_owned(), end + 1))
+    } else if prefix_len > 0 || LEGACY_ENTITIES.contains(&&name[..name_len]) {
+        Some((decode_entities(&format!("{};", &text[..end])).into_owned(), end))
+    } else {
+        None
+    }
+}
+
 pub fn decode_entities(text: &str) -> Cow<str> {
     if text.find('&').is_none() {
         return Cow::Borrowed(text);
@@ -59,6 +85,29 @@ pub fn decode_entities(text: &str) -> Cow<str> {
     Cow::Owned(buf)
 }
 
+// Decodes the percent-encoded octets of the given text.
+pub fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[i+1..i+3]).ok()
+                 .and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        if let Some(b) = byte {
+            result.push(b);
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
 pub fn load_json<T, P: AsRef<Path>>(path: P) -> Result<T, Error> where for<'a> T: Deserialize<'a> {
     let file = File::open(path).context("Can't open file.")?;
     serde_json::from_reader(file).context("Can't parse file.").map_err(Into::into)
@@ -87,6 +136,19 @@ where F1: FnMut(&T, &T) -> Ordering + 'a,
     })
 }
 
+pub fn format_duration(secs: u64) -> String {
+    let minutes = (secs + 30) / 60;
+    if minutes == 0 {
+        "< 1 min".to_string()
+    } else if minutes < 60 {
+        format!("{} min", minutes)
+    } else if minutes % 60 == 0 {
+        format!("{} h", minutes / 60)
+    } else {
+        format!("{} h {} min", minutes / 60, minutes % 60)
+    }
+}
+
 pub trait Normalize: ToOwned {
     fn normalize(&self) -> Self::Owned;
 }
@@ -151,4 +213,28 @@ mod tests {
         assert_eq!(decode_entities("a &#38; b"), "a & b");
         assert_eq!(decode_entities("a &lt; b &gt; c"), "a < b > c");
     }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(decode_reference("&amp; b"), Some(("&".to_string(), 5)));
+        assert_eq!(decode_reference("&nbsp b"), Some(("\u{a0}".to_string(), 5)));
+        assert_eq!(decode_reference("&#160 b"), Some(("\u{a0}".to_string(), 5)));
+        assert_eq!(decode_reference("&A and B;"), None);
+        assert_eq!(decode_reference("& b"), None);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%2Fc"), b"a b/c");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9"), "été".as_bytes());
+        assert_eq!(percent_decode("100% %zz %4"), b"100% %zz %4");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(10), "< 1 min");
+        assert_eq!(format_duration(40), "1 min");
+        assert_eq!(format_duration(3600), "1 h");
+        assert_eq!(format_duration(5 * 3600 + 7 * 60), "5 h 7 min");
+    }
 }
@@ -8,6 +8,8 @@ mod helpers;
 mod font;
 mod document;
 mod metadata;
+mod library;
+mod export;
 mod settings;
 mod frontlight;
 mod lightsensor;
@@ -17,16 +19,21 @@ use std::env;
 use std::fs;
 use std::process;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use failure::{Error, ResultExt, format_err};
 use regex::Regex;
 use getopts::Options;
 use titlecase::titlecase;
 use crate::helpers::{load_json, save_json};
-use crate::settings::{ImportSettings, CategoryProvider};
-use crate::metadata::{Info, Metadata, METADATA_FILENAME, IMPORTED_MD_FILENAME};
-use crate::metadata::{import, extract_metadata_from_epub, extract_metadata_from_filename, clean_up};
+use crate::settings::{ImportSettings, ReaderSettings, CategoryProvider, DEFAULT_FONT_SIZE};
+use crate::metadata::{Info, Metadata, METADATA_FILENAME, IMPORTED_MD_FILENAME, DATABASE_FILENAME};
+use crate::metadata::{import, extract_metadata_from_epub, extract_metadata_from_fb2,
+                      extract_metadata_from_mobi, extract_metadata_from_comic};
+use crate::metadata::{extract_metadata_from_filename, clean_up};
 use crate::document::{open, asciify};
+use crate::device::CURRENT_DEVICE;
+use crate::export::BookExport;
+use crate::library::{Library, SqliteLibrary};
 
 fn run() -> Result<(), Error> {
     let args: Vec<String> = env::args().skip(1).collect();
@@ -35,7 +42,7 @@ fn run() -> Result<(), Error> {
 
     opts.optflag("h", "help", "Print this help message.");
     opts.optflag("I", "import", "Import new books.");
-    opts.optflag("M", "extract-metadata-epub", "Extract metadata from ePUBs.");
+    opts.optflag("M", "extract-metadata-epub", "Extract metadata from ePUBs, FB2s, MOBIs and comics.");
     opts.optflag("F", "extract-metadata-filename", "Extract metadata from filenames.");
     opts.optflag("C", "consolidate", "Consolidate an existing database.");
     opts.optflag("N", "rename", "Rename files based on their info.");
@@ -43,16 +50,19 @@ fn run() -> Result<(), Error> {
     opts.optflag("U", "clean-up", "Remove entries with dangling paths.");
     opts.optflag("G", "merge", "Merge the imported entries into the library.");
     opts.optflag("Z", "initialize", "Initialize a database.");
+    opts.optflag("E", "export-annotations", "Export annotations and bookmarks.");
+    opts.optflag("D", "migrate-database", "Migrate the metadata to an SQLite database.");
     opts.optflag("t", "traverse-hidden", "Traverse hidden directories.");
     opts.optopt("a", "allowed-kinds", "Comma separated list of allowed kinds.", "ALLOWED_KINDS");
     opts.optopt("c", "category-providers", "Comma separated list of category providers.", "CATEGORY_PROVIDERS");
     opts.optopt("i", "input", "Input file name.", "INPUT_NAME");
     opts.optopt("o", "output", "Output file name.", "OUTPUT_NAME");
+    opts.optopt("e", "export-path", "Export directory.", "EXPORT_PATH");
 
     let matches = opts.parse(&args).context("Failed to parse the command line arguments.")?;
 
     if matches.opt_present("h") {
-        println!("{}", opts.usage("Usage: plato-import -h|-I|-M|-F|-C|-N|-U|-G|-Z|-Y [-t] [-a ALLOWED_KINDS] [-c CATEGORY_PROVIDERS] [-i INPUT_NAME] [-o OUTPUT_NAME] LIBRARY_PATH [DEST_LIBRARY_PATH]"));
+        println!("{}", opts.usage("Usage: plato-import -h|-I|-M|-F|-C|-N|-U|-G|-Z|-Y|-E|-D [-t] [-a ALLOWED_KINDS] [-c CATEGORY_PROVIDERS] [-i INPUT_NAME] [-o OUTPUT_NAME] [-e EXPORT_PATH] LIBRARY_PATH [DEST_LIBRARY_PATH]"));
         return Ok(());
     }
 
@@ -74,6 +84,8 @@ fn run() -> Result<(), Error> {
     if let Some(category_providers) = matches.opt_str("c").map(|v| v.split(',').filter_map(|k| CategoryProvider::from_str(k)).collect()) {
         import_settings.category_providers = category_providers;
     }
+    let export_path = matches.opt_str("e").map(PathBuf::from)
+                             .unwrap_or_else(|| ReaderSettings::default().export_path);
 
     if matches.opt_present("Z") {
         if input_path.exists() {
@@ -83,7 +95,7 @@ fn run() -> Result<(), Error> {
         }
     } else if matches.opt_present("I") {
         let metadata = load_json(input_path)?;
-        let metadata = import(library_path, &metadata, &import_settings)?;
+        let metadata = import(library_path, &metadata, &import_settings, &export_path)?;
         save_json(&metadata, output_path)?;
     } else if matches.opt_present("G") {
         let dest_library_path = matches.free.get(1).map(|s| Path::new(s))
@@ -105,11 +117,20 @@ fn run() -> Result<(), Error> {
         let metadata = load_json(&output_path)?;
         let dest_library_path = Path::new(&matches.free[1]);
         synchronize(library_path, dest_library_path, &metadata);
+    } else if matches.opt_present("E") {
+        let metadata = load_json(&input_path)?;
+        export_annotations(library_path, &library_path.join(export_path), &metadata);
+    } else if matches.opt_present("D") {
+        let metadata = load_json(&input_path)?;
+        migrate_database(&library_path.join(DATABASE_FILENAME), &metadata)?;
     } else {
         let mut metadata = load_json(&output_path)?;
 
         if matches.opt_present("M") {
             extract_metadata_from_epub(library_path, &mut metadata, &import_settings);
+            extract_metadata_from_fb2(library_path, &mut metadata, &import_settings);
+            extract_metadata_from_mobi(library_path, &mut metadata, &import_settings);
+            extract_metadata_from_comic(library_path, &mut metadata, &import_settings);
         }
 
         if matches.opt_present("F") {
@@ -208,6 +229,38 @@ pub fn synchronize(src_dir: &Path, dest_dir: &Path, metadata: &Metadata) {
     }
 }
 
+pub fn export_annotations(library_path: &Path, export_path: &Path, metadata: &Metadata) {
+    for info in metadata {
+        if info.reader.as_ref().map_or(true, |r| r.annotations.is_empty() && r.bookmarks.is_empty()) {
+            continue;
+        }
+
+        let path = library_path.join(&info.file.path);
+
+        if let Some(mut doc) = open(&path) {
+            let (width, height) = CURRENT_DEVICE.dims;
+            let font_size = info.reader.as_ref().and_then(|r| r.font_size)
+                                .unwrap_or(DEFAULT_FONT_SIZE);
+            doc.layout(width, height, font_size, CURRENT_DEVICE.dpi);
+            let export = BookExport::new(info, doc.as_mut());
+            if let Err(e) = export.save(export_path) {
+                println!("Can't export annotations from {}: {}.", path.display(), e);
+            } else {
+                println!("{}", info.file.path.display());
+            }
+        } else {
+            println!("Can't open {}.", path.display());
+        }
+    }
+}
+
+pub fn migrate_database(path: &Path, metadata: &Metadata) -> Result<(), Error> {
+    let mut library = SqliteLibrary::open(path)?;
+    library.save(metadata)?;
+    println!("{} entries written to {}.", metadata.len(), path.display());
+    Ok(())
+}
+
 pub fn file_name_from_info(info: &Info) -> String {
     if info.title.is_empty() {
         return "".to_string();
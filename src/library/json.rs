@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use failure::{Error, ResultExt};
+use crate::helpers::{load_json, save_json};
+use crate::metadata::Metadata;
+use super::Library;
+
+pub struct JsonLibrary {
+    path: PathBuf,
+}
+
+impl JsonLibrary {
+    pub fn new<P: AsRef<Path>>(path: P) -> JsonLibrary {
+        JsonLibrary {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Library for JsonLibrary {
+    fn load(&mut self) -> Result<Metadata, Error> {
+        load_json(&self.path)
+    }
+
+    // The data is written to a temporary file first, so that an interrupted
+    // write can't leave a truncated database behind.
+    fn save(&mut self, metadata: &Metadata) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        save_json(metadata, &tmp_path)?;
+        fs::rename(&tmp_path, &self.path)
+          .with_context(|_| format!("Can't rename {} to {}.", tmp_path.display(), self.path.display()))?;
+        Ok(())
+    }
+}
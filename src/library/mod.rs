@@ -0,0 +1,68 @@
+mod json;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::path::{Path, PathBuf};
+use std::collections::BTreeSet;
+use failure::Error;
+use crate::metadata::{Info, Metadata, SortMethod, SimpleStatus};
+
+pub use self::json::JsonLibrary;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteLibrary;
+
+#[derive(Debug, Clone)]
+pub struct Query<'a> {
+    pub status: Option<SimpleStatus>,
+    pub selected_categories: &'a BTreeSet<String>,
+    pub negated_categories: &'a BTreeSet<String>,
+    pub sort_method: SortMethod,
+    pub reverse_order: bool,
+}
+
+// The storage backend of the metadata. The metadata itself is kept in memory,
+// the backends that have indexes answer the queries of the home view.
+pub trait Library {
+    fn load(&mut self) -> Result<Metadata, Error>;
+
+    // Replaces the stored entries with the given metadata.
+    fn save(&mut self, metadata: &Metadata) -> Result<(), Error>;
+
+    // Persists the metadata before the storage is left alone, e.g. when suspending.
+    // Backends that write the changes as they happen have nothing left to do.
+    fn flush(&mut self, metadata: &Metadata) -> Result<(), Error> {
+        self.save(metadata)
+    }
+
+    // The following methods are used to persist changes as they happen.
+    // Backends that can't write partial changes rely on *flush* instead.
+    fn update(&mut self, _infos: &[&Info]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn remove(&mut self, _paths: &[&Path]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Releases the underlying resources, e.g. before sharing the storage.
+    fn close(&mut self) {
+    }
+
+    // Returns the sorted paths of the entries matching the status and the
+    // categories of the query, if the backend can answer it through its indexes.
+    fn query(&mut self, _query: &Query) -> Option<Vec<PathBuf>> {
+        None
+    }
+}
+
+// Picks the backend based on the extension of *path*.
+pub fn open_library<P: AsRef<Path>>(path: P) -> Result<Box<dyn Library>, Error> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") | Some("db") => Ok(Box::new(SqliteLibrary::open(path)?) as Box<dyn Library>),
+        #[cfg(not(feature = "sqlite"))]
+        Some("sqlite") | Some("db") => Err(failure::format_err!("SQLite support isn't enabled: {}.", path.display())),
+        _ => Ok(Box::new(JsonLibrary::new(path)) as Box<dyn Library>),
+    }
+}
@@ -0,0 +1,369 @@
+use std::path::{Path, PathBuf};
+use rusqlite::{Connection, Transaction, params, NO_PARAMS};
+use failure::{Error, ResultExt};
+use crate::metadata::{Info, Metadata, SortMethod, SimpleStatus, Status};
+use crate::symbolic_path::PATH_SEPARATOR;
+use super::{Library, Query};
+
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS books (
+        path TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        author TEXT NOT NULL,
+        year TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        status INTEGER NOT NULL,
+        progress REAL NOT NULL,
+        added TEXT NOT NULL,
+        opened TEXT,
+        pages INTEGER,
+        info TEXT NOT NULL,
+        reader TEXT
+    );
+    CREATE TABLE IF NOT EXISTS categories (
+        path TEXT NOT NULL REFERENCES books(path) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        PRIMARY KEY (path, name)
+    );
+    CREATE INDEX IF NOT EXISTS books_title ON books(title);
+    CREATE INDEX IF NOT EXISTS books_author ON books(author);
+    CREATE INDEX IF NOT EXISTS books_kind ON books(kind);
+    CREATE INDEX IF NOT EXISTS books_status ON books(status);
+    CREATE INDEX IF NOT EXISTS books_added ON books(added);
+    CREATE INDEX IF NOT EXISTS books_opened ON books(opened);
+    CREATE INDEX IF NOT EXISTS categories_name ON categories(name);
+";
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Each entry is stored as two JSON documents: the reader information, which
+// changes every time a book is opened, and the rest. The other columns hold
+// the sort keys and the status used by the home view queries, and the
+// categories are stored in their own table. The changes are written as they
+// happen, so there's nothing to do when flushing.
+pub struct SqliteLibrary {
+    path: PathBuf,
+    conn: Option<Connection>,
+}
+
+fn status_code(status: SimpleStatus) -> i64 {
+    match status {
+        SimpleStatus::New => 0,
+        SimpleStatus::Reading => 1,
+        SimpleStatus::Finished => 2,
+    }
+}
+
+// Follows the order of *sort_progress*: Finished < New < Reading.
+fn progress_key(info: &Info) -> f64 {
+    match info.status() {
+        Status::Finished => -2.0,
+        Status::New => -1.0,
+        Status::Reading(progress) => f64::from(progress),
+    }
+}
+
+fn sort_column(sort_method: SortMethod) -> &'static str {
+    match sort_method {
+        SortMethod::Opened => "opened",
+        SortMethod::Added => "added",
+        SortMethod::Progress => "progress",
+        SortMethod::Author => "author",
+        SortMethod::Title => "title",
+        SortMethod::Year => "year",
+        SortMethod::Size => "size",
+        SortMethod::Kind => "kind",
+        SortMethod::Pages => "pages",
+    }
+}
+
+// The descendants of *categ* are the names within [categ + sep, categ + succ(sep)).
+fn descendants_bounds(categ: &str) -> (String, String) {
+    let next_separator = (PATH_SEPARATOR as u8 + 1) as char;
+    (format!("{}{}", categ, PATH_SEPARATOR), format!("{}{}", categ, next_separator))
+}
+
+fn write_entry(tx: &Transaction, info: &Info) -> Result<(), Error> {
+    let path = info.file.path.to_string_lossy().into_owned();
+    let reader = info.reader.as_ref().map(serde_json::to_string).transpose()?;
+    let opened = info.reader.as_ref().map(|r| r.opened.format(DATE_FORMAT).to_string());
+    let pages = info.reader.as_ref().map(|r| r.pages_count as i64);
+    let data = serde_json::to_string(&Info { reader: None, .. info.clone() })?;
+    tx.execute("DELETE FROM categories WHERE path = ?1", params![path])?;
+    tx.execute("INSERT OR REPLACE INTO books (path, title, author, year, kind, size, status, progress,
+                                              added, opened, pages, info, reader)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+               params![path, info.alphabetic_title(), info.alphabetic_author(), info.year,
+                       info.file.kind, info.file.size as i64, status_code(info.simple_status()),
+                       progress_key(info), info.added.format(DATE_FORMAT).to_string(),
+                       opened, pages, data, reader])?;
+    for categ in &info.categories {
+        tx.execute("INSERT INTO categories (path, name) VALUES (?1, ?2)", params![path, categ])?;
+    }
+    Ok(())
+}
+
+fn read_entries(conn: &Connection) -> Result<Metadata, Error> {
+    let mut metadata = Vec::new();
+    let mut stmt = conn.prepare("SELECT info, reader FROM books")?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+    })?;
+
+    for row in rows {
+        let (info, reader) = row?;
+        let mut info: Info = serde_json::from_str(&info)?;
+        if let Some(ref reader) = reader {
+            info.reader = Some(serde_json::from_str(reader)?);
+        }
+        metadata.push(info);
+    }
+
+    Ok(metadata)
+}
+
+// The databases written before the indexed columns existed only hold the
+// JSON documents: the entries are read back and rewritten with the new schema.
+fn upgrade(conn: &mut Connection) -> Result<(), Error> {
+    let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+    let has_books: bool = conn.query_row("SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'books'",
+                                         NO_PARAMS, |row| row.get(0))?;
+    let tx = conn.transaction()?;
+    let metadata = if has_books {
+        let metadata = read_entries(&tx)?;
+        tx.execute("DROP TABLE books", NO_PARAMS)?;
+        metadata
+    } else {
+        Vec::new()
+    };
+    tx.execute_batch(SCHEMA)?;
+    for info in &metadata {
+        write_entry(&tx, info)?;
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    tx.commit()?;
+    Ok(())
+}
+
+// The connection is reopened lazily after having been closed.
+fn connect<'a>(path: &Path, conn: &'a mut Option<Connection>) -> Result<&'a mut Connection, Error> {
+    if conn.is_none() {
+        let mut c = Connection::open(path)
+                               .with_context(|_| format!("Can't open database {}.", path.display()))?;
+        upgrade(&mut c).context("Can't upgrade the database schema.")?;
+        c.execute_batch(SCHEMA).context("Can't create the database schema.")?;
+        *conn = Some(c);
+    }
+    Ok(conn.as_mut().unwrap())
+}
+
+impl SqliteLibrary {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteLibrary, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut conn = None;
+        connect(&path, &mut conn)?;
+        Ok(SqliteLibrary { path, conn })
+    }
+}
+
+impl Library for SqliteLibrary {
+    fn load(&mut self) -> Result<Metadata, Error> {
+        let conn = connect(&self.path, &mut self.conn)?;
+        read_entries(conn)
+    }
+
+    fn save(&mut self, metadata: &Metadata) -> Result<(), Error> {
+        let conn = connect(&self.path, &mut self.conn)?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM books", NO_PARAMS)?;
+        for info in metadata {
+            write_entry(&tx, info)?;
+        }
+        tx.commit().context("Can't commit the changes.")?;
+        Ok(())
+    }
+
+    fn flush(&mut self, _metadata: &Metadata) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn update(&mut self, infos: &[&Info]) -> Result<(), Error> {
+        if infos.is_empty() {
+            return Ok(());
+        }
+        let conn = connect(&self.path, &mut self.conn)?;
+        let tx = conn.transaction()?;
+        for info in infos {
+            write_entry(&tx, info)?;
+        }
+        tx.commit().context("Can't commit the changes.")?;
+        Ok(())
+    }
+
+    fn remove(&mut self, paths: &[&Path]) -> Result<(), Error> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let conn = connect(&self.path, &mut self.conn)?;
+        let tx = conn.transaction()?;
+        for path in paths {
+            tx.execute("DELETE FROM books WHERE path = ?1", params![path.to_string_lossy().into_owned()])?;
+        }
+        tx.commit().context("Can't commit the changes.")?;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.conn = None;
+    }
+
+    fn query(&mut self, query: &Query) -> Option<Vec<PathBuf>> {
+        let mut clauses = Vec::new();
+        let mut args = Vec::new();
+
+        if let Some(status) = query.status {
+            clauses.push(format!("status = {}", status_code(status)));
+        }
+
+        for (categs, prefix) in &[(query.selected_categories, "EXISTS"),
+                                  (query.negated_categories, "NOT EXISTS")] {
+            for categ in categs.iter() {
+                clauses.push(format!("{} (SELECT 1 FROM categories WHERE categories.path = books.path AND \
+                                      (categories.name = ? OR (categories.name >= ? AND categories.name < ?)))", prefix));
+                let (start, end) = descendants_bounds(categ);
+                args.push(categ.clone());
+                args.push(start);
+                args.push(end);
+            }
+        }
+
+        let order = if query.reverse_order { "DESC" } else { "ASC" };
+        let sql = format!("SELECT path FROM books{}{} ORDER BY {} {}, path",
+                          if clauses.is_empty() { "" } else { " WHERE " },
+                          clauses.join(" AND "), sort_column(query.sort_method), order);
+
+        let conn = connect(&self.path, &mut self.conn)
+                       .map_err(|e| eprintln!("Can't connect: {}", e)).ok()?;
+        let mut stmt = conn.prepare(&sql)
+                           .map_err(|e| eprintln!("Can't prepare query: {}", e)).ok()?;
+        let rows = stmt.query_map(&args, |row| row.get::<_, String>(0))
+                       .map_err(|e| eprintln!("Can't run query: {}", e)).ok()?;
+        rows.map(|row| row.map(PathBuf::from))
+            .collect::<Result<Vec<PathBuf>, _>>()
+            .map_err(|e| eprintln!("Can't read query results: {}", e)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use crate::metadata::{FileInfo, ReaderInfo};
+
+    fn book(path: &str, categories: &[&str], reader: Option<ReaderInfo>) -> Info {
+        Info {
+            title: path.to_string(),
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            file: FileInfo {
+                path: PathBuf::from(path),
+                kind: "epub".to_string(),
+                size: 0,
+            },
+            reader,
+            .. Default::default()
+        }
+    }
+
+    fn query<'a>(status: Option<SimpleStatus>, selected: &'a BTreeSet<String>, negated: &'a BTreeSet<String>) -> Query<'a> {
+        Query {
+            status,
+            selected_categories: selected,
+            negated_categories: negated,
+            sort_method: SortMethod::Title,
+            reverse_order: false,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut library = SqliteLibrary::open(":memory:").unwrap();
+        let mut metadata = vec![book("a.epub", &["Fiction.Novels"], None),
+                                book("b.epub", &[], Some(ReaderInfo::default()))];
+        library.save(&metadata).unwrap();
+        let loaded = library.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.iter().any(|info| info.file.path == PathBuf::from("b.epub") && info.reader.is_some()));
+
+        metadata.remove(0);
+        library.save(&metadata).unwrap();
+        assert_eq!(library.load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_partial_changes() {
+        let mut library = SqliteLibrary::open(":memory:").unwrap();
+        let a = book("a.epub", &["Fiction"], None);
+        let mut b = book("b.epub", &[], None);
+        library.update(&[&a, &b]).unwrap();
+        b.reader = Some(ReaderInfo::default());
+        library.update(&[&b]).unwrap();
+        library.remove(&[Path::new("a.epub")]).unwrap();
+        let loaded = library.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].reader.is_some());
+
+        let empty = BTreeSet::new();
+        let mut selected = BTreeSet::new();
+        selected.insert("Fiction".to_string());
+        assert!(library.query(&query(None, &selected, &empty)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_query() {
+        let mut library = SqliteLibrary::open(":memory:").unwrap();
+        let metadata = vec![book("c.epub", &["Fiction.Novels"], None),
+                            book("a.epub", &["Fiction"], Some(ReaderInfo::default())),
+                            book("b.epub", &["Fiction_Old", "fiction.Short"], None)];
+        library.save(&metadata).unwrap();
+
+        let empty = BTreeSet::new();
+        let paths = library.query(&query(None, &empty, &empty)).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("a.epub"), PathBuf::from("b.epub"), PathBuf::from("c.epub")]);
+
+        let mut selected = BTreeSet::new();
+        selected.insert("Fiction".to_string());
+        let paths = library.query(&query(None, &selected, &empty)).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("a.epub"), PathBuf::from("c.epub")]);
+
+        let paths = library.query(&query(Some(SimpleStatus::New), &selected, &empty)).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("c.epub")]);
+
+        let paths = library.query(&query(None, &empty, &selected)).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("b.epub")]);
+
+        let paths = library.query(&Query { sort_method: SortMethod::Opened,
+                                           reverse_order: true,
+                                           .. query(None, &empty, &empty) }).unwrap();
+        assert_eq!(paths[0], PathBuf::from("a.epub"));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE books (path TEXT PRIMARY KEY, info TEXT NOT NULL, reader TEXT);").unwrap();
+        let info = serde_json::to_string(&book("a.epub", &["Fiction"], None)).unwrap();
+        conn.execute("INSERT INTO books (path, info) VALUES (?1, ?2)", params!["a.epub", info]).unwrap();
+        upgrade(&mut conn).unwrap();
+        let names: i64 = conn.query_row("SELECT COUNT(*) FROM categories WHERE name = 'Fiction'",
+                                        NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(names, 1);
+        assert_eq!(read_entries(&conn).unwrap().len(), 1);
+    }
+}
@@ -12,6 +12,10 @@ mod helpers;
 mod dictionary;
 mod document;
 mod metadata;
+mod library;
+mod export;
+#[cfg(feature = "opds")]
+mod opds;
 mod symbolic_path;
 mod rtc;
 mod settings;
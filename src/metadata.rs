@@ -12,12 +12,16 @@ use regex::Regex;
 use failure::{Error, ResultExt};
 use crate::document::{Document, SimpleTocEntry, TextLocation};
 use crate::document::epub::EpubDocument;
+use crate::document::fb2::{Fb2Description, ZIPPED_FB2_KIND};
+use crate::document::mobi::MobiMetadata;
+use crate::document::comic::{ComicDocument, IMAGES_DIRECTORY_KIND, COMIC_INFO_NAME, is_image};
 use crate::helpers::simple_date_format;
 use crate::settings::{ImportSettings, CategoryProvider};
 use crate::document::file_kind;
 use crate::symbolic_path;
 
 pub const METADATA_FILENAME: &str = ".metadata.json";
+pub const DATABASE_FILENAME: &str = ".metadata.sqlite";
 pub const IMPORTED_MD_FILENAME: &str = ".metadata-imported.json";
 pub const TRASH_NAME: &str = ".trash";
 
@@ -186,6 +190,14 @@ impl TextAlign {
             TextAlign::Center => "align-center",
         }
     }
+
+    pub fn mirror(&self) -> TextAlign {
+        match self {
+            TextAlign::Left => TextAlign::Right,
+            TextAlign::Right => TextAlign::Left,
+            _ => *self,
+        }
+    }
 }
 
 impl fmt::Display for TextAlign {
@@ -207,6 +219,12 @@ pub struct ReaderInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_offset: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub left_offset: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zoom_factor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rotation: Option<i8>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cropping_margins: Option<CroppingMargins>,
@@ -223,6 +241,8 @@ pub struct ReaderInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub line_height: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedded_fonts: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub contrast_exponent: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub contrast_gray: Option<f32>,
@@ -232,18 +252,67 @@ pub struct ReaderInfo {
     pub bookmarks: BTreeSet<usize>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub annotations: Vec<Annotation>,
+    // The next page whose annotations have to be imported from the document file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations_import: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<ReadingSession>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadingSession {
+    #[serde(with = "simple_date_format")]
+    pub start: DateTime<Local>,
+    #[serde(with = "simple_date_format")]
+    pub end: DateTime<Local>,
+    // Reading time in seconds, idle periods excluded.
+    pub duration: u64,
+    // Number of page turns.
+    pub pages: usize,
+    // Distance covered forward, in locations.
+    pub progress: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ZoomMode {
     FitToPage,
     FitToWidth,
+    Reflow,
+    Custom,
+    Columns,
 }
 
 impl ReaderInfo {
     pub fn progress(&self) -> f32 {
         (self.current_page / self.pages_count) as f32
     }
+
+    pub fn reading_time(&self) -> u64 {
+        self.sessions.iter().map(|s| s.duration).sum()
+    }
+
+    pub fn pages_read(&self) -> usize {
+        self.sessions.iter().map(|s| s.pages).sum()
+    }
+
+    // Average reading time, in seconds, per location.
+    pub fn reading_speed(&self) -> Option<f32> {
+        reading_speed(&self.sessions)
+    }
+}
+
+// Below this amount of reading time, in seconds, the estimates aren't meaningful.
+const MIN_SAMPLE_DURATION: u64 = 120;
+
+pub fn reading_speed<'a, I>(sessions: I) -> Option<f32> where I: IntoIterator<Item=&'a ReadingSession> {
+    let (duration, progress) = sessions.into_iter()
+                                       .fold((0, 0), |(d, p), s| (d + s.duration, p + s.progress));
+    if duration < MIN_SAMPLE_DURATION || progress == 0 {
+        None
+    } else {
+        Some(duration as f32 / progress as f32)
+    }
 }
 
 impl Default for ReaderInfo {
@@ -255,6 +324,9 @@ impl Default for ReaderInfo {
             finished: false,
             zoom_mode: None,
             top_offset: None,
+            left_offset: None,
+            view_index: None,
+            zoom_factor: None,
             rotation: None,
             cropping_margins: None,
             margin_width: None,
@@ -263,11 +335,14 @@ impl Default for ReaderInfo {
             font_size: None,
             text_align: None,
             line_height: None,
+            embedded_fonts: None,
             contrast_exponent: None,
             contrast_gray: None,
             page_names: BTreeMap::new(),
             bookmarks: BTreeSet::new(),
             annotations: Vec::new(),
+            annotations_import: None,
+            sessions: Vec::new(),
         }
     }
 }
@@ -573,14 +648,19 @@ lazy_static! {
     ].iter().cloned().collect();
 }
 
-pub fn auto_import(dir: &Path, metadata: &Metadata, settings: &ImportSettings) -> Result<Metadata, Error> {
-    let mut imported_metadata = import(dir, metadata, settings)?;
+pub fn auto_import(dir: &Path, metadata: &Metadata, settings: &ImportSettings, export_path: &Path) -> Result<Metadata, Error> {
+    let mut imported_metadata = import(dir, metadata, settings, export_path)?;
     extract_metadata_from_epub(dir, &mut imported_metadata, settings);
+    extract_metadata_from_fb2(dir, &mut imported_metadata, settings);
+    extract_metadata_from_mobi(dir, &mut imported_metadata, settings);
+    extract_metadata_from_comic(dir, &mut imported_metadata, settings);
     Ok(imported_metadata)
 }
 
-pub fn import(dir: &Path, metadata: &Metadata, settings: &ImportSettings) -> Result<Metadata, Error> {
-    let files = find_files(dir, dir, settings.traverse_hidden)?;
+pub fn import(dir: &Path, metadata: &Metadata, settings: &ImportSettings, export_path: &Path) -> Result<Metadata, Error> {
+    let images_directories = settings.allowed_kinds.contains(IMAGES_DIRECTORY_KIND);
+    let export_path = dir.join(export_path);
+    let files = find_files(dir, dir, &export_path, settings.traverse_hidden, images_directories)?;
     let known: FnvHashSet<PathBuf> = metadata.iter()
                                              .map(|info| info.file.path.clone())
                                              .collect();
@@ -640,6 +720,136 @@ pub fn extract_metadata_from_epub(dir: &Path, metadata: &mut Metadata, settings:
     }
 }
 
+pub fn extract_metadata_from_fb2(dir: &Path, metadata: &mut Metadata, settings: &ImportSettings) {
+    let genres_as_categories = settings.category_providers.contains(&CategoryProvider::Subject);
+
+    for info in metadata {
+        if !info.title.is_empty() || (info.file.kind != "fb2" && info.file.kind != ZIPPED_FB2_KIND) {
+            continue;
+        }
+
+        let path = dir.join(&info.file.path);
+
+        match Fb2Description::new(&path) {
+            Ok(doc) => {
+                info.title = doc.title().unwrap_or_default();
+                info.author = doc.author().unwrap_or_default();
+                info.year = doc.year().unwrap_or_default();
+                info.publisher = doc.publisher().unwrap_or_default();
+                info.isbn = doc.isbn().unwrap_or_default();
+                info.series = doc.series().unwrap_or_default();
+                if !info.series.is_empty() {
+                    info.number = doc.series_index().unwrap_or_default();
+                }
+                info.language = doc.language().unwrap_or_default();
+                if genres_as_categories {
+                    info.categories.append(&mut doc.categories());
+                }
+                println!("{}", info.label());
+            },
+            Err(e) => eprintln!("{}: {}", info.file.path.display(), e),
+        }
+    }
+}
+
+pub fn extract_metadata_from_mobi(dir: &Path, metadata: &mut Metadata, settings: &ImportSettings) {
+    let subjects_as_categories = settings.category_providers.contains(&CategoryProvider::Subject);
+
+    for info in metadata {
+        if !info.title.is_empty() || !["mobi", "azw", "azw3"].contains(&info.file.kind.as_str()) {
+            continue;
+        }
+
+        let path = dir.join(&info.file.path);
+
+        match MobiMetadata::new(&path) {
+            Ok(doc) => {
+                info.title = doc.title().unwrap_or_default();
+                info.author = doc.author().unwrap_or_default();
+                info.year = doc.year().unwrap_or_default();
+                info.publisher = doc.publisher().unwrap_or_default();
+                info.isbn = doc.isbn().unwrap_or_default();
+                info.language = doc.language().unwrap_or_default();
+                if subjects_as_categories {
+                    info.categories.append(&mut doc.categories());
+                }
+                println!("{}", info.label());
+            },
+            Err(e) => eprintln!("{}: {}", info.file.path.display(), e),
+        }
+    }
+}
+
+pub fn extract_metadata_from_comic(dir: &Path, metadata: &mut Metadata, settings: &ImportSettings) {
+    let genres_as_categories = settings.category_providers.contains(&CategoryProvider::Subject);
+
+    for info in metadata {
+        if !info.title.is_empty() || !["cbz", "cbr", "cb7", IMAGES_DIRECTORY_KIND].contains(&info.file.kind.as_str()) {
+            continue;
+        }
+
+        let path = dir.join(&info.file.path);
+
+        match ComicDocument::new(&path) {
+            Ok(doc) => {
+                if !doc.has_comic_info() {
+                    continue;
+                }
+                info.title = doc.title().unwrap_or_default();
+                info.author = doc.author().unwrap_or_default();
+                info.year = doc.metadata("Year").unwrap_or_default();
+                info.publisher = doc.metadata("Publisher").unwrap_or_default();
+                info.series = doc.metadata("Series").unwrap_or_default();
+                info.number = doc.metadata("Number").unwrap_or_default();
+                info.volume = doc.metadata("Volume").unwrap_or_default();
+                info.language = doc.metadata("LanguageISO").unwrap_or_default();
+                if genres_as_categories {
+                    if let Some(genres) = doc.metadata("Genre") {
+                        info.categories.extend(genres.split(',').map(str::trim)
+                                                     .filter(|g| !g.is_empty())
+                                                     .map(String::from));
+                    }
+                }
+                println!("{}", info.label());
+            },
+            Err(e) => eprintln!("{}: {}", info.file.path.display(), e),
+        }
+    }
+}
+
+// A directory of images contains at least one image, no subdirectories
+// and no other files, besides *ComicInfo.xml* and the hidden ones.
+fn is_images_directory(dir: &Path) -> bool {
+    let mut has_images = false;
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(..) => return false,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.path().is_dir() {
+            return false;
+        }
+        if is_image(&name) {
+            has_images = true;
+        } else if !name.eq_ignore_ascii_case(COMIC_INFO_NAME) {
+            return false;
+        }
+    }
+    has_images
+}
+
+fn directory_size(dir: &Path) -> u64 {
+    fs::read_dir(dir).map(|entries| {
+        entries.filter_map(|e| e.ok())
+               .filter_map(|e| e.metadata().ok())
+               .map(|m| m.len()).sum()
+    }).unwrap_or_default()
+}
+
 pub fn extract_metadata_from_filename(metadata: &mut Metadata) {
     for info in metadata {
         if !info.title.is_empty() {
@@ -704,7 +914,7 @@ pub fn clean_up(dir: &Path, metadata: &mut Metadata) {
     });
 }
 
-fn find_files(root: &Path, dir: &Path, traverse_hidden: bool) -> Result<Vec<FileInfo>, Error> {
+fn find_files(root: &Path, dir: &Path, excluded: &Path, traverse_hidden: bool, images_directories: bool) -> Result<Vec<FileInfo>, Error> {
     let mut result = Vec::new();
 
     for entry in fs::read_dir(dir).context("Can't read directory.")? {
@@ -717,7 +927,21 @@ fn find_files(root: &Path, dir: &Path, traverse_hidden: bool) -> Result<Vec<File
                     continue;
                 }
             }
-            result.extend_from_slice(&find_files(root, path.as_path(), traverse_hidden)?);
+            // Skip the annotation exports.
+            if path == excluded {
+                continue;
+            }
+            if images_directories && is_images_directory(&path) {
+                result.push(
+                    FileInfo {
+                        path: path.strip_prefix(root).unwrap().to_path_buf(),
+                        kind: IMAGES_DIRECTORY_KIND.to_string(),
+                        size: directory_size(&path),
+                    }
+                );
+                continue;
+            }
+            result.extend_from_slice(&find_files(root, path.as_path(), excluded, traverse_hidden, images_directories)?);
         } else {
             if entry.file_name().to_string_lossy().starts_with('.') {
                 continue;
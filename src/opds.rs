@@ -0,0 +1,446 @@
+use std::fs;
+use std::time::Duration;
+use reqwest::blocking::Client;
+use regex::{Regex, Captures};
+use lazy_static::lazy_static;
+use serde_json::Value as JsonValue;
+use failure::{Error, format_err};
+use crate::document::html::dom::Node;
+use crate::document::html::xml::XmlParser;
+use crate::helpers::{decode_entities, percent_decode};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+const SEARCH_TERMS: &str = "{searchTerms";
+
+lazy_static! {
+    // A query parameter whose value is an optional template parameter.
+    static ref OPTIONAL_PAIR: Regex = Regex::new(r"([?&])[^?&=#{}]+=\{[^{}]+\?\}").unwrap();
+    static ref PARAMETER: Regex = Regex::new(r"\{([^{}?]+)(\??)\}").unwrap();
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Feed {
+    pub title: String,
+    pub url: String,
+    pub links: Vec<Link>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    pub title: String,
+    pub author: String,
+    pub summary: String,
+    pub language: String,
+    pub publisher: String,
+    pub year: String,
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Link {
+    pub href: String,
+    pub rel: String,
+    pub kind: String,
+    pub title: String,
+}
+
+impl Link {
+    pub fn is_acquisition(&self) -> bool {
+        self.rel.starts_with(ACQUISITION_REL)
+    }
+
+    pub fn is_navigation(&self) -> bool {
+        !self.is_acquisition() &&
+        (self.kind.starts_with("application/atom+xml") ||
+         self.kind.starts_with("application/opds+json"))
+    }
+
+    // The file extension associated with the media type of the link.
+    pub fn extension(&self) -> Option<&'static str> {
+        let kind = self.kind.split(';').next().unwrap_or("").trim();
+        match kind {
+            "application/epub+zip" => Some("epub"),
+            "application/pdf" => Some("pdf"),
+            "image/vnd.djvu" | "image/x-djvu" => Some("djvu"),
+            "application/x-cbz" | "application/vnd.comicbook+zip" => Some("cbz"),
+            "application/x-fictionbook+xml" | "text/fb2+xml" => Some("fb2"),
+            "application/x-mobipocket-ebook" => Some("mobi"),
+            "application/oxps" | "application/vnd.ms-xpsdocument" => Some("xps"),
+            "text/html" | "application/xhtml+xml" => Some("html"),
+            "text/plain" => Some("txt"),
+            _ => None,
+        }
+    }
+}
+
+impl Feed {
+    pub fn link(&self, rel: &str) -> Option<&Link> {
+        self.links.iter().find(|link| link.rel.split_whitespace().any(|r| r == rel))
+    }
+}
+
+impl Entry {
+    pub fn navigation_link(&self) -> Option<&Link> {
+        self.links.iter().find(|link| link.is_navigation())
+    }
+
+    pub fn acquisition_links(&self) -> impl Iterator<Item=&Link> {
+        self.links.iter().filter(|link| link.is_acquisition() && link.extension().is_some())
+    }
+}
+
+pub fn fetch(url: &str) -> Result<Vec<u8>, Error> {
+    if url.starts_with("file://") {
+        let path = percent_decode(&url["file://".len()..]);
+        return Ok(fs::read(String::from_utf8_lossy(&path).as_ref())?);
+    }
+
+    let client = Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut response = client.get(url).send()?
+                             .error_for_status()?;
+    let mut bytes = Vec::new();
+    response.copy_to(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn fetch_feed(url: &str) -> Result<Feed, Error> {
+    let bytes = fetch(url)?;
+    parse_feed(&String::from_utf8_lossy(&bytes), url)
+}
+
+// Returns the URL of the search results for the given query.
+pub fn search_url(feed: &Feed, query: &str) -> Result<String, Error> {
+    let link = feed.link("search")
+                   .ok_or_else(|| format_err!("The catalog isn't searchable."))?;
+    let template = if link.href.contains(SEARCH_TERMS) {
+        link.href.clone()
+    } else {
+        let bytes = fetch(&link.href)?;
+        parse_opensearch(&String::from_utf8_lossy(&bytes), &link.href)
+            .ok_or_else(|| format_err!("Can't find a search template."))?
+    };
+    Ok(fill_template(&template, query))
+}
+
+// Fills an OpenSearch template: the optional parameters are left out, and
+// the required ones, besides the search terms, get their default values.
+fn fill_template(template: &str, query: &str) -> String {
+    let query = percent_encode(query);
+    let url = template.replace("{searchTerms}", &query)
+                      .replace("{searchTerms?}", &query);
+    let url = OPTIONAL_PAIR.replace_all(&url, "$1");
+    let mut url = PARAMETER.replace_all(&url, |caps: &Captures| {
+        if &caps[2] == "?" {
+            return "";
+        }
+        // The parameters can be prefixed by a namespace.
+        match caps[1].rsplit(':').next().unwrap_or("") {
+            "startIndex" | "startPage" => "1",
+            "language" => "*",
+            "inputEncoding" | "outputEncoding" => "UTF-8",
+            _ => "",
+        }
+    }).into_owned();
+
+    // Remove the separators of the pairs that were left out.
+    if let Some(index) = url.find('?') {
+        let pairs = url[index+1..].split('&').filter(|pair| !pair.is_empty())
+                                  .collect::<Vec<&str>>().join("&");
+        url.truncate(index);
+        if !pairs.is_empty() {
+            url.push('?');
+            url.push_str(&pairs);
+        }
+    }
+
+    url
+}
+
+pub fn parse_feed(content: &str, url: &str) -> Result<Feed, Error> {
+    if content.trim_start().starts_with('{') {
+        let value: JsonValue = serde_json::from_str(content)?;
+        Ok(parse_json_feed(&value, url))
+    } else {
+        let root = XmlParser::new(content).parse();
+        let feed = root.find("feed")
+                       .ok_or_else(|| format_err!("Can't find the feed element."))?;
+        Ok(parse_atom_feed(feed, url))
+    }
+}
+
+fn parse_atom_feed(feed: &Node, url: &str) -> Feed {
+    let mut result = Feed {
+        url: url.to_string(),
+        .. Default::default()
+    };
+
+    for child in feed.children().into_iter().flatten() {
+        match child.tag_name() {
+            Some("title") => result.title = atom_text(child),
+            Some("link") => result.links.push(atom_link(child, url)),
+            Some("entry") => result.entries.push(atom_entry(child, url)),
+            _ => (),
+        }
+    }
+
+    result
+}
+
+fn atom_entry(entry: &Node, url: &str) -> Entry {
+    let mut result = Entry::default();
+
+    for child in entry.children().into_iter().flatten() {
+        match child.tag_name() {
+            Some("title") => result.title = atom_text(child),
+            Some("author") if result.author.is_empty() => {
+                result.author = child.find("name").map(atom_text).unwrap_or_default();
+            },
+            Some("summary") | Some("content") if result.summary.is_empty() => {
+                result.summary = strip_tags(&atom_text(child));
+            },
+            Some("dc:language") => result.language = atom_text(child),
+            Some("dc:publisher") | Some("publisher") => result.publisher = atom_text(child),
+            Some("dc:issued") | Some("published") if result.year.is_empty() => {
+                result.year = atom_text(child).chars().take(4).collect();
+            },
+            Some("link") => result.links.push(atom_link(child, url)),
+            _ => (),
+        }
+    }
+
+    result
+}
+
+fn atom_link(link: &Node, url: &str) -> Link {
+    Link {
+        href: resolve_url(url, &decode_entities(link.attr("href").unwrap_or(""))),
+        rel: link.attr("rel").unwrap_or("").to_string(),
+        kind: link.attr("type").unwrap_or("").to_string(),
+        title: link.attr("title").map(|t| decode_entities(t).into_owned()).unwrap_or_default(),
+    }
+}
+
+fn atom_text(node: &Node) -> String {
+    decode_entities(node.text_content().trim()).into_owned()
+}
+
+fn parse_json_feed(value: &JsonValue, url: &str) -> Feed {
+    let mut result = Feed {
+        url: url.to_string(),
+        title: json_str(&value["metadata"]["title"]),
+        links: json_links(&value["links"], url),
+        .. Default::default()
+    };
+
+    let groups = value["groups"].as_array().into_iter().flatten();
+
+    for collection in Some(value).into_iter().chain(groups) {
+        for nav in collection["navigation"].as_array().into_iter().flatten() {
+            result.entries.push(Entry {
+                title: json_str(&nav["title"]),
+                links: json_links(&JsonValue::Array(vec![nav.clone()]), url),
+                .. Default::default()
+            });
+        }
+
+        for publication in collection["publications"].as_array().into_iter().flatten() {
+            let metadata = &publication["metadata"];
+            result.entries.push(Entry {
+                title: json_str(&metadata["title"]),
+                author: json_contributor(&metadata["author"]),
+                summary: strip_tags(&json_str(&metadata["description"])),
+                language: json_contributor(&metadata["language"]),
+                publisher: json_contributor(&metadata["publisher"]),
+                year: json_str(&metadata["published"]).chars().take(4).collect(),
+                links: json_links(&publication["links"], url),
+            });
+        }
+    }
+
+    result
+}
+
+fn json_links(value: &JsonValue, url: &str) -> Vec<Link> {
+    value.as_array().into_iter().flatten().map(|link| {
+        let rel = match link["rel"] {
+            JsonValue::Array(ref rels) => rels.iter().filter_map(JsonValue::as_str)
+                                              .collect::<Vec<&str>>().join(" "),
+            ref rel => json_str(rel),
+        };
+        Link {
+            href: resolve_url(url, &json_str(&link["href"])),
+            rel,
+            kind: json_str(&link["type"]),
+            title: json_str(&link["title"]),
+        }
+    }).collect()
+}
+
+fn json_str(value: &JsonValue) -> String {
+    value.as_str().unwrap_or("").to_string()
+}
+
+// Contributors can be strings, objects or arrays of those.
+fn json_contributor(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(name) => name.clone(),
+        JsonValue::Object(..) => json_str(&value["name"]),
+        JsonValue::Array(values) => values.iter().map(json_contributor)
+                                          .filter(|name| !name.is_empty())
+                                          .collect::<Vec<String>>().join(", "),
+        _ => String::new(),
+    }
+}
+
+// Extracts the search template from an OpenSearch description document.
+pub fn parse_opensearch(content: &str, url: &str) -> Option<String> {
+    let root = XmlParser::new(content).parse();
+    let description = root.find("OpenSearchDescription")?;
+    description.children()?.iter().filter(|child| child.tag_name() == Some("Url"))
+               .find(|child| child.attr("type").map_or(false, |t| t.starts_with("application/atom+xml") ||
+                                                                  t.starts_with("application/opds+json")))
+               .and_then(|child| child.attr("template"))
+               .map(|template| resolve_url(url, &decode_entities(template)))
+}
+
+pub fn resolve_url(base: &str, href: &str) -> String {
+    if href.is_empty() || href.contains("://") {
+        return href.to_string();
+    }
+
+    let scheme_end = match base.find("://") {
+        Some(index) => index + 3,
+        None => return href.to_string(),
+    };
+
+    if href.starts_with("//") {
+        return format!("{}{}", &base[..scheme_end-2], href);
+    }
+
+    if href.starts_with('/') {
+        let host_end = base[scheme_end..].find('/')
+                                         .map(|index| scheme_end + index)
+                                         .unwrap_or_else(|| base.len());
+        return format!("{}{}", &base[..host_end], href);
+    }
+
+    let base = &base[..base.find(|c| c == '?' || c == '#').unwrap_or_else(|| base.len())];
+    let dir_end = base[scheme_end..].rfind('/')
+                                    .map(|index| scheme_end + index + 1)
+                                    .unwrap_or_else(|| base.len());
+    let mut url = base[..dir_end].to_string();
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    url.push_str(href);
+    url
+}
+
+fn strip_tags(text: &str) -> String {
+    let mut result = String::new();
+    let mut inside = false;
+    for c in text.chars() {
+        match c {
+            '<' => inside = true,
+            '>' if inside => {
+                inside = false;
+                result.push(' ');
+            },
+            _ if !inside => result.push(c),
+            _ => (),
+        }
+    }
+    decode_entities(&result).split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+pub fn percent_encode(text: &str) -> String {
+    let mut result = String::new();
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(b as char),
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_url() {
+        assert_eq!(resolve_url("http://a.org/opds/root.xml", "new.xml"), "http://a.org/opds/new.xml");
+        assert_eq!(resolve_url("http://a.org/opds/root.xml?page=2", "/search"), "http://a.org/search");
+        assert_eq!(resolve_url("https://a.org", "//b.org/c"), "https://b.org/c");
+        assert_eq!(resolve_url("file:///srv/catalog/index.xml", "books/recent.xml"), "file:///srv/catalog/books/recent.xml");
+        assert_eq!(resolve_url("file:///srv/catalog/index.xml", "/tmp/a.epub"), "file:///tmp/a.epub");
+    }
+
+    #[test]
+    fn test_atom_feed() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Recent &amp; Popular</title>
+  <link rel="search" type="application/atom+xml" href="search?q={searchTerms}"/>
+  <entry>
+    <title>Moby Dick</title>
+    <author><name>Herman Melville</name></author>
+    <dc:language>en</dc:language>
+    <summary type="html">&lt;p&gt;A whale.&lt;/p&gt;</summary>
+    <link rel="http://opds-spec.org/acquisition" type="application/epub+zip" href="/books/moby.epub"/>
+  </entry>
+  <entry>
+    <title>Fiction</title>
+    <link rel="subsection" type="application/atom+xml;profile=opds-catalog;kind=navigation" href="fiction.xml"/>
+  </entry>
+</feed>"#;
+        let feed = parse_feed(text, "file:///srv/catalog/index.xml").unwrap();
+        assert_eq!(feed.title, "Recent & Popular");
+        assert_eq!(feed.entries.len(), 2);
+        assert_eq!(feed.entries[0].author, "Herman Melville");
+        assert_eq!(feed.entries[0].summary, "A whale.");
+        let link = feed.entries[0].acquisition_links().next().unwrap();
+        assert_eq!(link.href, "file:///books/moby.epub");
+        assert_eq!(link.extension(), Some("epub"));
+        assert_eq!(feed.entries[1].navigation_link().map(|l| l.href.as_str()),
+                   Some("file:///srv/catalog/fiction.xml"));
+        assert_eq!(search_url(&feed, "white whale").ok().as_ref().map(String::as_str),
+                   Some("file:///srv/catalog/search?q=white%20whale"));
+    }
+
+    #[test]
+    fn test_search_template() {
+        assert_eq!(fill_template("http://a.org/search?q={searchTerms}&page={startPage?}", "white whale"),
+                   "http://a.org/search?q=white%20whale");
+        assert_eq!(fill_template("http://a.org/search?lang={language?}&q={searchTerms?}&n={count?}", "moby"),
+                   "http://a.org/search?q=moby");
+        assert_eq!(fill_template("http://a.org/search/{searchTerms}?start={startIndex}&lang={language}&by={atom:author?}", "moby"),
+                   "http://a.org/search/moby?start=1&lang=*");
+        assert_eq!(fill_template("http://a.org/search?q={searchTerms}&count={count}&p={opds:page}", "moby"),
+                   "http://a.org/search?q=moby&count=&p=");
+    }
+
+    #[test]
+    fn test_json_feed() {
+        let text = r#"{
+  "metadata": {"title": "Catalog"},
+  "links": [{"rel": "self", "href": "catalog.json", "type": "application/opds+json"}],
+  "navigation": [{"href": "new.json", "title": "New", "type": "application/opds+json"}],
+  "publications": [{
+    "metadata": {"title": "Ulysses", "author": [{"name": "James Joyce"}], "published": "1922-02-02"},
+    "links": [{"rel": "http://opds-spec.org/acquisition/open-access", "href": "ulysses.epub", "type": "application/epub+zip"}]
+  }]
+}"#;
+        let feed = parse_feed(text, "http://a.org/opds/catalog.json").unwrap();
+        assert_eq!(feed.title, "Catalog");
+        assert_eq!(feed.entries.len(), 2);
+        assert_eq!(feed.entries[0].navigation_link().map(|l| l.href.as_str()),
+                   Some("http://a.org/opds/new.json"));
+        assert_eq!(feed.entries[1].author, "James Joyce");
+        assert_eq!(feed.entries[1].year, "1922");
+        assert!(feed.entries[1].acquisition_links().next().is_some());
+    }
+}
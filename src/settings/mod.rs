@@ -4,7 +4,7 @@ use std::fmt::{self, Debug};
 use std::path::PathBuf;
 use std::collections::{HashSet, HashMap, BTreeMap};
 use serde::{Serialize, Deserialize};
-use crate::metadata::{SortMethod, TextAlign};
+use crate::metadata::{SortMethod, TextAlign, METADATA_FILENAME, DATABASE_FILENAME};
 use crate::frontlight::LightLevels;
 use crate::color::BLACK;
 use crate::device::CURRENT_DEVICE;
@@ -33,6 +33,22 @@ pub enum RotationLock {
     Current,
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LibraryBackend {
+    Json,
+    Sqlite,
+}
+
+impl LibraryBackend {
+    pub fn filename(self) -> &'static str {
+        match self {
+            LibraryBackend::Json => METADATA_FILENAME,
+            LibraryBackend::Sqlite => DATABASE_FILENAME,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum ButtonScheme {
@@ -50,6 +66,7 @@ impl fmt::Display for ButtonScheme {
 #[serde(default, rename_all = "kebab-case")]
 pub struct Settings {
     pub library_path: PathBuf,
+    pub library_backend: LibraryBackend,
     pub keyboard_layout: String,
     pub frontlight: bool,
     pub wifi: bool,
@@ -70,6 +87,7 @@ pub struct Settings {
     pub dictionary: DictionarySettings,
     pub sketch: SketchSettings,
     pub calculator: CalculatorSettings,
+    pub opds: OpdsSettings,
     pub battery: BatterySettings,
     pub frontlight_levels: LightLevels,
 }
@@ -136,6 +154,21 @@ pub struct CalculatorSettings {
     pub history_size: usize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct OpdsSettings {
+    pub save_path: PathBuf,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub catalogs: Vec<OpdsCatalog>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct OpdsCatalog {
+    pub name: String,
+    pub url: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct Pen {
@@ -168,6 +201,15 @@ impl Default for SketchSettings {
     }
 }
 
+impl Default for OpdsSettings {
+    fn default() -> Self {
+        OpdsSettings {
+            save_path: PathBuf::from("Downloads"),
+            catalogs: Vec::new(),
+        }
+    }
+}
+
 impl Default for CalculatorSettings {
     fn default() -> Self {
         CalculatorSettings {
@@ -225,6 +267,13 @@ pub struct ReaderSettings {
     pub text_align: TextAlign,
     pub margin_width: i32,
     pub line_height: f32,
+    pub export_path: PathBuf,
+    // The kinds of documents whose margins are cropped automatically when first opened.
+    pub auto_crop: HashSet<String>,
+    // Save the passwords of the protected documents, encrypted, on the device.
+    pub remember_passwords: bool,
+    // Memory, in megabytes, used to keep the rendered pages.
+    pub cache_size: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -262,6 +311,10 @@ impl Default for ReaderSettings {
             text_align: DEFAULT_TEXT_ALIGN,
             margin_width: DEFAULT_MARGIN_WIDTH,
             line_height: DEFAULT_LINE_HEIGHT,
+            export_path: PathBuf::from("Annotations"),
+            auto_crop: HashSet::new(),
+            remember_passwords: false,
+            cache_size: 32,
         }
     }
 }
@@ -272,8 +325,8 @@ impl Default for ImportSettings {
             unshare_trigger: true,
             startup_trigger: true,
             traverse_hidden: false,
-            allowed_kinds: ["pdf", "djvu", "epub",
-                            "fb2", "xps", "oxps", "cbz"].iter().map(|k| k.to_string()).collect(),
+            allowed_kinds: ["pdf", "djvu", "epub", "fb2", "fb2.zip", "mobi", "azw", "azw3",
+                            "xps", "oxps", "cbz", "cbr", "cb7", "txt", "md"].iter().map(|k| k.to_string()).collect(),
             category_providers: [CategoryProvider::Path].iter().cloned().collect(),
         }
     }
@@ -292,6 +345,7 @@ impl Default for Settings {
     fn default() -> Self {
         Settings {
             library_path: CURRENT_DEVICE.library_path(),
+            library_backend: LibraryBackend::Json,
             keyboard_layout: "English".to_string(),
             frontlight: true,
             wifi: false,
@@ -308,6 +362,7 @@ impl Default for Settings {
             dictionary: DictionarySettings::default(),
             sketch: SketchSettings::default(),
             calculator: CalculatorSettings::default(),
+            opds: OpdsSettings::default(),
             battery: BatterySettings::default(),
             frontlight_levels: LightLevels::default(),
             frontlight_presets: Vec::new(),
@@ -1,5 +1,6 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::collections::VecDeque;
 use failure::{Error, format_err};
 use rand::{Rng, thread_rng};
@@ -29,6 +30,15 @@ impl Default for TrashEntry {
 
 type Trash = VecDeque<Vec<TrashEntry>>;
 
+// Directories of images are trashed as a whole.
+fn remove(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
 const CONTENTS_NAME: &str = "contents.json";
 const SIZE_LIMIT: u64 = 32 * 1024 * 1024;
 const MIN_PACKETS: usize = 8;
@@ -85,7 +95,7 @@ pub fn trash(paths: &FnvHashSet<PathBuf>, context: &mut Context) -> Result<(), E
     if total_size > SIZE_LIMIT && packets_count >= MIN_PACKETS {
         while let Some(mut entries) = contents.pop_back() {
             while let Some(entry) = entries.pop() {
-                if fs::remove_file(trash_path.join(&entry.name))
+                if remove(&trash_path.join(&entry.name))
                       .map_err(|e| eprintln!("Can't remove {}: {}", &entry.name, e)).is_err() {
                     entries.push(entry);
                     break;
@@ -145,7 +155,7 @@ pub fn empty(context: &Context) -> Result<(), Error> {
 
     for entries in &contents {
         for entry in entries {
-            fs::remove_file(trash_path.join(&entry.name))
+            remove(&trash_path.join(&entry.name))
                .map_err(|e| eprintln!("Can't remove {}: {}", &entry.name, e)).ok();
         }
     }
@@ -79,12 +79,17 @@ pub fn toggle_main_menu(view: &mut dyn View, rect: Rectangle, enable: Option<boo
                                    n == context.display.rotation)
         ).collect::<Vec<EntryKind>>();
 
-        let apps = vec![EntryKind::Command("Dictionary".to_string(),
-                                           EntryId::Launch(AppCmd::Dictionary { query: "".to_string(), language: "".to_string() })),
-                        EntryKind::Command("Calculator".to_string(),
-                                           EntryId::Launch(AppCmd::Calculator)),
-                        EntryKind::Command("Sketch".to_string(),
-                                           EntryId::Launch(AppCmd::Sketch))];
+        let mut apps = vec![EntryKind::Command("Dictionary".to_string(),
+                                               EntryId::Launch(AppCmd::Dictionary { query: "".to_string(), language: "".to_string() })),
+                            EntryKind::Command("Calculator".to_string(),
+                                               EntryId::Launch(AppCmd::Calculator)),
+                            EntryKind::Command("Sketch".to_string(),
+                                               EntryId::Launch(AppCmd::Sketch))];
+        #[cfg(feature = "opds")]
+        apps.push(EntryKind::Command("Catalogs".to_string(),
+                                     EntryId::Launch(AppCmd::Opds)));
+        apps.push(EntryKind::Command("Statistics".to_string(),
+                                     EntryId::Launch(AppCmd::Statistics)));
 
         let mut entries = vec![EntryKind::CheckBox("Invert Colors".to_string(),
                                                    EntryId::ToggleInverted,
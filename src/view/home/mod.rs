@@ -35,9 +35,11 @@ use crate::gesture::GestureEvent;
 use crate::input::{DeviceEvent, ButtonCode, ButtonStatus};
 use crate::device::{CURRENT_DEVICE, BAR_SIZES};
 use crate::symbolic_path::SymbolicPath;
-use crate::helpers::{load_json, save_json};
+use crate::helpers::save_json;
+use crate::library::{Library, Query, open_library};
 use crate::unit::scale_by_dpi;
 use crate::trash::{self, trash, untrash};
+use crate::document::fingerprint;
 use crate::app::Context;
 use crate::color::BLACK;
 use crate::geom::{Rectangle, CycleDir, halves};
@@ -60,6 +62,7 @@ pub struct Home {
     query: Option<Regex>,
     target_path: Option<PathBuf>,
     target_category: Option<String>,
+    protected_info: Option<Box<Info>>,
     sort_method: SortMethod,
     status_filter: Option<SimpleStatus>,
     reverse_order: bool,
@@ -183,6 +186,7 @@ impl Home {
             query: None,
             target_path: None,
             target_category: None,
+            protected_info: None,
             sort_method,
             status_filter: None,
             reverse_order,
@@ -195,7 +199,25 @@ impl Home {
         })
     }
 
-    fn refresh_visibles(&mut self, update: bool, reset_page: bool, hub: &Hub, context: &mut Context) {
+    // Asks the library for the matching entries, in the current order.
+    fn query_library(&self, context: &mut Context) -> Option<Metadata> {
+        let paths = context.library.query(&Query {
+            status: self.status_filter,
+            selected_categories: &self.selected_categories,
+            negated_categories: &self.negated_categories,
+            sort_method: self.sort_method,
+            reverse_order: self.reverse_order,
+        })?;
+        let infos: FnvHashMap<&PathBuf, &Info> = context.metadata.iter()
+                                                        .map(|info| (&info.file.path, info))
+                                                        .collect();
+        Some(paths.iter().filter_map(|path| infos.get(path))
+                  .filter(|info| info.is_match(&self.query))
+                  .map(|info| (*info).clone()).collect())
+    }
+
+    // The metadata is already sorted, so the matching entries are too.
+    fn filter_visibles(&mut self, context: &Context) {
         self.visible_books = context.metadata.iter().filter(|info| {
             info.is_match(&self.query) &&
             (self.status_filter.is_none() || info.simple_status() == self.status_filter.unwrap()) &&
@@ -207,6 +229,14 @@ impl Home {
              (self.negated_categories.is_disjoint(&info.categories) &&
               info.categories.iter().all(|c| c.ancestors().all(|a| !self.negated_categories.contains(a)))))
         }).cloned().collect();
+    }
+
+    fn refresh_visibles(&mut self, update: bool, reset_page: bool, hub: &Hub, context: &mut Context) {
+        if let Some(books) = self.query_library(context) {
+            self.visible_books = books;
+        } else {
+            self.filter_visibles(context);
+        }
 
         self.visible_categories = self.visible_books.iter()
                                       .flat_map(|info| info.categories.clone()).collect();
@@ -1017,6 +1047,8 @@ impl Home {
     fn undo(&mut self, hub: &Hub, context: &mut Context) {
         if let Some(entry) = self.history.pop_back() {
             context.metadata = entry.metadata;
+            context.library.save(&context.metadata)
+                   .map_err(|e| eprintln!("Can't save metadata: {}", e)).ok();
             if entry.restore_books {
                 untrash(context).map_err(|e| eprintln!("Can't restore books from trash: {}", e)).ok();
             }
@@ -1031,6 +1063,7 @@ impl Home {
         if trash(&paths, context).map_err(|e| eprintln!("Can't trash matches: {}", e)).is_ok() {
             self.history_push(true, context);
             context.metadata.retain(|info| !paths.contains(&info.file.path));
+            remove_from_library(&mut context.library, &paths.iter().map(PathBuf::as_path).collect::<Vec<&Path>>());
             context.settings.intermission_images.retain(|_, path| !paths.contains(path));
             self.refresh_visibles(true, false, hub, context);
         }
@@ -1045,15 +1078,19 @@ impl Home {
         let mut paths: FnvHashSet<PathBuf> = self.visible_books.drain(..)
                                                  .map(|info| info.file.path).collect();
 
+        let mut changed = Vec::new();
+
         for info in &mut context.metadata {
             if paths.remove(&info.file.path) {
                 info.categories.extend(categs.clone());
+                changed.push(&*info);
                 if paths.is_empty() {
                     break;
                 }
             }
         }
 
+        update_library(&mut context.library, &changed);
         self.refresh_visibles(true, false, hub, context);
     }
 
@@ -1076,16 +1113,22 @@ impl Home {
             }
         }).collect();
 
+        let mut changed = Vec::new();
+
         for info in &mut context.metadata {
-            info.categories = info.categories.iter().filter_map(|c| {
-                if c == categ || c.is_descendant_of(categ) {
-                    None
-                } else {
-                    Some(c.clone())
-                }
-            }).collect();
+            if info.categories.iter().any(|c| c == categ || c.is_descendant_of(categ)) {
+                info.categories = info.categories.iter().filter_map(|c| {
+                    if c == categ || c.is_descendant_of(categ) {
+                        None
+                    } else {
+                        Some(c.clone())
+                    }
+                }).collect();
+                changed.push(&*info);
+            }
         }
 
+        update_library(&mut context.library, &changed);
         self.refresh_visibles(true, false, hub, context);
     }
 
@@ -1116,24 +1159,31 @@ impl Home {
             }
         }).collect();
 
+        let mut changed = Vec::new();
+
         for info in &mut context.metadata {
-            info.categories = info.categories.iter().map(|c| {
-                if c == categ_old {
-                    categ_new.to_string()
-                } else if c.is_descendant_of(categ_old) {
-                    categ_new.join(&c[categ_old.len()+1..])
-                } else {
-                    c.clone()
-                }
-            }).collect();
+            if info.categories.iter().any(|c| c == categ_old || c.is_descendant_of(categ_old)) {
+                info.categories = info.categories.iter().map(|c| {
+                    if c == categ_old {
+                        categ_new.to_string()
+                    } else if c.is_descendant_of(categ_old) {
+                        categ_new.join(&c[categ_old.len()+1..])
+                    } else {
+                        c.clone()
+                    }
+                }).collect();
+                changed.push(&*info);
+            }
         }
 
+        update_library(&mut context.library, &changed);
         self.refresh_visibles(true, false, hub, context);
     }
 
     fn add_document(&mut self, mut info: Info, hub: &Hub, context: &mut Context) {
         if let Ok(path) = info.file.path.strip_prefix(&context.settings.library_path) {
             info.file.path = path.to_path_buf();
+            update_library(&mut context.library, &[&info]);
             context.metadata.push(info);
             // TODO: Only update bars and shelves once.
             self.refresh_visibles(true, false, hub, context);
@@ -1146,6 +1196,7 @@ impl Home {
         if trash(&paths, context).map_err(|e| eprintln!("Can't trash {}: {}", path.display(), e)).is_ok() {
             self.history_push(true, context);
             context.metadata.retain(|info| info.file.path != *path);
+            remove_from_library(&mut context.library, &[path.as_path()]);
             context.settings.intermission_images.retain(|_, path| !paths.contains(path));
             self.refresh_visibles(true, false, hub, context);
         }
@@ -1161,6 +1212,7 @@ impl Home {
         for info in &mut context.metadata {
             if info.file.path == *path {
                 info.categories.extend(categs.clone());
+                update_library(&mut context.library, &[&*info]);
                 break;
             }
         }
@@ -1175,6 +1227,7 @@ impl Home {
         for info in &mut context.metadata {
             if info.file.path == *path {
                 info.categories.remove(categ);
+                update_library(&mut context.library, &[&*info]);
                 break;
             }
         }
@@ -1199,6 +1252,7 @@ impl Home {
                         });
                     }
                 }
+                update_library(&mut context.library, &[&*info]);
                 break;
             }
         }
@@ -1236,7 +1290,11 @@ impl Home {
         }
 
         sort(&mut context.metadata, self.sort_method, self.reverse_order);
-        sort(&mut self.visible_books, self.sort_method, self.reverse_order);
+        if let Some(books) = self.query_library(context) {
+            self.visible_books = books;
+        } else {
+            sort(&mut self.visible_books, self.sort_method, self.reverse_order);
+        }
         self.update_shelf(false, hub, context);
         let search_visible = locate::<SearchBar>(self).is_some();
         self.update_top_bar(search_visible, hub);
@@ -1256,25 +1314,27 @@ impl Home {
     }
 
     fn save_as(&mut self, filename: Option<&str>, context: &mut Context) {
-        let path = if let Some(filename) = filename.as_ref() {
-            context.settings.library_path.join(format!(".metadata-{}.json", filename))
+        let result = if let Some(filename) = filename.as_ref() {
+            let path = context.settings.library_path.join(format!(".metadata-{}.json", filename));
+            save_json(&self.visible_books, path)
         } else {
-            context.settings.library_path.join(&context.filename)
+            context.library.save(&self.visible_books)
         };
-        save_json(&self.visible_books, path).map_err(|e| {
+        result.map_err(|e| {
             eprintln!("Can't save: {}.", e);
         }).ok();
     }
 
     fn load(&mut self, filename: &PathBuf, hub: &Hub, context: &mut Context) {
-        let md = load_json::<Metadata, _>(context.settings.library_path.join(filename))
-                           .map_err(|e| eprintln!("Can't load: {}", e));
-        if let Ok(metadata) = md {
-            let saved = save_json(&context.metadata,
-                                  context.settings.library_path.join(&context.filename))
-                                 .map_err(|e| eprintln!("Can't save: {}", e)).is_ok();
+        let md = open_library(context.settings.library_path.join(filename))
+                             .and_then(|mut library| library.load().map(|metadata| (library, metadata)))
+                             .map_err(|e| eprintln!("Can't load: {}", e));
+        if let Ok((library, metadata)) = md {
+            let saved = context.library.flush(&context.metadata)
+                               .map_err(|e| eprintln!("Can't save: {}", e)).is_ok();
             if saved {
                 context.filename = filename.clone();
+                context.library = library;
                 context.metadata = metadata;
                 self.history.clear();
                 self.selected_categories.clear();
@@ -1285,8 +1345,8 @@ impl Home {
     }
 
     fn reload(&mut self, hub: &Hub, context: &mut Context) {
-        let md = load_json::<Metadata, _>(context.settings.library_path.join(&context.filename))
-                           .map_err(|e| eprintln!("Can't load: {}", e));
+        let md = context.library.load()
+                        .map_err(|e| eprintln!("Can't load: {}", e));
         if let Ok(metadata) = md {
             context.metadata = metadata;
             self.history.clear();
@@ -1299,16 +1359,23 @@ impl Home {
     fn clean_up(&mut self, hub: &Hub, context: &mut Context) {
         self.history_push(false, context);
         let library_path = &context.settings.library_path;
+        let paths: FnvHashSet<PathBuf> = context.metadata.iter().map(|info| info.file.path.clone()).collect();
         clean_up(library_path, &mut context.metadata);
+        let remaining: FnvHashSet<&PathBuf> = context.metadata.iter().map(|info| &info.file.path).collect();
+        let removed: Vec<&Path> = paths.iter().filter(|path| !remaining.contains(path))
+                                       .map(PathBuf::as_path).collect();
+        remove_from_library(&mut context.library, &removed);
         self.refresh_visibles(true, false, hub, context);
     }
 
     fn import(&mut self, hub: &Hub, context: &mut Context) {
         let imd = auto_import(&context.settings.library_path,
                               &context.metadata,
-                              &context.settings.import)
+                              &context.settings.import,
+                              &context.settings.reader.export_path)
                              .map_err(|e| eprintln!("Can't import: {}", e));
         if let Ok(mut imported_metadata) = imd {
+            update_library(&mut context.library, &imported_metadata.iter().collect::<Vec<&Info>>());
             context.metadata.append(&mut imported_metadata);
             sort(&mut context.metadata, self.sort_method, self.reverse_order);
             self.refresh_visibles(true, false, hub, context);
@@ -1316,6 +1383,16 @@ impl Home {
     }
 }
 
+fn update_library(library: &mut Box<dyn Library>, infos: &[&Info]) {
+    library.update(infos)
+           .map_err(|e| eprintln!("Can't update metadata: {}", e)).ok();
+}
+
+fn remove_from_library(library: &mut Box<dyn Library>, paths: &[&Path]) {
+    library.remove(paths)
+           .map_err(|e| eprintln!("Can't remove metadata: {}", e)).ok();
+}
+
 // TODO: make the update_* and resize_* methods take a mutable bit fields as argument and make a
 // generic method for updating everything based on the bit field to avoid needlessly updating
 // things multiple times?
@@ -1402,6 +1479,32 @@ impl View for Home {
                 self.toggle_keyboard(false, true, None, hub, context);
                 false
             },
+            Event::Close(ViewId::Password) => {
+                self.protected_info = None;
+                self.toggle_keyboard(false, true, None, hub, context);
+                false
+            },
+            Event::AskPassword(ref info, rejected) => {
+                if locate_by_id(self, ViewId::Password).is_some() {
+                    return true;
+                }
+                if rejected {
+                    let notif = Notification::new(ViewId::WrongPasswordNotif,
+                                                  "Wrong password.".to_string(),
+                                                  hub,
+                                                  context);
+                    self.children.push(Box::new(notif) as Box<dyn View>);
+                }
+                self.protected_info = Some(info.clone());
+                let password = NamedInput::new("Password".to_string(),
+                                               ViewId::Password,
+                                               ViewId::PasswordInput,
+                                               16, context).hidden(true);
+                hub.send(Event::Render(*password.rect(), UpdateMode::Gui)).ok();
+                hub.send(Event::Focus(Some(ViewId::PasswordInput))).ok();
+                self.children.push(Box::new(password) as Box<dyn View>);
+                true
+            },
             Event::Select(EntryId::Sort(sort_method)) => {
                 self.set_sort_method(sort_method, hub, context);
                 true
@@ -1543,6 +1646,20 @@ impl View for Home {
                 self.toggle_keyboard(false, true, None, hub, context);
                 true
             },
+            Event::Submit(ViewId::PasswordInput, ref password) => {
+                self.toggle_keyboard(false, true, None, hub, context);
+                if let Some(info) = self.protected_info.take() {
+                    let path = context.settings.library_path.join(&info.file.path);
+                    match fingerprint(&path) {
+                        Ok(fp) => {
+                            context.passwords.insert(fp, password.to_string());
+                            hub.send(Event::Open(info)).ok();
+                        },
+                        Err(e) => eprintln!("Can't compute the fingerprint of {}: {}", path.display(), e),
+                    }
+                }
+                true
+            },
             Event::Submit(ViewId::HomeSearchInput, ref text) => {
                 self.query = make_query(text);
                 if self.query.is_some() {
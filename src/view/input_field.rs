@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use crate::device::CURRENT_DEVICE;
 use crate::framebuffer::{Framebuffer, UpdateMode};
 use super::{View, Event, Hub, Bus, KeyboardEvent, ViewId, EntryId, TextKind};
@@ -18,6 +19,7 @@ pub struct InputField {
     placeholder: String,
     cursor: usize,
     border: bool,
+    hidden: bool,
     focused: bool,
 }
 
@@ -74,7 +76,6 @@ fn word_boundary(text: &str, index: usize, dir: LinearDir) -> usize {
     }
 }
 
-// TODO: hidden chars (password…)
 impl InputField {
     pub fn new(rect: Rectangle, id: ViewId) -> InputField {
         InputField {
@@ -86,6 +87,7 @@ impl InputField {
             placeholder: "".to_string(),
             cursor: 0,
             border: true,
+            hidden: false,
             focused: false,
         }
     }
@@ -118,6 +120,20 @@ impl InputField {
         }
     }
 
+    // The characters of hidden fields, e.g. passwords, are replaced by bullets
+    // and aren't recorded in the input history.
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    fn displayed_text(&self) -> Cow<str> {
+        if self.hidden {
+            Cow::Owned("•".repeat(self.text.chars().count()))
+        } else {
+            Cow::Borrowed(&self.text)
+        }
+    }
+
     pub fn text_before_cursor(&self) -> &str {
         &self.text[..self.cursor]
     }
@@ -188,7 +204,7 @@ impl InputField {
         let font = font_from_style(fonts, &NORMAL_STYLE, dpi);
         let padding = font.em() as i32;
         let max_width = self.rect.width().saturating_sub(2 * padding as u32) as i32;
-        let mut plan = font.plan(&self.text, None, Some(&["-liga".to_string()]));
+        let mut plan = font.plan(&self.displayed_text(), None, Some(&["-liga".to_string()]));
         let index = char_position(&self.text, self.cursor).unwrap_or_else(|| self.text.chars().count());
         let lower_index = font.crop_around(&mut plan, index, max_width as u32);
         lower_index.saturating_sub(1) + plan.index_from_advance(position.x - self.rect.min.x - padding)
@@ -210,7 +226,9 @@ impl View for InputField {
                 true
             },
             Event::Gesture(GestureEvent::HoldFingerShort(center, _)) if self.rect.includes(center) => {
-                hub.send(Event::ToggleInputHistoryMenu(self.id, self.rect)).ok();
+                if !self.hidden {
+                    hub.send(Event::ToggleInputHistoryMenu(self.id, self.rect)).ok();
+                }
                 true
             },
             Event::Focus(id_opt) => {
@@ -249,7 +267,9 @@ impl View for InputField {
                     },
                     KeyboardEvent::Submit => {
                         bus.push_back(Event::Submit(self.id, self.text.clone()));
-                        context.record_input(&self.text, self.id);
+                        if !self.hidden {
+                            context.record_input(&self.text, self.id);
+                        }
                     },
                 };
                 hub.send(Event::RenderNoWait(self.rect, UpdateMode::Gui)).ok();
@@ -288,7 +308,7 @@ impl View for InputField {
             (font.plan(&self.placeholder, Some(max_width as u32), None),
              TEXT_NORMAL[2])
         } else {
-            (font.plan(&self.text, None, Some(&["-liga".to_string()])),
+            (font.plan(&self.displayed_text(), None, Some(&["-liga".to_string()])),
             TEXT_NORMAL[1])
         };
 
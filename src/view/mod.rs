@@ -22,6 +22,7 @@ pub mod page_label;
 pub mod named_input;
 pub mod labeled_icon;
 pub mod top_bar;
+pub mod paged_bottom_bar;
 pub mod search_bar;
 pub mod confirmation;
 pub mod notification;
@@ -40,6 +41,9 @@ pub mod reader;
 pub mod dictionary;
 pub mod calculator;
 pub mod sketch;
+#[cfg(feature = "opds")]
+pub mod opds;
+pub mod statistics;
 
 use std::time::Duration;
 use std::path::PathBuf;
@@ -59,6 +63,8 @@ use crate::gesture::GestureEvent;
 use self::calculator::LineOrigin;
 use self::key::KeyKind;
 use self::intermission::IntermKind;
+#[cfg(feature = "opds")]
+use crate::opds::Feed;
 use crate::app::Context;
 
 pub const THICKNESS_SMALL: f32 = 1.0;
@@ -235,8 +241,9 @@ pub enum Event {
     AddDocument(Box<Info>),
     RemoveDocument(PathBuf),
     Open(Box<Info>),
+    // The boolean tells whether a previous password was rejected.
+    AskPassword(Box<Info>, bool),
     OpenToc(Vec<TocEntry>, usize),
-    LoadPixmap(usize),
     Update(UpdateMode),
     Invalid(Box<Info>),
     Remove(Box<Info>),
@@ -258,6 +265,8 @@ pub enum Event {
     PropagateSelect(EntryId),
     EditLanguages,
     Define(String),
+    #[cfg(feature = "opds")]
+    Feed(usize, Box<Feed>),
     Submit(ViewId, String),
     Slider(SliderId, f32, FingerStatus),
     ToggleNear(ViewId, Rectangle),
@@ -306,6 +315,9 @@ pub enum AppCmd {
         query: String,
         language: String,
     },
+    #[cfg(feature = "opds")]
+    Opds,
+    Statistics,
 }
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
@@ -355,14 +367,18 @@ pub enum ViewId {
     AddCategoriesInput,
     RenameCategory,
     RenameCategoryInput,
+    Password,
+    PasswordInput,
     HomeSearchInput,
     ReaderSearchInput,
     DictionarySearchInput,
+    OpdsSearchInput,
     CalculatorInput,
     SearchBar,
     Keyboard,
     ConfirmShare,
     MarginCropper,
+    Footnote,
     TopBottomBars,
     TableOfContents,
     MessageNotif,
@@ -372,6 +388,8 @@ pub enum ViewId {
     LoadSketchNotif,
     NoSearchResultsNotif,
     InvalidSearchQueryNotif,
+    ExportAnnotationsNotif,
+    WrongPasswordNotif,
     LowBatteryNotif,
     NetUpNotif,
     SubMenu(u8),
@@ -468,6 +486,7 @@ pub enum EntryId {
     SecondColumn(SecondColumn),
     ApplyCroppings(usize, PageScheme),
     RemoveCroppings,
+    AutoCroppings,
     SetZoomMode(ZoomMode),
     SetPageName,
     RemovePageName,
@@ -488,6 +507,7 @@ pub enum EntryId {
     SetTextAlign(TextAlign),
     SetMarginWidth(i32),
     SetLineHeight(i32),
+    ToggleEmbeddedFonts,
     SetContrastExponent(i32),
     SetContrastGray(i32),
     SetRotationLock(Option<RotationLock>),
@@ -504,9 +524,12 @@ pub enum EntryId {
     SetPenColor(u8),
     TogglePenDynamism,
     ReloadDictionaries,
+    LoadCatalog(usize),
     New,
     Refresh,
     OpenMetadata,
+    ExportAnnotations,
+    SaveAnnotations,
     TakeScreenshot,
     StartNickel,
     Reboot,
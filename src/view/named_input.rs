@@ -79,6 +79,13 @@ impl NamedInput {
         }
     }
 
+    pub fn hidden(mut self, hidden: bool) -> NamedInput {
+        if let Some(input_field) = self.children[1].downcast_mut::<InputField>() {
+            input_field.set_hidden(hidden);
+        }
+        self
+    }
+
     pub fn set_text(&mut self, text: &str, hub: &Hub, context: &mut Context) {
         if let Some(input_field) = self.children[1].downcast_mut::<InputField>() {
             input_field.set_text(text, true, hub, context);
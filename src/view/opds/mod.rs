@@ -0,0 +1,653 @@
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::sync::mpsc;
+use std::hash::{Hash, Hasher};
+use fnv::FnvHasher;
+use chrono::Local;
+use failure::Error;
+use crate::device::{CURRENT_DEVICE, BAR_SIZES};
+use crate::framebuffer::{Framebuffer, UpdateMode, Pixmap};
+use crate::geom::{Rectangle, Point, Dir, CycleDir, halves};
+use crate::unit::scale_by_dpi;
+use crate::font::Fonts;
+use crate::view::{View, Event, Hub, Bus, ViewId, EntryId, EntryKind};
+use crate::view::{THICKNESS_MEDIUM};
+use crate::document::{Document, Location, asciify};
+use crate::document::html::HtmlDocument;
+use crate::view::common::{locate_by_id, locate};
+use crate::view::common::{toggle_main_menu, toggle_battery_menu, toggle_clock_menu};
+use crate::gesture::GestureEvent;
+use crate::color::BLACK;
+use crate::app::Context;
+use crate::metadata::{Info, FileInfo};
+use crate::opds::{Feed, Entry, fetch, fetch_feed, search_url};
+use crate::view::filler::Filler;
+use crate::view::image::Image;
+use crate::view::keyboard::Keyboard;
+use crate::view::menu::{Menu, MenuKind};
+use crate::view::search_bar::SearchBar;
+use crate::view::top_bar::TopBar;
+use crate::view::paged_bottom_bar::PagedBottomBar;
+
+const VIEWER_STYLESHEET: &str = "css/opds.css";
+const USER_STYLESHEET: &str = "css/opds-user.css";
+const MAX_SUMMARY_LENGTH: usize = 320;
+
+pub struct Opds {
+    rect: Rectangle,
+    children: Vec<Box<dyn View>>,
+    doc: HtmlDocument,
+    location: usize,
+    feed: Option<Feed>,
+    history: Vec<String>,
+    // Incremented with each feed request: the feeds of the previous ones are dropped.
+    request: usize,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Links are encoded in the *href* attribute:
+// `#i` opens the i-th catalog, `@i` follows the navigation link of the i-th entry,
+// `!i,j` downloads the j-th acquisition link of the i-th entry, `^rel` follows
+// the feed link with the given relation and `<` goes back to the previous feed.
+fn feed_to_content(feed: Option<&Feed>, has_back: bool, context: &Context) -> String {
+    let mut content = String::new();
+
+    let feed = match feed {
+        Some(feed) => feed,
+        None => {
+            let catalogs = &context.settings.opds.catalogs;
+            if catalogs.is_empty() {
+                content.push_str("<p class=\"info\">No catalogs defined.</p>");
+            } else {
+                content.push_str("<ul class=\"catalogs\">\n");
+                for (index, catalog) in catalogs.iter().enumerate() {
+                    content.push_str(&format!("<li><a href=\"#{}\">{}</a></li>\n", index, escape(&catalog.name)));
+                }
+                content.push_str("</ul>\n");
+            }
+            return content;
+        },
+    };
+
+    if !feed.title.is_empty() {
+        content.push_str(&format!("<h1 class=\"feed\">{}</h1>\n", escape(&feed.title)));
+    }
+
+    for (i, entry) in feed.entries.iter().enumerate() {
+        content.push_str("<div class=\"entry\">\n");
+        if entry.navigation_link().is_some() {
+            content.push_str(&format!("<h2 class=\"title\"><a href=\"@{}\">{}</a></h2>\n", i, escape(&entry.title)));
+        } else {
+            content.push_str(&format!("<h2 class=\"title\">{}</h2>\n", escape(&entry.title)));
+        }
+        if !entry.author.is_empty() {
+            content.push_str(&format!("<p class=\"author\">{}</p>\n", escape(&entry.author)));
+        }
+        if !entry.summary.is_empty() {
+            let mut summary: String = entry.summary.chars().take(MAX_SUMMARY_LENGTH).collect();
+            if summary.len() < entry.summary.len() {
+                summary.push('…');
+            }
+            content.push_str(&format!("<p class=\"summary\">{}</p>\n", escape(&summary)));
+        }
+        let acquisitions = entry.links.iter().enumerate()
+                                .filter(|(_, link)| link.is_acquisition())
+                                .filter_map(|(j, link)| link.extension().map(|ext| {
+                                    format!("<a href=\"!{},{}\">{}</a>", i, j, ext.to_uppercase())
+                                }))
+                                .collect::<Vec<String>>();
+        if !acquisitions.is_empty() {
+            content.push_str(&format!("<p class=\"acquisitions\">{}</p>\n", acquisitions.join(" ")));
+        }
+        content.push_str("</div>\n");
+    }
+
+    if feed.entries.is_empty() {
+        content.push_str("<p class=\"info\">No entries found.</p>\n");
+    }
+
+    let mut navigation = Vec::new();
+    if has_back {
+        navigation.push("<a href=\"<\">Back</a>".to_string());
+    }
+    for &(rel, label) in &[("up", "Up"), ("previous", "Previous"), ("next", "Next")] {
+        if feed.link(rel).is_some() {
+            navigation.push(format!("<a href=\"^{}\">{}</a>", rel, label));
+        }
+    }
+    if !navigation.is_empty() {
+        content.push_str(&format!("<p class=\"navigation\">{}</p>\n", navigation.join(" · ")));
+    }
+
+    content
+}
+
+fn download_file(url: &str, dir: &Path, path: &Path) -> Result<u64, Error> {
+    fs::create_dir_all(dir)?;
+    let bytes = fetch(url)?;
+    fs::write(path, &bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+impl Opds {
+    pub fn new(rect: Rectangle, hub: &Hub, context: &mut Context) -> Opds {
+        let mut children = Vec::new();
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, _) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (small_thickness, big_thickness) = halves(thickness);
+
+        let top_bar = TopBar::new(rect![rect.min.x, rect.min.y,
+                                        rect.max.x, rect.min.y + small_height as i32 - small_thickness],
+                                  Event::Back,
+                                  "Catalogs".to_string(),
+                                  context);
+        children.push(Box::new(top_bar) as Box<dyn View>);
+
+        let separator = Filler::new(rect![rect.min.x, rect.min.y + small_height as i32 - small_thickness,
+                                          rect.max.x, rect.min.y + small_height as i32 + big_thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let search_bar = SearchBar::new(rect![rect.min.x, rect.min.y + small_height as i32 + big_thickness,
+                                              rect.max.x, rect.min.y + 2 * small_height as i32 - small_thickness],
+                                        ViewId::OpdsSearchInput,
+                                        "", "", context);
+        children.push(Box::new(search_bar) as Box<dyn View>);
+
+        let separator = Filler::new(rect![rect.min.x, rect.min.y + 2 * small_height as i32 - small_thickness,
+                                          rect.max.x, rect.min.y + 2 * small_height as i32 + big_thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let image_rect = rect![rect.min.x, rect.min.y + 2 * small_height as i32 + big_thickness,
+                               rect.max.x, rect.max.y - small_height as i32 - small_thickness];
+
+        let image = Image::new(image_rect, Pixmap::new(1, 1));
+        children.push(Box::new(image) as Box<dyn View>);
+
+        let mut doc = HtmlDocument::new_from_memory("");
+        doc.layout(image_rect.width(), image_rect.height(), context.settings.dictionary.font_size, dpi);
+        doc.set_margin_width(context.settings.dictionary.margin_width);
+        doc.set_viewer_stylesheet(VIEWER_STYLESHEET);
+        doc.set_user_stylesheet(USER_STYLESHEET);
+
+        let separator = Filler::new(rect![rect.min.x, rect.max.y - small_height as i32 - small_thickness,
+                                          rect.max.x, rect.max.y - small_height as i32 + big_thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let bottom_bar = PagedBottomBar::new(rect![rect.min.x, rect.max.y - small_height as i32 + big_thickness,
+                                                   rect.max.x, rect.max.y],
+                                             "", false, false);
+        children.push(Box::new(bottom_bar) as Box<dyn View>);
+
+        hub.send(Event::Render(rect, UpdateMode::Gui)).ok();
+
+        let mut opds = Opds {
+            rect,
+            children,
+            doc,
+            location: 0,
+            feed: None,
+            history: Vec::new(),
+            request: 0,
+        };
+
+        if context.settings.opds.catalogs.len() == 1 {
+            opds.load_catalog(0, hub, context);
+        } else {
+            opds.update(hub, context);
+        }
+
+        opds
+    }
+
+    fn toggle_title_menu(&mut self, rect: Rectangle, enable: Option<bool>, hub: &Hub, context: &mut Context) {
+        if let Some(index) = locate_by_id(self, ViewId::TitleMenu) {
+            if let Some(true) = enable {
+                return;
+            }
+
+            hub.send(Event::Expose(*self.child(index).rect(), UpdateMode::Gui)).ok();
+            self.children.remove(index);
+        } else {
+            if let Some(false) = enable {
+                return;
+            }
+            let entries = context.settings.opds.catalogs.iter().enumerate()
+                                 .map(|(index, catalog)| EntryKind::Command(catalog.name.clone(),
+                                                                            EntryId::LoadCatalog(index)))
+                                 .collect::<Vec<EntryKind>>();
+            if entries.is_empty() {
+                return;
+            }
+            let title_menu = Menu::new(rect, ViewId::TitleMenu, MenuKind::DropDown, entries, context);
+            hub.send(Event::Render(*title_menu.rect(), UpdateMode::Gui)).ok();
+            self.children.push(Box::new(title_menu) as Box<dyn View>);
+        }
+    }
+
+    fn toggle_keyboard(&mut self, enable: bool, hub: &Hub, context: &mut Context) {
+        if let Some(index) = locate::<Keyboard>(self) {
+            if enable {
+                return;
+            }
+
+            let mut rect = *self.child(index).rect();
+            rect.absorb(self.child(index-1).rect());
+            self.children.drain(index - 1 ..= index);
+
+            hub.send(Event::Expose(rect, UpdateMode::Gui)).ok();
+            hub.send(Event::Focus(None)).ok();
+        } else {
+            if !enable {
+                return;
+            }
+
+            let dpi = CURRENT_DEVICE.dpi;
+            let (_, height) = context.display.dims;
+            let &(small_height, big_height) = BAR_SIZES.get(&(height, dpi)).unwrap();
+            let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+            let (small_thickness, big_thickness) = halves(thickness);
+
+            let mut kb_rect = rect![self.rect.min.x,
+                                    self.rect.max.y - (small_height + 3 * big_height) as i32 + big_thickness,
+                                    self.rect.max.x,
+                                    self.rect.max.y - small_height as i32 - small_thickness];
+
+            let index = locate::<PagedBottomBar>(self).unwrap() + 1;
+
+            let keyboard = Keyboard::new(&mut kb_rect, false, context);
+            self.children.insert(index, Box::new(keyboard) as Box<dyn View>);
+
+            let separator = Filler::new(rect![self.rect.min.x, kb_rect.min.y - thickness,
+                                              self.rect.max.x, kb_rect.min.y],
+                                        BLACK);
+            self.children.insert(index, Box::new(separator) as Box<dyn View>);
+
+            for i in index..=index+1 {
+                hub.send(Event::Render(*self.child(i).rect(), UpdateMode::Gui)).ok();
+            }
+        }
+    }
+
+    fn reseed(&mut self, hub: &Hub, context: &mut Context) {
+        let (tx, _rx) = mpsc::channel();
+        if let Some(top_bar) = self.child_mut(0).downcast_mut::<TopBar>() {
+            top_bar.update_frontlight_icon(&tx, context);
+        }
+        hub.send(Event::ClockTick).ok();
+        hub.send(Event::BatteryTick).ok();
+        hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
+    }
+
+    fn go_to_neighbor(&mut self, dir: CycleDir, hub: &Hub) {
+        let location = match dir {
+            CycleDir::Previous => Location::Previous(self.location),
+            CycleDir::Next => Location::Next(self.location),
+        };
+        if let Some(image) = self.children[4].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(location, 1.0) {
+                image.update(pixmap, hub);
+                self.location = loc;
+            }
+        }
+        if let Some(bottom_bar) = self.children[6].downcast_mut::<PagedBottomBar>() {
+            bottom_bar.update_icons(self.doc.resolve_location(Location::Previous(self.location)).is_some(),
+                                    self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+    }
+
+    fn update(&mut self, hub: &Hub, context: &mut Context) {
+        let content = feed_to_content(self.feed.as_ref(), self.history.len() > 1, context);
+        self.doc.update(&content);
+        if let Some(image) = self.children[4].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(Location::Exact(0), 1.0) {
+                image.update(pixmap, hub);
+                self.location = loc;
+            }
+        }
+        if let Some(bottom_bar) = self.children[6].downcast_mut::<PagedBottomBar>() {
+            let name = self.feed.as_ref().map(|feed| feed.title.as_str()).unwrap_or("");
+            bottom_bar.update_name(name, hub);
+            bottom_bar.update_icons(false, self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+    }
+
+    fn load_catalog(&mut self, index: usize, hub: &Hub, context: &mut Context) {
+        if let Some(url) = context.settings.opds.catalogs.get(index).map(|c| c.url.clone()) {
+            self.history.clear();
+            self.load_feed(url, hub);
+        }
+    }
+
+    fn load_feed(&mut self, url: String, hub: &Hub) {
+        self.request = self.request.wrapping_add(1);
+        let request = self.request;
+        let hub2 = hub.clone();
+        thread::spawn(move || {
+            match fetch_feed(&url) {
+                Ok(feed) => hub2.send(Event::Feed(request, Box::new(feed))).ok(),
+                Err(e) => hub2.send(Event::Notify(format!("Can't load {}: {}.", url, e))).ok(),
+            };
+        });
+    }
+
+    fn search(&mut self, query: &str, hub: &Hub) {
+        let feed = match self.feed.clone() {
+            Some(feed) => feed,
+            None => return,
+        };
+        self.request = self.request.wrapping_add(1);
+        let request = self.request;
+        let query = query.to_string();
+        let hub2 = hub.clone();
+        thread::spawn(move || {
+            match search_url(&feed, &query).and_then(|url| fetch_feed(&url)) {
+                Ok(feed) => hub2.send(Event::Feed(request, Box::new(feed))).ok(),
+                Err(e) => hub2.send(Event::Notify(format!("Can't search for {}: {}.", query, e))).ok(),
+            };
+        });
+    }
+
+    // Adds the feed to the navigation history, unless we're going back to the previous one.
+    fn set_feed(&mut self, feed: Feed, hub: &Hub, context: &mut Context) {
+        let len = self.history.len();
+        if len > 1 && self.history[len - 2] == feed.url {
+            self.history.pop();
+        } else if self.history.last() != Some(&feed.url) {
+            self.history.push(feed.url.clone());
+        }
+        self.feed = Some(feed);
+        self.update(hub, context);
+    }
+
+    fn download(&mut self, entry: &Entry, link_index: usize, hub: &Hub, context: &mut Context) {
+        let link = match entry.links.get(link_index) {
+            Some(link) => link.clone(),
+            None => return,
+        };
+        let kind = link.extension().unwrap_or("epub").to_string();
+        let mut name = asciify(&entry.title).replace('/', " ");
+        if name.is_empty() {
+            name = "Untitled".to_string();
+        }
+        // Books with the same title are told apart by a hash of their URL.
+        let mut hasher = FnvHasher::default();
+        link.href.hash(&mut hasher);
+        let dir = context.settings.library_path.join(&context.settings.opds.save_path);
+        let path = dir.join(format!("{} {:08X}.{}", name, hasher.finish() as u32, kind));
+
+        if path.exists() {
+            hub.send(Event::Notify(format!("{} already exists.", path.display()))).ok();
+            return;
+        }
+
+        let mut info = Info {
+            title: entry.title.clone(),
+            author: entry.author.clone(),
+            language: entry.language.clone(),
+            publisher: entry.publisher.clone(),
+            year: entry.year.clone(),
+            .. Default::default()
+        };
+        let hub2 = hub.clone();
+
+        thread::spawn(move || {
+            match download_file(&link.href, &dir, &path) {
+                Ok(size) => {
+                    info.file = FileInfo {
+                        path: path.clone(),
+                        kind,
+                        size,
+                    };
+                    info.added = Local::now();
+                    hub2.send(Event::Notify(format!("Downloaded {}.", info.title))).ok();
+                    hub2.send(Event::AddDocument(Box::new(info))).ok();
+                },
+                Err(e) => {
+                    fs::remove_file(&path).ok();
+                    hub2.send(Event::Notify(format!("Can't download {}: {}.", info.title, e))).ok();
+                },
+            }
+        });
+    }
+
+    fn follow_link(&mut self, pt: Point, hub: &Hub, context: &mut Context) {
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, _) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (_, big_thickness) = halves(thickness);
+        let offset = pt!(self.rect.min.x, self.rect.min.y + 2 * small_height as i32 + big_thickness);
+
+        let mut target = None;
+
+        if let Some((links, _)) = self.doc.links(Location::Exact(self.location)) {
+            for link in links {
+                let rect = link.rect.to_rect() + offset;
+                if rect.includes(pt) {
+                    target = Some(link.text);
+                    break;
+                }
+            }
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                let half_width = self.rect.width() as i32 / 2;
+                if pt.x - offset.x < half_width {
+                    self.go_to_neighbor(CycleDir::Previous, hub);
+                } else {
+                    self.go_to_neighbor(CycleDir::Next, hub);
+                }
+                return;
+            },
+        };
+
+        if target.starts_with('#') {
+            if let Ok(index) = target[1..].parse::<usize>() {
+                self.load_catalog(index, hub, context);
+            }
+        } else if target.starts_with('<') {
+            let len = self.history.len();
+            if len > 1 {
+                let url = self.history[len - 2].clone();
+                self.load_feed(url, hub);
+            }
+        } else if target.starts_with('^') {
+            if let Some(url) = self.feed.as_ref().and_then(|feed| feed.link(&target[1..]))
+                                   .map(|link| link.href.clone()) {
+                self.load_feed(url, hub);
+            }
+        } else if target.starts_with('@') {
+            if let Some(url) = target[1..].parse::<usize>().ok()
+                                          .and_then(|i| self.feed.as_ref().and_then(|feed| feed.entries.get(i)))
+                                          .and_then(|entry| entry.navigation_link())
+                                          .map(|link| link.href.clone()) {
+                self.load_feed(url, hub);
+            }
+        } else if target.starts_with('!') {
+            let mut indices = target[1..].split(',').filter_map(|s| s.parse::<usize>().ok());
+            if let (Some(i), Some(j)) = (indices.next(), indices.next()) {
+                if let Some(entry) = self.feed.as_ref().and_then(|feed| feed.entries.get(i)).cloned() {
+                    self.download(&entry, j, hub, context);
+                }
+            }
+        }
+    }
+}
+
+impl View for Opds {
+    fn handle_event(&mut self, evt: &Event, hub: &Hub, _bus: &mut Bus, context: &mut Context) -> bool {
+        match *evt {
+            Event::Feed(request, ref feed) => {
+                if request == self.request {
+                    self.set_feed(feed.as_ref().clone(), hub, context);
+                }
+                true
+            },
+            Event::Submit(ViewId::OpdsSearchInput, ref text) => {
+                if !text.is_empty() {
+                    self.toggle_keyboard(false, hub, context);
+                    self.search(text, hub);
+                }
+                true
+            },
+            Event::Select(EntryId::LoadCatalog(index)) => {
+                self.load_catalog(index, hub, context);
+                true
+            },
+            Event::Page(dir) => {
+                self.go_to_neighbor(dir, hub);
+                true
+            },
+            Event::Gesture(GestureEvent::Swipe { dir, start, .. }) if self.rect.includes(start) => {
+                match dir {
+                    Dir::West => self.go_to_neighbor(CycleDir::Next, hub),
+                    Dir::East => self.go_to_neighbor(CycleDir::Previous, hub),
+                    _ => (),
+                }
+                true
+            },
+            Event::Gesture(GestureEvent::Tap(center)) if self.rect.includes(center) => {
+                self.follow_link(center, hub, context);
+                true
+            },
+            Event::Close(ViewId::SearchBar) => {
+                hub.send(Event::Back).ok();
+                true
+            },
+            Event::Focus(v) => {
+                if v.is_some() {
+                    self.toggle_keyboard(true, hub, context);
+                }
+                true
+            },
+            Event::ToggleNear(ViewId::TitleMenu, rect) => {
+                self.toggle_title_menu(rect, None, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::MainMenu, rect) => {
+                toggle_main_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::BatteryMenu, rect) => {
+                toggle_battery_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::ClockMenu, rect) => {
+                toggle_clock_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::Reseed => {
+                self.reseed(hub, context);
+                true
+            },
+            Event::Gesture(GestureEvent::Cross(_)) => {
+                hub.send(Event::Back).ok();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn render(&self, _fb: &mut dyn Framebuffer, _rect: Rectangle, _fonts: &mut Fonts) {
+    }
+
+    fn resize(&mut self, rect: Rectangle, hub: &Hub, context: &mut Context) {
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, big_height) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (small_thickness, big_thickness) = halves(thickness);
+
+        self.children[0].resize(rect![rect.min.x, rect.min.y,
+                                      rect.max.x, rect.min.y + small_height as i32 - small_thickness],
+                                hub, context);
+
+        self.children[1].resize(rect![rect.min.x, rect.min.y + small_height as i32 - small_thickness,
+                                      rect.max.x, rect.min.y + small_height as i32 + big_thickness],
+                                hub, context);
+
+        self.children[2].resize(rect![rect.min.x, rect.min.y + small_height as i32 + big_thickness,
+                                      rect.max.x, rect.min.y + 2 * small_height as i32 - small_thickness],
+                                hub, context);
+
+        self.children[3].resize(rect![rect.min.x, rect.min.y + 2 * small_height as i32 - small_thickness,
+                                      rect.max.x, rect.min.y + 2 * small_height as i32 + big_thickness],
+                                hub, context);
+
+        let image_rect = rect![rect.min.x, rect.min.y + 2 * small_height as i32 + big_thickness,
+                               rect.max.x, rect.max.y - small_height as i32 - small_thickness];
+        self.doc.layout(image_rect.width(), image_rect.height(), context.settings.dictionary.font_size, dpi);
+        if let Some(image) = self.children[4].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(Location::Exact(self.location), 1.0) {
+                let (tx, _rx) = mpsc::channel();
+                image.update(pixmap, &tx);
+                self.location = loc;
+            }
+        }
+        self.children[4].resize(image_rect, hub, context);
+
+        self.children[5].resize(rect![rect.min.x, rect.max.y - small_height as i32 - small_thickness,
+                                      rect.max.x, rect.max.y - small_height as i32 + big_thickness],
+                                hub, context);
+
+        self.children[6].resize(rect![rect.min.x, rect.max.y - small_height as i32 + big_thickness,
+                                      rect.max.x, rect.max.y],
+                                hub, context);
+        if let Some(bottom_bar) = self.children[6].downcast_mut::<PagedBottomBar>() {
+            bottom_bar.update_icons(self.doc.resolve_location(Location::Previous(self.location)).is_some(),
+                                    self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+        let mut index = 7;
+        if self.len() >= 9 {
+            if self.children[8].is::<Keyboard>() {
+                let kb_rect = rect![rect.min.x,
+                                    rect.max.y - (small_height + 3 * big_height) as i32 + big_thickness,
+                                    rect.max.x,
+                                    rect.max.y - small_height as i32 - small_thickness];
+                self.children[8].resize(kb_rect, hub, context);
+                let kb_rect = *self.children[8].rect();
+                self.children[7].resize(rect![rect.min.x, kb_rect.min.y - thickness,
+                                              rect.max.x, kb_rect.min.y],
+                                        hub, context);
+                index = 9;
+            }
+        }
+
+        for i in index..self.children.len() {
+            self.children[i].resize(rect, hub, context);
+        }
+
+        self.rect = rect;
+        hub.send(Event::Render(self.rect, UpdateMode::Full)).ok();
+    }
+
+    fn rect(&self) -> &Rectangle {
+        &self.rect
+    }
+
+    fn rect_mut(&mut self) -> &mut Rectangle {
+        &mut self.rect
+    }
+
+    fn children(&self) -> &Vec<Box<dyn View>> {
+        &self.children
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<Box<dyn View>> {
+        &mut self.children
+    }
+}
@@ -4,6 +4,7 @@ use crate::color::{BLACK, WHITE};
 use crate::gesture::GestureEvent;
 use crate::geom::{Rectangle};
 use crate::document::BYTES_PER_PAGE;
+use crate::helpers::format_duration;
 use crate::framebuffer::{Framebuffer, UpdateMode};
 use super::{View, Event, Hub, Bus, ViewId};
 use crate::app::Context;
@@ -14,6 +15,12 @@ pub struct PageLabel {
     current_page: usize,
     pages_count: usize,
     synthetic: bool,
+    // The page number and the number of pages of the current layout.
+    page_numbers: Option<(usize, usize)>,
+    // The index of the view and the number of views of the current page, in reflow and column modes.
+    sub_page: Option<(usize, usize)>,
+    // Estimated reading time left, in seconds, in the current chapter and in the book.
+    time_left: Option<(Option<u64>, u64)>,
 }
 
 impl PageLabel {
@@ -24,6 +31,9 @@ impl PageLabel {
             current_page,
             pages_count,
             synthetic,
+            page_numbers: None,
+            sub_page: None,
+            time_left: None,
         }
     }
 
@@ -33,23 +43,65 @@ impl PageLabel {
         hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
     }
 
+    pub fn update_page_numbers(&mut self, page_numbers: Option<(usize, usize)>, hub: &Hub) {
+        if self.page_numbers != page_numbers {
+            self.page_numbers = page_numbers;
+            hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
+        }
+    }
+
+    pub fn update_sub_page(&mut self, sub_page: Option<(usize, usize)>, hub: &Hub) {
+        if self.sub_page != sub_page {
+            self.sub_page = sub_page;
+            hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
+        }
+    }
+
+    pub fn update_time_left(&mut self, time_left: Option<(Option<u64>, u64)>, hub: &Hub) {
+        if self.time_left != time_left {
+            self.time_left = time_left;
+            hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
+        }
+    }
+
+    fn time_left_text(&self, size: u8) -> Option<String> {
+        let (chapter, book) = self.time_left?;
+        match (size, chapter) {
+            (0, Some(chapter)) => Some(format!("{} left in chapter, {} in book",
+                                               format_duration(chapter), format_duration(book))),
+            (0, None) | (1, _) => Some(format!("{} left", format_duration(book))),
+            _ => None,
+        }
+    }
+
     pub fn text(&self, size: u8) -> String {
         if self.pages_count == 0 {
             return "No pages".to_string();
         }
-        let (current_page, pages_count, precision) = if self.synthetic {
+        let (current_page, pages_count, precision) = if let Some((number, count)) = self.page_numbers {
+            (number as f64 + 1.0, count as f64, 0)
+        } else if self.synthetic {
             (self.current_page as f64 / BYTES_PER_PAGE,
              self.pages_count as f64 / BYTES_PER_PAGE, 1)
         } else {
             (self.current_page as f64 + 1.0,
              self.pages_count as f64, 0)
         };
-        let percent = 100.0 * self.current_page as f32 / self.pages_count as f32;
+        // When the views are numbered across the book, the source page is shown instead.
+        let (sub_page, fraction) = self.sub_page.map_or((String::new(), 0.0), |(index, count)| {
+            let text = if self.page_numbers.is_some() {
+                format!(" (p. {})", self.current_page + 1)
+            } else {
+                format!(" ({}/{})", index + 1, count)
+            };
+            (text, index as f32 / count as f32)
+        });
+        let percent = 100.0 * (self.current_page as f32 + fraction) / self.pages_count as f32;
         match size {
-            0 => format!("Page {1:.0$} of {2:.0$} ({3:.1}%)", precision, current_page, pages_count, percent),
-            1 => format!("P. {1:.0$} of {2:.0$} ({3:.1}%)", precision, current_page, pages_count, percent),
+            0 => format!("Page {1:.0$}{2} of {3:.0$} ({4:.1}%)", precision, current_page, sub_page, pages_count, percent),
+            1 => format!("P. {1:.0$}{2} of {3:.0$} ({4:.1}%)", precision, current_page, sub_page, pages_count, percent),
             2 => format!("{1:.0$}/{2:.0$} ({3:.1}%)", precision, current_page, pages_count, percent),
-            3 => format!("{1:.0$} ({2:.1}%)", precision, current_page, percent),
+            3 => format!("{1:.0$}{2} ({3:.1}%)", precision, current_page, sub_page, percent),
             _ => format!("{:.1}%", percent),
         }
     }
@@ -76,12 +128,16 @@ impl View for PageLabel {
         let font = font_from_style(fonts, &NORMAL_STYLE, dpi);
         let padding = font.em() as i32 / 2;
         let max_width = self.rect.width().saturating_sub(2 * padding as u32) as i32;
-        let mut plan = font.plan(&self.text(0), None, None);
-        for size in 1..=4 {
+        let mut texts = (0..=1).filter_map(|size| self.time_left_text(size))
+                               .map(|time_left| format!("{} · {}", self.text(2), time_left))
+                               .collect::<Vec<String>>();
+        texts.extend((0..=4).map(|size| self.text(size)));
+        let mut plan = font.plan(&texts[0], None, None);
+        for text in &texts[1..] {
             if plan.width <= max_width as u32 {
                 break;
             }
-            plan = font.plan(&self.text(size), None, None);
+            plan = font.plan(text, None, None);
         }
         font.crop_right(&mut plan, max_width as u32);
         let dx = padding + (max_width - plan.width as i32) / 2;
@@ -0,0 +1,131 @@
+use crate::framebuffer::Pixmap;
+use crate::document::{Document, Location};
+use crate::metadata::{Margin, CroppingMargins};
+use crate::geom::Rectangle;
+use super::reflow::components;
+
+// Maximum number of pages analyzed.
+const SAMPLE_SIZE: usize = 16;
+// Height, in pixels, at which the pages are analyzed.
+const SAMPLE_HEIGHT: f32 = 600.0;
+// Even and odd pages get their own margins when they differ by more than this.
+const ASYMMETRY_THRESHOLD: f32 = 0.02;
+// Space left around the content.
+const PADDING: f32 = 0.01;
+
+// The margins around the content of a page. Specks, scanning borders and the
+// thin lines close to the top or bottom edge, such as page numbers and running
+// heads, are ignored.
+fn pixmap_margin(pixmap: &Pixmap) -> Option<Margin> {
+    let (width, height) = (pixmap.width as i32, pixmap.height as i32);
+    let min_size = (height / 200).max(1);
+    let boxes = components(pixmap, &rect![0, 0, width, height]).into_iter()
+                    .filter(|b| (b.width() as i32 > min_size || b.height() as i32 > min_size) &&
+                                b.min.x > 0 && b.min.y > 0 && b.max.x < width && b.max.y < height)
+                    .collect::<Vec<Rectangle>>();
+
+    // Group the components into horizontal bands separated by blank space.
+    let gap = height / 40;
+    let mut bands: Vec<Rectangle> = Vec::new();
+    for b in boxes {
+        match bands.last_mut() {
+            Some(band) if b.min.y <= band.max.y + gap => band.absorb(&b),
+            _ => bands.push(b),
+        }
+    }
+
+    let is_thin = |r: &Rectangle| (r.height() as i32) < height / 30;
+    if bands.len() > 1 && is_thin(&bands[0]) && bands[0].max.y < height / 10 {
+        bands.remove(0);
+    }
+    if bands.len() > 1 && is_thin(&bands[bands.len() - 1]) && bands[bands.len() - 1].min.y > 9 * height / 10 {
+        bands.pop();
+    }
+
+    let mut bounds = *bands.first()?;
+    for band in &bands[1..] {
+        bounds.absorb(band);
+    }
+
+    Some(Margin::new(bounds.min.y as f32 / height as f32,
+                     (width - bounds.max.x) as f32 / width as f32,
+                     (height - bounds.max.y) as f32 / height as f32,
+                     bounds.min.x as f32 / width as f32))
+}
+
+// Keeps, for each side, the tenth percentile of the margins, so that a few
+// pages with unusual content don't shrink the crop of the whole document.
+fn combine(margins: &[Margin]) -> Margin {
+    let pick = |side: fn(&Margin) -> f32| {
+        let mut values = margins.iter().map(side).collect::<Vec<f32>>();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        (values[values.len() / 10] - PADDING).max(0.0)
+    };
+    Margin::new(pick(|m| m.top), pick(|m| m.right), pick(|m| m.bottom), pick(|m| m.left))
+}
+
+// Analyzes pairs of consecutive pages spread over the document, skipping
+// the front and back matter of long documents.
+pub fn auto_crop(doc: &mut dyn Document) -> Option<CroppingMargins> {
+    let count = doc.pages_count();
+    let (start, end) = if count > 20 {
+        (count / 10, count - count / 10)
+    } else {
+        (0, count)
+    };
+    let step = ((end - start) / (SAMPLE_SIZE / 2)).max(1);
+    let mut margins = [Vec::new(), Vec::new()];
+
+    for i in (start..end).step_by(step).take(SAMPLE_SIZE / 2) {
+        for index in i..(i + 2).min(count) {
+            let margin = doc.dims(index).and_then(|(_, height)| {
+                doc.pixmap(Location::Exact(index), SAMPLE_HEIGHT / height)
+            }).and_then(|(pixmap, _)| pixmap_margin(&pixmap));
+            if let Some(margin) = margin {
+                margins[index % 2].push(margin);
+            }
+        }
+    }
+
+    if !margins[0].is_empty() && !margins[1].is_empty() {
+        let even = combine(&margins[0]);
+        let odd = combine(&margins[1]);
+        if (even.left - odd.left).abs() > ASYMMETRY_THRESHOLD ||
+           (even.right - odd.right).abs() > ASYMMETRY_THRESHOLD {
+            return Some(CroppingMargins::EvenOdd([even, odd]));
+        }
+    }
+
+    let all = margins[0].iter().chain(margins[1].iter()).cloned().collect::<Vec<Margin>>();
+    if all.is_empty() {
+        None
+    } else {
+        Some(CroppingMargins::Any(combine(&all)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixmap_margin() {
+        let mut pixmap = Pixmap::new(100, 200);
+        let mut fill = |x0: usize, y0: usize, x1: usize, y1: usize| {
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    pixmap.data[y * 100 + x] = 0;
+                }
+            }
+        };
+        // Body, page number and a scanning border.
+        fill(20, 30, 80, 150);
+        fill(48, 190, 52, 194);
+        fill(0, 0, 3, 200);
+        let margin = pixmap_margin(&pixmap).unwrap();
+        assert_eq!(margin.top, 0.15);
+        assert_eq!(margin.left, 0.2);
+        assert_eq!(margin.right, 0.2);
+        assert_eq!(margin.bottom, 0.25);
+    }
+}
@@ -86,6 +86,21 @@ impl BottomBar {
         page_label.update(current_page, pages_count, hub);
     }
 
+    pub fn update_page_numbers(&mut self, page_numbers: Option<(usize, usize)>, hub: &Hub) {
+        let page_label = self.child_mut(2).downcast_mut::<PageLabel>().unwrap();
+        page_label.update_page_numbers(page_numbers, hub);
+    }
+
+    pub fn update_sub_page(&mut self, sub_page: Option<(usize, usize)>, hub: &Hub) {
+        let page_label = self.child_mut(2).downcast_mut::<PageLabel>().unwrap();
+        page_label.update_sub_page(sub_page, hub);
+    }
+
+    pub fn update_time_left(&mut self, time_left: Option<(Option<u64>, u64)>, hub: &Hub) {
+        let page_label = self.child_mut(2).downcast_mut::<PageLabel>().unwrap();
+        page_label.update_time_left(time_left, hub);
+    }
+
     pub fn update_icons(&mut self, neighbors: &Neighbors, hub: &Hub) {
         let is_prev_disabled = neighbors.previous_page.is_none();
 
@@ -0,0 +1,172 @@
+use crate::framebuffer::Pixmap;
+use crate::geom::Rectangle;
+use super::reflow::{Piece, components};
+
+// Maximum depth of the recursive cuts.
+const MAX_DEPTH: usize = 8;
+
+fn bounds(boxes: &[Rectangle]) -> Option<Rectangle> {
+    let mut result = *boxes.first()?;
+    for b in &boxes[1..] {
+        result.absorb(b);
+    }
+    Some(result)
+}
+
+// The blank intervals, at least `min_gap` long, between the projections of the given spans.
+fn gaps<I>(spans: I, min_gap: i32) -> Vec<(i32, i32)> where I: Iterator<Item=(i32, i32)> {
+    let mut spans = spans.collect::<Vec<(i32, i32)>>();
+    spans.sort();
+    let mut result = Vec::new();
+    let mut end = match spans.first() {
+        Some(&(_, end)) => end,
+        None => return result,
+    };
+    for &(start, stop) in &spans[1..] {
+        if start - end >= min_gap {
+            result.push((end, start));
+        }
+        end = end.max(stop);
+    }
+    result
+}
+
+fn gutter(boxes: &[Rectangle], min_gap: i32) -> Option<(i32, i32)> {
+    gaps(boxes.iter().map(|b| (b.min.x, b.max.x)), min_gap).into_iter()
+                                                           .max_by_key(|&(start, end)| end - start)
+}
+
+// Splits the boxes into horizontal strips separated by blank rows.
+fn strips(mut boxes: Vec<Rectangle>, min_gap: i32) -> Vec<Vec<Rectangle>> {
+    let cuts = gaps(boxes.iter().map(|b| (b.min.y, b.max.y)), min_gap);
+    boxes.sort_by_key(|b| b.min.y);
+    let mut result = vec![Vec::new(); cuts.len() + 1];
+    for b in boxes {
+        let index = cuts.iter().take_while(|&&(start, _)| b.min.y >= start).count();
+        result[index].push(b);
+    }
+    result
+}
+
+// Panels and figures: large strips holding a component that spans most of their height.
+fn is_panel(strip: &[Rectangle], page_height: i32) -> bool {
+    bounds(strip).map_or(false, |r| {
+        let height = r.height() as i32;
+        6 * height > page_height && strip.iter().any(|b| 2 * b.height() as i32 > height)
+    })
+}
+
+// A recursive XY-cut. The boxes are cut along the widest vertical gap when
+// there's one. Otherwise, they're split into strips, and the consecutive
+// strips that share a gutter are cut together, while the consecutive strips
+// of text are merged.
+fn cut(boxes: Vec<Rectangle>, min_gap: (i32, i32), page_height: i32, rtl: bool, depth: usize, regions: &mut Vec<Rectangle>) {
+    if boxes.is_empty() {
+        return;
+    }
+
+    if depth < MAX_DEPTH {
+        if let Some((start, end)) = gutter(&boxes, min_gap.0) {
+            let x = (start + end) / 2;
+            let (left, right): (Vec<Rectangle>, Vec<Rectangle>) = boxes.into_iter().partition(|b| b.center().x < x);
+            let (first, second) = if rtl { (right, left) } else { (left, right) };
+            cut(first, min_gap, page_height, rtl, depth + 1, regions);
+            cut(second, min_gap, page_height, rtl, depth + 1, regions);
+            return;
+        }
+    }
+
+    let strips = strips(boxes, min_gap.1);
+    if depth >= MAX_DEPTH || strips.len() == 1 {
+        regions.extend(strips.iter().filter_map(|s| bounds(s)));
+        return;
+    }
+
+    let mut groups: Vec<(Vec<Rectangle>, Option<(i32, i32)>)> = Vec::new();
+    for strip in strips {
+        let strip_gutter = gutter(&strip, min_gap.0);
+        let merge = match groups.last() {
+            Some(&(_, Some(g))) => strip_gutter.map_or(false, |sg| {
+                g.1.min(sg.1) - g.0.max(sg.0) >= min_gap.0
+            }),
+            Some(&(ref last, None)) => strip_gutter.is_none() &&
+                                       !is_panel(last, page_height) &&
+                                       !is_panel(&strip, page_height),
+            None => false,
+        };
+        if merge {
+            let group = groups.last_mut().unwrap();
+            if let (Some(g), Some(sg)) = (group.1, strip_gutter) {
+                group.1 = Some((g.0.max(sg.0), g.1.min(sg.1)));
+            }
+            group.0.extend(strip);
+        } else {
+            groups.push((strip, strip_gutter));
+        }
+    }
+
+    for (group, group_gutter) in groups {
+        if group_gutter.is_some() {
+            cut(group, min_gap, page_height, rtl, depth + 1, regions);
+        } else if let Some(r) = bounds(&group) {
+            regions.push(r);
+        }
+    }
+}
+
+// The columns or panels of the frame of a page pixmap, in reading order.
+pub fn regions(pixmap: &Pixmap, frame: &Rectangle, rtl: bool) -> Vec<Rectangle> {
+    let min_size = (frame.height() as i32 / 300).max(1);
+    let boxes = components(pixmap, frame).into_iter()
+                                         .filter(|b| b.width() as i32 > min_size || b.height() as i32 > min_size)
+                                         .collect::<Vec<Rectangle>>();
+    let min_gap = ((frame.width() as i32 / 50).max(2), (frame.height() as i32 / 100).max(2));
+    let mut regions = Vec::new();
+    cut(boxes, min_gap, frame.height() as i32, rtl, 0, &mut regions);
+    regions
+}
+
+// Each region is shown in as many views as needed to cover its height.
+pub fn views(regions: &[Rectangle], width: i32, height: i32) -> Vec<Vec<Piece>> {
+    let mut result = Vec::new();
+    if width <= 0 || height <= 0 {
+        return vec![Vec::new()];
+    }
+
+    for r in regions {
+        let w = (r.width() as i32).min(width);
+        let x0 = r.min.x + (r.width() as i32 - w) / 2;
+        let dx = (width - w) / 2;
+        let mut y0 = r.min.y;
+        while y0 < r.max.y {
+            let h = (r.max.y - y0).min(height);
+            let dy = (height - h) / 2;
+            result.push(vec![Piece { frame: rect![x0, y0, x0 + w, y0 + h], position: pt!(dx, dy) }]);
+            y0 += h;
+        }
+    }
+
+    if result.is_empty() {
+        result.push(Vec::new());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_two_columns() {
+        let mut boxes = vec![rect![10, 5, 90, 10]];
+        for i in 0..5 {
+            let y = 20 + 10 * i;
+            boxes.push(rect![10, y, 45, y + 6]);
+            boxes.push(rect![55, y + 3, 90, y + 9]);
+        }
+        let mut regions = Vec::new();
+        cut(boxes, (4, 4), 100, false, 0, &mut regions);
+        assert_eq!(regions, vec![rect![10, 5, 90, 10], rect![10, 20, 45, 66], rect![55, 23, 90, 69]]);
+    }
+}
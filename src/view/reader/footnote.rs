@@ -0,0 +1,152 @@
+use crate::device::CURRENT_DEVICE;
+use crate::document::{Document, Location};
+use crate::document::html::HtmlDocument;
+use crate::framebuffer::{Framebuffer, Pixmap};
+use crate::geom::{Rectangle, Dir, CycleDir};
+use crate::font::{Fonts, font_from_style, NORMAL_STYLE};
+use crate::view::{View, Event, Hub, Bus, ViewId, THICKNESS_MEDIUM};
+use crate::view::filler::Filler;
+use crate::view::image::Image;
+use crate::view::button::Button;
+use crate::gesture::GestureEvent;
+use crate::color::{BLACK, WHITE};
+use crate::unit::scale_by_dpi;
+use crate::app::Context;
+
+const LABEL_JUMP: &str = "Go to Note";
+
+pub struct Footnote {
+    rect: Rectangle,
+    children: Vec<Box<dyn View>>,
+    doc: HtmlDocument,
+    location: usize,
+}
+
+impl Footnote {
+    pub fn new(rect: Rectangle, content: &str, target: Option<usize>, font_size: f32, context: &mut Context) -> Footnote {
+        let mut children = Vec::new();
+        let dpi = CURRENT_DEVICE.dpi;
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+
+        let (x_height, padding, button_width) = {
+            let font = font_from_style(&mut context.fonts, &NORMAL_STYLE, dpi);
+            let plan = font.plan(LABEL_JUMP, None, None);
+            let padding = font.em() as i32;
+            (font.x_heights.0 as i32, padding, plan.width as i32 + 2 * padding)
+        };
+        let button_height = 4 * x_height;
+
+        let rect = rect![rect.min.x, rect.max.y - rect.height() as i32 / 3,
+                         rect.max.x, rect.max.y];
+
+        let separator = Filler::new(rect![rect.min.x, rect.min.y,
+                                          rect.max.x, rect.min.y + thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let image_rect = rect![rect.min.x, rect.min.y + thickness,
+                               rect.max.x, rect.max.y - button_height - padding];
+
+        let mut doc = HtmlDocument::new_from_memory(content);
+        doc.layout(image_rect.width(), image_rect.height(), font_size, dpi);
+
+        let (pixmap, location) = doc.pixmap(Location::Exact(0), 1.0)
+                                    .unwrap_or_else(|| (Pixmap::new(1, 1), 0));
+        let image = Image::new(image_rect, pixmap);
+        children.push(Box::new(image) as Box<dyn View>);
+
+        if let Some(target) = target {
+            let button_rect = rect![rect.max.x - button_width - padding,
+                                    rect.max.y - button_height - padding / 2,
+                                    rect.max.x - padding,
+                                    rect.max.y - padding / 2];
+            let button = Button::new(button_rect, Event::GoTo(target), LABEL_JUMP.to_string());
+            children.push(Box::new(button) as Box<dyn View>);
+        }
+
+        Footnote {
+            rect,
+            children,
+            doc,
+            location,
+        }
+    }
+
+    fn go_to_neighbor(&mut self, dir: CycleDir, hub: &Hub) {
+        let location = match dir {
+            CycleDir::Previous => Location::Previous(self.location),
+            CycleDir::Next => Location::Next(self.location),
+        };
+        if let Some(image) = self.children[1].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(location, 1.0) {
+                image.update(pixmap, hub);
+                self.location = loc;
+            }
+        }
+    }
+}
+
+impl View for Footnote {
+    fn handle_event(&mut self, evt: &Event, hub: &Hub, _bus: &mut Bus, _context: &mut Context) -> bool {
+        match *evt {
+            Event::GoTo(..) => {
+                hub.send(Event::Close(ViewId::Footnote)).ok();
+                false
+            },
+            Event::Gesture(GestureEvent::Swipe { dir, start, .. }) if self.rect.includes(start) => {
+                match dir {
+                    Dir::West => self.go_to_neighbor(CycleDir::Next, hub),
+                    Dir::East => self.go_to_neighbor(CycleDir::Previous, hub),
+                    _ => (),
+                }
+                true
+            },
+            Event::Gesture(GestureEvent::Tap(center)) if self.rect.includes(center) => {
+                if center.x < self.rect.min.x + self.rect.width() as i32 / 2 {
+                    self.go_to_neighbor(CycleDir::Previous, hub);
+                } else {
+                    self.go_to_neighbor(CycleDir::Next, hub);
+                }
+                true
+            },
+            Event::Gesture(GestureEvent::Tap(..)) => {
+                hub.send(Event::Close(ViewId::Footnote)).ok();
+                true
+            },
+            Event::Gesture(..) => true,
+            _ => false,
+        }
+    }
+
+    fn render(&self, fb: &mut dyn Framebuffer, _rect: Rectangle, _fonts: &mut Fonts) {
+        fb.draw_rectangle(&self.rect, WHITE);
+    }
+
+    fn resize(&mut self, _rect: Rectangle, hub: &Hub, _context: &mut Context) {
+        hub.send(Event::Close(ViewId::Footnote)).ok();
+    }
+
+    fn is_background(&self) -> bool {
+        true
+    }
+
+    fn rect(&self) -> &Rectangle {
+        &self.rect
+    }
+
+    fn rect_mut(&mut self) -> &mut Rectangle {
+        &mut self.rect
+    }
+
+    fn children(&self) -> &Vec<Box<dyn View>> {
+        &self.children
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<Box<dyn View>> {
+        &mut self.children
+    }
+
+    fn id(&self) -> Option<ViewId> {
+        Some(ViewId::Footnote)
+    }
+}
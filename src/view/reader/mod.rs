@@ -2,17 +2,25 @@ mod tool_bar;
 mod bottom_bar;
 mod results_bar;
 mod margin_cropper;
+mod footnote;
 mod results_label;
+mod reflow;
+mod auto_crop;
+mod columns;
+mod prerender;
 
 use std::f32;
+use std::fs;
+use std::mem;
 use std::thread;
 use std::cmp::Ordering;
 use std::sync::{Arc, Mutex, mpsc};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering as AtomicOrdering;
-use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
 use std::collections::{VecDeque, BTreeMap, HashMap, HashSet};
-use chrono::Local;
+use chrono::{Local, DateTime};
 use regex::Regex;
 use septem::prelude::*;
 use septem::{Roman, Digit};
@@ -25,6 +33,10 @@ use crate::helpers::AsciiExtension;
 use crate::font::Fonts;
 use crate::font::family_names;
 use self::margin_cropper::{MarginCropper, BUTTON_DIAMETER};
+use self::footnote::Footnote;
+use self::reflow::Piece;
+use self::auto_crop::auto_crop;
+use self::prerender::{ResourceCache, Prerenderer, Imported};
 use super::top_bar::TopBar;
 use self::tool_bar::ToolBar;
 use self::bottom_bar::BottomBar;
@@ -41,31 +53,44 @@ use crate::settings::{guess_frontlight, FinishedAction};
 use crate::settings::{DEFAULT_FONT_FAMILY, DEFAULT_TEXT_ALIGN, DEFAULT_LINE_HEIGHT, DEFAULT_MARGIN_WIDTH};
 use crate::frontlight::LightLevels;
 use crate::gesture::GestureEvent;
-use crate::document::{Document, open, Location, TextLocation, BoundedText, Neighbors, BYTES_PER_PAGE};
+use crate::document::{Document, open, fingerprint, Location, TextLocation, BoundedText, Neighbors, BYTES_PER_PAGE};
 use crate::document::{TocEntry, SimpleTocEntry, TocLocation, toc_as_html, chapter_from_index};
 use crate::document::pdf::PdfOpener;
 use crate::metadata::{Info, FileInfo, ReaderInfo, Annotation, TextAlign, ZoomMode, PageScheme};
+use crate::metadata::{ReadingSession, reading_speed};
 use crate::metadata::{Margin, CroppingMargins, make_query};
 use crate::metadata::{DEFAULT_CONTRAST_EXPONENT, DEFAULT_CONTRAST_GRAY};
 use crate::geom::{Point, Rectangle, Boundary, CornerSpec, BorderSpec, Dir, DiagDir, CycleDir, LinearDir, Axis, halves};
 use crate::color::{BLACK, WHITE};
+use crate::export::BookExport;
 use crate::app::Context;
 
 const HISTORY_SIZE: usize = 32;
 const RECT_DIST_JITTER: f32 = 24.0;
 const ANNOTATION_DRIFT: u8 =  32;
+// In reflow mode, pages are rendered at this multiple of the fit to width scale.
+const REFLOW_ZOOM: f32 = 2.0;
+// Bounds of the zoom factor, relative to the fit to page scale.
+const MAX_ZOOM_FACTOR: f32 = 4.0;
+const MAX_COLUMN_ZOOM: f32 = 4.0;
+// The time spent on a single page is capped to this value, so that the
+// periods during which the device is left unattended aren't counted.
+const MAX_PAGE_DURATION: Duration = Duration::from_secs(300);
 
 pub struct Reader {
     rect: Rectangle,
     children: Vec<Box<dyn View>>,
     doc: Arc<Mutex<Box<dyn Document>>>,
-    cache: BTreeMap<usize, Resource>,
+    cache: ResourceCache,
+    prerenderer: Prerenderer,
     text: HashMap<usize, Vec<BoundedText>>,
     annotations: HashMap<usize, Vec<Annotation>>,
     chunks: Vec<RenderChunk>,
     focus: Option<ViewId>,
     search: Option<Search>,
     search_direction: LinearDir,
+    // The layout changes requested during a search, applied when it ends.
+    deferred_changes: Vec<LayoutChange>,
     held_buttons: HashSet<ButtonCode>,
     selection: Option<Selection>,
     target_annotation: Option<[TextLocation; 2]>,
@@ -77,7 +102,9 @@ pub struct Reader {
     view_port: ViewPort,
     contrast: Contrast,
     synthetic: bool,
+    rtl: bool,
     page_turns: usize,
+    session: Option<Session>,
     refresh_every: u8,
     reflowable: bool,
     ephemeral: bool,
@@ -88,6 +115,11 @@ pub struct Reader {
 struct ViewPort {
     zoom_mode: ZoomMode,
     top_offset: i32,
+    left_offset: i32,
+    // In reflow and column modes, the index of the view within the current page.
+    view_index: usize,
+    // In custom mode, the zoom relative to the fit to page scale.
+    zoom_factor: f32,
     margin_width: i32,
 }
 
@@ -96,11 +128,53 @@ impl Default for ViewPort {
         ViewPort {
             zoom_mode: ZoomMode::FitToPage,
             top_offset: 0,
+            left_offset: 0,
+            view_index: 0,
+            zoom_factor: 1.0,
             margin_width: 0,
         }
     }
 }
 
+#[derive(Debug)]
+struct Session {
+    start: DateTime<Local>,
+    last_update: Instant,
+    duration: Duration,
+    progress: usize,
+    location: usize,
+}
+
+impl Session {
+    fn new(location: usize) -> Session {
+        Session {
+            start: Local::now(),
+            last_update: Instant::now(),
+            duration: Duration::default(),
+            progress: 0,
+            location,
+        }
+    }
+
+    fn tick(&mut self, location: usize) {
+        let now = Instant::now();
+        self.duration += now.duration_since(self.last_update).min(MAX_PAGE_DURATION);
+        self.progress += location.saturating_sub(self.location);
+        self.location = location;
+        self.last_update = now;
+    }
+
+    fn reading_session(&self, pages: usize) -> ReadingSession {
+        ReadingSession {
+            start: self.start,
+            end: Local::now(),
+            duration: self.duration.as_secs(),
+            pages,
+            progress: self.progress,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum State {
     Idle,
@@ -120,6 +194,20 @@ struct Resource {
     pixmap: Pixmap,
     frame: Rectangle,
     scale: f32,
+    // The successive views of the page, in reflow and column modes.
+    views: Vec<Vec<Piece>>,
+}
+
+// Everything that affects the rendering of a page, besides the contrast,
+// which is applied when drawing.
+#[derive(Debug, Clone)]
+struct RenderParams {
+    rect: Rectangle,
+    margin_width: i32,
+    zoom_mode: ZoomMode,
+    zoom_factor: f32,
+    cropping_margins: Option<CroppingMargins>,
+    rtl: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -151,6 +239,16 @@ impl Default for Search {
     }
 }
 
+#[derive(Debug)]
+enum LayoutChange {
+    FontSize(f32),
+    TextAlign(TextAlign),
+    FontFamily(String),
+    LineHeight(f32),
+    EmbeddedFonts,
+    MarginWidth(i32),
+}
+
 #[derive(Debug)]
 struct Contrast {
     exponent: f32,
@@ -172,21 +270,95 @@ fn scaling_factor(rect: &Rectangle, cropping_margin: &Margin, screen_margin_widt
     let frame_width = (1.0 - (cropping_margin.left + cropping_margin.right)) * page_width;
     let width_ratio = surface_width / frame_width;
     match zoom_mode {
-        ZoomMode::FitToPage => {
+        ZoomMode::FitToPage | ZoomMode::Custom | ZoomMode::Columns => {
             let surface_height = (rect.height() as i32 - 2 * screen_margin_width) as f32;
             let frame_height = (1.0 - (cropping_margin.top + cropping_margin.bottom)) * page_height;
             let height_ratio = surface_height / frame_height;
             width_ratio.min(height_ratio)
         },
         ZoomMode::FitToWidth => width_ratio,
+        ZoomMode::Reflow => REFLOW_ZOOM * width_ratio,
     }
 }
 
+fn cropping_frame(pixmap: &Pixmap, cropping_margin: &Margin) -> Rectangle {
+    rect![(cropping_margin.left * pixmap.width as f32).ceil() as i32,
+          (cropping_margin.top * pixmap.height as f32).ceil() as i32,
+          ((1.0 - cropping_margin.right) * pixmap.width as f32).floor() as i32,
+          ((1.0 - cropping_margin.bottom) * pixmap.height as f32).floor() as i32]
+}
+
 fn build_pixmap(rect: &Rectangle, doc: &mut dyn Document, location: usize) -> (Pixmap, usize) {
     let scale = scaling_factor(rect, &Margin::default(), 0, doc.dims(location).unwrap(), ZoomMode::FitToPage);
     doc.pixmap(Location::Exact(location), scale).unwrap()
 }
 
+fn render_resource(doc: &mut dyn Document, location: usize, params: &RenderParams) -> Option<Resource> {
+    let cropping_margin = params.cropping_margins.as_ref()
+                                .map(|c| c.margin(location))
+                                .cloned().unwrap_or_default();
+    let dims = doc.dims(location)?;
+    let screen_margin_width = params.margin_width;
+    let available_width = params.rect.width() as i32 - 2 * screen_margin_width;
+    let available_height = params.rect.height() as i32 - 2 * screen_margin_width;
+    let mut scale = scaling_factor(&params.rect, &cropping_margin, screen_margin_width, dims, params.zoom_mode);
+    let mut regions = Vec::new();
+
+    match params.zoom_mode {
+        ZoomMode::Custom => scale *= params.zoom_factor,
+        // The regions are detected on the page fitted to the screen, and the
+        // page is then rendered so that the widest region fits the screen.
+        ZoomMode::Columns => {
+            if let Some((pixmap, _)) = doc.pixmap(Location::Exact(location), scale) {
+                let frame = cropping_frame(&pixmap, &cropping_margin);
+                regions = columns::regions(&pixmap, &frame, params.rtl).into_iter()
+                                  .map(|r| r.to_boundary() / scale)
+                                  .collect::<Vec<Boundary>>();
+                let max_width = regions.iter().map(|r| r.width()).fold(0.0, f32::max);
+                if max_width > 0.0 {
+                    scale = (available_width as f32 / max_width).min(MAX_COLUMN_ZOOM * scale);
+                }
+            }
+        },
+        _ => (),
+    }
+
+    let (pixmap, _) = doc.pixmap(Location::Exact(location), scale)?;
+    let frame = cropping_frame(&pixmap, &cropping_margin);
+    let views = if params.zoom_mode == ZoomMode::Columns {
+        let regions = regions.iter()
+                             .filter_map(|r| (*r * scale).to_rect().intersection(&frame))
+                             .collect::<Vec<Rectangle>>();
+        columns::views(&regions, available_width, available_height)
+    } else if params.zoom_mode == ZoomMode::Reflow {
+        let text = doc.lines(Location::Exact(location))
+                      .map(|(lines, _)| {
+                          let words = doc.words(Location::Exact(location))
+                                         .map(|(words, _)| words).unwrap_or_default();
+                          reflow::text_lines(&lines, &words, scale, &frame)
+                      })
+                      .unwrap_or_default();
+        let lines = reflow::segment(&pixmap, &frame, text);
+        reflow::layout(&lines, available_width, available_height)
+    } else {
+        Vec::new()
+    };
+
+    Some(Resource { pixmap, frame, scale, views })
+}
+
+// The other views of the current page are neighbors too.
+fn adjust_neighbors(neighbors: &mut Neighbors, current_page: usize, sub_page: Option<(usize, usize)>) {
+    if let Some((index, count)) = sub_page {
+        if index > 0 {
+            neighbors.previous_page = Some(current_page);
+        }
+        if index + 1 < count {
+            neighbors.next_page = Some(current_page);
+        }
+    }
+}
+
 fn find_cut(frame: &Rectangle, y_pos: i32, scale: f32, dir: LinearDir, lines: &[BoundedText]) -> Option<i32> {
     let y_pos_u = y_pos as f32 / scale;
     let frame_u = frame.to_boundary() / scale;
@@ -234,12 +406,47 @@ fn find_cut(frame: &Rectangle, y_pos: i32, scale: f32, dir: LinearDir, lines: &[
     }
 }
 
+// Tries the known password of a protected document, and asks for one when it
+// doesn't unlock the document.
+fn unlock(doc: &mut dyn Document, path: &Path, info: &Info, hub: &Hub, context: &mut Context) -> bool {
+    let fp = match fingerprint(path) {
+        Ok(fp) => fp,
+        Err(e) => {
+            eprintln!("Can't compute the fingerprint of {}: {}", path.display(), e);
+            return false;
+        },
+    };
+
+    let password = context.passwords.get(&fp).map(String::from);
+
+    if let Some(password) = password {
+        if doc.authenticate(&password) {
+            if context.settings.reader.remember_passwords {
+                context.passwords.remember(&fp)
+                       .map_err(|e| eprintln!("Can't save passwords: {}", e)).ok();
+            }
+            return true;
+        }
+        context.passwords.remove(&fp)
+               .map_err(|e| eprintln!("Can't save passwords: {}", e)).ok();
+        hub.send(Event::AskPassword(Box::new(info.clone()), true)).ok();
+    } else {
+        hub.send(Event::AskPassword(Box::new(info.clone()), false)).ok();
+    }
+
+    false
+}
+
 impl Reader {
     pub fn new(rect: Rectangle, mut info: Info, hub: &Hub, context: &mut Context) -> Option<Reader> {
-        let settings = &context.settings;
-        let path = settings.library_path.join(&info.file.path);
+        let path = context.settings.library_path.join(&info.file.path);
 
         open(&path).and_then(|mut doc| {
+            if doc.is_protected() && !unlock(doc.as_mut(), &path, &info, hub, context) {
+                return None;
+            }
+
+            let settings = &context.settings;
             let (width, height) = context.display.dims;
             let font_size = info.reader.as_ref().and_then(|r| r.font_size)
                                 .unwrap_or(settings.reader.font_size);
@@ -275,6 +482,10 @@ impl Reader {
                 doc.set_text_align(text_align);
             }
 
+            if info.reader.as_ref().and_then(|r| r.embedded_fonts) == Some(false) {
+                doc.set_embedded_fonts(false);
+            }
+
             let mut view_port = ViewPort::default();
             let mut contrast = Contrast::default();
             let pages_count = doc.pages_count();
@@ -300,6 +511,18 @@ impl Reader {
                     view_port.top_offset = top_offset;
                 }
 
+                if let Some(left_offset) = r.left_offset {
+                    view_port.left_offset = left_offset;
+                }
+
+                if let Some(view_index) = r.view_index {
+                    view_port.view_index = view_index;
+                }
+
+                if let Some(zoom_factor) = r.zoom_factor {
+                    view_port.zoom_factor = zoom_factor;
+                }
+
                 if !doc.is_reflowable() {
                     view_port.margin_width = mm_to_px(r.screen_margin_width.unwrap_or(0) as f32,
                                                       CURRENT_DEVICE.dpi) as i32;
@@ -315,31 +538,50 @@ impl Reader {
             } else {
                 current_page = first_location;
 
+                let cropping_margins = if !doc.is_reflowable() &&
+                                          settings.reader.auto_crop.contains(&info.file.kind) {
+                    auto_crop(doc.as_mut())
+                } else {
+                    None
+                };
+
+                let page_names = doc.page_names();
+
                 info.reader = Some(ReaderInfo {
                     current_page,
                     pages_count,
+                    cropping_margins,
+                    annotations_import: Some(0),
+                    page_names,
                     .. Default::default()
                 });
             }
 
             let synthetic = doc.has_synthetic_page_numbers();
+            let rtl = doc.is_right_to_left();
             let reflowable = doc.is_reflowable();
+            let cache_size = settings.reader.cache_size;
 
             println!("{}", info.file.path.display());
 
             hub.send(Event::Update(UpdateMode::Partial)).ok();
 
+            let doc = Arc::new(Mutex::new(doc));
+            let prerenderer = Prerenderer::new(Arc::downgrade(&doc));
+
             Some(Reader {
                 rect,
                 children: Vec::new(),
-                doc: Arc::new(Mutex::new(doc)),
-                cache: BTreeMap::new(),
+                doc,
+                cache: ResourceCache::new(cache_size << 20),
+                prerenderer,
                 text: HashMap::new(),
                 annotations: HashMap::new(),
                 chunks: Vec::new(),
                 focus: None,
                 search: None,
                 search_direction: LinearDir::Forward,
+                deferred_changes: Vec::new(),
                 held_buttons: HashSet::new(),
                 selection: None,
                 target_annotation: None,
@@ -350,7 +592,9 @@ impl Reader {
                 pages_count,
                 view_port,
                 synthetic,
+                rtl,
                 page_turns: 0,
+                session: Some(Session::new(current_page)),
                 refresh_every: settings.reader.refresh_every,
                 contrast,
                 ephemeral: false,
@@ -401,17 +645,22 @@ impl Reader {
 
         hub.send(Event::Update(UpdateMode::Partial)).ok();
 
+        let doc = Arc::new(Mutex::new(Box::new(doc) as Box<dyn Document>));
+        let prerenderer = Prerenderer::new(Arc::downgrade(&doc));
+
         Reader {
             rect,
             children: vec![],
-            doc: Arc::new(Mutex::new(Box::new(doc))),
-            cache: BTreeMap::new(),
+            doc,
+            cache: ResourceCache::new(context.settings.reader.cache_size << 20),
+            prerenderer,
             text: HashMap::new(),
             annotations: HashMap::new(),
             chunks: Vec::new(),
             focus: None,
             search: None,
             search_direction: LinearDir::Forward,
+            deferred_changes: Vec::new(),
             held_buttons: HashSet::new(),
             selection: None,
             target_annotation: None,
@@ -422,7 +671,9 @@ impl Reader {
             pages_count,
             view_port: ViewPort::default(),
             synthetic: false,
+            rtl: false,
             page_turns: 0,
+            session: None,
             refresh_every: context.settings.reader.refresh_every,
             contrast: Contrast::default(),
             ephemeral: true,
@@ -431,25 +682,29 @@ impl Reader {
         }
     }
 
+    fn render_params(&self) -> RenderParams {
+        RenderParams {
+            rect: self.rect,
+            margin_width: self.view_port.margin_width,
+            zoom_mode: self.view_port.zoom_mode,
+            zoom_factor: self.view_port.zoom_factor,
+            cropping_margins: self.info.reader.as_ref()
+                                  .and_then(|r| r.cropping_margins.clone()),
+            rtl: self.rtl,
+        }
+    }
+
     fn load_pixmap(&mut self, location: usize) {
+        self.prerenderer.collect(Some(location), &mut self.cache);
+
         if self.cache.contains_key(&location) {
             return;
         }
 
+        let params = self.render_params();
         let mut doc = self.doc.lock().unwrap();
-        let cropping_margin = self.info.reader.as_ref()
-                                  .and_then(|r| r.cropping_margins.as_ref()
-                                                 .map(|c| c.margin(location)))
-                                  .cloned().unwrap_or_default();
-        let dims = doc.dims(location).unwrap();
-        let screen_margin_width = self.view_port.margin_width;
-        let scale = scaling_factor(&self.rect, &cropping_margin, screen_margin_width, dims, self.view_port.zoom_mode);
-        if let Some((pixmap, _)) = doc.pixmap(Location::Exact(location), scale) {
-            let frame = rect![(cropping_margin.left * pixmap.width as f32).ceil() as i32,
-                              (cropping_margin.top * pixmap.height as f32).ceil() as i32,
-                              ((1.0 - cropping_margin.right) * pixmap.width as f32).floor() as i32,
-                              ((1.0 - cropping_margin.bottom) * pixmap.height as f32).floor() as i32];
-            self.cache.insert(location, Resource { pixmap, frame, scale });
+        if let Some(resource) = render_resource(doc.as_mut(), location, &params) {
+            self.cache.insert(location, resource);
         }
     }
 
@@ -467,6 +722,11 @@ impl Reader {
     }
 
     fn go_to_page(&mut self, location: usize, record: bool, hub: &Hub) {
+        self.go_to_view(location, 0, record, hub);
+    }
+
+    // In reflow and column modes, the *view* is the index of the view within the page.
+    fn go_to_view(&mut self, location: usize, view: usize, record: bool, hub: &Hub) {
         let loc = {
             let mut doc = self.doc.lock().unwrap();
             doc.resolve_location(Location::Exact(location))
@@ -485,6 +745,8 @@ impl Reader {
             }
 
             self.view_port.top_offset = 0;
+            self.view_port.left_offset = 0;
+            self.view_port.view_index = view;
             self.current_page = location;
             self.update(None, hub);
             self.update_bottom_bar(hub);
@@ -602,7 +864,8 @@ impl Reader {
     }
 
     fn page_scroll(&mut self, delta_y: i32, hub: &Hub, _context: &mut Context) {
-        if delta_y == 0 {
+        if delta_y == 0 || self.view_port.zoom_mode == ZoomMode::Reflow ||
+           self.view_port.zoom_mode == ZoomMode::Columns {
             return;
         }
 
@@ -662,9 +925,35 @@ impl Reader {
         }
     }
 
+    fn show_footnote(&mut self, content: &str, location: Option<usize>, hub: &Hub, context: &mut Context) {
+        if let Some(index) = locate_by_id(self, ViewId::Footnote) {
+            hub.send(Event::Expose(*self.child(index).rect(), UpdateMode::Gui)).ok();
+            self.children.remove(index);
+        }
+
+        let font_size = self.info.reader.as_ref().and_then(|r| r.font_size)
+                            .unwrap_or(context.settings.reader.font_size);
+        let footnote = Footnote::new(self.rect, content, location, font_size, context);
+        hub.send(Event::Render(*footnote.rect(), UpdateMode::Gui)).ok();
+        self.children.push(Box::new(footnote) as Box<dyn View>);
+    }
+
+    // Directions associated with the left and right sides of the screen.
+    fn progression(&self) -> (CycleDir, CycleDir) {
+        if self.rtl {
+            (CycleDir::Previous, CycleDir::Next)
+        } else {
+            (CycleDir::Next, CycleDir::Previous)
+        }
+    }
+
     fn go_to_neighbor(&mut self, dir: CycleDir, hub: &Hub, context: &mut Context) {
         let current_page = self.current_page;
         let top_offset = self.view_port.top_offset;
+        let left_offset = self.view_port.left_offset;
+        let view_index = self.view_port.view_index;
+        let available_width = self.rect.width() as i32 - 2 * self.view_port.margin_width;
+        let available_height = self.rect.height() as i32 - 2 * self.view_port.margin_width;
 
         let loc = {
             let neighloc = if dir == CycleDir::Previous {
@@ -673,7 +962,6 @@ impl Reader {
                     ZoomMode::FitToWidth => {
                         let first_chunk = self.chunks.first().cloned().unwrap();
                         let mut location = first_chunk.location;
-                        let available_height = self.rect.height() as i32 - 2 * self.view_port.margin_width;
                         let mut height = 0;
 
                         loop {
@@ -711,6 +999,31 @@ impl Reader {
                         self.view_port.top_offset = next_top_offset;
                         Location::Exact(location)
                     },
+                    // The offsets are clamped when updating.
+                    ZoomMode::Custom => {
+                        if left_offset > 0 {
+                            self.view_port.left_offset = (left_offset - available_width).max(0);
+                            Location::Exact(current_page)
+                        } else if top_offset > 0 {
+                            self.view_port.left_offset = i32::max_value();
+                            self.view_port.top_offset = (top_offset - available_height).max(0);
+                            Location::Exact(current_page)
+                        } else {
+                            self.view_port.left_offset = i32::max_value();
+                            self.view_port.top_offset = i32::max_value();
+                            Location::Previous(current_page)
+                        }
+                    },
+                    ZoomMode::Reflow | ZoomMode::Columns => {
+                        if view_index > 0 {
+                            self.view_port.view_index = view_index - 1;
+                            Location::Exact(current_page)
+                        } else {
+                            // The index is clamped to the last view when updating.
+                            self.view_port.view_index = usize::max_value();
+                            Location::Previous(current_page)
+                        }
+                    },
                 }
             } else {
                 match self.view_port.zoom_mode {
@@ -727,13 +1040,39 @@ impl Reader {
                             Location::Exact(last_chunk.location)
                         }
                     },
+                    ZoomMode::Custom => {
+                        let frame = self.cache[&current_page].frame;
+                        if left_offset + available_width < frame.width() as i32 {
+                            self.view_port.left_offset = left_offset + available_width;
+                            Location::Exact(current_page)
+                        } else if top_offset + available_height < frame.height() as i32 {
+                            self.view_port.left_offset = 0;
+                            self.view_port.top_offset = top_offset + available_height;
+                            Location::Exact(current_page)
+                        } else {
+                            self.view_port.left_offset = 0;
+                            self.view_port.top_offset = 0;
+                            Location::Next(current_page)
+                        }
+                    },
+                    ZoomMode::Reflow | ZoomMode::Columns => {
+                        if view_index + 1 < self.cache[&current_page].views.len() {
+                            self.view_port.view_index = view_index + 1;
+                            Location::Exact(current_page)
+                        } else {
+                            self.view_port.view_index = 0;
+                            Location::Next(current_page)
+                        }
+                    },
                 }
             };
             let mut doc = self.doc.lock().unwrap();
             doc.resolve_location(neighloc)
         };
         match loc {
-            Some(location) if location != current_page || self.view_port.top_offset != top_offset => {
+            Some(location) if location != current_page || self.view_port.top_offset != top_offset ||
+                              self.view_port.left_offset != left_offset ||
+                              self.view_port.view_index != view_index => {
                 if let Some(ref mut s) = self.search {
                     s.current_page = s.highlights.range(..=location).count().saturating_sub(1);
                 }
@@ -747,6 +1086,9 @@ impl Reader {
                 }
             },
             _ => {
+                self.view_port.top_offset = top_offset;
+                self.view_port.left_offset = left_offset;
+                self.view_port.view_index = view_index;
                 match dir {
                     CycleDir::Next => {
                         self.finished = true;
@@ -791,6 +1133,8 @@ impl Reader {
         }
         if let Some(location) = loc {
             self.view_port.top_offset = 0;
+            self.view_port.left_offset = 0;
+            self.view_port.view_index = 0;
             self.current_page = location;
             self.update_results_bar(hub);
             self.update_bottom_bar(hub);
@@ -812,6 +1156,8 @@ impl Reader {
                 s.current_page = s.highlights.range(..=location).count().saturating_sub(1);
             }
             self.view_port.top_offset = 0;
+            self.view_port.left_offset = 0;
+            self.view_port.view_index = 0;
             self.current_page = location;
             self.update_results_bar(hub);
             self.update_bottom_bar(hub);
@@ -819,25 +1165,88 @@ impl Reader {
         }
     }
 
+    // Average reading time per location, derived from the previous sessions and the current one.
+    fn reading_speed(&self) -> Option<f32> {
+        let current = self.session.as_ref().map(|s| s.reading_session(self.page_turns));
+        let previous = self.info.reader.as_ref().map_or(&[][..], |r| &r.sessions[..]);
+        reading_speed(previous.iter().chain(current.iter()))
+    }
+
     fn update_bottom_bar(&mut self, hub: &Hub) {
         if let Some(index) = locate::<BottomBar>(self) {
             let current_page = self.current_page;
+            let pages_count = self.pages_count;
+            let speed = self.reading_speed();
+            let sub_page = self.sub_page();
             let mut doc = self.doc.lock().unwrap();
-            let chapter = self.toc().or_else(|| doc.toc())
-                              .as_ref().and_then(|toc| doc.chapter(current_page, toc))
-                              .map(|c| c.title.clone())
-                              .unwrap_or_default();
+            let toc = self.toc().or_else(|| doc.toc());
+            let chapter = toc.as_ref().and_then(|toc| doc.chapter(current_page, toc))
+                             .map(|c| c.title.clone())
+                             .unwrap_or_default();
+            let time_left = speed.map(|speed| {
+                let estimate = |end: usize| (speed * end.saturating_sub(current_page) as f32) as u64;
+                let chapter_left = toc.as_ref().map(|toc| {
+                    let end = doc.chapter_relative(current_page, CycleDir::Next, toc)
+                                 .and_then(|c| doc.resolve_location(c.location.clone()))
+                                 .filter(|&end| end > current_page)
+                                 .unwrap_or(pages_count);
+                    estimate(end)
+                });
+                (chapter_left, estimate(pages_count))
+            });
+            let page_numbers = self.reflow_page_numbers()
+                                   .or_else(|| doc.page_numbers(current_page));
             let bottom_bar = self.children[index].as_mut().downcast_mut::<BottomBar>().unwrap();
-            let neighbors = Neighbors {
+            let mut neighbors = Neighbors {
                 previous_page: doc.resolve_location(Location::Previous(current_page)),
                 next_page: doc.resolve_location(Location::Next(current_page)),
             };
+            adjust_neighbors(&mut neighbors, current_page, sub_page);
             bottom_bar.update_page_label(self.current_page, self.pages_count, hub);
+            bottom_bar.update_page_numbers(page_numbers, hub);
+            bottom_bar.update_sub_page(sub_page, hub);
+            bottom_bar.update_time_left(time_left, hub);
             bottom_bar.update_icons(&neighbors, hub);
             bottom_bar.update_chapter(&chapter, hub);
         }
     }
 
+    // The number of the current view among the views of all the pages, and
+    // the number of views, once the views of every page are counted.
+    fn reflow_page_numbers(&self) -> Option<(usize, usize)> {
+        if self.view_port.zoom_mode != ZoomMode::Reflow {
+            return None;
+        }
+        let counts = self.cache.views_counts();
+        if counts.len() < self.pages_count {
+            return None;
+        }
+        let previous = counts.range(..self.current_page).map(|(_, count)| count).sum::<usize>();
+        Some((previous + self.view_port.view_index, counts.values().sum()))
+    }
+
+    // The page and the view of the given reflowed page.
+    fn reflow_location(&self, number: usize) -> Option<(usize, usize)> {
+        let mut previous = 0;
+        for (&location, &count) in self.cache.views_counts() {
+            if number < previous + count {
+                return Some((location, number - previous));
+            }
+            previous += count;
+        }
+        self.cache.views_counts().iter().next_back()
+            .map(|(&location, &count)| (location, count - 1))
+    }
+
+    fn sub_page(&self) -> Option<(usize, usize)> {
+        if self.view_port.zoom_mode != ZoomMode::Reflow &&
+           self.view_port.zoom_mode != ZoomMode::Columns {
+            return None;
+        }
+        self.cache.get(&self.current_page)
+            .map(|r| (self.view_port.view_index, r.views.len()))
+    }
+
     fn update_tool_bar(&mut self, hub: &Hub, context: &mut Context) {
         if let Some(index) = locate::<ToolBar>(self) {
             let tool_bar = self.children[index].as_mut().downcast_mut::<ToolBar>().unwrap();
@@ -891,8 +1300,10 @@ impl Reader {
     fn update_annotations(&mut self) {
         self.annotations.clear();
         if let Some(annotations) = self.info.reader.as_ref().map(|r| &r.annotations).filter(|a| !a.is_empty()) {
-            for chunk in &self.chunks {
-                let words = &self.text[&chunk.location];
+            let mut locations = self.chunks.iter().map(|c| c.location).collect::<Vec<usize>>();
+            locations.dedup();
+            for location in locations {
+                let words = &self.text[&location];
                 if words.is_empty() {
                     continue;
                 }
@@ -900,7 +1311,7 @@ impl Reader {
                     let [start, end] = annot.selection;
                     if (start >= words[0].location && start <= words[words.len()-1].location) ||
                        (end >= words[0].location && end <= words[words.len()-1].location) {
-                        self.annotations.entry(chunk.location)
+                        self.annotations.entry(location)
                             .or_insert_with(|| Vec::new())
                             .push(annot.clone());
                     }
@@ -909,8 +1320,36 @@ impl Reader {
         }
     }
 
+    // Merges the annotations imported from the document file. Those that are
+    // already known, because they were saved in the file, are skipped.
+    fn collect_annotations(&mut self) {
+        let pages_count = self.pages_count;
+        if let Some(ref mut r) = self.info.reader {
+            for Imported { location, annotations } in self.prerenderer.imported() {
+                // The pages imported before the last request are sent again.
+                if r.annotations_import != Some(location) {
+                    continue;
+                }
+                if let Some(annotations) = annotations {
+                    for annot in annotations {
+                        if r.annotations.iter().all(|a| a.selection != annot.selection) {
+                            r.annotations.push(annot);
+                        }
+                    }
+                    r.annotations_import = Some(location + 1).filter(|&l| l < pages_count);
+                } else {
+                    r.annotations_import = None;
+                }
+            }
+        }
+    }
+
     fn update(&mut self, update_mode: Option<UpdateMode>, hub: &Hub) {
         self.page_turns += 1;
+        self.collect_annotations();
+        if let Some(session) = self.session.as_mut() {
+            session.tick(self.current_page);
+        }
         let update_mode = update_mode.unwrap_or_else(|| {
             if self.refresh_every == 0 || self.page_turns % (self.refresh_every as usize) != 0 {
                 UpdateMode::Partial
@@ -972,41 +1411,66 @@ impl Reader {
                     }
                 }
             },
+            ZoomMode::Custom => {
+                self.load_pixmap(location);
+                self.load_text(location);
+                let Resource { frame, scale, .. } = self.cache[&location];
+                let available_width = self.rect.width() as i32 - 2 * smw;
+                let available_height = self.rect.height() as i32 - 2 * smw;
+                let left_offset = self.view_port.left_offset.min(frame.width() as i32 - available_width).max(0);
+                let top_offset = self.view_port.top_offset.min(frame.height() as i32 - available_height).max(0);
+                self.view_port.left_offset = left_offset;
+                self.view_port.top_offset = top_offset;
+                let min = frame.min + pt!(left_offset, top_offset);
+                let max = pt!((min.x + available_width).min(frame.max.x),
+                              (min.y + available_height).min(frame.max.y));
+                let frame = rect![min, max];
+                let dx = smw + (available_width - frame.width() as i32) / 2;
+                let dy = smw + (available_height - frame.height() as i32) / 2;
+                self.chunks.push(RenderChunk { frame, location, position: pt!(dx, dy), scale });
+            },
+            ZoomMode::Reflow | ZoomMode::Columns => {
+                self.load_pixmap(location);
+                self.load_text(location);
+                let Resource { ref views, scale, .. } = self.cache[&location];
+                let index = self.view_port.view_index.min(views.len().saturating_sub(1));
+                self.view_port.view_index = index;
+                for piece in views.get(index).into_iter().flatten() {
+                    self.chunks.push(RenderChunk { frame: piece.frame, location,
+                                                   position: piece.position + pt!(smw, smw), scale });
+                }
+                // Blank pages still need a chunk to keep track of the location.
+                if self.chunks.is_empty() {
+                    self.chunks.push(RenderChunk { frame: Rectangle::default(), location,
+                                                   position: pt!(smw, smw), scale });
+                }
+            },
         }
 
         hub.send(Event::Render(self.rect, update_mode)).ok();
         let first_location = self.chunks.first().map(|c| c.location).unwrap();
         let last_location = self.chunks.last().map(|c| c.location).unwrap();
 
-        while self.cache.len() > 3 {
-            let left_count = self.cache.range(..first_location).count();
-            let right_count = self.cache.range(last_location+1..).count();
-            let extremum = if left_count >= right_count {
-                self.cache.keys().next().cloned().unwrap()
-            } else {
-                self.cache.keys().next_back().cloned().unwrap()
-            };
-            self.cache.remove(&extremum);
-        }
+        let locations = self.chunks.iter().map(|c| c.location).collect::<Vec<usize>>();
+        self.cache.pin(&locations);
 
         self.update_annotations();
 
-        let doc2 = self.doc.clone();
-        let hub2 = hub.clone();
-        thread::spawn(move || {
-            let mut doc = doc2.lock().unwrap();
-            if let Some(next_location) = doc.resolve_location(Location::Next(last_location)) {
-                hub2.send(Event::LoadPixmap(next_location)).ok();
-            }
-        });
-        let doc3 = self.doc.clone();
-        let hub3 = hub.clone();
-        thread::spawn(move || {
-            let mut doc = doc3.lock().unwrap();
-            if let Some(previous_location) = doc.resolve_location(Location::Previous(first_location)) {
-                hub3.send(Event::LoadPixmap(previous_location)).ok();
-            }
-        });
+        let params = self.render_params();
+        self.prerenderer.request(first_location, last_location, &params, &self.cache);
+
+        if self.view_port.zoom_mode == ZoomMode::Reflow &&
+           self.cache.views_counts().len() < self.pages_count {
+            self.prerenderer.index(self.pages_count, &params, &self.cache);
+        }
+
+        if self.synthetic {
+            self.prerenderer.paginate();
+        }
+
+        if let Some(next) = self.info.reader.as_ref().and_then(|r| r.annotations_import) {
+            self.prerenderer.import(next, self.pages_count);
+        }
     }
 
     fn search(&mut self, text: &str, query: Regex, hub: &Hub) {
@@ -1435,12 +1899,14 @@ impl Reader {
             self.children.insert(index, Box::new(separator) as Box<dyn View>);
             index += 1;
 
-            let neighbors = Neighbors {
+            let sub_page = self.sub_page();
+            let mut neighbors = Neighbors {
                 previous_page: doc.resolve_location(Location::Previous(self.current_page)),
                 next_page: doc.resolve_location(Location::Next(self.current_page)),
             };
+            adjust_neighbors(&mut neighbors, self.current_page, sub_page);
 
-            let bottom_bar = BottomBar::new(rect![self.rect.min.x,
+            let mut bottom_bar = BottomBar::new(rect![self.rect.min.x,
                                                   self.rect.max.y - small_height as i32 + big_thickness,
                                                   self.rect.max.x,
                                                   self.rect.max.y],
@@ -1450,6 +1916,7 @@ impl Reader {
                                             self.pages_count,
                                             &neighbors,
                                             self.synthetic);
+            bottom_bar.update_sub_page(sub_page, hub);
             self.children.insert(index, Box::new(bottom_bar) as Box<dyn View>);
 
             for i in 0..=index {
@@ -1666,10 +2133,22 @@ impl Reader {
                                                              zoom_mode == ZoomMode::FitToPage),
                                       EntryKind::RadioButton("Fit to Width".to_string(),
                                                              EntryId::SetZoomMode(ZoomMode::FitToWidth),
-                                                             zoom_mode == ZoomMode::FitToWidth)]));
+                                                             zoom_mode == ZoomMode::FitToWidth),
+                                      EntryKind::RadioButton("Reflow".to_string(),
+                                                             EntryId::SetZoomMode(ZoomMode::Reflow),
+                                                             zoom_mode == ZoomMode::Reflow),
+                                      EntryKind::RadioButton("Columns".to_string(),
+                                                             EntryId::SetZoomMode(ZoomMode::Columns),
+                                                             zoom_mode == ZoomMode::Columns)]));
             }
             entries.push(EntryKind::Command("Metadata".to_string(),
                                             EntryId::OpenMetadata));
+            entries.push(EntryKind::Command("Export Annotations".to_string(),
+                                            EntryId::ExportAnnotations));
+            if self.info.file.kind == "pdf" {
+                entries.push(EntryKind::Command("Save Annotations".to_string(),
+                                                EntryId::SaveAnnotations));
+            }
             let title_menu = Menu::new(rect, ViewId::TitleMenu, MenuKind::DropDown, entries, context);
             hub.send(Event::Render(*title_menu.rect(), UpdateMode::Gui)).ok();
             self.children.push(Box::new(title_menu) as Box<dyn View>);
@@ -1697,9 +2176,16 @@ impl Reader {
                                      .and_then(|r| r.font_family.clone())
                                      .unwrap_or_else(|| context.settings.reader.font_family.clone());
             families.insert(DEFAULT_FONT_FAMILY.to_string());
-            let entries = families.iter().map(|f| EntryKind::RadioButton(f.clone(),
-                                                                         EntryId::SetFontFamily(f.clone()),
-                                                                         *f == current_family)).collect();
+            let mut entries: Vec<EntryKind> = families.iter().map(|f| EntryKind::RadioButton(f.clone(),
+                                                                                             EntryId::SetFontFamily(f.clone()),
+                                                                                             *f == current_family)).collect();
+            let embedded_fonts = self.info.reader.as_ref()
+                                     .and_then(|r| r.embedded_fonts)
+                                     .unwrap_or(true);
+            entries.push(EntryKind::Separator);
+            entries.push(EntryKind::CheckBox("Embedded Fonts".to_string(),
+                                             EntryId::ToggleEmbeddedFonts,
+                                             embedded_fonts));
             let font_family_menu = Menu::new(rect, ViewId::FontFamilyMenu, MenuKind::DropDown, entries, context);
             hub.send(Event::Render(*font_family_menu.rect(), UpdateMode::Gui)).ok();
             self.children.push(Box::new(font_family_menu) as Box<dyn View>);
@@ -1933,9 +2419,11 @@ impl Reader {
             let is_applied = self.info.reader.as_ref()
                                  .map(|r| r.cropping_margins.is_some())
                                  .unwrap_or(false);
+            entries.extend_from_slice(&[EntryKind::Separator,
+                                        EntryKind::Command("Auto".to_string(), EntryId::AutoCroppings)]);
+
             if is_applied {
-                entries.extend_from_slice(&[EntryKind::Separator,
-                                            EntryKind::Command("Remove".to_string(), EntryId::RemoveCroppings)]);
+                entries.push(EntryKind::Command("Remove".to_string(), EntryId::RemoveCroppings));
             }
 
             let margin_cropper_menu = Menu::new(rect, ViewId::MarginCropperMenu, MenuKind::DropDown, entries, context);
@@ -1970,8 +2458,31 @@ impl Reader {
         }
     }
 
+    // A search holds the document until it ends, hence the layout changes
+    // are deferred until then. The prerenderer, on the other hand, only
+    // holds it for the page it's rendering.
+    fn defer_layout_change(&mut self, change: LayoutChange) -> bool {
+        if self.search.as_ref().map_or(false, |s| s.running.load(AtomicOrdering::Relaxed)) {
+            self.deferred_changes.push(change);
+            return true;
+        }
+        self.prerenderer.cancel();
+        false
+    }
+
+    fn apply_layout_change(&mut self, change: LayoutChange, hub: &Hub, context: &mut Context) {
+        match change {
+            LayoutChange::FontSize(font_size) => self.set_font_size(font_size, hub, context),
+            LayoutChange::TextAlign(text_align) => self.set_text_align(text_align, hub, context),
+            LayoutChange::FontFamily(font_family) => self.set_font_family(&font_family, hub, context),
+            LayoutChange::LineHeight(line_height) => self.set_line_height(line_height, hub, context),
+            LayoutChange::EmbeddedFonts => self.toggle_embedded_fonts(hub, context),
+            LayoutChange::MarginWidth(width) => self.set_margin_width(width, hub, context),
+        }
+    }
+
     fn set_font_size(&mut self, font_size: f32, hub: &Hub, context: &mut Context) {
-        if Arc::strong_count(&self.doc) > 1 {
+        if self.defer_layout_change(LayoutChange::FontSize(font_size)) {
             return;
         }
 
@@ -2005,7 +2516,7 @@ impl Reader {
     }
 
     fn set_text_align(&mut self, text_align: TextAlign, hub: &Hub, context: &mut Context) {
-        if Arc::strong_count(&self.doc) > 1 {
+        if self.defer_layout_change(LayoutChange::TextAlign(text_align)) {
             return;
         }
 
@@ -2036,7 +2547,7 @@ impl Reader {
     }
 
     fn set_font_family(&mut self, font_family: &str, hub: &Hub, context: &mut Context) {
-        if Arc::strong_count(&self.doc) > 1 {
+        if self.defer_layout_change(LayoutChange::FontFamily(font_family.to_string())) {
             return;
         }
 
@@ -2073,7 +2584,7 @@ impl Reader {
     }
 
     fn set_line_height(&mut self, line_height: f32, hub: &Hub, context: &mut Context) {
-        if Arc::strong_count(&self.doc) > 1 {
+        if self.defer_layout_change(LayoutChange::LineHeight(line_height)) {
             return;
         }
 
@@ -2103,8 +2614,43 @@ impl Reader {
         self.update_bottom_bar(hub);
     }
 
+    fn toggle_embedded_fonts(&mut self, hub: &Hub, context: &mut Context) {
+        if self.defer_layout_change(LayoutChange::EmbeddedFonts) {
+            return;
+        }
+
+        let embedded_fonts = !self.info.reader.as_ref()
+                                  .and_then(|r| r.embedded_fonts)
+                                  .unwrap_or(true);
+
+        if let Some(ref mut r) = self.info.reader {
+            r.embedded_fonts = Some(embedded_fonts);
+        }
+
+        {
+            let mut doc = self.doc.lock().unwrap();
+            doc.set_embedded_fonts(embedded_fonts);
+
+            if self.synthetic {
+                let current_page = self.current_page.min(doc.pages_count() - 1);
+                if let Some(location) =  doc.resolve_location(Location::Exact(current_page)) {
+                    self.current_page = location;
+                }
+            } else {
+                self.pages_count = doc.pages_count();
+                self.current_page = self.current_page.min(self.pages_count - 1);
+            }
+        }
+
+        self.cache.clear();
+        self.text.clear();
+        self.update(None, hub);
+        self.update_tool_bar(hub, context);
+        self.update_bottom_bar(hub);
+    }
+
     fn set_margin_width(&mut self, width: i32, hub: &Hub, context: &mut Context) {
-        if Arc::strong_count(&self.doc) > 1 {
+        if self.defer_layout_change(LayoutChange::MarginWidth(width)) {
             return;
         }
 
@@ -2135,9 +2681,11 @@ impl Reader {
             }
         } else {
             let next_margin_width = mm_to_px(width as f32, CURRENT_DEVICE.dpi) as i32;
-            let ratio = (self.rect.width() as i32 - 2 * next_margin_width) as f32 /
-                        (self.rect.width() as i32 - 2 * self.view_port.margin_width) as f32;
-            self.view_port.top_offset = (self.view_port.top_offset as f32 * ratio) as i32;
+            if self.view_port.zoom_mode == ZoomMode::FitToWidth {
+                let ratio = (self.rect.width() as i32 - 2 * next_margin_width) as f32 /
+                            (self.rect.width() as i32 - 2 * self.view_port.margin_width) as f32;
+                self.view_port.top_offset = (self.view_port.top_offset as f32 * ratio) as i32;
+            }
             self.view_port.margin_width = next_margin_width;
         }
 
@@ -2187,8 +2735,63 @@ impl Reader {
         }
         self.view_port.zoom_mode = zoom_mode;
         self.view_port.top_offset = 0;
+        self.view_port.left_offset = 0;
+        self.view_port.view_index = 0;
         self.cache.clear();
         self.update(None, hub);
+        self.update_bottom_bar(hub);
+    }
+
+    // Scales the current zoom by the given ratio, keeping the point under
+    // the fingers in place.
+    fn zoom(&mut self, ratio: f32, center: Point, hub: &Hub) {
+        let zoom_factor = if self.view_port.zoom_mode == ZoomMode::Custom {
+            self.view_port.zoom_factor
+        } else {
+            1.0
+        };
+        let next_zoom_factor = (zoom_factor * ratio).min(MAX_ZOOM_FACTOR);
+
+        if next_zoom_factor <= 1.0 {
+            self.set_zoom_mode(ZoomMode::FitToPage, hub);
+            return;
+        }
+
+        let focus = match self.view_port.zoom_mode {
+            ZoomMode::FitToPage | ZoomMode::Custom => self.chunks.first().and_then(|chunk| {
+                self.cache.get(&chunk.location)
+                    .map(|r| (chunk.frame.min - r.frame.min, center - chunk.position))
+            }),
+            _ => None,
+        };
+
+        let (offset, delta) = focus.unwrap_or_else(|| (pt!(0, 0), pt!(0, 0)));
+        let ratio = next_zoom_factor / zoom_factor;
+        self.view_port.left_offset = ((offset.x + delta.x) as f32 * ratio) as i32 - delta.x;
+        self.view_port.top_offset = ((offset.y + delta.y) as f32 * ratio) as i32 - delta.y;
+        self.view_port.zoom_factor = next_zoom_factor;
+        self.view_port.zoom_mode = ZoomMode::Custom;
+        self.cache.clear();
+        self.update(None, hub);
+        self.update_bottom_bar(hub);
+    }
+
+    fn pan(&mut self, delta: Point, hub: &Hub) {
+        let frame = match self.cache.get(&self.current_page) {
+            Some(r) => r.frame,
+            None => return,
+        };
+        let smw = self.view_port.margin_width;
+        let max_left_offset = (frame.width() as i32 - (self.rect.width() as i32 - 2 * smw)).max(0);
+        let max_top_offset = (frame.height() as i32 - (self.rect.height() as i32 - 2 * smw)).max(0);
+        let left_offset = (self.view_port.left_offset - delta.x).max(0).min(max_left_offset);
+        let top_offset = (self.view_port.top_offset - delta.y).max(0).min(max_top_offset);
+        if left_offset == self.view_port.left_offset && top_offset == self.view_port.top_offset {
+            return;
+        }
+        self.view_port.left_offset = left_offset;
+        self.view_port.top_offset = top_offset;
+        self.update(None, hub);
     }
 
     fn crop_margins(&mut self, index: usize, margin: &Margin, hub: &Hub) {
@@ -2321,7 +2924,11 @@ impl Reader {
             if let Some(words) = self.text.get(&chunk.location) {
                 for word in words {
                     if word.location >= start && word.location <= end {
-                        let rect = (word.rect * chunk.scale).to_rect() - chunk.frame.min + chunk.position;
+                        let rect = (word.rect * chunk.scale).to_rect();
+                        if !rect.overlaps(&chunk.frame) {
+                            continue;
+                        }
+                        let rect = rect - chunk.frame.min + chunk.position;
                         if let Some(ref mut r) = result {
                             r.absorb(&rect);
                         } else {
@@ -2392,16 +2999,35 @@ impl Reader {
             return;
         }
 
+        self.collect_annotations();
+
         if let Some(ref mut r) = self.info.reader {
             r.current_page = self.current_page;
             r.pages_count = self.pages_count;
             r.finished = self.finished;
-            if self.view_port.zoom_mode == ZoomMode::FitToPage {
-                r.zoom_mode = None;
-                r.top_offset = None;
+            match self.view_port.zoom_mode {
+                ZoomMode::FitToPage => {
+                    r.zoom_mode = None;
+                    r.top_offset = None;
+                    r.view_index = None;
+                },
+                ZoomMode::Reflow | ZoomMode::Columns => {
+                    r.zoom_mode = Some(self.view_port.zoom_mode);
+                    r.top_offset = None;
+                    r.view_index = Some(self.view_port.view_index);
+                },
+                _ => {
+                    r.zoom_mode = Some(self.view_port.zoom_mode);
+                    r.top_offset = Some(self.view_port.top_offset);
+                    r.view_index = None;
+                },
+            }
+            if self.view_port.zoom_mode == ZoomMode::Custom {
+                r.left_offset = Some(self.view_port.left_offset);
+                r.zoom_factor = Some(self.view_port.zoom_factor);
             } else {
-                r.zoom_mode = Some(self.view_port.zoom_mode);
-                r.top_offset = Some(self.view_port.top_offset);
+                r.left_offset = None;
+                r.zoom_factor = None;
             }
             r.rotation = Some(context.display.rotation);
             if (self.contrast.exponent - DEFAULT_CONTRAST_EXPONENT).abs() > f32::EPSILON {
@@ -2415,6 +3041,14 @@ impl Reader {
                 r.contrast_exponent = None;
                 r.contrast_gray = None;
             }
+            if let Some(session) = self.session.as_mut() {
+                session.tick(self.current_page);
+                // The first update displays the initial page.
+                let pages = self.page_turns.saturating_sub(1);
+                if pages > 0 {
+                    r.sessions.push(session.reading_session(pages));
+                }
+            }
         }
 
         for i in &mut context.metadata {
@@ -2423,6 +3057,9 @@ impl Reader {
                 break;
             }
         }
+
+        context.library.update(&[&self.info])
+               .map_err(|e| eprintln!("Can't update {}: {}", self.info.file.path.display(), e)).ok();
     }
 }
 
@@ -2435,10 +3072,26 @@ impl View for Reader {
                 hub.send(Event::Select(EntryId::Rotate(n))).ok();
                 true
             },
+            Event::Gesture(GestureEvent::Swipe { dir, start, end }) if self.rect.includes(start) &&
+                                                                       self.view_port.zoom_mode == ZoomMode::Custom => {
+                let left_offset = self.view_port.left_offset;
+                self.pan(end - start, hub);
+                // Turn the page when the swipe can't move the view any further.
+                if self.view_port.left_offset == left_offset {
+                    let (west_dir, east_dir) = self.progression();
+                    match dir {
+                        Dir::West => self.go_to_neighbor(west_dir, hub, context),
+                        Dir::East => self.go_to_neighbor(east_dir, hub, context),
+                        _ => (),
+                    }
+                }
+                true
+            },
             Event::Gesture(GestureEvent::Swipe { dir, start, end, .. }) if self.rect.includes(start) => {
+                let (west_dir, east_dir) = self.progression();
                 match dir {
-                    Dir::West => self.go_to_neighbor(CycleDir::Next, hub, context),
-                    Dir::East => self.go_to_neighbor(CycleDir::Previous, hub, context),
+                    Dir::West => self.go_to_neighbor(west_dir, hub, context),
+                    Dir::East => self.go_to_neighbor(east_dir, hub, context),
                     Dir::South | Dir::North => self.page_scroll(end.y - start.y, hub, context),
                 };
                 true
@@ -2456,6 +3109,15 @@ impl View for Reader {
                 }
                 true
             },
+            Event::Gesture(GestureEvent::Spread { starts, ends, .. }) |
+            Event::Gesture(GestureEvent::Pinch { starts, ends, .. }) if self.rect.includes(starts[0]) => {
+                if !self.reflowable {
+                    let ratio = (ends[1] - ends[0]).length() / (starts[1] - starts[0]).length().max(1.0);
+                    let center = (starts[0] + starts[1]) / 2;
+                    self.zoom(ratio, center, hub);
+                }
+                true
+            },
             Event::Gesture(GestureEvent::Arrow { dir, .. }) => {
                 match dir {
                     Dir::West => {
@@ -2556,7 +3218,11 @@ impl View for Reader {
 
                 for chunk in &self.chunks {
                     for word in &self.text[&chunk.location] {
-                        let rect = (word.rect * chunk.scale).to_rect() - chunk.frame.min + chunk.position;
+                        let rect = (word.rect * chunk.scale).to_rect();
+                        if !rect.overlaps(&chunk.frame) {
+                            continue;
+                        }
+                        let rect = rect - chunk.frame.min + chunk.position;
                         rects.push((rect, word.location));
                         let d = position.rdist2(&rect);
                         if d < dmax && d < dmin {
@@ -2653,7 +3319,11 @@ impl View for Reader {
 
                 for chunk in &self.chunks {
                     for word in &self.text[&chunk.location] {
-                        let rect = (word.rect * chunk.scale).to_rect() - chunk.frame.min + chunk.position;
+                        let rect = (word.rect * chunk.scale).to_rect();
+                        if !rect.overlaps(&chunk.frame) {
+                            continue;
+                        }
+                        let rect = rect - chunk.frame.min + chunk.position;
                         rects.push((rect, word.location));
                         let d = center.rdist2(&rect);
                         if d < dmax && d < dmin {
@@ -2765,12 +3435,21 @@ impl View for Reader {
                 let mut dmin = u32::max_value();
                 let dmax = (scale_by_dpi(RECT_DIST_JITTER, CURRENT_DEVICE.dpi) as i32).pow(2) as u32;
 
+                let mut links = HashMap::new();
+
                 for chunk in &self.chunks {
-                    let (links, _) = self.doc.lock().ok()
-                                         .and_then(|mut doc| doc.links(Location::Exact(chunk.location)))
-                                         .unwrap_or((Vec::new(), 0));
-                    for link in links {
-                        let rect = (link.rect * chunk.scale).to_rect() - chunk.frame.min + chunk.position;
+                    let links = links.entry(chunk.location).or_insert_with(|| {
+                        self.doc.lock().ok()
+                            .and_then(|mut doc| doc.links(Location::Exact(chunk.location)))
+                            .map(|(links, _)| links)
+                            .unwrap_or_default()
+                    });
+                    for link in links.iter() {
+                        let rect = (link.rect * chunk.scale).to_rect();
+                        if !rect.overlaps(&chunk.frame) {
+                            continue;
+                        }
+                        let rect = rect - chunk.frame.min + chunk.position;
                         let d = center.rdist2(&rect);
                         if d < dmax && d < dmin {
                             dmin = d;
@@ -2800,9 +3479,14 @@ impl View for Reader {
                             self.go_to_page(index.saturating_sub(1), true, hub);
                         }
                     } else {
-                        let mut doc = self.doc.lock().unwrap();
-                        let loc = Location::LocalUri(self.current_page, link.text.clone());
-                        if let Some(location) = doc.resolve_location(loc) {
+                        let (location, note) = {
+                            let mut doc = self.doc.lock().unwrap();
+                            let loc = Location::LocalUri(self.current_page, link.text.clone());
+                            (doc.resolve_location(loc), doc.footnote(link.location.location(), &link.text))
+                        };
+                        if let Some(content) = note {
+                            self.show_footnote(&content, location, hub, context);
+                        } else if let Some(location) = location {
                             hub.send(Event::GoTo(location)).ok();
                         } else {
                             println!("Can't resolve URI: {}.", link.text);
@@ -2820,6 +3504,7 @@ impl View for Reader {
                 let x2 = self.rect.max.x - db;
                 let sx1 = self.rect.min.x + ds;
                 let sx2 = self.rect.max.x - ds;
+                let (left_dir, right_dir) = self.progression();
 
                 if center.x < x1 {
                     let dc = sx1 - center.x;
@@ -2841,7 +3526,7 @@ impl View for Reader {
                     // Left ear.
                     } else {
                         if self.search.is_none() {
-                            self.go_to_neighbor(CycleDir::Next, hub, context);
+                            self.go_to_neighbor(left_dir, hub, context);
                         } else {
                             self.go_to_results_neighbor(CycleDir::Previous, hub);
                         }
@@ -2861,7 +3546,7 @@ impl View for Reader {
                     // Right ear.
                     } else {
                         if self.search.is_none() {
-                            self.go_to_neighbor(CycleDir::Previous, hub, context);
+                            self.go_to_neighbor(right_dir, hub, context);
                         } else {
                             self.go_to_results_neighbor(CycleDir::Next, hub);
                         }
@@ -2881,7 +3566,11 @@ impl View for Reader {
 
                 for chunk in &self.chunks {
                     for word in &self.text[&chunk.location] {
-                        let rect = (word.rect * chunk.scale).to_rect() - chunk.frame.min + chunk.position;
+                        let rect = (word.rect * chunk.scale).to_rect();
+                        if !rect.overlaps(&chunk.frame) {
+                            continue;
+                        }
+                        let rect = rect - chunk.frame.min + chunk.position;
                         let d = center.rdist2(&rect);
                         if d < dmax && d < dmin {
                             dmin = d;
@@ -2923,10 +3612,6 @@ impl View for Reader {
                 self.update(Some(mode), hub);
                 true
             },
-            Event::LoadPixmap(location) => {
-                self.load_pixmap(location);
-                true
-            },
             Event::Submit(ViewId::GoToPageInput, ref text) => {
                 let re = Regex::new(r#"^([-+"'])?(.+)$"#).unwrap();
                 if let Some(caps) = re.captures(text) {
@@ -2935,6 +3620,15 @@ impl View for Reader {
                         if let Some(location) = self.find_page_by_name(&caps[2]) {
                             self.go_to_page(location, true, hub);
                         }
+                    } else if let (Some((current, _)), Ok(number)) = (self.reflow_page_numbers(), caps[2].parse::<usize>()) {
+                        let index = match prefix {
+                            Some("-") => current.saturating_sub(number),
+                            Some("+") => current + number,
+                            _ => number.saturating_sub(1),
+                        };
+                        if let Some((location, view)) = self.reflow_location(index) {
+                            self.go_to_view(location, view, true, hub);
+                        }
                     } else {
                         if let Ok(number) = caps[2].parse::<f64>() {
                             let location = if !self.synthetic {
@@ -3015,6 +3709,55 @@ impl View for Reader {
                 }
                 true
             },
+            Event::Select(EntryId::ExportAnnotations) => {
+                let export = {
+                    let mut doc = self.doc.lock().unwrap();
+                    BookExport::new(&self.info, doc.as_mut())
+                };
+                let msg = if export.is_empty() {
+                    "No annotations to export.".to_string()
+                } else {
+                    let export_path = context.settings.library_path.join(&context.settings.reader.export_path);
+                    match export.save(&export_path) {
+                        Err(e) => format!("Can't export annotations: {}.", e),
+                        Ok(..) => format!("Exported annotations to {}.", export_path.display()),
+                    }
+                };
+                let notif = Notification::new(ViewId::ExportAnnotationsNotif,
+                                              msg, hub, context);
+                self.children.push(Box::new(notif) as Box<dyn View>);
+                true
+            },
+            Event::Select(EntryId::SaveAnnotations) => {
+                let path = context.settings.library_path.join(&self.info.file.path);
+                let fp = fingerprint(&path).ok();
+                let result = {
+                    let annotations = self.info.reader.as_ref()
+                                          .map(|r| r.annotations.as_slice())
+                                          .unwrap_or(&[]);
+                    let mut doc = self.doc.lock().unwrap();
+                    doc.save_annotations(annotations, &path)
+                };
+                let msg = match result {
+                    Err(e) => format!("Can't save annotations: {}", e),
+                    Ok(0) => "No new annotations to save.".to_string(),
+                    Ok(count) => {
+                        if let Ok(metadata) = fs::metadata(&path) {
+                            self.info.file.size = metadata.len();
+                        }
+                        // The password of the document is keyed by the fingerprint of its file.
+                        if let (Some(fp), Ok(new_fp)) = (fp, fingerprint(&path)) {
+                            context.passwords.rename(&fp, &new_fp)
+                                   .map_err(|e| eprintln!("Can't save passwords: {}", e)).ok();
+                        }
+                        format!("Saved {} annotation(s) to {}.", count, self.info.file.path.display())
+                    },
+                };
+                let notif = Notification::new(ViewId::ExportAnnotationsNotif,
+                                              msg, hub, context);
+                self.children.push(Box::new(notif) as Box<dyn View>);
+                true
+            },
             Event::Page(dir) => {
                 self.go_to_neighbor(dir, hub, context);
                 true
@@ -3238,6 +3981,9 @@ impl View for Reader {
                     self.toggle_search_bar(true, hub, context);
                     hub.send(Event::Focus(Some(ViewId::ReaderSearchInput))).ok();
                 }
+                for change in mem::replace(&mut self.deferred_changes, Vec::new()) {
+                    self.apply_layout_change(change, hub, context);
+                }
                 true
             },
             Event::Select(EntryId::AnnotateSelection) => {
@@ -3351,6 +4097,22 @@ impl View for Reader {
                 });
                 true
             },
+            Event::Select(EntryId::AutoCroppings) => {
+                let cropping_margins = {
+                    let mut doc = self.doc.lock().unwrap();
+                    auto_crop(doc.as_mut())
+                };
+                if let Some(cropping_margins) = cropping_margins {
+                    if let Some(r) = self.info.reader.as_mut() {
+                        r.cropping_margins = Some(cropping_margins);
+                    }
+                    // The margin cropper would show the previous margins.
+                    self.toggle_margin_cropper(false, hub, context);
+                    self.cache.clear();
+                    self.update(None, hub);
+                }
+                true
+            },
             Event::Select(EntryId::RemoveCroppings) => {
                 if let Some(r) = self.info.reader.as_mut() {
                     r.cropping_margins = None;
@@ -3383,6 +4145,10 @@ impl View for Reader {
                 self.set_margin_width(width, hub, context);
                 true
             },
+            Event::Select(EntryId::ToggleEmbeddedFonts) => {
+                self.toggle_embedded_fonts(hub, context);
+                true
+            },
             Event::Select(EntryId::SetLineHeight(v)) => {
                 let line_height = 1.0 + v as f32 / 10.0;
                 self.set_line_height(line_height, hub, context);
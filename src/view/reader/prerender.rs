@@ -0,0 +1,489 @@
+use std::thread;
+use std::ops::Index;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Weak, Mutex, mpsc};
+use std::sync::mpsc::TryRecvError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::document::{Document, Location};
+use crate::metadata::Annotation;
+use crate::geom::CycleDir;
+use super::{Resource, RenderParams, render_resource};
+
+// The rendered pages. Once they take more than the given amount of memory,
+// the least recently used ones are evicted, except the pages on screen.
+pub(super) struct ResourceCache {
+    entries: BTreeMap<usize, Entry>,
+    pinned: Vec<usize>,
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    // Incremented each time the cache is cleared, to discard the pages
+    // rendered with obsolete parameters.
+    generation: usize,
+    // The number of views of each page, in reflow mode. Unlike the pages,
+    // they're kept until the cache is cleared.
+    views_counts: BTreeMap<usize, usize>,
+}
+
+struct Entry {
+    resource: Resource,
+    last_use: u64,
+}
+
+impl ResourceCache {
+    pub fn new(capacity: usize) -> ResourceCache {
+        ResourceCache {
+            entries: BTreeMap::new(),
+            pinned: Vec::new(),
+            capacity,
+            size: 0,
+            tick: 0,
+            generation: 0,
+            views_counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn contains_key(&self, location: &usize) -> bool {
+        self.entries.contains_key(location)
+    }
+
+    pub fn get(&self, location: &usize) -> Option<&Resource> {
+        self.entries.get(location).map(|e| &e.resource)
+    }
+
+    pub fn locations(&self) -> Vec<usize> {
+        self.entries.keys().cloned().collect()
+    }
+
+    pub fn views_counts(&self) -> &BTreeMap<usize, usize> {
+        &self.views_counts
+    }
+
+    pub fn set_views_count(&mut self, location: usize, count: usize) {
+        self.views_counts.insert(location, count);
+    }
+
+    // Eviction is deferred until the next call to `pin`, so that the pages
+    // being laid out can't be removed.
+    pub fn insert(&mut self, location: usize, resource: Resource) {
+        self.views_counts.insert(location, resource.views.len().max(1));
+        self.tick += 1;
+        self.size += resource_size(&resource);
+        let entry = Entry { resource, last_use: self.tick };
+        if let Some(previous) = self.entries.insert(location, entry) {
+            self.size -= resource_size(&previous.resource);
+        }
+    }
+
+    // Marks the given pages as the ones on screen, and evicts the least
+    // recently used pages until the cache fits within its capacity.
+    pub fn pin(&mut self, locations: &[usize]) {
+        self.tick += 1;
+        for location in locations {
+            if let Some(entry) = self.entries.get_mut(location) {
+                entry.last_use = self.tick;
+            }
+        }
+        self.pinned = locations.to_vec();
+
+        while self.size > self.capacity {
+            let pinned = &self.pinned;
+            let oldest = self.entries.iter()
+                             .filter(|(location, _)| !pinned.contains(location))
+                             .min_by_key(|(_, entry)| entry.last_use)
+                             .map(|(location, _)| *location);
+            if let Some(location) = oldest {
+                if let Some(entry) = self.entries.remove(&location) {
+                    self.size -= resource_size(&entry.resource);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.views_counts.clear();
+        self.size = 0;
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
+
+impl<'a> Index<&'a usize> for ResourceCache {
+    type Output = Resource;
+
+    fn index(&self, location: &usize) -> &Resource {
+        &self.entries[location].resource
+    }
+}
+
+fn resource_size(resource: &Resource) -> usize {
+    resource.pixmap.data.len()
+}
+
+struct Job {
+    epoch: usize,
+    generation: usize,
+    anchor: usize,
+    dir: CycleDir,
+    known: Vec<usize>,
+    params: RenderParams,
+}
+
+// Counts the views of the pages that aren't known yet, when there's nothing
+// else to render.
+struct IndexJob {
+    generation: usize,
+    next: usize,
+    pages_count: usize,
+    known: BTreeSet<usize>,
+    params: RenderParams,
+}
+
+// Imports the annotations stored in the document file, one page at a time.
+struct ImportJob {
+    next: usize,
+    pages_count: usize,
+}
+
+enum Task {
+    Render(Job),
+    Index(IndexJob),
+    Paginate,
+    Import(ImportJob),
+    Cancel,
+}
+
+struct Rendered {
+    generation: usize,
+    location: usize,
+    // The pages rendered for the index only send their number of views.
+    resource: Option<Resource>,
+    views_count: Option<usize>,
+}
+
+// The annotations of a page, or `None` if the document doesn't support them.
+pub struct Imported {
+    pub location: usize,
+    pub annotations: Option<Vec<Annotation>>,
+}
+
+// Renders the pages around the current one in a worker thread.
+pub struct Prerenderer {
+    jobs: mpsc::Sender<Task>,
+    results: mpsc::Receiver<Rendered>,
+    imports: mpsc::Receiver<Imported>,
+    // Incremented with each request: the jobs of the previous ones are skipped.
+    epoch: Arc<AtomicUsize>,
+    // The generation and location of the page being rendered.
+    current: Arc<Mutex<Option<(usize, usize)>>>,
+}
+
+impl Prerenderer {
+    // The worker only holds a weak reference to the document, and only locks
+    // it for one step of a job at a time, so that the reader doesn't wait
+    // for pages it no longer needs.
+    pub fn new(doc: Weak<Mutex<Box<dyn Document>>>) -> Prerenderer {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<Task>();
+        let (results_tx, results_rx) = mpsc::channel();
+        let (imports_tx, imports_rx) = mpsc::channel();
+        let epoch = Arc::new(AtomicUsize::new(0));
+        let current = Arc::new(Mutex::new(None));
+        let epoch2 = Arc::clone(&epoch);
+        let current2 = Arc::clone(&current);
+
+        thread::spawn(move || {
+            let mut index: Option<IndexJob> = None;
+            // The generation and the next page of the last index, so that
+            // the following requests resume it instead of starting over.
+            let mut index_cursor: Option<(usize, usize)> = None;
+            let mut paginate = false;
+            let mut import: Option<ImportJob> = None;
+
+            loop {
+                // The index, the page numbers and the annotations are only
+                // computed when there's no pending job.
+                let task = if index.is_some() || paginate || import.is_some() {
+                    match jobs_rx.try_recv() {
+                        Ok(task) => Some(task),
+                        Err(TryRecvError::Empty) => None,
+                        Err(TryRecvError::Disconnected) => break,
+                    }
+                } else {
+                    match jobs_rx.recv() {
+                        Ok(task) => Some(task),
+                        Err(_) => break,
+                    }
+                };
+
+                let job = match task {
+                    Some(Task::Render(job)) => job,
+                    Some(Task::Index(mut job)) => {
+                        if let Some((generation, next)) = index_cursor {
+                            if generation == job.generation {
+                                job.next = next;
+                            }
+                        }
+                        index = Some(job);
+                        continue;
+                    },
+                    Some(Task::Paginate) => {
+                        paginate = true;
+                        continue;
+                    },
+                    Some(Task::Import(job)) => {
+                        import = Some(job);
+                        continue;
+                    },
+                    Some(Task::Cancel) => {
+                        index = None;
+                        paginate = false;
+                        import = None;
+                        continue;
+                    },
+                    None if index.is_none() && paginate => {
+                        let doc = match doc.upgrade() {
+                            Some(doc) => doc,
+                            None => break,
+                        };
+                        paginate = doc.lock().unwrap().paginate();
+                        continue;
+                    },
+                    None if index.is_none() => {
+                        let job = import.as_mut().unwrap();
+                        let location = job.next;
+                        if location >= job.pages_count {
+                            import = None;
+                            continue;
+                        }
+                        job.next = location + 1;
+
+                        let doc = match doc.upgrade() {
+                            Some(doc) => doc,
+                            None => break,
+                        };
+                        let annotations = doc.lock().unwrap().annotations(location);
+                        if annotations.is_none() {
+                            import = None;
+                        }
+                        if imports_tx.send(Imported { location, annotations }).is_err() {
+                            break;
+                        }
+                        continue;
+                    },
+                    None => {
+                        let job = index.as_mut().unwrap();
+                        let location = (job.next..job.pages_count).find(|l| !job.known.contains(l));
+                        let location = match location {
+                            Some(location) => location,
+                            None => {
+                                index_cursor = Some((job.generation, job.pages_count));
+                                index = None;
+                                continue;
+                            },
+                        };
+                        job.next = location + 1;
+                        index_cursor = Some((job.generation, job.next));
+
+                        let doc = match doc.upgrade() {
+                            Some(doc) => doc,
+                            None => break,
+                        };
+                        let views_count = {
+                            let mut doc = doc.lock().unwrap();
+                            render_resource(doc.as_mut(), location, &job.params)
+                                           .map(|r| r.views.len().max(1))
+                        };
+                        let rendered = Rendered { generation: job.generation, location, resource: None, views_count };
+                        if results_tx.send(rendered).is_err() {
+                            break;
+                        }
+                        continue;
+                    },
+                };
+
+                if job.epoch != epoch2.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                let doc = match doc.upgrade() {
+                    Some(doc) => doc,
+                    None => break,
+                };
+
+                let location = {
+                    let mut doc = doc.lock().unwrap();
+                    match job.dir {
+                        CycleDir::Next => doc.resolve_location(Location::Next(job.anchor)),
+                        CycleDir::Previous => doc.resolve_location(Location::Previous(job.anchor)),
+                    }
+                };
+
+                if let Some(location) = location.filter(|l| !job.known.contains(l)) {
+                    // The reader might have moved on while the location was resolved.
+                    if job.epoch != epoch2.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let mut doc = doc.lock().unwrap();
+                    if job.epoch != epoch2.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    *current2.lock().unwrap() = Some((job.generation, location));
+                    let resource = render_resource(doc.as_mut(), location, &job.params);
+                    drop(doc);
+                    let views_count = resource.as_ref().map(|r| r.views.len().max(1));
+                    let rendered = Rendered { generation: job.generation, location, resource, views_count };
+                    let sent = results_tx.send(rendered).is_ok();
+                    *current2.lock().unwrap() = None;
+                    if !sent {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Prerenderer {
+            jobs: jobs_tx,
+            results: results_rx,
+            imports: imports_rx,
+            epoch,
+            current,
+        }
+    }
+
+    // Cancels the pending jobs and queues the rendering of the pages that
+    // precede and follow the given range.
+    pub fn request(&self, first_location: usize, last_location: usize, params: &RenderParams, cache: &ResourceCache) {
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        let known = cache.locations();
+        for &(anchor, dir) in &[(last_location, CycleDir::Next), (first_location, CycleDir::Previous)] {
+            self.jobs.send(Task::Render(Job {
+                epoch,
+                generation: cache.generation(),
+                anchor,
+                dir,
+                known: known.clone(),
+                params: params.clone(),
+            })).ok();
+        }
+    }
+
+    // Cancels the pending jobs and the building of the index, so that the
+    // document is released once the page being rendered is done.
+    pub fn cancel(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.jobs.send(Task::Cancel).ok();
+    }
+
+    // Queues the counting of the views of the pages whose number of views
+    // isn't known yet. The count resumes where the previous one stopped, as
+    // long as the cache wasn't cleared in between.
+    pub fn index(&self, pages_count: usize, params: &RenderParams, cache: &ResourceCache) {
+        self.jobs.send(Task::Index(IndexJob {
+            generation: cache.generation(),
+            next: 0,
+            pages_count,
+            known: cache.views_counts().keys().cloned().collect(),
+            params: params.clone(),
+        })).ok();
+    }
+
+    // Queues the computation of the page numbers of the document.
+    pub fn paginate(&self) {
+        self.jobs.send(Task::Paginate).ok();
+    }
+
+    // Queues the import of the annotations of the pages that follow the given one.
+    pub fn import(&self, next: usize, pages_count: usize) {
+        self.jobs.send(Task::Import(ImportJob { next, pages_count })).ok();
+    }
+
+    // Returns the annotations imported since the last call.
+    pub fn imported(&self) -> Vec<Imported> {
+        self.imports.try_iter().collect()
+    }
+
+    // Moves the rendered pages into the cache. If the given page is being
+    // rendered, waits for it instead of rendering it twice.
+    pub fn collect(&self, location: Option<usize>, cache: &mut ResourceCache) {
+        let generation = cache.generation();
+        let mut wait = location.map_or(false, |l| {
+            *self.current.lock().unwrap() == Some((generation, l))
+        });
+
+        loop {
+            let rendered = if wait {
+                self.results.recv().ok()
+            } else {
+                self.results.try_recv().ok()
+            };
+
+            let rendered = match rendered {
+                Some(rendered) => rendered,
+                None => break,
+            };
+
+            if Some(rendered.location) == location {
+                wait = false;
+            }
+
+            if rendered.generation == generation {
+                if let Some(count) = rendered.views_count {
+                    cache.set_views_count(rendered.location, count);
+                }
+                if !cache.contains_key(&rendered.location) {
+                    if let Some(resource) = rendered.resource {
+                        cache.insert(rendered.location, resource);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::Pixmap;
+
+    fn resource(size: u32) -> Resource {
+        Resource {
+            pixmap: Pixmap::new(size, 1),
+            frame: rect![0, 0, size as i32, 1],
+            scale: 1.0,
+            views: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = ResourceCache::new(30);
+        for location in 0..4 {
+            cache.insert(location, resource(10));
+        }
+        cache.pin(&[0]);
+        assert_eq!(cache.locations(), vec![0, 2, 3]);
+        cache.insert(4, resource(40));
+        cache.pin(&[4]);
+        assert_eq!(cache.locations(), vec![4]);
+    }
+
+    #[test]
+    fn test_views_counts() {
+        let mut cache = ResourceCache::new(10);
+        cache.insert(0, resource(10));
+        cache.insert(1, resource(10));
+        cache.set_views_count(2, 3);
+        cache.pin(&[1]);
+        assert_eq!(cache.locations(), vec![1]);
+        assert_eq!(cache.views_counts().iter().map(|(l, c)| (*l, *c)).collect::<Vec<_>>(),
+                   vec![(0, 1), (1, 1), (2, 3)]);
+        cache.clear();
+        assert!(cache.views_counts().is_empty());
+    }
+}
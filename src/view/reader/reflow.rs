@@ -0,0 +1,455 @@
+use std::mem;
+use fnv::FnvHashMap;
+use crate::framebuffer::Pixmap;
+use crate::document::BoundedText;
+use crate::geom::{Point, Rectangle};
+
+// Pixels darker than this are considered to be ink.
+const INK_THRESHOLD: u8 = 160;
+// Smaller components, in pixels, are considered to be noise.
+const MIN_AREA: u32 = 3;
+// Components taller than this many times the median height are treated as figures.
+const BLOCK_RATIO: i32 = 6;
+// Gaps wider than this fraction of the line height separate words.
+const WORD_GAP_RATIO: f32 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub rect: Rectangle,
+    pub words: Vec<Rectangle>,
+    // Figures are laid out on their own, and sliced if they don't fit.
+    pub block: bool,
+}
+
+// A region of the page pixmap, and its position within the reflowed page.
+#[derive(Debug, Clone)]
+pub struct Piece {
+    pub frame: Rectangle,
+    pub position: Point,
+}
+
+fn median(mut values: Vec<i32>) -> Option<i32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Bounding boxes of the 8-connected components of ink. The components are
+// built from the runs of each row, which are joined to the touching runs of
+// the previous row.
+pub fn components(pixmap: &Pixmap, frame: &Rectangle) -> Vec<Rectangle> {
+    let width = pixmap.width as i32;
+    let frame = match frame.intersection(&rect![0, 0, width, pixmap.height as i32]) {
+        Some(frame) => frame,
+        None => return Vec::new(),
+    };
+    let mut parents = Vec::new();
+    let mut runs: Vec<(i32, i32, i32, usize)> = Vec::new();
+    let mut previous = 0..0;
+
+    for y in frame.min.y..frame.max.y {
+        let start = runs.len();
+        let row = &pixmap.data[(y * width) as usize..((y + 1) * width) as usize];
+        let mut x = frame.min.x;
+        while x < frame.max.x {
+            if row[x as usize] >= INK_THRESHOLD {
+                x += 1;
+                continue;
+            }
+            let x0 = x;
+            while x < frame.max.x && row[x as usize] < INK_THRESHOLD {
+                x += 1;
+            }
+            let id = parents.len();
+            parents.push(id);
+            for j in previous.clone() {
+                let (px0, px1, _, pid) = runs[j];
+                if px0 <= x && x0 <= px1 {
+                    let a = find(&mut parents, pid);
+                    let b = find(&mut parents, id);
+                    if a != b {
+                        parents[b] = a;
+                    }
+                }
+            }
+            runs.push((x0, x, y, id));
+        }
+        previous = start..runs.len();
+    }
+
+    let mut boxes: FnvHashMap<usize, Rectangle> = FnvHashMap::default();
+    for &(x0, x1, y, id) in &runs {
+        let root = find(&mut parents, id);
+        let rect = rect![x0, y, x1, y + 1];
+        boxes.entry(root).and_modify(|r| r.absorb(&rect)).or_insert(rect);
+    }
+
+    let mut boxes = boxes.into_iter().map(|(_, r)| r)
+                         .filter(|r| r.area() >= MIN_AREA)
+                         .collect::<Vec<Rectangle>>();
+    boxes.sort_by_key(|r| (r.min.y, r.min.x));
+    boxes
+}
+
+// Finds the widest vertical strip, in the middle half of the frame, that is
+// almost free of ink and has text on both sides.
+fn find_gutter(boxes: &[Rectangle], frame: &Rectangle, min_width: i32) -> Option<i32> {
+    let width = frame.width() as usize;
+    let mut ink = vec![0i32; width];
+    for b in boxes {
+        for x in b.min.x.max(frame.min.x)..b.max.x.min(frame.max.x) {
+            ink[(x - frame.min.x) as usize] += b.height() as i32;
+        }
+    }
+
+    let max_ink = frame.height() as i32 / 20;
+    let (lo, hi) = (width / 4, 3 * width / 4);
+    let mut best: Option<(usize, usize)> = None;
+    let mut start = None;
+
+    for x in lo..=hi {
+        if x < hi && ink[x] <= max_ink {
+            if start.is_none() {
+                start = Some(x);
+            }
+        } else if let Some(s) = start.take() {
+            if best.map_or(true, |(_, len)| x - s > len) {
+                best = Some((s, x - s));
+            }
+        }
+    }
+
+    let (s, len) = best.filter(|&(_, len)| len as i32 >= min_width)?;
+    let total: i32 = ink.iter().sum();
+    let left: i32 = ink[..s].iter().sum();
+    let right: i32 = ink[s+len..].iter().sum();
+    if 3 * left < total || 3 * right < total {
+        return None;
+    }
+    Some(frame.min.x + (s + len / 2) as i32)
+}
+
+fn group_words(line: &mut Line) {
+    let mut boxes = mem::replace(&mut line.words, Vec::new());
+    boxes.sort_by_key(|b| b.min.x);
+    let gap = ((line.rect.height() as f32 * WORD_GAP_RATIO) as i32).max(1);
+    for b in boxes {
+        if let Some(last) = line.words.last_mut() {
+            if b.min.x - last.max.x < gap {
+                last.absorb(&b);
+                continue;
+            }
+        }
+        line.words.push(b);
+    }
+}
+
+// Groups the components into lines of words. A component joins a line when
+// they overlap vertically by at least half the height of the smaller one.
+// Thin lines, made of dots or accents, are then merged into their nearest neighbor.
+fn group_lines(boxes: Vec<Rectangle>, median_height: i32) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+
+    for b in boxes {
+        let height = b.height() as i32;
+        let index = lines.iter().rposition(|l| {
+            let overlap = l.rect.max.y.min(b.max.y) - l.rect.min.y.max(b.min.y);
+            2 * overlap >= height.min(l.rect.height() as i32)
+        });
+        if let Some(i) = index {
+            lines[i].rect.absorb(&b);
+            lines[i].words.push(b);
+        } else {
+            lines.push(Line { rect: b, words: vec![b], block: false });
+        }
+    }
+
+    lines.sort_by_key(|l| l.rect.min.y);
+
+    let mut i = 0;
+    while i < lines.len() {
+        if lines.len() > 1 && 2 * (lines[i].rect.height() as i32) < median_height {
+            let rect = lines[i].rect;
+            let gap = |j: usize| lines[j].rect.min.y.max(rect.min.y) - lines[j].rect.max.y.min(rect.max.y);
+            let target = [i.wrapping_sub(1), i + 1].iter().cloned()
+                                                   .filter(|&j| j < lines.len())
+                                                   .min_by_key(|&j| gap(j))
+                                                   .filter(|&j| gap(j) < median_height);
+            if let Some(j) = target {
+                let line = lines.remove(i);
+                let j = if j > i { j - 1 } else { j };
+                lines[j].rect.absorb(&line.rect);
+                lines[j].words.extend(line.words);
+                continue;
+            }
+        }
+        i += 1;
+    }
+
+    for line in &mut lines {
+        group_words(line);
+    }
+
+    lines
+}
+
+// Builds lines from the text layer of a page. The words are assigned to the
+// line that contains their center.
+pub fn text_lines(lines: &[BoundedText], words: &[BoundedText], scale: f32, frame: &Rectangle) -> Vec<Line> {
+    let mut result = lines.iter()
+                          .filter_map(|l| (l.rect * scale).to_rect().intersection(frame))
+                          .map(|rect| Line { rect, words: Vec::new(), block: false })
+                          .collect::<Vec<Line>>();
+
+    for w in words {
+        if let Some(rect) = (w.rect * scale).to_rect().intersection(frame) {
+            let center = rect.center();
+            if let Some(line) = result.iter_mut().find(|l| l.rect.includes(center)) {
+                line.words.push(rect);
+            }
+        }
+    }
+
+    for line in &mut result {
+        if line.words.is_empty() {
+            line.words.push(line.rect);
+        } else {
+            line.words.sort_by_key(|w| w.min.x);
+        }
+    }
+
+    result
+}
+
+// Splits the frame of a page pixmap into lines of words, in reading order.
+// The given text lines are used when available, otherwise the lines are
+// found through connected-component analysis. In both cases, the figures
+// are detected from the pixmap.
+pub fn segment(pixmap: &Pixmap, frame: &Rectangle, text: Vec<Line>) -> Vec<Line> {
+    // Components covering most of the page are borders or scanning artifacts.
+    let max_area = frame.area() / 2;
+    let boxes = components(pixmap, frame).into_iter()
+                                         .filter(|b| b.area() <= max_area)
+                                         .collect::<Vec<Rectangle>>();
+    let median_height = median(boxes.iter().map(|b| b.height() as i32).collect())
+                              .unwrap_or(1).max(1);
+
+    let (mut blocks, others): (Vec<Rectangle>, Vec<Rectangle>) =
+        boxes.into_iter().partition(|b| b.height() as i32 > BLOCK_RATIO * median_height);
+    let mut rest = Vec::new();
+    for b in others {
+        if let Some(block) = blocks.iter_mut().find(|k| k.overlaps(&b)) {
+            block.absorb(&b);
+        } else {
+            rest.push(b);
+        }
+    }
+
+    let mut lines = if text.is_empty() {
+        if let Some(x) = find_gutter(&rest, frame, 2 * median_height) {
+            let (left, right): (Vec<Rectangle>, Vec<Rectangle>) = rest.into_iter().partition(|b| b.center().x < x);
+            let mut lines = group_lines(left, median_height);
+            lines.extend(group_lines(right, median_height));
+            lines
+        } else {
+            group_lines(rest, median_height)
+        }
+    } else {
+        text.into_iter()
+            .filter(|l| !blocks.iter().any(|b| b.includes(l.rect.center())))
+            .collect()
+    };
+
+    blocks.sort_by_key(|b| (b.min.y, b.min.x));
+
+    for b in blocks {
+        let index = lines.iter().position(|l| l.rect.min.y >= b.min.y &&
+                                              l.rect.min.x < b.max.x && b.min.x < l.rect.max.x)
+                         .unwrap_or_else(|| lines.len());
+        lines.insert(index, Line { rect: b, words: vec![b], block: true });
+    }
+
+    lines
+}
+
+// A line starts a paragraph when it's indented, preceded by a short line or
+// separated from the previous line by a blank. The first line of a column
+// continues the paragraph of the previous column.
+fn starts_paragraph(previous: &Line, line: &Line, line_height: i32) -> bool {
+    let same_column = line.rect.min.x < previous.rect.max.x && previous.rect.min.x < line.rect.max.x;
+    if !same_column || line.rect.min.y < previous.rect.min.y {
+        return false;
+    }
+    line.rect.min.y - previous.rect.max.y > line_height ||
+    line.rect.min.x - previous.rect.min.x > line_height ||
+    previous.rect.max.x + 2 * line_height < line.rect.max.x
+}
+
+struct Layout {
+    width: i32,
+    height: i32,
+    pages: Vec<Vec<Piece>>,
+    x: i32,
+    y: i32,
+    // The frame, horizontal position and ascent of the words of the current line.
+    line: Vec<(Rectangle, i32, i32)>,
+}
+
+impl Layout {
+    fn new_page(&mut self) {
+        self.pages.push(Vec::new());
+        self.y = 0;
+    }
+
+    fn flush(&mut self, leading: i32) {
+        if self.line.is_empty() {
+            return;
+        }
+        let ascent = self.line.iter().map(|&(_, _, a)| a).max().unwrap_or(0);
+        let descent = self.line.iter().map(|&(f, _, a)| f.height() as i32 - a).max().unwrap_or(0);
+        let line_height = ascent + descent;
+        if self.y > 0 && self.y + line_height > self.height {
+            self.new_page();
+        }
+        for (frame, x, a) in self.line.drain(..) {
+            let position = pt!(x, self.y + ascent - a);
+            self.pages.last_mut().unwrap().push(Piece { frame, position });
+        }
+        self.y += line_height + leading;
+        self.x = 0;
+    }
+
+    fn push_word(&mut self, frame: Rectangle, ascent: i32, space: i32, leading: i32) {
+        let width = frame.width() as i32;
+        if !self.line.is_empty() && self.x + space + width > self.width {
+            self.flush(leading);
+        }
+        if !self.line.is_empty() {
+            self.x += space;
+        }
+        self.line.push((frame, self.x, ascent));
+        self.x += width;
+    }
+
+    // Blocks wider than the page are cut into vertical strips, and each strip
+    // is split across pages if needed.
+    fn push_block(&mut self, frame: Rectangle, leading: i32) {
+        self.flush(leading);
+        for x0 in (frame.min.x..frame.max.x).step_by(self.width as usize) {
+            let x1 = (x0 + self.width).min(frame.max.x);
+            let dx = (self.width - (x1 - x0)) / 2;
+            let mut y0 = frame.min.y;
+            while y0 < frame.max.y {
+                let rest = frame.max.y - y0;
+                if self.y > 0 && rest > self.height - self.y {
+                    self.new_page();
+                    continue;
+                }
+                let h = rest.min(self.height - self.y);
+                self.pages.last_mut().unwrap().push(Piece { frame: rect![x0, y0, x1, y0 + h],
+                                                            position: pt!(dx, self.y) });
+                self.y += h;
+                y0 += h;
+                if y0 < frame.max.y {
+                    self.new_page();
+                }
+            }
+        }
+        self.y += leading;
+    }
+}
+
+// Lays out the words of the given lines into pages of the given dimensions.
+// There's always at least one page.
+pub fn layout(lines: &[Line], width: i32, height: i32) -> Vec<Vec<Piece>> {
+    if width <= 0 || height <= 0 {
+        return vec![Vec::new()];
+    }
+
+    let line_height = median(lines.iter().filter(|l| !l.block)
+                                  .map(|l| l.rect.height() as i32).collect())
+                            .unwrap_or(height / 40).max(1);
+    let space = (line_height / 3).max(1);
+    let leading = line_height / 4;
+    let mut layout = Layout {
+        width,
+        height,
+        pages: vec![Vec::new()],
+        x: 0,
+        y: 0,
+        line: Vec::new(),
+    };
+    let mut previous: Option<&Line> = None;
+
+    for line in lines {
+        if line.block {
+            layout.push_block(line.rect, leading);
+            previous = Some(line);
+            continue;
+        }
+
+        if let Some(p) = previous {
+            if p.block || starts_paragraph(p, line, line_height) {
+                layout.flush(leading);
+                layout.y += line_height / 2;
+                layout.x = line_height.min(width / 4);
+            }
+        }
+
+        let baseline = median(line.words.iter().map(|w| w.max.y).collect())
+                             .unwrap_or(line.rect.max.y);
+
+        for w in &line.words {
+            if w.width() as i32 > width {
+                layout.push_block(*w, leading);
+            } else {
+                layout.push_word(*w, baseline - w.min.y, space, leading);
+            }
+        }
+
+        previous = Some(line);
+    }
+
+    layout.flush(leading);
+    layout.pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_components() {
+        let mut pixmap = Pixmap::new(12, 6);
+        for &(x, y) in &[(1, 1), (2, 1), (3, 2), (4, 3), (8, 1), (8, 2), (9, 2), (10, 4)] {
+            pixmap.data[y * 12 + x] = 0;
+        }
+        let boxes = components(&pixmap, &rect![0, 0, 12, 6]);
+        assert_eq!(boxes, vec![rect![1, 1, 5, 4], rect![8, 1, 10, 3]]);
+    }
+
+    #[test]
+    fn test_layout() {
+        let line = Line {
+            rect: rect![0, 0, 100, 10],
+            words: vec![rect![0, 0, 30, 10], rect![40, 0, 70, 10], rect![80, 0, 100, 10]],
+            block: false,
+        };
+        let pages = layout(&[line.clone(), line], 70, 30);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].len(), 4);
+        assert_eq!(pages[0][1].position, pt!(33, 0));
+        assert_eq!(pages[0][2].position, pt!(0, 12));
+        assert_eq!(pages[1][0].frame, rect![40, 0, 70, 10]);
+    }
+}
@@ -15,7 +15,7 @@ use crate::view::menu::{Menu, MenuKind};
 use crate::view::common::{locate_by_id};
 use crate::view::{View, Event, Hub, Bus, EntryKind, EntryId, ViewId};
 use crate::framebuffer::{Framebuffer, UpdateMode, Pixmap};
-use crate::metadata::import;
+use crate::metadata::{Info, import};
 use crate::settings::{ImportSettings, Pen};
 use crate::font::Fonts;
 use crate::color::{BLACK, WHITE};
@@ -181,9 +181,12 @@ impl Sketch {
             };
             let imported_metadata = import(&context.settings.library_path,
                                            &context.metadata,
-                                           &import_settings);
+                                           &import_settings,
+                                           &context.settings.reader.export_path);
             if let Ok(mut imported_metadata) = imported_metadata {
                 imported_metadata.retain(|info| info.file.path.starts_with(&suffix));
+                context.library.update(&imported_metadata.iter().collect::<Vec<&Info>>())
+                       .map_err(|e| eprintln!("Can't add the imported metadata: {}", e)).ok();
                 context.metadata.append(&mut imported_metadata);
             }
         }
@@ -0,0 +1,403 @@
+
+use std::sync::mpsc;
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+use chrono::NaiveDate;
+use crate::device::{CURRENT_DEVICE, BAR_SIZES};
+use crate::framebuffer::{Framebuffer, UpdateMode, Pixmap};
+use crate::geom::{Rectangle, Point, Dir, CycleDir, halves};
+use crate::unit::scale_by_dpi;
+use crate::font::Fonts;
+use crate::view::{View, Event, Hub, Bus, ViewId};
+use crate::view::THICKNESS_MEDIUM;
+use crate::document::{Document, Location};
+use crate::document::html::HtmlDocument;
+use crate::view::common::{toggle_main_menu, toggle_battery_menu, toggle_clock_menu};
+use crate::gesture::GestureEvent;
+use crate::color::BLACK;
+use crate::app::Context;
+use crate::metadata::{Info, Metadata};
+use crate::helpers::format_duration;
+use crate::view::filler::Filler;
+use crate::view::image::Image;
+use crate::view::top_bar::TopBar;
+use crate::view::paged_bottom_bar::PagedBottomBar;
+
+const VIEWER_STYLESHEET: &str = "css/statistics.css";
+const USER_STYLESHEET: &str = "css/statistics-user.css";
+const MAX_DAYS: usize = 30;
+
+pub struct Statistics {
+    rect: Rectangle,
+    children: Vec<Box<dyn View>>,
+    doc: HtmlDocument,
+    location: usize,
+    // The books with at least one reading session, most recently read first.
+    books: Vec<PathBuf>,
+    book: Option<PathBuf>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, word)
+    } else {
+        format!("{} {}s", count, word)
+    }
+}
+
+fn books_with_sessions(metadata: &Metadata) -> Vec<PathBuf> {
+    let mut books = metadata.iter()
+                            .filter_map(|info| info.reader.as_ref()
+                                                   .and_then(|r| r.sessions.last())
+                                                   .map(|s| (s.end, info.file.path.clone())))
+                            .collect::<Vec<_>>();
+    books.sort_by(|a, b| b.0.cmp(&a.0));
+    books.into_iter().map(|(_, path)| path).collect()
+}
+
+// Links are encoded in the *href* attribute: `@i` shows the history of
+// the i-th book and `<` goes back to the overview.
+fn overview_to_content(books: &[PathBuf], metadata: &Metadata) -> String {
+    let mut content = String::new();
+    let mut days: BTreeMap<NaiveDate, (u64, usize)> = BTreeMap::new();
+    let mut total = (0, 0);
+
+    for session in metadata.iter().filter_map(|info| info.reader.as_ref())
+                           .flat_map(|r| r.sessions.iter()) {
+        let day = days.entry(session.start.date().naive_local()).or_insert((0, 0));
+        day.0 += session.duration;
+        day.1 += session.pages;
+        total.0 += session.duration;
+        total.1 += session.pages;
+    }
+
+    if days.is_empty() {
+        content.push_str("<p class=\"info\">No reading sessions recorded.</p>\n");
+        return content;
+    }
+
+    content.push_str(&format!("<p class=\"summary\">{} read, {} turned, {}.</p>\n",
+                              format_duration(total.0), plural(total.1, "page"), plural(books.len(), "book")));
+
+    content.push_str("<h1>Daily Totals</h1>\n<ul class=\"days\">\n");
+    for (date, (duration, pages)) in days.iter().rev().take(MAX_DAYS) {
+        content.push_str(&format!("<li><span class=\"date\">{}</span> {}, {}</li>\n",
+                                  date.format("%a %Y-%m-%d"), format_duration(*duration), plural(*pages, "page")));
+    }
+    content.push_str("</ul>\n");
+
+    content.push_str("<h1>Books</h1>\n<ul class=\"books\">\n");
+    for (index, path) in books.iter().enumerate() {
+        if let Some(info) = metadata.iter().find(|info| info.file.path == *path) {
+            let duration = info.reader.as_ref().map_or(0, |r| r.reading_time());
+            content.push_str(&format!("<li><a href=\"@{}\">{}</a> {}</li>\n",
+                                      index, escape(&info.title), format_duration(duration)));
+        }
+    }
+    content.push_str("</ul>\n");
+
+    content
+}
+
+fn book_to_content(info: &Info) -> String {
+    let mut content = String::new();
+    content.push_str(&format!("<h1 class=\"title\">{}</h1>\n", escape(&info.title)));
+    if !info.author.is_empty() {
+        content.push_str(&format!("<p class=\"author\">{}</p>\n", escape(&info.author)));
+    }
+
+    if let Some(r) = info.reader.as_ref() {
+        let duration = r.reading_time();
+        let pages = r.pages_read();
+        content.push_str(&format!("<p class=\"summary\">{} read, {} turned, {}.</p>\n",
+                                  format_duration(duration), plural(pages, "page"),
+                                  plural(r.sessions.len(), "session")));
+        if pages > 0 {
+            content.push_str(&format!("<p class=\"summary\">{:.0} seconds per page.</p>\n",
+                                      duration as f32 / pages as f32));
+        }
+        if let Some(speed) = r.reading_speed().filter(|_| !r.finished) {
+            let left = (speed * r.pages_count.saturating_sub(r.current_page) as f32) as u64;
+            content.push_str(&format!("<p class=\"summary\">About {} left.</p>\n", format_duration(left)));
+        }
+
+        content.push_str("<ul class=\"sessions\">\n");
+        for session in r.sessions.iter().rev() {
+            content.push_str(&format!("<li><span class=\"date\">{}–{}</span> {}, {}</li>\n",
+                                      session.start.format("%Y-%m-%d %H:%M"), session.end.format("%H:%M"),
+                                      format_duration(session.duration), plural(session.pages, "page")));
+        }
+        content.push_str("</ul>\n");
+    }
+
+    content.push_str("<p class=\"navigation\"><a href=\"<\">Back</a></p>\n");
+
+    content
+}
+
+impl Statistics {
+    pub fn new(rect: Rectangle, hub: &Hub, context: &mut Context) -> Statistics {
+        let mut children = Vec::new();
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, _) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (small_thickness, big_thickness) = halves(thickness);
+
+        let top_bar = TopBar::new(rect![rect.min.x, rect.min.y,
+                                        rect.max.x, rect.min.y + small_height as i32 - small_thickness],
+                                  Event::Back,
+                                  "Statistics".to_string(),
+                                  context);
+        children.push(Box::new(top_bar) as Box<dyn View>);
+
+        let separator = Filler::new(rect![rect.min.x, rect.min.y + small_height as i32 - small_thickness,
+                                          rect.max.x, rect.min.y + small_height as i32 + big_thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let image_rect = rect![rect.min.x, rect.min.y + small_height as i32 + big_thickness,
+                               rect.max.x, rect.max.y - small_height as i32 - small_thickness];
+
+        let image = Image::new(image_rect, Pixmap::new(1, 1));
+        children.push(Box::new(image) as Box<dyn View>);
+
+        let mut doc = HtmlDocument::new_from_memory("");
+        doc.layout(image_rect.width(), image_rect.height(), context.settings.dictionary.font_size, dpi);
+        doc.set_margin_width(context.settings.dictionary.margin_width);
+        doc.set_viewer_stylesheet(VIEWER_STYLESHEET);
+        doc.set_user_stylesheet(USER_STYLESHEET);
+
+        let separator = Filler::new(rect![rect.min.x, rect.max.y - small_height as i32 - small_thickness,
+                                          rect.max.x, rect.max.y - small_height as i32 + big_thickness],
+                                    BLACK);
+        children.push(Box::new(separator) as Box<dyn View>);
+
+        let bottom_bar = PagedBottomBar::new(rect![rect.min.x, rect.max.y - small_height as i32 + big_thickness,
+                                                   rect.max.x, rect.max.y],
+                                             "", false, false);
+        children.push(Box::new(bottom_bar) as Box<dyn View>);
+
+        hub.send(Event::Render(rect, UpdateMode::Gui)).ok();
+
+        let mut statistics = Statistics {
+            rect,
+            children,
+            doc,
+            location: 0,
+            books: books_with_sessions(&context.metadata),
+            book: None,
+        };
+
+        statistics.update(hub, context);
+        statistics
+    }
+
+    fn reseed(&mut self, hub: &Hub, context: &mut Context) {
+        let (tx, _rx) = mpsc::channel();
+        if let Some(top_bar) = self.child_mut(0).downcast_mut::<TopBar>() {
+            top_bar.update_frontlight_icon(&tx, context);
+        }
+        hub.send(Event::ClockTick).ok();
+        hub.send(Event::BatteryTick).ok();
+        hub.send(Event::Render(self.rect, UpdateMode::Gui)).ok();
+    }
+
+    fn go_to_neighbor(&mut self, dir: CycleDir, hub: &Hub) {
+        let location = match dir {
+            CycleDir::Previous => Location::Previous(self.location),
+            CycleDir::Next => Location::Next(self.location),
+        };
+        if let Some(image) = self.children[2].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(location, 1.0) {
+                image.update(pixmap, hub);
+                self.location = loc;
+            }
+        }
+        if let Some(bottom_bar) = self.children[4].downcast_mut::<PagedBottomBar>() {
+            bottom_bar.update_icons(self.doc.resolve_location(Location::Previous(self.location)).is_some(),
+                                    self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+    }
+
+    fn update(&mut self, hub: &Hub, context: &mut Context) {
+        let info = self.book.as_ref()
+                       .and_then(|path| context.metadata.iter().find(|info| info.file.path == *path));
+        let (content, name) = match info {
+            Some(info) => (book_to_content(info), info.title.clone()),
+            None => (overview_to_content(&self.books, &context.metadata), "Overview".to_string()),
+        };
+        self.doc.update(&content);
+        if let Some(image) = self.children[2].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(Location::Exact(0), 1.0) {
+                image.update(pixmap, hub);
+                self.location = loc;
+            }
+        }
+        if let Some(bottom_bar) = self.children[4].downcast_mut::<PagedBottomBar>() {
+            bottom_bar.update_name(&name, hub);
+            bottom_bar.update_icons(false, self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+    }
+
+    fn follow_link(&mut self, pt: Point, hub: &Hub, context: &mut Context) {
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, _) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (_, big_thickness) = halves(thickness);
+        let offset = pt!(self.rect.min.x, self.rect.min.y + small_height as i32 + big_thickness);
+
+        let mut target = None;
+
+        if let Some((links, _)) = self.doc.links(Location::Exact(self.location)) {
+            for link in links {
+                let rect = link.rect.to_rect() + offset;
+                if rect.includes(pt) {
+                    target = Some(link.text);
+                    break;
+                }
+            }
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                let half_width = self.rect.width() as i32 / 2;
+                if pt.x - offset.x < half_width {
+                    self.go_to_neighbor(CycleDir::Previous, hub);
+                } else {
+                    self.go_to_neighbor(CycleDir::Next, hub);
+                }
+                return;
+            },
+        };
+
+        if target.starts_with('<') {
+            self.book = None;
+            self.update(hub, context);
+        } else if target.starts_with('@') {
+            if let Some(path) = target[1..].parse::<usize>().ok()
+                                           .and_then(|index| self.books.get(index)) {
+                self.book = Some(path.clone());
+                self.update(hub, context);
+            }
+        }
+    }
+}
+
+impl View for Statistics {
+    fn handle_event(&mut self, evt: &Event, hub: &Hub, _bus: &mut Bus, context: &mut Context) -> bool {
+        match *evt {
+            Event::Page(dir) => {
+                self.go_to_neighbor(dir, hub);
+                true
+            },
+            Event::Gesture(GestureEvent::Swipe { dir, start, .. }) if self.rect.includes(start) => {
+                match dir {
+                    Dir::West => self.go_to_neighbor(CycleDir::Next, hub),
+                    Dir::East => self.go_to_neighbor(CycleDir::Previous, hub),
+                    _ => (),
+                }
+                true
+            },
+            Event::Gesture(GestureEvent::Tap(center)) if self.rect.includes(center) => {
+                self.follow_link(center, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::MainMenu, rect) => {
+                toggle_main_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::BatteryMenu, rect) => {
+                toggle_battery_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::ToggleNear(ViewId::ClockMenu, rect) => {
+                toggle_clock_menu(self, rect, None, hub, context);
+                true
+            },
+            Event::Reseed => {
+                self.reseed(hub, context);
+                true
+            },
+            Event::Gesture(GestureEvent::Cross(_)) => {
+                hub.send(Event::Back).ok();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn render(&self, _fb: &mut dyn Framebuffer, _rect: Rectangle, _fonts: &mut Fonts) {
+    }
+
+    fn resize(&mut self, rect: Rectangle, hub: &Hub, context: &mut Context) {
+        let dpi = CURRENT_DEVICE.dpi;
+        let (_, height) = context.display.dims;
+        let &(small_height, _) = BAR_SIZES.get(&(height, dpi)).unwrap();
+        let thickness = scale_by_dpi(THICKNESS_MEDIUM, dpi) as i32;
+        let (small_thickness, big_thickness) = halves(thickness);
+
+        self.children[0].resize(rect![rect.min.x, rect.min.y,
+                                      rect.max.x, rect.min.y + small_height as i32 - small_thickness],
+                                hub, context);
+
+        self.children[1].resize(rect![rect.min.x, rect.min.y + small_height as i32 - small_thickness,
+                                      rect.max.x, rect.min.y + small_height as i32 + big_thickness],
+                                hub, context);
+
+        let image_rect = rect![rect.min.x, rect.min.y + small_height as i32 + big_thickness,
+                               rect.max.x, rect.max.y - small_height as i32 - small_thickness];
+        self.doc.layout(image_rect.width(), image_rect.height(), context.settings.dictionary.font_size, dpi);
+        if let Some(image) = self.children[2].downcast_mut::<Image>() {
+            if let Some((pixmap, loc)) = self.doc.pixmap(Location::Exact(self.location), 1.0) {
+                let (tx, _rx) = mpsc::channel();
+                image.update(pixmap, &tx);
+                self.location = loc;
+            }
+        }
+        self.children[2].resize(image_rect, hub, context);
+
+        self.children[3].resize(rect![rect.min.x, rect.max.y - small_height as i32 - small_thickness,
+                                      rect.max.x, rect.max.y - small_height as i32 + big_thickness],
+                                hub, context);
+
+        self.children[4].resize(rect![rect.min.x, rect.max.y - small_height as i32 + big_thickness,
+                                      rect.max.x, rect.max.y],
+                                hub, context);
+        if let Some(bottom_bar) = self.children[4].downcast_mut::<PagedBottomBar>() {
+            bottom_bar.update_icons(self.doc.resolve_location(Location::Previous(self.location)).is_some(),
+                                    self.doc.resolve_location(Location::Next(self.location)).is_some(), hub);
+        }
+
+        for i in 5..self.children.len() {
+            self.children[i].resize(rect, hub, context);
+        }
+
+        self.rect = rect;
+        hub.send(Event::Render(self.rect, UpdateMode::Full)).ok();
+    }
+
+    fn rect(&self) -> &Rectangle {
+        &self.rect
+    }
+
+    fn rect_mut(&mut self) -> &mut Rectangle {
+        &mut self.rect
+    }
+
+    fn children(&self) -> &Vec<Box<dyn View>> {
+        &self.children
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<Box<dyn View>> {
+        &mut self.children
+    }
+}
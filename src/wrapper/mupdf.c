@@ -1,4 +1,5 @@
 #include <mupdf/fitz.h>
+#include <mupdf/pdf.h>
 
 #define WRAP(name, ret_type, failure_val, call, ...) \
     ret_type mp_##name(fz_context *ctx, ##__VA_ARGS__) { \
@@ -14,5 +15,55 @@ WRAP(load_page, fz_page*, NULL, fz_load_page(ctx, doc, pageno), fz_document *doc
 WRAP(load_outline, fz_outline*, NULL, fz_load_outline(ctx, doc), fz_document *doc)
 WRAP(load_links, fz_link*, NULL, fz_load_links(ctx, page), fz_page *page)
 WRAP(count_pages, int, -1, fz_count_pages(ctx, doc), fz_document *doc)
+WRAP(authenticate_password, int, 0, fz_authenticate_password(ctx, doc, password), fz_document *doc, const char *password)
 WRAP(new_pixmap_from_page, fz_pixmap*, NULL, fz_new_pixmap_from_page(ctx, page, mat, cs, alpha), fz_page *page, fz_matrix mat, fz_colorspace *cs, int alpha)
 WRAP(new_stext_page_from_page, fz_stext_page*, NULL, fz_new_stext_page_from_page(ctx, page, options), fz_page *page, fz_stext_options *options)
+
+WRAP(annot_type, int, -1, pdf_annot_type(ctx, annot), pdf_annot *annot)
+WRAP(annot_contents, const char*, NULL, pdf_annot_contents(ctx, annot), pdf_annot *annot)
+WRAP(annot_quad_point_count, int, 0, pdf_annot_quad_point_count(ctx, annot), pdf_annot *annot)
+WRAP(bound_annot, fz_rect, fz_empty_rect, pdf_bound_annot(ctx, annot), pdf_annot *annot)
+
+int mp_annot_quad_point(fz_context *ctx, pdf_annot *annot, int i, fz_quad *quad) {
+    int ret = 0;
+    fz_try (ctx) { *quad = pdf_annot_quad_point(ctx, annot, i); }
+    fz_catch (ctx) { ret = -1; }
+    return ret;
+}
+
+int mp_create_highlight(fz_context *ctx, pdf_page *page, const fz_quad *quads, int count, const char *contents) {
+    int ret = 0;
+    pdf_annot *annot = NULL;
+    fz_var(annot);
+    fz_try (ctx) {
+        annot = pdf_create_annot(ctx, page, PDF_ANNOT_HIGHLIGHT);
+        for (int i = 0; i < count; i++) {
+            pdf_add_annot_quad_point(ctx, annot, quads[i]);
+        }
+        if (contents != NULL && contents[0] != '\0') {
+            pdf_set_annot_contents(ctx, annot, contents);
+        }
+        pdf_update_annot(ctx, annot);
+    }
+    fz_always (ctx) { pdf_drop_annot(ctx, annot); }
+    fz_catch (ctx) { ret = -1; }
+    return ret;
+}
+
+int mp_save_document(fz_context *ctx, pdf_document *doc, const char *path) {
+    int ret = 0;
+    pdf_write_options opts = { 0 };
+    fz_try (ctx) { pdf_save_document(ctx, doc, path, &opts); }
+    fz_catch (ctx) { ret = -1; }
+    return ret;
+}
+
+int mp_aes_crypt_cbc(const unsigned char key[32], int encrypt, unsigned char iv[16], const unsigned char *input, unsigned char *output, size_t len) {
+    fz_aes aes;
+    int ret = encrypt ? fz_aes_setkey_enc(&aes, key, 256) : fz_aes_setkey_dec(&aes, key, 256);
+    if (ret != 0) {
+        return -1;
+    }
+    fz_aes_crypt_cbc(&aes, encrypt ? FZ_AES_ENCRYPT : FZ_AES_DECRYPT, len, iv, input, output);
+    return 0;
+}
@@ -4,6 +4,9 @@ declare -A urls=(
 	# Compression
 	["zlib"]="https://zlib.net/zlib-1.2.11.tar.gz"
 	["bzip2"]="https://ftp.osuosl.org/pub/clfs/conglomeration/bzip2/bzip2-1.0.6.tar.gz"
+	["xz"]="https://tukaani.org/xz/xz-5.2.4.tar.gz"
+	# Archives
+	["libarchive"]="https://www.libarchive.org/downloads/libarchive-3.4.2.tar.gz"
 	# Images
 	["libpng"]="https://download.sourceforge.net/libpng/libpng-1.6.37.tar.gz"
 	["libjpeg"]="http://www.ijg.org/files/jpegsrc.v9c.tar.gz"
@@ -0,0 +1,13 @@
+#! /bin/sh
+
+TRIPLE=arm-linux-gnueabihf
+ZLIB_DIR=../zlib
+BZIP2_DIR=../bzip2
+XZ_DIR=../xz/src/liblzma
+export CFLAGS="-O2 -mcpu=cortex-a9 -mfpu=neon"
+export CXXFLAGS="$CFLAGS"
+export CPPFLAGS="-I${ZLIB_DIR} -I${BZIP2_DIR} -I${XZ_DIR}/api"
+export LDFLAGS="-L${ZLIB_DIR} -L${BZIP2_DIR} -L${XZ_DIR}/.libs"
+
+./configure --host=${TRIPLE} --disable-bsdtar --disable-bsdcpio --disable-bsdcat --disable-acl --disable-xattr \
+	--without-xml2 --without-expat --without-openssl --without-nettle --without-lz4 --without-zstd --without-lzo2 && make